@@ -0,0 +1,152 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use anyhow::{Result, Context};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditLogConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// JSON Lines file the entries are appended to, relative to the data directory
+    #[serde(default = "default_audit_log_path")]
+    pub path: String,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_audit_log_path(),
+        }
+    }
+}
+
+fn default_audit_log_path() -> String {
+    "logs/audit.jsonl".to_string()
+}
+
+/// One line of the audit log. Only summaries are recorded, never row data.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub run_id: String,
+    /// SHA-256 of the config file the sync ran with, if it was loaded from a file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum AuditEvent {
+    /// Rows one sync wrote to a table
+    Write {
+        endpoint: String,
+        table: String,
+        inserted: usize,
+        updated: usize,
+        deleted: usize,
+    },
+    /// DDL a backend ran on its own, such as schema evolution adding a column
+    SchemaChange {
+        backend: String,
+        table: String,
+        statement: String,
+    },
+}
+
+/// Append-only audit trail, kept apart from the rotating application log
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self { path: path.as_ref().to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append entries as JSON lines and flush them to disk. The file is opened in append
+    /// mode for every batch, so existing lines are never rewritten.
+    pub fn append(&self, entries: &[AuditEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create audit log directory: {}", parent.display()))?;
+        }
+
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open audit log: {}", self.path.display()))?;
+        file.write_all(lines.as_bytes())
+            .with_context(|| format!("Failed to write audit log: {}", self.path.display()))?;
+        file.sync_data()
+            .with_context(|| format!("Failed to flush audit log: {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = AuditLog::new(dir.path().join("logs").join("audit.jsonl"));
+
+        let entry = |event| AuditEntry {
+            timestamp: Utc::now(),
+            run_id: "run-1".to_string(),
+            config_hash: Some("abc".to_string()),
+            event,
+        };
+        let schema_change = entry(AuditEvent::SchemaChange {
+            backend: "SQLite".to_string(),
+            table: "devices".to_string(),
+            statement: "ALTER TABLE devices ADD COLUMN model TEXT".to_string(),
+        });
+        let write = entry(AuditEvent::Write {
+            endpoint: "devices".to_string(),
+            table: "devices".to_string(),
+            inserted: 3,
+            updated: 1,
+            deleted: 0,
+        });
+
+        audit_log.append(std::slice::from_ref(&schema_change)).unwrap();
+        audit_log.append(std::slice::from_ref(&write)).unwrap();
+        audit_log.append(&[]).unwrap();
+
+        let content = fs::read_to_string(audit_log.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(serde_json::from_str::<AuditEntry>(lines[0]).unwrap(), schema_change);
+        assert_eq!(serde_json::from_str::<AuditEntry>(lines[1]).unwrap(), write);
+
+        let json: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(json["event"], "write");
+        assert_eq!(json["runId"], "run-1");
+        assert_eq!(json["configHash"], "abc");
+        assert_eq!(json["inserted"], 3);
+    }
+}
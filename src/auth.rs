@@ -1,13 +1,26 @@
 use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use chrono::{DateTime, Utc};
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use reqwest::Client;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use rustls_pemfile::Item;
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::io::BufReader;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-use crate::config::AppConfig;
+use crate::config::{AppConfig, AuthMode};
+use crate::error::AuthError;
 use crate::metrics;
+use crate::webhook::WebhookManager;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TokenResponse {
@@ -17,28 +30,153 @@ struct TokenResponse {
     scope: String,
 }
 
+/// Seconds before its expiry a cached token is replaced
+const TOKEN_REFRESH_MARGIN_SECONDS: i64 = 60;
+
+/// Access token shared by all requests until shortly before it expires
 #[derive(Debug, Clone)]
-pub struct AccessToken {
+pub struct CachedToken {
     pub token: String,
     pub expires_at: DateTime<Utc>,
+    /// Scope (or resource) the token was issued for
+    pub scope: String,
 }
 
-impl AccessToken {
+impl CachedToken {
     pub fn is_expired(&self) -> bool {
         Utc::now() >= self.expires_at
     }
 
     pub fn is_expiring_soon(&self) -> bool {
-        // Consider token expiring if it expires within 5 minutes
-        Utc::now() + chrono::Duration::minutes(5) >= self.expires_at
+        Utc::now() + chrono::Duration::seconds(TOKEN_REFRESH_MARGIN_SECONDS) >= self.expires_at
+    }
+
+    /// Whether the token can still be sent for `scope`
+    fn is_valid_for(&self, scope: &str) -> bool {
+        self.scope == scope && !self.is_expiring_soon()
     }
 }
 
+/// Azure AD host client credential tokens are requested from
+const AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+
+/// Token endpoint of the Azure instance metadata service, which hands out managed identity tokens
+const IMDS_TOKEN_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// IMDS API version the token request is made with
+const IMDS_API_VERSION: &str = "2018-02-01";
+
+/// Token issued by the instance metadata service, which sends `expires_in` as a string
+#[derive(Debug, Deserialize)]
+struct ManagedIdentityTokenResponse {
+    access_token: String,
+    expires_in: serde_json::Value,
+}
+
+/// `client_assertion_type` of a token request authenticated with a signed JWT
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// How long a client assertion is accepted for
+const CLIENT_ASSERTION_LIFETIME_MINUTES: i64 = 10;
+
+/// Certificate credential of the app: the key that signs client assertions and the SHA-1
+/// thumbprint Azure AD looks the registered certificate up by
+pub struct ClientCertificate {
+    signing_key: SigningKey<Sha256>,
+    thumbprint: Vec<u8>,
+}
+
+impl ClientCertificate {
+    /// Read the RSA private key (PKCS#8 or PKCS#1) from a PEM file, and the certificate
+    /// to compute the thumbprint from unless `thumbprint` is given
+    pub fn load(path: &str, thumbprint: Option<&str>) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open certificate file {}", path))?;
+        let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+            .with_context(|| format!("Failed to parse certificate file {}", path))?;
+
+        let mut key = None;
+        let mut certificate = None;
+        for item in items {
+            match item {
+                Item::PKCS8Key(der) if key.is_none() => {
+                    key = Some(RsaPrivateKey::from_pkcs8_der(&der)
+                        .with_context(|| format!("Private key in {} is not an RSA key", path))?);
+                }
+                Item::RSAKey(der) if key.is_none() => {
+                    key = Some(RsaPrivateKey::from_pkcs1_der(&der)
+                        .with_context(|| format!("Failed to parse RSA private key in {}", path))?);
+                }
+                Item::X509Certificate(der) if certificate.is_none() => certificate = Some(der),
+                _ => {}
+            }
+        }
+
+        let key = key.with_context(|| format!("No PEM RSA private key found in {}", path))?;
+        let thumbprint = match thumbprint {
+            Some(thumbprint) => parse_thumbprint(thumbprint)?,
+            None => {
+                let certificate = certificate.with_context(|| {
+                    format!("No PEM certificate found in {} - add it or set certificateThumbprint", path)
+                })?;
+                Sha1::digest(&certificate).to_vec()
+            }
+        };
+
+        Ok(Self {
+            signing_key: SigningKey::new(key),
+            thumbprint,
+        })
+    }
+
+    /// RS256-signed JWT identifying `client_id` to the token endpoint at `token_url`
+    pub fn client_assertion(&self, client_id: &str, token_url: &str, now: DateTime<Utc>) -> Result<String> {
+        let header = serde_json::json!({
+            "alg": "RS256",
+            "typ": "JWT",
+            "x5t": URL_SAFE_NO_PAD.encode(&self.thumbprint),
+        });
+        let claims = serde_json::json!({
+            "aud": token_url,
+            "iss": client_id,
+            "sub": client_id,
+            "jti": uuid::Uuid::new_v4().to_string(),
+            "nbf": now.timestamp(),
+            "exp": (now + chrono::Duration::minutes(CLIENT_ASSERTION_LIFETIME_MINUTES)).timestamp(),
+        });
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = self.signing_key.try_sign(signing_input.as_bytes())
+            .context("Failed to sign client assertion")?;
+        Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.to_bytes())))
+    }
+}
+
+/// Thumbprint bytes from hex, ignoring the separators some tools print between bytes
+fn parse_thumbprint(thumbprint: &str) -> Result<Vec<u8>> {
+    let digits: String = thumbprint.chars().filter(|c| !matches!(c, ':' | ' ' | '-')).collect();
+    let bytes = hex::decode(&digits)
+        .with_context(|| format!("Certificate thumbprint {} is not hex", thumbprint))?;
+    if bytes.len() != 20 {
+        anyhow::bail!("Certificate thumbprint {} is not a 40 digit SHA-1 thumbprint", thumbprint);
+    }
+    Ok(bytes)
+}
+
 #[derive(Clone, Debug)]
 pub struct AuthClient {
     config: AppConfig,
     client: Client,
-    token: Arc<RwLock<Option<AccessToken>>>,
+    /// Client for the metadata service, which bypasses any HTTP(S)_PROXY since a proxy
+    /// cannot reach the link-local IMDS address
+    imds_client: Client,
+    token: Arc<RwLock<Option<CachedToken>>>,
+    authority_host: String,
+    imds_endpoint: String,
+    webhooks: Option<Arc<WebhookManager>>,
 }
 
 impl AuthClient {
@@ -47,54 +185,133 @@ impl AuthClient {
             .timeout(std::time::Duration::from_secs(30))
             .build()
             .expect("Failed to create HTTP client");
+        let imds_client = Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .no_proxy()
+            .build()
+            .expect("Failed to create HTTP client");
 
         Self {
             config,
             client,
+            imds_client,
             token: Arc::new(RwLock::new(None)),
+            authority_host: AUTHORITY_HOST.to_string(),
+            imds_endpoint: IMDS_TOKEN_ENDPOINT.to_string(),
+            webhooks: None,
         }
     }
 
+    /// Send an `authentication_failed` webhook whenever a token request fails
+    pub fn with_webhooks(mut self, webhooks: Option<Arc<WebhookManager>>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Request client credential tokens from `host` instead of Azure AD
+    #[cfg(test)]
+    pub fn with_authority_host(mut self, host: &str) -> Self {
+        self.authority_host = host.to_string();
+        self
+    }
+
+    /// Request managed identity tokens from `endpoint` instead of the metadata service
+    #[cfg(test)]
+    pub fn with_imds_endpoint(mut self, endpoint: &str) -> Self {
+        self.imds_endpoint = endpoint.to_string();
+        self
+    }
+
+    /// Client that always hands out the given token, for tests against a local server
+    #[cfg(test)]
+    pub fn with_static_token(config: AppConfig, token: &str) -> Self {
+        let client = Self::new(config);
+        *client.token.try_write().expect("new client is not shared yet") = Some(CachedToken {
+            token: token.to_string(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            scope: client.scope(),
+        });
+        client
+    }
+
+    /// Scope tokens are requested for, which the cached token has to match
+    fn scope(&self) -> String {
+        self.config.scopes.join(" ")
+    }
+
+    /// Cached token, refreshed shortly before it expires. Concurrent callers share one
+    /// refresh: they queue on the write lock and find the token the first one fetched.
     pub async fn get_access_token(&self) -> Result<String> {
-        // Check if we have a valid token
+        let scope = self.scope();
         {
             let token_guard = self.token.read().await;
-            if let Some(ref token) = *token_guard {
-                if !token.is_expiring_soon() {
-                    debug!("Using cached access token");
-                    return Ok(token.token.clone());
-                }
+            if let Some(token) = token_guard.as_ref().filter(|token| token.is_valid_for(&scope)) {
+                debug!("Using cached access token");
+                return Ok(token.token.clone());
             }
         }
 
-        // Need to refresh the token
-        info!("Refreshing access token");
-        let new_token = self.refresh_token().await?;
-        
-        // Update the cached token
-        {
-            let mut token_guard = self.token.write().await;
-            *token_guard = Some(new_token.clone());
+        let mut token_guard = self.token.write().await;
+        if let Some(token) = token_guard.as_ref().filter(|token| token.is_valid_for(&scope)) {
+            debug!("Using access token refreshed by a concurrent request");
+            return Ok(token.token.clone());
         }
 
-        metrics::TOKEN_REFRESH_TOTAL.inc();
+        info!("Refreshing access token");
+        let new_token = match self.refresh_token().await {
+            Ok(token) => token,
+            Err(e) => {
+                self.report_failure(&e);
+                return Err(e);
+            }
+        };
+        *token_guard = Some(new_token.clone());
+
+        metrics::AUTH_TOKEN_REFRESH_TOTAL.inc();
         Ok(new_token.token)
     }
 
-    async fn refresh_token(&self) -> Result<AccessToken> {
-        let token_url = format!(
-            "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
-            self.config.tenant_id
-        );
+    /// Count a failed token request and announce it, with the Azure AD reason when
+    /// the token endpoint gave one
+    fn report_failure(&self, error: &anyhow::Error) {
+        metrics::AUTH_FAILURE_TOTAL.inc();
+
+        let Some(webhooks) = &self.webhooks else { return };
+        let webhooks = Arc::clone(webhooks);
+        let message = format!("{:#}", error);
+        let tenant_id = self.config.tenant_id.clone();
+        let reason_code = error.downcast_ref::<AuthError>().map(|e| e.reason_code().to_string());
+        tokio::spawn(async move {
+            if let Err(e) = webhooks.send_authentication_failed(message, tenant_id, reason_code).await {
+                warn!("Failed to send authentication failed webhook: {}", e);
+            }
+        });
+    }
+
+    async fn refresh_token(&self) -> Result<CachedToken> {
+        if self.config.auth_mode == AuthMode::ManagedIdentity {
+            return self.refresh_managed_identity_token().await;
+        }
+
+        let token_url = format!("{}/{}/oauth2/v2.0/token", self.authority_host, self.config.tenant_id);
 
-        let params = [
-            ("client_id", &self.config.client_id),
-            ("client_secret", &self.config.client_secret),
-            ("scope", &"https://graph.microsoft.com/.default".to_string()),
-            ("grant_type", &"client_credentials".to_string()),
+        let scope = self.scope();
+        let mut params = vec![
+            ("client_id", self.config.client_id.clone()),
+            ("scope", scope.clone()),
+            ("grant_type", "client_credentials".to_string()),
         ];
+        // Read on every refresh, so a renewed certificate is picked up without a restart
+        match self.config.certificate_path.as_deref() {
+            Some(path) => {
+                let certificate = ClientCertificate::load(path, self.config.certificate_thumbprint.as_deref())?;
+                params.push(("client_assertion_type", CLIENT_ASSERTION_TYPE.to_string()));
+                params.push(("client_assertion", certificate.client_assertion(&self.config.client_id, &token_url, Utc::now())?));
+            }
+            None => params.push(("client_secret", self.config.client_secret.clone())),
+        }
 
-        debug!("Requesting access token from: {}", token_url);
+        debug!("Requesting access token from: {} (scope: {})", token_url, scope);
 
         let response = self
             .client
@@ -107,12 +324,15 @@ impl AuthClient {
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_default();
-            warn!("Token request failed with status {}: {}", status, error_text);
-            return Err(anyhow::anyhow!(
-                "Token request failed with status {}: {}",
-                status,
-                error_text
-            ));
+            let auth_error = AuthError::from_token_response(status.as_u16(), &error_text);
+            error!(
+                "Token request failed [{}]: {} - {}. {}",
+                auth_error.reason_code(),
+                auth_error,
+                auth_error.guidance(),
+                auth_error.description()
+            );
+            return Err(auth_error.into());
         }
 
         let token_response: TokenResponse = response
@@ -124,9 +344,60 @@ impl AuthClient {
 
         info!("Successfully obtained access token, expires at: {}", expires_at);
 
-        Ok(AccessToken {
+        Ok(CachedToken {
             token: token_response.access_token,
             expires_at,
+            scope,
+        })
+    }
+
+    /// Get a token for the VM's or container's managed identity from the instance metadata
+    /// service; a `clientId` selects a user-assigned identity
+    async fn refresh_managed_identity_token(&self) -> Result<CachedToken> {
+        // IMDS asks for the resource, not a scope
+        let resource = self.config.scopes.first()
+            .map(|scope| scope.trim_end_matches(".default").to_string())
+            .context("No token scope configured")?;
+        let mut query = vec![("api-version", IMDS_API_VERSION), ("resource", resource.as_str())];
+        if !self.config.client_id.is_empty() {
+            query.push(("client_id", self.config.client_id.as_str()));
+        }
+
+        debug!("Requesting managed identity token from: {} (resource: {})", self.imds_endpoint, resource);
+
+        let response = self
+            .imds_client
+            .get(&self.imds_endpoint)
+            .header("Metadata", "true")
+            .query(&query)
+            .send()
+            .await
+            .context("Failed to reach the instance metadata service for a managed identity token")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            let auth_error = AuthError::from_token_response(status.as_u16(), &error_text);
+            error!("Managed identity token request failed [{}]: {}", auth_error.reason_code(), auth_error);
+            return Err(auth_error.into());
+        }
+
+        let token_response: ManagedIdentityTokenResponse = response
+            .json()
+            .await
+            .context("Failed to parse managed identity token response")?;
+        let expires_in = token_response.expires_in.as_u64()
+            .or_else(|| token_response.expires_in.as_str()?.parse().ok())
+            .context("Managed identity token response has no valid expires_in")?;
+
+        let expires_at = Utc::now() + chrono::Duration::seconds(expires_in as i64);
+
+        info!("Successfully obtained managed identity token, expires at: {}", expires_at);
+
+        Ok(CachedToken {
+            token: token_response.access_token,
+            expires_at,
+            scope: self.scope(),
         })
     }
 
@@ -144,7 +415,7 @@ impl AuthClient {
 
         if response.status() == 401 {
             // Token might be invalid, clear cache and retry once
-            warn!("Received 401, clearing token cache and retrying");
+            log::warn!("Received 401, clearing token cache and retrying");
             {
                 let mut token_guard = self.token.write().await;
                 *token_guard = None;
@@ -174,28 +445,216 @@ mod tests {
 
     #[test]
     fn test_token_expiry() {
-        let token = AccessToken {
+        let token = CachedToken {
             token: "test_token".to_string(),
             expires_at: Utc::now() + Duration::hours(1),
+            scope: "https://graph.microsoft.com/.default".to_string(),
         };
         
         assert!(!token.is_expired());
+        assert!(token.is_valid_for("https://graph.microsoft.com/.default"));
+        assert!(!token.is_valid_for("https://management.azure.com/.default"));
         assert!(!token.is_expiring_soon());
         
-        let expiring_token = AccessToken {
+        let expiring_token = CachedToken {
             token: "test_token".to_string(),
-            expires_at: Utc::now() + Duration::minutes(2),
+            expires_at: Utc::now() + Duration::seconds(30),
+            scope: String::new(),
         };
         
         assert!(!expiring_token.is_expired());
         assert!(expiring_token.is_expiring_soon());
         
-        let expired_token = AccessToken {
+        let expired_token = CachedToken {
             token: "test_token".to_string(),
             expires_at: Utc::now() - Duration::minutes(1),
+            scope: String::new(),
         };
         
         assert!(expired_token.is_expired());
         assert!(expired_token.is_expiring_soon());
     }
+
+    #[test]
+    fn test_client_assertion() {
+        use rsa::pkcs1v15::{Signature, VerifyingKey};
+        use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+        use rsa::signature::Verifier;
+
+        let key = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 1024).unwrap();
+        let mut key_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut key_file, key.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+        let path = key_file.path().to_string_lossy().into_owned();
+
+        // Without a certificate in the file the thumbprint has to be configured
+        assert!(ClientCertificate::load(&path, None).is_err());
+        assert!(ClientCertificate::load(&path, Some("not-hex")).is_err());
+        let thumbprint = "00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:00:11:22:33";
+        let certificate = ClientCertificate::load(&path, Some(thumbprint)).unwrap();
+
+        let token_url = "https://login.microsoftonline.com/tenant/oauth2/v2.0/token";
+        let now = Utc::now();
+        let assertion = certificate.client_assertion("client", token_url, now).unwrap();
+        let parts: Vec<&str> = assertion.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let decode = |part: &str| -> serde_json::Value {
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+        };
+
+        let header = decode(parts[0]);
+        assert_eq!(header["alg"], "RS256");
+        assert_eq!(header["typ"], "JWT");
+        let x5t = URL_SAFE_NO_PAD.decode(header["x5t"].as_str().unwrap()).unwrap();
+        assert_eq!(hex::encode_upper(x5t), thumbprint.replace(':', ""));
+
+        let claims = decode(parts[1]);
+        assert_eq!(claims["aud"], token_url);
+        assert_eq!(claims["iss"], "client");
+        assert_eq!(claims["sub"], "client");
+        assert!(claims["jti"].as_str().is_some_and(|jti| !jti.is_empty()));
+        assert_eq!(claims["nbf"], now.timestamp());
+        assert_eq!(claims["exp"], now.timestamp() + 600);
+
+        let signature = Signature::try_from(URL_SAFE_NO_PAD.decode(parts[2]).unwrap().as_slice()).unwrap();
+        let verifying_key = VerifyingKey::<Sha256>::new(key.to_public_key());
+        let signing_input = format!("{}.{}", parts[0], parts[1]);
+        assert!(verifying_key.verify(signing_input.as_bytes(), &signature).is_ok());
+        assert!(verifying_key.verify(b"tampered", &signature).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_credentials_token_cached() {
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            tenant_id: "tenant".to_string(),
+            ..Default::default()
+        };
+        let token_endpoint = server.mock("POST", "/tenant/oauth2/v2.0/token")
+            .match_body(mockito::Matcher::UrlEncoded("grant_type".into(), "client_credentials".into()))
+            .with_body(r#"{"access_token": "cc-token", "expires_in": 3599, "token_type": "Bearer", "scope": "https://graph.microsoft.com/.default"}"#)
+            .expect(1)
+            .create_async().await;
+
+        let client = AuthClient::new(config).with_authority_host(&server.url());
+        // Concurrent callers on an empty cache wait for one refresh instead of each
+        // requesting a token
+        let shared = client.clone();
+        let (first, second) = tokio::join!(client.get_access_token(), shared.get_access_token());
+        assert_eq!(first.unwrap(), "cc-token");
+        assert_eq!(second.unwrap(), "cc-token");
+        // Cached until it is about to expire
+        assert_eq!(client.get_access_token().await.unwrap(), "cc-token");
+        token_endpoint.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_managed_identity_token() {
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            auth_mode: AuthMode::ManagedIdentity,
+            client_id: "11111111-2222-3333-4444-555555555555".to_string(),
+            ..Default::default()
+        };
+        let imds = server.mock("GET", "/metadata/identity/oauth2/token")
+            .match_header("metadata", "true")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("api-version".into(), IMDS_API_VERSION.into()),
+                mockito::Matcher::UrlEncoded("resource".into(), "https://graph.microsoft.com/".into()),
+                mockito::Matcher::UrlEncoded("client_id".into(), "11111111-2222-3333-4444-555555555555".into()),
+            ]))
+            .with_body(r#"{"access_token": "mi-token", "expires_in": "3599", "token_type": "Bearer"}"#)
+            .expect(1)
+            .create_async().await;
+
+        let client = AuthClient::new(config)
+            .with_imds_endpoint(&format!("{}/metadata/identity/oauth2/token", server.url()));
+        assert_eq!(client.get_access_token().await.unwrap(), "mi-token");
+        // Cached until it is about to expire
+        assert_eq!(client.get_access_token().await.unwrap(), "mi-token");
+        imds.assert_async().await;
+
+        let expires_at = client.token.read().await.as_ref().unwrap().expires_at;
+        assert!(expires_at > Utc::now() + Duration::minutes(59));
+    }
+
+    #[tokio::test]
+    async fn test_token_failure_sends_webhook() {
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            tenant_id: "tenant".to_string(),
+            ..Default::default()
+        };
+        server.mock("POST", "/tenant/oauth2/v2.0/token")
+            .with_status(401)
+            .with_body(r#"{"error":"invalid_client","error_description":"AADSTS7000215: Invalid client secret provided.","error_codes":[7000215]}"#)
+            .create_async().await;
+        let webhook = server.mock("POST", "/webhook")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "event": "authentication_failed",
+                "data": {"tenant_id": "tenant", "reason_code": "invalid_client"}
+            })))
+            .create_async().await;
+        let webhooks = WebhookManager::new(crate::webhook::WebhookConfig {
+            enabled: true,
+            url: format!("{}/webhook", server.url()),
+            events: vec![crate::webhook::WebhookEvent::AuthenticationFailed],
+            ..Default::default()
+        });
+
+        let failures = metrics::AUTH_FAILURE_TOTAL.get();
+        let client = AuthClient::new(config)
+            .with_authority_host(&server.url())
+            .with_webhooks(Some(Arc::new(webhooks)));
+        assert!(client.get_access_token().await.is_err());
+        assert!(metrics::AUTH_FAILURE_TOTAL.get() > failures);
+
+        // The webhook is sent in the background
+        for _ in 0..50 {
+            if webhook.matched_async().await {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        webhook.assert_async().await;
+    }
+
+    #[test]
+    fn test_auth_error_from_token_response() {
+        let body = r#"{"error":"invalid_client","error_description":"AADSTS7000222: The provided client secret keys for app '1234' are expired.","error_codes":[7000222]}"#;
+        let err = AuthError::from_token_response(401, body);
+        assert!(matches!(err, AuthError::ExpiredSecret { .. }));
+        assert_eq!(err.reason_code(), "expired_secret");
+        assert!(err.description().contains("expired"));
+
+        let body = r#"{"error":"invalid_client","error_description":"AADSTS7000215: Invalid client secret provided.","error_codes":[7000215]}"#;
+        assert_eq!(AuthError::from_token_response(401, body).reason_code(), "invalid_client");
+
+        let body = r#"{"error":"invalid_request","error_description":"AADSTS90002: Tenant 'contoso' not found.","error_codes":[90002]}"#;
+        assert_eq!(AuthError::from_token_response(400, body).reason_code(), "tenant_not_found");
+
+        let body = r#"{"error":"invalid_grant","error_description":"AADSTS90072: User account 'user@fabrikam.com' from identity provider 'live.com' does not exist in tenant 'Contoso'.","error_codes":[90072]}"#;
+        let err = AuthError::from_token_response(400, body);
+        assert!(matches!(err, AuthError::AccountNotInTenant { .. }));
+        assert_eq!(err.reason_code(), "account_not_in_tenant");
+
+        let body = r#"{"error":"invalid_grant","error_description":"AADSTS53003: Access has been blocked by Conditional Access policies.","error_codes":[53003]}"#;
+        let err = AuthError::from_token_response(400, body);
+        assert_eq!(err.reason_code(), "conditional_access");
+        assert_eq!(err.to_string(), "blocked by conditional access (AADSTS53003)");
+    }
+
+    #[test]
+    fn test_auth_error_unknown_response() {
+        let err = AuthError::from_token_response(500, "upstream unavailable");
+        assert_eq!(err.reason_code(), "token_request_failed");
+        assert_eq!(err.description(), "upstream unavailable");
+
+        // Errors survive being wrapped in anyhow context
+        let wrapped = anyhow::Error::from(err).context("Failed to get access token");
+        assert!(wrapped.downcast_ref::<AuthError>().is_some());
+    }
 }
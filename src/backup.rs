@@ -15,6 +15,9 @@ pub struct BackupConfig {
     pub schedule_enabled: bool,
     #[serde(rename = "scheduleInterval")]
     pub schedule_interval: Option<String>,
+    /// Copy the SQLite database before every sync, so a bad sync can be rolled back
+    #[serde(rename = "backupBeforeSync", default)]
+    pub backup_before_sync: bool,
 }
 
 impl Default for BackupConfig {
@@ -25,6 +28,7 @@ impl Default for BackupConfig {
             max_backups: 10,
             schedule_enabled: true,
             schedule_interval: Some("24h".to_string()),
+            backup_before_sync: false,
         }
     }
 }
@@ -0,0 +1,215 @@
+use std::path::PathBuf;
+use std::time::Instant;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::config::{AppConfig, DatabaseConfig, SqliteConfig};
+use crate::endpoint::{EndpointConfig, EndpointsConfig, PredefinedEndpoints};
+use crate::mock_graph_api::MockGraphApiConfig;
+use crate::storage::{self, BACKEND_KINDS};
+use crate::sync::SyncService;
+
+/// Table the benchmark writes to, dropped before and after each run
+pub const BENCH_TABLE: &str = "bench_devices";
+
+/// Endpoint name of the benchmark, so it never shares sync state with a real endpoint
+const BENCH_ENDPOINT: &str = "bench";
+
+/// Results of one `bench` run
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchReport {
+    /// `sqlite` for a temporary SQLite database, otherwise the configured backend
+    pub backend: String,
+    pub devices: u32,
+    /// Rows in the table after the sync
+    pub rows_stored: i64,
+    pub duration_seconds: f64,
+    pub rows_per_second: f64,
+    /// Highest resident memory of the process, where the platform reports it
+    pub peak_memory_bytes: Option<u64>,
+    /// Size of the table on disk (of the whole temporary database for SQLite)
+    pub database_size_bytes: Option<i64>,
+}
+
+impl BenchReport {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("Backend:        {}\n", self.backend));
+        out.push_str(&format!("Devices:        {}\n", self.devices));
+        out.push_str(&format!("Rows stored:    {}\n", self.rows_stored));
+        out.push_str(&format!("Duration:       {:.2}s\n", self.duration_seconds));
+        out.push_str(&format!("Throughput:     {:.0} rows/s\n", self.rows_per_second));
+        out.push_str(&format!("Peak memory:    {}\n", format_bytes(self.peak_memory_bytes.map(|b| b as i64))));
+        out.push_str(&format!("Database size:  {}\n", format_bytes(self.database_size_bytes)));
+        out
+    }
+}
+
+fn format_bytes(bytes: Option<i64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Sync `devices` mock devices through the real fetch, filter and store pipeline into
+/// `backend` and measure it. `sqlite` uses a temporary database; `postgres` and `mssql`
+/// use the connection from `config` and write to [`BENCH_TABLE`] there.
+pub async fn run_benchmark(config: &AppConfig, devices: u32, backend: &str) -> Result<BenchReport> {
+    let backend = backend.to_lowercase();
+    if !BACKEND_KINDS.contains(&backend.as_str()) {
+        anyhow::bail!("Unknown backend '{}', expected one of: {}", backend, BACKEND_KINDS.join(", "));
+    }
+
+    let temp_sqlite = (backend == "sqlite")
+        .then(|| std::env::temp_dir().join(format!("msgraph-bench-{}.db", uuid::Uuid::new_v4())));
+    let database = bench_database(&config.database, &backend, temp_sqlite.as_ref())?;
+    let bench_config = AppConfig {
+        mock_graph_api: Some(MockGraphApiConfig {
+            enabled: true,
+            response_delay_ms: (0, 0),
+            ..Default::default()
+        }),
+        endpoints: Some(EndpointsConfig {
+            endpoints: vec![bench_endpoint(devices)],
+            ..Default::default()
+        }),
+        database,
+        device_os_filter: vec!["*".to_string()],
+        stale_device_max_age_days: None,
+        webhook: None,
+        backup: None,
+        audit_log: None,
+        rate_limit: None,
+        record_responses: false,
+        replay_from: None,
+        ..config.clone()
+    };
+
+    let result = measure(&bench_config, devices, &backend).await;
+    if let Some(ref path) = temp_sqlite {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+    result
+}
+
+async fn measure(config: &AppConfig, devices: u32, backend: &str) -> Result<BenchReport> {
+    // A table left by an earlier run would turn every insert into a skipped unchanged row
+    drop_bench_table(&config.database, backend).await?;
+
+    let mut sync_service = SyncService::new(config.clone()).await?;
+    info!("Benchmarking a sync of {} mock devices into {}", devices, backend);
+    let started = Instant::now();
+    let result = sync_service.sync_once().await;
+    let duration = started.elapsed();
+    if let Err(e) = sync_service.cleanup().await {
+        warn!("Error during cleanup: {}", e);
+    }
+    result?;
+
+    let mut storage = storage::connect_backend(&config.database, backend).await?
+        .with_context(|| format!("No {} backend is configured under database", backend))?;
+    let rows_stored = storage.get_table_count(BENCH_TABLE).await?;
+    let database_size_bytes = storage.table_size_bytes(BENCH_TABLE).await
+        .unwrap_or_else(|e| {
+            warn!("Could not read the size of table {}: {}", BENCH_TABLE, e);
+            None
+        });
+    storage.drop_table(BENCH_TABLE).await?;
+    storage.cleanup().await?;
+
+    let duration_seconds = duration.as_secs_f64();
+    Ok(BenchReport {
+        backend: backend.to_string(),
+        devices,
+        rows_stored,
+        duration_seconds,
+        rows_per_second: if duration_seconds > 0.0 { rows_stored as f64 / duration_seconds } else { 0.0 },
+        peak_memory_bytes: peak_memory_bytes(),
+        database_size_bytes,
+    })
+}
+
+fn bench_endpoint(devices: u32) -> EndpointConfig {
+    EndpointConfig {
+        name: BENCH_ENDPOINT.to_string(),
+        table_name: BENCH_TABLE.to_string(),
+        mock_object_count: Some(devices),
+        mock_config: None,
+        ..PredefinedEndpoints::managed_devices()
+    }
+}
+
+/// Only the benchmarked backend, enabled: a temporary SQLite file or the configured server
+fn bench_database(database: &DatabaseConfig, backend: &str, temp_sqlite: Option<&PathBuf>) -> Result<DatabaseConfig> {
+    let mut bench = DatabaseConfig {
+        sqlite: None,
+        postgres: None,
+        mssql: None,
+        ..database.clone()
+    };
+    match backend {
+        "sqlite" => {
+            let path = temp_sqlite.context("No temporary SQLite path")?;
+            bench.sqlite = Some(SqliteConfig {
+                enabled: true,
+                database_path: path.to_string_lossy().to_string(),
+                vacuum_into: None,
+            });
+        }
+        "postgres" => {
+            let postgres = database.postgres.clone().context("No postgres backend is configured under database")?;
+            bench.postgres = Some(crate::config::PostgresConfig {
+                enabled: true,
+                read_replica_connection_string: None,
+                ..postgres
+            });
+        }
+        _ => {
+            let mssql = database.mssql.clone().context("No mssql backend is configured under database")?;
+            bench.mssql = Some(crate::config::MssqlConfig { enabled: true, ..mssql });
+        }
+    }
+    Ok(bench)
+}
+
+async fn drop_bench_table(database: &DatabaseConfig, backend: &str) -> Result<()> {
+    if let Some(mut storage) = storage::connect_backend(database, backend).await? {
+        storage.drop_table(BENCH_TABLE).await?;
+        storage.cleanup().await?;
+    }
+    Ok(())
+}
+
+/// Peak resident set size (`VmHWM`) on Linux; `None` elsewhere
+fn peak_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.trim_start_matches("VmHWM:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_benchmark_sqlite() {
+        let report = run_benchmark(&AppConfig::default(), 250, "SQLite").await.unwrap();
+        assert_eq!(report.backend, "sqlite");
+        assert_eq!(report.rows_stored, 250);
+        assert!(report.rows_per_second > 0.0);
+        assert!(report.database_size_bytes.is_some_and(|size| size > 0));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["rowsStored"], 250);
+
+        assert!(run_benchmark(&AppConfig::default(), 10, "oracle").await.is_err());
+        // No postgres section to connect with
+        assert!(run_benchmark(&AppConfig::default(), 10, "postgres").await.is_err());
+    }
+}
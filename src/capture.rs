@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// One Graph (or mock) response page as written by `recordResponses`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedResponse {
+    pub endpoint: String,
+    /// Position among the requests made for this endpoint, starting at 1
+    pub sequence: u32,
+    pub url: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    pub body: serde_json::Value,
+}
+
+/// Writes every response page to `<dir>/<endpoint>/<sequence>.json`, so a sync can be
+/// replayed later with `replayFrom`
+#[derive(Debug)]
+pub struct ResponseRecorder {
+    dir: PathBuf,
+    sequences: Mutex<HashMap<String, u32>>,
+}
+
+impl ResponseRecorder {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into(), sequences: Mutex::default() }
+    }
+
+    /// Number the next sync's responses from 1 again
+    pub fn reset(&self) {
+        self.sequences.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// Write one response with tokens redacted. A failed write is logged and never
+    /// fails the sync.
+    pub fn record(&self, endpoint: &str, url: &str, status: u16, etag: Option<&str>, body: &serde_json::Value) {
+        let sequence = next_sequence(&self.sequences, endpoint);
+        if sequence == 1 {
+            // Pages of an earlier, longer capture would otherwise be replayed after this one's
+            let path = response_path(&self.dir, endpoint, sequence);
+            if let Some(endpoint_dir) = path.parent() {
+                match fs::remove_dir_all(endpoint_dir) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => warn!(
+                        "Failed to clear earlier responses of endpoint {} in {}: {}", endpoint, endpoint_dir.display(), e
+                    ),
+                    _ => {}
+                }
+            }
+        }
+        let mut body = body.clone();
+        redact_tokens(&mut body);
+        let response = RecordedResponse {
+            endpoint: endpoint.to_string(),
+            sequence,
+            url: crate::logging::sanitize_log_message(url),
+            status,
+            etag: etag.map(str::to_string),
+            body,
+        };
+
+        let path = response_path(&self.dir, endpoint, sequence);
+        let written = path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&path, serde_json::to_vec_pretty(&response).unwrap_or_default()));
+        match written {
+            Ok(()) => debug!("Recorded response {} of endpoint {} to {}", sequence, endpoint, path.display()),
+            Err(e) => warn!("Failed to record response {} of endpoint {} to {}: {}", sequence, endpoint, path.display(), e),
+        }
+    }
+}
+
+/// Serves the responses a [`ResponseRecorder`] wrote, in the order they were recorded
+#[derive(Debug)]
+pub struct ResponseReplay {
+    dir: PathBuf,
+    sequences: Mutex<HashMap<String, u32>>,
+}
+
+impl ResponseReplay {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        if !dir.is_dir() {
+            anyhow::bail!("Replay directory {} does not exist", dir.display());
+        }
+        Ok(Self { dir, sequences: Mutex::default() })
+    }
+
+    /// Replay the capture from its first response again, for the next sync
+    pub fn reset(&self) {
+        self.sequences.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// The next recorded response of `endpoint`
+    pub fn next(&self, endpoint: &str) -> Result<RecordedResponse> {
+        let sequence = next_sequence(&self.sequences, endpoint);
+        let path = response_path(&self.dir, endpoint, sequence);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("No recorded response {} for endpoint {} ({})", sequence, endpoint, path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse recorded response {}", path.display()))
+    }
+}
+
+fn next_sequence(sequences: &Mutex<HashMap<String, u32>>, endpoint: &str) -> u32 {
+    let mut sequences = sequences.lock().unwrap_or_else(|e| e.into_inner());
+    let sequence = sequences.entry(endpoint.to_string()).or_insert(0);
+    *sequence += 1;
+    *sequence
+}
+
+fn response_path(dir: &Path, endpoint: &str, sequence: u32) -> PathBuf {
+    let endpoint: String = endpoint.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    dir.join(endpoint).join(format!("{:05}.json", sequence))
+}
+
+/// Redact tokens and secrets inside string values (skip tokens in next links, bearer
+/// tokens) the same way the log does, keeping the JSON itself intact
+fn redact_tokens(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) => *text = crate::logging::sanitize_log_message(text),
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_tokens),
+        serde_json::Value::Object(map) => map.values_mut().for_each(redact_tokens),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = TempDir::new().unwrap();
+        let recorder = ResponseRecorder::new(dir.path());
+        let first = serde_json::json!({
+            "value": [{"id": "1", "deviceName": "PC-01"}],
+            "@odata.nextLink": "https://graph.microsoft.com/v1.0/users?$skiptoken=secret"
+        });
+        recorder.record("devices", "https://graph.microsoft.com/v1.0/users", 200, Some("W/\"1\""), &first);
+        recorder.record("devices", "https://graph.microsoft.com/v1.0/users?$skiptoken=secret", 200, None, &serde_json::json!({"value": []}));
+        recorder.record("all users", "https://graph.microsoft.com/v1.0/users", 304, None, &serde_json::Value::Null);
+
+        let replay = ResponseReplay::new(dir.path()).unwrap();
+        let page = replay.next("devices").unwrap();
+        assert_eq!(page.sequence, 1);
+        assert_eq!(page.etag.as_deref(), Some("W/\"1\""));
+        assert_eq!(page.body["value"], first["value"]);
+        assert_eq!(page.body["@odata.nextLink"], "https://graph.microsoft.com/v1.0/users?$skiptoken=***");
+
+        assert!(replay.next("devices").unwrap().url.ends_with("$skiptoken=***"));
+        assert_eq!(replay.next("all users").unwrap().status, 304);
+        assert!(replay.next("devices").is_err());
+
+        assert!(ResponseReplay::new(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_next_sync_replaces_capture() {
+        let dir = TempDir::new().unwrap();
+        let recorder = ResponseRecorder::new(dir.path());
+        for page in 0..3 {
+            recorder.record("devices", "https://graph.microsoft.com/v1.0/users", 200, None, &serde_json::json!({"page": page}));
+        }
+
+        // The next sync starts over at 1 and leaves nothing of the longer first capture
+        recorder.reset();
+        recorder.record("devices", "https://graph.microsoft.com/v1.0/users", 200, None, &serde_json::json!({"page": "new"}));
+        assert_eq!(fs::read_dir(dir.path().join("devices")).unwrap().count(), 1);
+
+        let replay = ResponseReplay::new(dir.path()).unwrap();
+        assert_eq!(replay.next("devices").unwrap().body["page"], "new");
+        assert!(replay.next("devices").is_err());
+
+        // Every sync of a daemon replays the capture from the start
+        replay.reset();
+        assert_eq!(replay.next("devices").unwrap().sequence, 1);
+    }
+}
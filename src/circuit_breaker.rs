@@ -0,0 +1,273 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+/// When to stop calling something that keeps failing, and for how long
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures before the circuit opens; 0 disables the breaker
+    #[serde(rename = "maxConsecutiveFailures", default = "default_max_consecutive_failures")]
+    pub max_consecutive_failures: u32,
+    /// How long the circuit stays open the first time it trips
+    #[serde(rename = "cooldownSeconds", default = "default_cooldown_seconds")]
+    pub cooldown_seconds: u64,
+    /// Upper bound for the cooldown, which doubles every time a re-test fails
+    #[serde(rename = "maxCooldownSeconds", default = "default_max_cooldown_seconds")]
+    pub max_cooldown_seconds: u64,
+    /// Fixed interval between re-tests of an open circuit (e.g. "15m"), used instead of
+    /// the doubling cooldown when set
+    #[serde(rename = "reEnableCheckInterval", default, skip_serializing_if = "Option::is_none")]
+    pub re_enable_check_interval: Option<String>,
+}
+
+fn default_max_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_cooldown_seconds() -> u64 {
+    300
+}
+
+fn default_max_cooldown_seconds() -> u64 {
+    21600
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            max_consecutive_failures: default_max_consecutive_failures(),
+            cooldown_seconds: default_cooldown_seconds(),
+            max_cooldown_seconds: default_max_cooldown_seconds(),
+            re_enable_check_interval: None,
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    /// Cooldown after the given number of trips in a row (1 = first trip)
+    pub fn cooldown(&self, trips: u32) -> Duration {
+        if let Some(interval) = self.re_enable_check_interval() {
+            return interval;
+        }
+        let multiplier = 2u64.saturating_pow(trips.saturating_sub(1));
+        let seconds = self.cooldown_seconds.saturating_mul(multiplier).min(self.max_cooldown_seconds);
+        Duration::seconds(seconds as i64)
+    }
+
+    /// `reEnableCheckInterval` as a duration; an unparseable value (rejected by
+    /// validation) falls back to the doubling cooldown
+    pub fn re_enable_check_interval(&self) -> Option<Duration> {
+        let interval = crate::config::parse_duration(self.re_enable_check_interval.as_deref()?).ok()?;
+        Duration::from_std(interval).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CircuitState {
+    /// Calls go through normally
+    Closed,
+    /// Calls are skipped until the cooldown ends
+    Open,
+    /// Cooldown is over; the next call decides whether the circuit closes or reopens
+    HalfOpen,
+}
+
+/// Most recent failure of an endpoint, kept until its next success
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LastError {
+    pub message: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Point-in-time view of a circuit, as exposed on `/health`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub open_until: Option<DateTime<Utc>>,
+    /// Earliest time the next sync re-tests the endpoint; absent while closed
+    pub next_probe_at: Option<DateTime<Utc>>,
+    /// A re-test was requested with `endpoint retry` and has not run yet
+    pub probe_requested: bool,
+    pub last_error: Option<LastError>,
+}
+
+/// Circuit breakers by name, shared between the sync loop and the HTTP server
+pub type SharedCircuits = Arc<RwLock<BTreeMap<String, CircuitBreaker>>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct CircuitBreaker {
+    consecutive_failures: u32,
+    trips: u32,
+    open_until: Option<DateTime<Utc>>,
+    probe_requested: bool,
+    last_error: Option<LastError>,
+}
+
+impl CircuitBreaker {
+    pub fn state(&self, now: DateTime<Utc>) -> CircuitState {
+        match self.open_until {
+            None => CircuitState::Closed,
+            Some(until) if now < until => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether a call should be attempted right now
+    pub fn allows(&self, now: DateTime<Utc>) -> bool {
+        self.state(now) != CircuitState::Open
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn last_error(&self) -> Option<&LastError> {
+        self.last_error.as_ref()
+    }
+
+    /// Remember why the last call failed; cleared by the next success
+    pub fn set_last_error(&mut self, last_error: LastError) {
+        self.last_error = Some(last_error);
+    }
+
+    pub fn probe_requested(&self) -> bool {
+        self.probe_requested
+    }
+
+    /// End the cooldown now so the next call re-tests; returns false if the circuit
+    /// is closed and there is nothing to re-test
+    pub fn request_probe(&mut self, now: DateTime<Utc>) -> bool {
+        match self.open_until {
+            None => false,
+            Some(until) => {
+                self.open_until = Some(until.min(now));
+                self.probe_requested = true;
+                true
+            }
+        }
+    }
+
+    /// Reset after a successful call; returns true if the circuit had been open
+    pub fn record_success(&mut self) -> bool {
+        let was_open = self.open_until.is_some();
+        *self = Self::default();
+        was_open
+    }
+
+    /// Count a failed call; returns the time the circuit stays open until if this
+    /// failure opened it (or a half-open re-test failed)
+    pub fn record_failure(&mut self, config: &CircuitBreakerConfig, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.consecutive_failures += 1;
+        self.probe_requested = false;
+
+        if config.max_consecutive_failures == 0 {
+            return None;
+        }
+
+        if self.open_until.is_some() || self.consecutive_failures >= config.max_consecutive_failures {
+            self.trips += 1;
+            let until = now + config.cooldown(self.trips);
+            self.open_until = Some(until);
+            return Some(until);
+        }
+
+        None
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> CircuitStatus {
+        CircuitStatus {
+            state: self.state(now),
+            consecutive_failures: self.consecutive_failures,
+            open_until: self.open_until,
+            next_probe_at: self.open_until.map(|until| until.max(now)),
+            probe_requested: self.probe_requested,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker_trips_and_recovers() {
+        let config = CircuitBreakerConfig {
+            max_consecutive_failures: 3,
+            cooldown_seconds: 60,
+            max_cooldown_seconds: 200,
+            re_enable_check_interval: None,
+        };
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker::default();
+
+        assert!(breaker.record_failure(&config, now).is_none());
+        assert!(breaker.record_failure(&config, now).is_none());
+        let until = breaker.record_failure(&config, now).expect("third failure opens the circuit");
+        assert_eq!(until, now + Duration::seconds(60));
+        assert!(!breaker.allows(now));
+        assert_eq!(breaker.state(until), CircuitState::HalfOpen);
+
+        // A failed re-test reopens with a doubled, capped cooldown
+        assert_eq!(breaker.record_failure(&config, until), Some(until + Duration::seconds(120)));
+        let later = until + Duration::seconds(120);
+        assert_eq!(breaker.record_failure(&config, later), Some(later + Duration::seconds(200)));
+
+        breaker.set_last_error(LastError { message: "403 Forbidden".to_string(), at: later });
+        assert_eq!(breaker.status(later).last_error.unwrap().message, "403 Forbidden");
+
+        assert!(breaker.record_success());
+        assert_eq!(breaker.last_error(), None);
+        assert_eq!(breaker.state(later), CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures(), 0);
+        assert!(!breaker.record_success());
+    }
+
+    #[test]
+    fn test_circuit_breaker_re_enable_check_interval() {
+        let config = CircuitBreakerConfig {
+            max_consecutive_failures: 1,
+            re_enable_check_interval: Some("15m".to_string()),
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker::default();
+        assert!(!breaker.request_probe(now));
+
+        // Every re-test waits the same interval instead of doubling
+        let until = breaker.record_failure(&config, now).unwrap();
+        assert_eq!(until, now + Duration::minutes(15));
+        assert_eq!(breaker.record_failure(&config, until), Some(until + Duration::minutes(15)));
+        assert_eq!(breaker.status(now).next_probe_at, Some(until + Duration::minutes(15)));
+
+        // A requested probe ends the cooldown right away
+        assert!(breaker.request_probe(now));
+        assert!(breaker.allows(now));
+        assert!(breaker.status(now).probe_requested);
+        assert_eq!(breaker.status(now).next_probe_at, Some(now));
+        breaker.record_failure(&config, now);
+        assert!(!breaker.probe_requested());
+        assert!(!breaker.allows(now));
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled() {
+        let config = CircuitBreakerConfig {
+            max_consecutive_failures: 0,
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker::default();
+
+        for _ in 0..10 {
+            assert!(breaker.record_failure(&config, now).is_none());
+        }
+        assert!(breaker.allows(now));
+        assert_eq!(breaker.consecutive_failures(), 10);
+    }
+}
@@ -0,0 +1,729 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::process;
+use std::path::{Path, PathBuf};
+use tokio::signal;
+
+use crate::config::{AppConfig, ConfigSource};
+use crate::logging::{setup_logging, setup_quiet_logging};
+use crate::sync::{self, SyncService};
+use crate::{auth, bench, circuit_breaker, config_validator, lock, metrics, mock_server, permissions, service_manager, storage, version};
+
+#[derive(Parser)]
+#[command(name = "MSGraphDBSynchronizer")]
+#[command(about = "Microsoft Graph API database synchronization service with multi-endpoint support")]
+#[command(version = version::get_version())]
+#[command(author = version::get_company_name())]
+struct Cli {
+    /// Path or http(s) URL of the configuration (default: config.json next to the
+    /// executable, then in the current directory; env: MSGRAPH_CONFIG)
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    /// Config profile to merge over the base config (env: MSGRAPH_PROFILE)
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Install the service
+    Install,
+    /// Uninstall the service
+    Uninstall,
+    /// Start the service
+    Start,
+    /// Stop the service
+    Stop,
+    /// Restart the service
+    Restart,
+    /// Show service status
+    Status,
+    /// Run the service in foreground
+    Run {
+        /// Write endpoints in the first sync even if far fewer records were fetched than
+        /// minExpectedRatio expects; later syncs check again
+        #[arg(long)]
+        allow_shrink: bool,
+    },
+    /// Run a single sync of all enabled endpoints and exit
+    Sync {
+        /// Write records to stdout as newline-delimited JSON instead of the database
+        #[arg(long)]
+        stdout: bool,
+        /// Write endpoints even if far fewer records were fetched than minExpectedRatio
+        /// expects, after devices were really retired in bulk
+        #[arg(long)]
+        allow_shrink: bool,
+    },
+    /// Print a snapshot of metrics, table row counts and backend health, then exit
+    Metrics {
+        /// Output JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rebuild an endpoint's table from its stored raw JSON using the current config
+    Reprocess {
+        /// Endpoint name or table name
+        endpoint: String,
+    },
+    /// Copy every endpoint table and the sync state from one configured backend to another
+    Migrate {
+        /// Backend to read from: sqlite, postgres or mssql
+        #[arg(long)]
+        from: String,
+        /// Backend to write to: sqlite, postgres or mssql (need not be enabled)
+        #[arg(long)]
+        to: String,
+        /// Rows read and written at a time
+        #[arg(long, default_value_t = 1000)]
+        batch_size: u32,
+    },
+    /// Print the CREATE/ALTER TABLE statements a sync would run, without running them
+    PreviewSchema {
+        /// Endpoint name or table name (default: all enabled endpoints)
+        endpoint: Option<String>,
+    },
+    /// Check the access token's Graph permissions against the enabled endpoints
+    CheckPermissions,
+    /// Serve the mock Graph API over HTTP for testing real clients against it
+    MockServe {
+        /// Port to listen on (default: mockGraphApi.servePort, then 8089)
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Sync mock devices into a backend and report throughput, memory and database size
+    Bench {
+        /// Number of mock devices to sync
+        #[arg(long, default_value_t = 10000)]
+        devices: u32,
+        /// sqlite (a temporary database), postgres or mssql (the configured connection)
+        #[arg(long, default_value = "sqlite")]
+        backend: String,
+        /// Output JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Have the running instance finish its current sync, start no new one and exit
+    Drain {
+        /// Wait until the instance has exited and released its lock file
+        #[arg(long)]
+        wait: bool,
+        /// Give up waiting after this many seconds
+        #[arg(long, default_value_t = 3600)]
+        timeout: u64,
+    },
+    /// Manage the endpoints of the running instance
+    Endpoint {
+        #[command(subcommand)]
+        action: EndpointAction,
+    },
+    /// Show detailed version information
+    Version,
+    /// Validate configuration file
+    Validate,
+}
+
+#[derive(Subcommand)]
+enum EndpointAction {
+    /// Re-test an endpoint disabled by its circuit breaker on the next sync
+    Retry {
+        /// Endpoint name
+        name: String,
+    },
+}
+
+/// Parse the command line and run the command. This is the binary's entry point,
+/// not part of the library API.
+pub async fn run() -> Result<()> {
+    let cli = Cli::parse();
+
+    // Config is loaded by each command from the source given on the command line
+    let source = ConfigSource {
+        // Absolute, so `install` writes a path the service can use from its own directory
+        config: cli.config.map(|source| if crate::config::is_config_url(&source) {
+            source
+        } else {
+            std::env::current_dir()
+                .map(|dir| dir.join(&source).to_string_lossy().to_string())
+                .unwrap_or(source)
+        }),
+        profile: cli.profile,
+    };
+
+    match cli.command {
+        Commands::Install => install_service(&source).await,
+        Commands::Uninstall => uninstall_service().await,
+        Commands::Start => start_service().await,
+        Commands::Stop => stop_service().await,
+        Commands::Restart => restart_service().await,
+        Commands::Status => show_status(&source).await,
+        Commands::Run { allow_shrink } => run_service(&source, allow_shrink).await,
+        Commands::Sync { stdout, allow_shrink } => run_sync_once(&source, stdout, allow_shrink).await,
+        Commands::Metrics { json } => show_metrics(&source, json).await,
+        Commands::Reprocess { endpoint } => reprocess(&source, &endpoint).await,
+        Commands::Migrate { from, to, batch_size } => migrate(&source, &from, &to, batch_size).await,
+        Commands::PreviewSchema { endpoint } => preview_schema(&source, endpoint.as_deref()).await,
+        Commands::MockServe { port } => mock_serve(&source, port).await,
+        Commands::CheckPermissions => check_permissions(&source).await,
+        Commands::Bench { devices, backend, json } => run_bench(&source, devices, &backend, json).await,
+        Commands::Drain { wait, timeout } => drain(&source, wait, timeout).await,
+        Commands::Endpoint { action: EndpointAction::Retry { name } } => retry_endpoint(&source, &name).await,
+        Commands::Version => {
+            version::print_version_info();
+            Ok(())
+        }
+        Commands::Validate => match source.url() {
+            Some(url) => config_validator::validate_remote_config_command(&url, source.profile().as_deref()).await,
+            None => config_validator::validate_config_command(source.file_path(), source.profile().as_deref()),
+        },
+    }
+}
+
+async fn install_service(source: &ConfigSource) -> Result<()> {
+    service_manager::ServiceManager::install(source).await
+}
+
+async fn uninstall_service() -> Result<()> {
+    service_manager::ServiceManager::uninstall().await
+}
+
+async fn start_service() -> Result<()> {
+    service_manager::ServiceManager::start().await
+}
+
+async fn stop_service() -> Result<()> {
+    service_manager::ServiceManager::stop().await
+}
+
+async fn restart_service() -> Result<()> {
+    service_manager::ServiceManager::restart().await
+}
+
+async fn show_status(source: &ConfigSource) -> Result<()> {
+    service_manager::ServiceManager::status().await?;
+
+    // Also catches instances started with `run` or `sync` outside the service manager
+    if let Ok(config) = AppConfig::load(source).await {
+        let lock_path = config.lock_file_path()?;
+        match lock::InstanceLock::holder(&lock_path) {
+            Some(pid) => {
+                println!("Instance running: yes (PID {}, lock file {})", pid, lock_path.display());
+                if config.enable_prometheus {
+                    if let Ok(addr) = config.metrics_client_addr() {
+                        print_endpoint_health(addr, &config).await;
+                    }
+                }
+            }
+            None => println!("Instance running: no"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Request to the running instance's metrics server, with its scrape credentials
+fn instance_request(
+    addr: std::net::SocketAddr,
+    config: &AppConfig,
+    method: reqwest::Method,
+    path: &str,
+) -> Result<reqwest::RequestBuilder> {
+    let tls = config.metrics_tls.as_ref();
+    let url = format!("{}://{}{}", if tls.is_some() { "https" } else { "http" }, addr, path);
+    let mut builder = reqwest::Client::builder().timeout(std::time::Duration::from_secs(2));
+    if let Some(tls) = tls {
+        builder = builder.use_preconfigured_tls(metrics::load_client_tls(tls)?);
+    }
+    let client = builder.build()?;
+    let mut request = client.request(method, &url);
+    if let Some(auth) = metrics::MetricsAuth::from_config(config) {
+        request = request.header(reqwest::header::AUTHORIZATION, auth.header_value());
+    }
+    Ok(request)
+}
+
+/// Metric values of the running instance, read from its `/metrics`. A running
+/// instance that cannot be scraped is logged and reported as none.
+async fn instance_metrics(config: &AppConfig) -> Option<BTreeMap<String, f64>> {
+    if !config.enable_prometheus {
+        return None;
+    }
+    lock::InstanceLock::holder(&config.lock_file_path().ok()?)?;
+    let addr = config.metrics_client_addr().ok()?;
+    let scrape = async {
+        let response = instance_request(addr, config, reqwest::Method::GET, "/metrics")?
+            .send().await?
+            .error_for_status()?;
+        anyhow::Ok(metrics::parse_text_values(&response.text().await?))
+    };
+    match scrape.await {
+        Ok(values) => Some(values),
+        Err(e) => {
+            warn!("Failed to read metrics of the running instance on {}: {:#}", addr, e);
+            None
+        }
+    }
+}
+
+/// Print endpoint circuit state as reported by the running instance's `/health`
+async fn print_endpoint_health(addr: std::net::SocketAddr, config: &AppConfig) {
+    let probe = async {
+        let response = instance_request(addr, config, reqwest::Method::GET, "/health")?
+            .send().await?;
+        anyhow::Ok(response.json::<serde_json::Value>().await?)
+    };
+    let report = match probe.await {
+        Ok(report) => report,
+        Err(e) => {
+            println!("Health: unavailable - failed to reach the running instance on {}: {:#}", addr, e);
+            return;
+        }
+    };
+
+    println!("Health: {}", report["status"].as_str().unwrap_or("unknown"));
+    if let Some(requested_at) = report["drainRequestedAt"].as_str() {
+        println!("Draining since {} - exits after the current sync", requested_at);
+    }
+    if let Some(hash) = report["configHash"].as_str() {
+        let drift = if report["configDrift"].as_bool() == Some(true) {
+            " - config file changed since load, restart to apply"
+        } else {
+            ""
+        };
+        println!("Config: sha256 {}{}", hash, drift);
+    }
+    if let Some(endpoints) = report["endpoints"].as_object() {
+        for (name, status) in endpoints {
+            let state = status["state"].as_str().unwrap_or("unknown");
+            match status["openUntil"].as_str() {
+                Some(until) if state != "closed" => println!(
+                    "  {}: {} ({} consecutive failures, disabled until {}, next probe {}{})",
+                    name,
+                    state,
+                    status["consecutiveFailures"],
+                    until,
+                    status["nextProbeAt"].as_str().unwrap_or(until),
+                    if status["probeRequested"].as_bool() == Some(true) { ", retry requested" } else { "" }
+                ),
+                _ => println!("  {}: {}", name, state),
+            }
+            if let Some(message) = status["lastError"]["message"].as_str() {
+                println!("    last error ({}): {}", status["lastError"]["at"].as_str().unwrap_or("unknown"), message);
+            }
+        }
+    }
+}
+
+async fn retry_endpoint(source: &ConfigSource, name: &str) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    let lock_path = config.lock_file_path()?;
+    if lock::InstanceLock::holder(&lock_path).is_none() {
+        anyhow::bail!("No running instance; endpoints are re-tested on every start anyway");
+    }
+    if !config.enable_prometheus {
+        anyhow::bail!("Retrying an endpoint needs the metrics server (enablePrometheus)");
+    }
+
+    let addr = config.metrics_client_addr()?;
+    let path = format!("/endpoints/{}/retry", name);
+    let response = instance_request(addr, &config, reqwest::Method::POST, &path)?
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the running instance on {}", addr))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        anyhow::bail!("Retry of endpoint {} refused ({}): {}", name, status, response.text().await.unwrap_or_default());
+    }
+
+    println!("Endpoint {} will be re-tested on the next sync", name);
+    Ok(())
+}
+
+async fn drain(source: &ConfigSource, wait: bool, timeout_seconds: u64) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    let lock_path = config.lock_file_path()?;
+    let Some(pid) = lock::InstanceLock::holder(&lock_path) else {
+        println!("No running instance");
+        return Ok(());
+    };
+    if !config.enable_prometheus {
+        anyhow::bail!("Draining an instance needs the metrics server (enablePrometheus)");
+    }
+
+    let addr = config.metrics_client_addr()?;
+    let response = instance_request(addr, &config, reqwest::Method::POST, "/drain")?
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the running instance on {}", addr))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        anyhow::bail!("Drain refused ({}): {}", status, response.text().await.unwrap_or_default());
+    }
+    println!("Instance (PID {}) will exit after its current sync", pid);
+    if !wait {
+        return Ok(());
+    }
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(timeout_seconds);
+    while lock::InstanceLock::holder(&lock_path).is_some() {
+        if std::time::Instant::now() >= deadline {
+            anyhow::bail!("Instance (PID {}) still running after {}s", pid, timeout_seconds);
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    println!("Instance (PID {}) has exited", pid);
+    Ok(())
+}
+
+async fn run_service(source: &ConfigSource, allow_shrink: bool) -> Result<()> {
+    // Load configuration
+    println!("Loading configuration...");
+    let config = AppConfig::load(source).await?;
+    println!("Configuration loaded");
+
+    // Setup logging
+    println!("Setting up logging...");
+    setup_logging(&config).await?;
+    println!("Logging setup complete");
+
+    info!("Starting {} v{}", version::get_product_name(), version::get_version());
+    log_profile(&config);
+
+    // Refuse to run next to another instance; held until the end of this function
+    let _instance_lock = lock::InstanceLock::acquire(&config.lock_file_path()?)?;
+
+    // Initialize metrics if enabled
+    let endpoint_circuits = circuit_breaker::SharedCircuits::default();
+    let drain = sync::DrainSignal::default();
+    if config.enable_prometheus {
+        info!("Initializing Prometheus metrics");
+        metrics::init_metrics();
+        let tls = config.metrics_tls.as_ref().map(metrics::load_server_tls).transpose()?;
+        tokio::spawn(metrics::start_metrics_server(
+            config.metrics_socket_addr()?,
+            endpoint_circuits.clone(),
+            config.loaded_file.clone(),
+            drain.clone(),
+            tls,
+            metrics::MetricsAuth::from_config(&config),
+        ));
+    }
+
+    // Edits to the config file only apply after a restart, so keep an eye on them
+    if let Some(file) = config.loaded_file.clone() {
+        info!("Running with config file {} (sha256 {})", file.path.display(), file.hash);
+        tokio::spawn(metrics::watch_config_drift(file, std::time::Duration::from_secs(60)));
+    }
+
+    // Create and start sync service
+    info!("Creating sync service");
+    let mut sync_service = SyncService::new(config).await?
+        .with_endpoint_circuits(endpoint_circuits)
+        .with_drain_signal(drain.clone())
+        .with_shrink_allowed(allow_shrink);
+    info!("Sync service created");
+
+    // A stop from the service manager or OS drains like `drain`, so no sync is cut off
+    tokio::spawn(async move {
+        stop_requested().await;
+        if drain.request() {
+            info!("Stop requested, finishing the sync in progress before exiting");
+        }
+    });
+    
+    // Setup graceful shutdown
+    let shutdown_signal = async {
+        signal::ctrl_c().await.expect("Failed to install CTRL+C signal handler");
+        info!("Shutdown signal received");
+    };
+    
+    // Run the service
+    tokio::select! {
+        result = sync_service.run() => {
+            if let Err(e) = result {
+                error!("Service error: {}", e);
+                process::exit(1);
+            }
+            info!("Drained, shutting down");
+        }
+        _ = shutdown_signal => {
+            info!("Shutting down gracefully");
+        }
+    }
+
+    // Clean up resources
+    info!("Cleaning up resources...");
+    if let Err(e) = sync_service.cleanup().await {
+        error!("Error during cleanup: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Resolves when the OS asks the process to stop: SIGTERM on Unix (systemd, launchd,
+/// `docker stop`), or a console close, logoff or shutdown on Windows. Ctrl+C is not
+/// included; it still stops right away.
+async fn stop_requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        use tokio::signal::windows::{ctrl_close, ctrl_logoff, ctrl_shutdown};
+        match (ctrl_close(), ctrl_logoff(), ctrl_shutdown()) {
+            (Ok(mut close), Ok(mut logoff), Ok(mut shutdown)) => {
+                tokio::select! {
+                    _ = close.recv() => {}
+                    _ = logoff.recv() => {}
+                    _ = shutdown.recv() => {}
+                }
+            }
+            _ => {
+                warn!("Failed to install console stop handlers");
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+fn log_profile(config: &AppConfig) {
+    match config.profile {
+        Some(ref profile) => info!("Using config profile: {}", profile),
+        None => info!("Using the base config, no profile selected"),
+    }
+}
+
+async fn run_sync_once(source: &ConfigSource, stdout: bool, allow_shrink: bool) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+
+    if stdout {
+        // Only JSON may reach stdout, so logs go to the log file alone
+        setup_quiet_logging(&config).await?;
+        info!("Starting one-shot export to stdout");
+
+        let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+        match sync::export_ndjson(&config, &mut out).await {
+            Ok(count) => info!("Exported {} items to stdout", count),
+            // The reader went away (e.g. `| head`), which is not an error for us
+            Err(e) if is_broken_pipe(&e) => info!("Stdout closed, stopping export"),
+            Err(e) => {
+                error!("Export failed: {}", e);
+                return Err(e);
+            }
+        }
+        return Ok(());
+    }
+
+    setup_logging(&config).await?;
+    info!("Starting one-shot sync");
+    log_profile(&config);
+
+    let _instance_lock = lock::InstanceLock::acquire(&config.lock_file_path()?)?;
+
+    let mut sync_service = SyncService::new(config).await?
+        .with_shrink_allowed(allow_shrink);
+    let result = sync_service.sync_once().await;
+
+    if let Err(e) = sync_service.cleanup().await {
+        error!("Error during cleanup: {}", e);
+    }
+
+    result.map(|_| ())
+}
+
+async fn reprocess(source: &ConfigSource, endpoint: &str) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    setup_logging(&config).await?;
+
+    // Rewrites rows, so never alongside a running sync
+    let _instance_lock = lock::InstanceLock::acquire(&config.lock_file_path()?)?;
+
+    match sync::reprocess_endpoint(&config, endpoint).await? {
+        Some(count) => println!("Reprocessed {} records for endpoint {}", count, endpoint),
+        None => println!(
+            "Nothing to reprocess: no raw JSON is stored for endpoint {}. Records can only be rebuilt from raw JSON kept in the table's data column",
+            endpoint
+        ),
+    }
+
+    Ok(())
+}
+
+async fn migrate(source: &ConfigSource, from: &str, to: &str, batch_size: u32) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    setup_logging(&config).await?;
+
+    let (from, to) = (from.to_lowercase(), to.to_lowercase());
+    if from == to {
+        anyhow::bail!("Source and destination are both the {} backend", from);
+    }
+
+    // Reads must not interleave with a sync writing the source
+    let _instance_lock = lock::InstanceLock::acquire(&config.lock_file_path()?)?;
+
+    let mut source = storage::connect_backend(&config.database, &from).await?
+        .with_context(|| format!("No {} backend is configured under database", from))?;
+    let mut destination = storage::connect_backend(&config.database, &to).await?
+        .with_context(|| format!("No {} backend is configured under database", to))?;
+    destination.initialize().await?;
+
+    let endpoints = config.get_endpoints_config().endpoints;
+    let mut tables: Vec<String> = endpoints.iter().map(|endpoint| endpoint.table_name.clone()).collect();
+    tables.sort();
+    tables.dedup();
+    let endpoint_names: Vec<String> = endpoints.iter().map(|endpoint| endpoint.name.clone()).collect();
+
+    let migrations = storage::migrate_tables(source.as_mut(), destination.as_mut(), &tables, &endpoint_names, batch_size).await?;
+    source.cleanup().await?;
+    destination.cleanup().await?;
+
+    println!("Migrated {} -> {}", from, to);
+    let mut mismatched = 0;
+    for migration in &migrations {
+        let status = if migration.verified() { "OK" } else { "MISMATCH" };
+        println!(
+            "  {:<8} {}: {} rows in source, {} copied, {} in destination",
+            status, migration.table_name, migration.source_rows, migration.copied, migration.destination_rows
+        );
+        if !migration.verified() {
+            mismatched += 1;
+        }
+    }
+    if migrations.is_empty() {
+        println!("  Nothing to migrate: the {} backend holds no endpoint tables", from);
+    }
+
+    if mismatched > 0 {
+        anyhow::bail!("Row counts differ for {} table(s) - was the destination not empty?", mismatched);
+    }
+    Ok(())
+}
+
+async fn preview_schema(source: &ConfigSource, endpoint: Option<&str>) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    // Keep stdout clean so the statements can be handed to a change review as is
+    setup_quiet_logging(&config).await?;
+
+    for preview in sync::preview_schema(&config, endpoint).await? {
+        for (backend, statements) in preview.backends {
+            println!("-- {} -> {} ({})", preview.endpoint, preview.table_name, backend);
+            match statements {
+                Ok(statements) if statements.is_empty() => println!("-- no changes"),
+                Ok(statements) => {
+                    for statement in statements {
+                        println!("{};", statement);
+                    }
+                }
+                Err(e) => println!("-- failed to inspect the table: {}", e),
+            }
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+async fn check_permissions(source: &ConfigSource) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    setup_quiet_logging(&config).await?;
+
+    let token = auth::AuthClient::new(config.clone()).get_access_token().await?;
+    let granted = permissions::token_permissions(&token)?;
+    println!("Token permissions: {}", if granted.is_empty() { "none".to_string() } else { granted.join(", ") });
+    println!();
+
+    let endpoints_config = config.get_endpoints_config();
+    let checks = permissions::check_endpoints(&granted, &endpoints_config.get_enabled_endpoints());
+    for check in &checks {
+        match (check.granted, check.missing()) {
+            (Some(permission), _) => println!("  OK       {} ({})", check.endpoint, permission),
+            (None, Some(permission)) => println!("  MISSING  {} (needs {})", check.endpoint, permission),
+            (None, None) => println!("  UNKNOWN  {} (no known permission for this collection)", check.endpoint),
+        }
+    }
+
+    let missing = permissions::missing_permissions(&checks);
+    if missing.is_empty() {
+        println!();
+        println!("All enabled endpoints with known permissions are covered");
+        return Ok(());
+    }
+
+    println!();
+    println!("Grant these application permissions (with admin consent) to the app registration:");
+    for (permission, endpoints) in &missing {
+        println!("  {} - blocks {}", permission, endpoints.join(", "));
+    }
+    anyhow::bail!("{} permission(s) missing", missing.len())
+}
+
+async fn mock_serve(source: &ConfigSource, port: Option<u16>) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    setup_logging(&config).await?;
+
+    let mock_config = config.mock_graph_api.clone().unwrap_or_default();
+    let port = port.or(mock_config.serve_port).unwrap_or(mock_server::DEFAULT_MOCK_SERVE_PORT);
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+
+    mock_server::serve(mock_config, config.get_endpoints_config().endpoints, addr).await
+}
+
+async fn run_bench(source: &ConfigSource, devices: u32, backend: &str, json: bool) -> Result<()> {
+    // A temporary SQLite database needs nothing from the config, server backends need
+    // its connection settings
+    let config = match AppConfig::load(source).await {
+        Ok(config) => config,
+        Err(_) if backend.eq_ignore_ascii_case("sqlite") => AppConfig::default(),
+        Err(e) => return Err(e),
+    };
+    if json {
+        // Keep stdout clean for the results
+        setup_quiet_logging(&config).await?;
+    } else {
+        setup_logging(&config).await?;
+    }
+
+    let report = bench::run_benchmark(&config, devices, backend).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report.render());
+    }
+
+    Ok(())
+}
+
+async fn show_metrics(source: &ConfigSource, json: bool) -> Result<()> {
+    let config = AppConfig::load(source).await?;
+    // Keep stdout clean for the snapshot itself
+    setup_quiet_logging(&config).await?;
+
+    let snapshot = metrics::collect_snapshot(&config, instance_metrics(&config).await).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+    } else {
+        print!("{}", snapshot.render_table());
+    }
+
+    Ok(())
+}
+
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+}
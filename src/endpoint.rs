@@ -1,13 +1,16 @@
 use serde::{Deserialize, Serialize};
 use anyhow::{Result, Context};
-use log::{info, debug, warn};
-use std::collections::HashMap;
+use log::{info, debug, warn, trace};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::time::Duration;
+use chrono::{DateTime, SecondsFormat, Utc};
 use reqwest::Client;
 use tokio::time::sleep;
 use crate::auth::AuthClient;
+use crate::capture::{ResponseRecorder, ResponseReplay};
+use crate::error::{ConfigError, GraphError};
 use crate::mock_graph_api::MockGraphApi;
-use crate::rate_limiter::{RateLimitedClient, RateLimitConfig};
+use crate::rate_limiter::{parse_retry_after_header, RateLimitedClient, RateLimitConfig, RequestBudget, RetryBudget, ThrottleGate};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EndpointMockConfig {
@@ -32,6 +35,84 @@ fn default_object_count() -> u32 {
     1000
 }
 
+/// How OData control annotations (`@odata.type`, `@odata.etag`, ...) on records are handled
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ODataAnnotationMode {
+    /// Remove annotations before storage
+    #[default]
+    Strip,
+    /// Keep annotations under a sanitized name (e.g. `@odata.type` -> `odata_type`)
+    Keep,
+}
+
+/// SQL type of a column from `columnTypes`: one type for every backend, or one per
+/// backend (`sqlite`, `postgres`, `mssql`)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ColumnTypeOverride {
+    All(String),
+    PerBackend(BTreeMap<String, String>),
+}
+
+impl ColumnTypeOverride {
+    /// Type for one backend kind; `None` leaves the column to type inference there
+    pub fn for_backend(&self, kind: &str) -> Option<&str> {
+        match self {
+            Self::All(sql_type) => Some(sql_type),
+            Self::PerBackend(types) => types.get(kind).map(String::as_str),
+        }
+    }
+}
+
+/// Whether a sync fetches everything or only what changed since the last one
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// Fetch changes since the watermark when `incrementalField` is set, else everything
+    #[default]
+    Incremental,
+    /// Always fetch everything
+    Full,
+    /// Like `incremental`, with a full pull every `fullSyncEvery` to reconcile
+    Auto,
+}
+
+/// What happens to stored rows a complete fetch of their endpoint no longer returns
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionMode {
+    /// Keep them
+    #[default]
+    None,
+    /// Keep them with the time they went missing in `deleted_at`
+    Soft,
+    /// Delete them
+    Hard,
+}
+
+/// What happens when an endpoint returns more items than `maxItems`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MaxItemsPolicy {
+    /// Stop paginating and store the items fetched so far
+    #[default]
+    Store,
+    /// Fail the endpoint's sync without storing anything
+    Fail,
+}
+
+/// HTTP method used for an endpoint's first request
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    /// Plain collection reads
+    #[default]
+    Get,
+    /// Actions and `$batch`, which take a JSON request body
+    Post,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EndpointConfig {
     /// Name/identifier for this endpoint
@@ -65,6 +146,137 @@ pub struct EndpointConfig {
     /// Mock API configuration for this endpoint
     #[serde(rename = "mockConfig")]
     pub mock_config: Option<EndpointMockConfig>,
+    /// Handling of `@odata.*` annotations in returned records
+    #[serde(rename = "odataAnnotations", default)]
+    pub odata_annotations: ODataAnnotationMode,
+    /// Keep navigation properties expanded via `$expand` (stored as JSON)
+    #[serde(rename = "keepNavigationProperties", default = "default_enabled")]
+    pub keep_navigation_properties: bool,
+    /// Page size requested via `$top` on the first request (optional, Graph default if not set)
+    #[serde(rename = "pageSize")]
+    pub page_size: Option<u32>,
+    /// Only store these fields (optional, `id` is always kept)
+    #[serde(rename = "storeFields")]
+    pub store_fields: Option<Vec<String>>,
+    /// Never store these fields, even if Graph returns them
+    #[serde(rename = "excludeFields", default)]
+    pub exclude_fields: Vec<String>,
+    /// SQL types used instead of the inferred ones when a field's column is added
+    #[serde(rename = "columnTypes", default)]
+    pub column_types: HashMap<String, ColumnTypeOverride>,
+    /// Store these fields as salted SHA256 tokens instead of clear text
+    #[serde(rename = "hashFields", default)]
+    pub hash_fields: Vec<String>,
+    /// Timestamp field used to only fetch records changed since the last sync
+    /// (e.g. `lastModifiedDateTime`), for endpoints without delta query support
+    #[serde(rename = "incrementalField")]
+    pub incremental_field: Option<String>,
+    /// Subtracted from the stored watermark to cover clock skew between Graph replicas
+    #[serde(rename = "incrementalSafetyMarginSeconds", default = "default_incremental_safety_margin_seconds")]
+    pub incremental_safety_margin_seconds: u64,
+    /// Whether syncs are incremental, always full, or incremental with periodic full pulls
+    #[serde(rename = "syncMode", default)]
+    pub sync_mode: SyncMode,
+    /// How often `auto` mode forces a full pull (e.g. "24h"; default 24h)
+    #[serde(rename = "fullSyncEvery")]
+    pub full_sync_every: Option<String>,
+    /// Send the last response's ETag as `If-None-Match` and skip storage on 304 Not Modified
+    #[serde(rename = "useEtag", default)]
+    pub use_etag: bool,
+    /// Fetch through the collection's `/delta` function and resume from the stored
+    /// `@odata.deltaLink`, so a sync only transfers what changed since the last one
+    #[serde(rename = "useDelta", default)]
+    pub use_delta: bool,
+    /// Stop paginating after this many items (optional, overrides the global `maxItems`)
+    #[serde(rename = "maxItems")]
+    pub max_items: Option<usize>,
+    /// What to do once `maxItems` is reached (optional, overrides the global `onMaxItems`)
+    #[serde(rename = "onMaxItems")]
+    pub on_max_items: Option<MaxItemsPolicy>,
+    /// Higher priorities sync first; endpoints with equal priority keep their configured order
+    #[serde(default)]
+    pub priority: u32,
+    /// Fields always added to `selectFields` (optional, overrides the built-in set for the collection)
+    #[serde(rename = "requiredSelectFields")]
+    pub required_select_fields: Option<Vec<String>>,
+    /// HTTP method of the first request; next pages are always fetched with GET
+    #[serde(rename = "httpMethod", default)]
+    pub http_method: HttpMethod,
+    /// JSON body sent with a POST request (optional)
+    #[serde(rename = "requestBody")]
+    pub request_body: Option<serde_json::Value>,
+    /// Per-item lookup merged into each record, relative to the Graph version root with an
+    /// `{id}` placeholder (optional), e.g. `/deviceManagement/managedDevices/{id}?$select=...`
+    #[serde(rename = "detailEndpoint")]
+    pub detail_endpoint: Option<String>,
+    /// Secondary indexes on the endpoint's table, one list of field names per index
+    #[serde(default)]
+    pub indexes: Vec<Vec<String>>,
+    /// JMESPath expression each record is replaced with before it is stored
+    pub transform: Option<String>,
+    /// Field whose value becomes the row id, so the records augment the rows another
+    /// endpoint stores in the same table instead of needing a table of their own (optional)
+    #[serde(rename = "mergeKey")]
+    pub merge_key: Option<String>,
+    /// Whether rows Graph no longer returns are kept, marked deleted or deleted
+    #[serde(rename = "deletionMode", default)]
+    pub deletion_mode: DeletionMode,
+}
+
+impl EndpointConfig {
+    /// Fields a non-empty `selectFields` is always extended with
+    pub fn required_select_fields(&self) -> Vec<&str> {
+        match &self.required_select_fields {
+            Some(fields) => fields.iter().map(|f| f.as_str()).collect(),
+            None => default_required_select_fields(&self.endpoint_url).to_vec(),
+        }
+    }
+
+    /// Required fields `selectFields` leaves out (Graph matches names case-insensitively)
+    pub fn missing_required_select_fields(&self) -> Vec<&str> {
+        let Some(fields) = self.select_fields.as_ref().filter(|fields| !fields.is_empty()) else {
+            return Vec::new();
+        };
+
+        self.required_select_fields()
+            .into_iter()
+            .filter(|required| !fields.iter().any(|f| f.eq_ignore_ascii_case(required)))
+            .collect()
+    }
+
+    /// `selectFields` with the missing required fields appended, as sent in `$select`
+    pub fn effective_select_fields(&self) -> Option<Vec<String>> {
+        let mut fields = self.select_fields.clone()?;
+        fields.extend(self.missing_required_select_fields().into_iter().map(|f| f.to_string()));
+        Some(fields)
+    }
+
+    /// Column holding the change detection hash. Endpoints merging into a shared table
+    /// each keep their own, so they do not rewrite each other's rows on every sync.
+    pub fn hash_column(&self) -> String {
+        match self.merge_key {
+            Some(_) => format!("{}_{}", crate::storage::HASH_COLUMN, crate::storage::sanitize_column_name(&self.name)),
+            None => crate::storage::HASH_COLUMN.to_string(),
+        }
+    }
+
+    /// Time between the full pulls of `auto` mode; an unparseable `fullSyncEvery`
+    /// (rejected by validation) falls back to the 24 hour default
+    pub fn full_sync_interval(&self) -> chrono::Duration {
+        self.full_sync_every.as_deref()
+            .and_then(|every| crate::config::parse_duration(every).ok())
+            .and_then(|every| chrono::Duration::from_std(every).ok())
+            .unwrap_or_else(|| chrono::Duration::hours(24))
+    }
+
+    /// Whether this sync has to fetch everything, given when the last full pull finished
+    pub fn full_sync_due(&self, last_full_sync_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        match self.sync_mode {
+            SyncMode::Incremental => false,
+            SyncMode::Full => true,
+            SyncMode::Auto => last_full_sync_at.is_none_or(|at| now - at >= self.full_sync_interval()),
+        }
+    }
 }
 
 impl Default for EndpointConfig {
@@ -84,6 +296,30 @@ impl Default for EndpointConfig {
                 object_count: 30000,
                 enabled: true,
             }),
+            odata_annotations: ODataAnnotationMode::default(),
+            keep_navigation_properties: true,
+            page_size: None,
+            store_fields: None,
+            exclude_fields: Vec::new(),
+            column_types: HashMap::new(),
+            hash_fields: Vec::new(),
+            incremental_field: None,
+            incremental_safety_margin_seconds: default_incremental_safety_margin_seconds(),
+            sync_mode: SyncMode::default(),
+            full_sync_every: None,
+            use_etag: false,
+            use_delta: false,
+            max_items: None,
+            on_max_items: None,
+            priority: 0,
+            required_select_fields: None,
+            http_method: HttpMethod::Get,
+            request_body: None,
+            detail_endpoint: None,
+            indexes: Vec::new(),
+            transform: None,
+            merge_key: None,
+            deletion_mode: DeletionMode::default(),
         }
     }
 }
@@ -92,24 +328,213 @@ fn default_enabled() -> bool {
     true
 }
 
+fn default_incremental_safety_margin_seconds() -> u64 {
+    300
+}
+
+/// Graph's general `$top` maximum for collections without a more specific cap
+pub const DEFAULT_MAX_PAGE_SIZE: u32 = 999;
+
+/// Known `$top` caps for common collections, matched against the end of the URL path
+const KNOWN_PAGE_SIZE_CAPS: &[(&str, u32)] = &[
+    ("/devicemanagement/manageddevices", 999),
+    ("/users", 999),
+    ("/groups", 999),
+    ("/devices", 999),
+    ("/devicemanagement/devicecompliancepolicies", 100),
+];
+
+/// Largest page size Graph accepts for the given endpoint URL
+pub fn max_page_size(endpoint_url: &str) -> u32 {
+    let path = url::Url::parse(endpoint_url)
+        .map(|u| u.path().trim_end_matches('/').to_lowercase())
+        .unwrap_or_default();
+
+    KNOWN_PAGE_SIZE_CAPS.iter()
+        .find(|(suffix, _)| path.ends_with(suffix))
+        .map(|(_, cap)| *cap)
+        .unwrap_or(DEFAULT_MAX_PAGE_SIZE)
+}
+
+/// Properties Graph v1.0 accepts in `$select` for common collections, matched against the
+/// end of the URL path like [`KNOWN_PAGE_SIZE_CAPS`]. Not exhaustive for beta, so unknown
+/// fields are only worth a warning.
+const KNOWN_SELECT_FIELDS: &[(&str, &[&str])] = &[
+    ("/devicemanagement/manageddevices", &[
+        "id", "userId", "deviceName", "managedDeviceOwnerType", "enrolledDateTime",
+        "lastSyncDateTime", "operatingSystem", "complianceState", "jailBroken", "managementAgent",
+        "osVersion", "easActivated", "easDeviceId", "easActivationDateTime", "azureADRegistered",
+        "deviceEnrollmentType", "activationLockBypassCode", "emailAddress", "azureADDeviceId",
+        "deviceRegistrationState", "deviceCategoryDisplayName", "isSupervised",
+        "exchangeLastSuccessfulSyncDateTime", "exchangeAccessState", "exchangeAccessStateReason",
+        "remoteAssistanceSessionUrl", "remoteAssistanceSessionErrorDetails", "isEncrypted",
+        "userPrincipalName", "model", "manufacturer", "imei",
+        "complianceGracePeriodExpirationDateTime", "serialNumber", "phoneNumber",
+        "androidSecurityPatchLevel", "userDisplayName", "configurationManagerClientEnabledFeatures",
+        "wiFiMacAddress", "deviceHealthAttestationState", "subscriberCarrier", "meid",
+        "totalStorageSpaceInBytes", "freeStorageSpaceInBytes", "managedDeviceName",
+        "partnerReportedThreatState", "requireUserEnrollmentApproval",
+        "managementCertificateExpirationDate", "iccid", "udid", "notes", "ethernetMacAddress",
+        "physicalMemoryInBytes", "enrollmentProfileName", "deviceActionResults",
+    ]),
+    ("/users", &[
+        "id", "accountEnabled", "ageGroup", "assignedLicenses", "assignedPlans", "businessPhones",
+        "city", "companyName", "consentProvidedForMinor", "country", "createdDateTime",
+        "creationType", "deletedDateTime", "department", "displayName", "employeeHireDate",
+        "employeeId", "employeeOrgData", "employeeType", "externalUserState",
+        "externalUserStateChangeDateTime", "faxNumber", "givenName", "identities", "imAddresses",
+        "isResourceAccount", "jobTitle", "lastPasswordChangeDateTime",
+        "legalAgeGroupClassification", "licenseAssignmentStates", "mail", "mailNickname",
+        "mobilePhone", "officeLocation", "onPremisesDistinguishedName", "onPremisesDomainName",
+        "onPremisesExtensionAttributes", "onPremisesImmutableId", "onPremisesLastSyncDateTime",
+        "onPremisesProvisioningErrors", "onPremisesSamAccountName", "onPremisesSecurityIdentifier",
+        "onPremisesSyncEnabled", "onPremisesUserPrincipalName", "otherMails", "passwordPolicies",
+        "passwordProfile", "postalCode", "preferredDataLocation", "preferredLanguage",
+        "provisionedPlans", "proxyAddresses", "securityIdentifier", "showInAddressList",
+        "signInActivity", "signInSessionsValidFromDateTime", "state", "streetAddress", "surname",
+        "usageLocation", "userPrincipalName", "userType",
+    ]),
+    ("/groups", &[
+        "id", "assignedLabels", "assignedLicenses", "classification", "createdDateTime",
+        "deletedDateTime", "description", "displayName", "expirationDateTime", "groupTypes",
+        "isAssignableToRole", "licenseProcessingState", "mail", "mailEnabled", "mailNickname",
+        "membershipRule", "membershipRuleProcessingState", "onPremisesDomainName",
+        "onPremisesLastSyncDateTime", "onPremisesNetBiosName", "onPremisesProvisioningErrors",
+        "onPremisesSamAccountName", "onPremisesSecurityIdentifier", "onPremisesSyncEnabled",
+        "preferredDataLocation", "preferredLanguage", "proxyAddresses", "renewedDateTime",
+        "securityEnabled", "securityIdentifier", "serviceProvisioningErrors", "theme",
+        "uniqueName", "visibility",
+    ]),
+    ("/devices", &[
+        "id", "accountEnabled", "alternativeSecurityIds", "approximateLastSignInDateTime",
+        "complianceExpirationDateTime", "createdDateTime", "deletedDateTime", "deviceCategory",
+        "deviceId", "deviceMetadata", "deviceOwnership", "deviceVersion", "displayName",
+        "enrollmentProfileName", "enrollmentType", "extensionAttributes", "isCompliant",
+        "isManaged", "isRooted", "managementType", "manufacturer", "mdmAppId", "model",
+        "onPremisesLastSyncDateTime", "onPremisesSecurityIdentifier", "onPremisesSyncEnabled",
+        "operatingSystem", "operatingSystemVersion", "physicalIds", "profileType",
+        "registrationDateTime", "systemLabels", "trustType",
+    ]),
+];
+
+/// Managed device fields the sync depends on: `id` keys rows, the OS fields feed the device
+/// filter and the rest are the identifiers fingerprinting and device names fall back on
+const DEVICE_REQUIRED_SELECT_FIELDS: &[&str] = &[
+    "id", "deviceName", "operatingSystem", "osVersion", "serialNumber", "imei",
+    "azureADDeviceId", "model", "enrolledDateTime",
+];
+
+/// Fields added to `selectFields` unless an endpoint sets `requiredSelectFields`
+pub fn default_required_select_fields(endpoint_url: &str) -> &'static [&'static str] {
+    let path = url::Url::parse(endpoint_url)
+        .map(|u| u.path().trim_end_matches('/').to_lowercase())
+        .unwrap_or_default();
+
+    if path.ends_with("/devicemanagement/manageddevices") {
+        DEVICE_REQUIRED_SELECT_FIELDS
+    } else {
+        &["id"]
+    }
+}
+
+/// `$select` properties known to be valid for the endpoint URL, `None` for collections we
+/// have no list for
+pub fn known_select_fields(endpoint_url: &str) -> Option<&'static [&'static str]> {
+    let path = url::Url::parse(endpoint_url)
+        .map(|u| u.path().trim_end_matches('/').to_lowercase())
+        .unwrap_or_default();
+
+    KNOWN_SELECT_FIELDS.iter()
+        .find(|(suffix, _)| path.ends_with(suffix))
+        .map(|(_, fields)| *fields)
+}
+
+/// Select fields not in the known set for the endpoint (Graph matches names case-insensitively)
+pub fn unknown_select_fields(endpoint: &EndpointConfig) -> Vec<&str> {
+    let (Some(fields), Some(known)) = (&endpoint.select_fields, known_select_fields(&endpoint.endpoint_url)) else {
+        return Vec::new();
+    };
+
+    fields.iter()
+        .map(|f| f.as_str())
+        .filter(|f| !known.iter().any(|k| k.eq_ignore_ascii_case(f)))
+        .collect()
+}
+
+/// True when the fields actually selected leave out `id`, which every row is keyed on. Only
+/// possible when `requiredSelectFields` overrides the built-in set without it.
+pub fn select_fields_missing_id(endpoint: &EndpointConfig) -> bool {
+    endpoint.effective_select_fields()
+        .is_some_and(|fields| !fields.is_empty() && !fields.iter().any(|f| f.eq_ignore_ascii_case("id")))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EndpointsConfig {
     /// List of endpoints to synchronize
     pub endpoints: Vec<EndpointConfig>,
+    /// Hosts endpoint URLs may point to; absent means no restriction
+    #[serde(rename = "allowedEndpointHosts", default, skip_serializing_if = "Option::is_none")]
+    pub allowed_endpoint_hosts: Option<Vec<String>>,
+    /// Collection paths endpoints may request, with or without the API version
+    /// (`/deviceManagement/managedDevices`, `/v1.0/users`); absent means no restriction
+    #[serde(rename = "allowedEndpointPaths", default, skip_serializing_if = "Option::is_none")]
+    pub allowed_endpoint_paths: Option<Vec<String>>,
+    /// Default cap on the number of items fetched per endpoint; absent means no cap
+    #[serde(rename = "maxItems", default, skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<usize>,
+    /// Default for what happens once an endpoint reaches its cap
+    #[serde(rename = "onMaxItems", default)]
+    pub on_max_items: MaxItemsPolicy,
+    /// Largest response body accepted from Graph (or a proxy in front of it)
+    #[serde(rename = "maxResponseBytes", default = "default_max_response_bytes")]
+    pub max_response_bytes: u64,
+    /// Most items accepted in a single page
+    #[serde(rename = "maxItemsPerPage", default = "default_max_items_per_page")]
+    pub max_items_per_page: usize,
+}
+
+fn default_max_response_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_max_items_per_page() -> usize {
+    100_000
 }
 
 impl Default for EndpointsConfig {
     fn default() -> Self {
         Self {
             endpoints: vec![EndpointConfig::default()],
+            allowed_endpoint_hosts: None,
+            allowed_endpoint_paths: None,
+            max_items: None,
+            on_max_items: MaxItemsPolicy::default(),
+            max_response_bytes: default_max_response_bytes(),
+            max_items_per_page: default_max_items_per_page(),
         }
     }
 }
 
+/// Lowercased URL path without trailing slash and without a leading
+/// `/v1.0` or `/beta` version segment
+fn normalize_endpoint_path(path: &str) -> String {
+    let path = path.trim_end_matches('/').to_lowercase();
+    for version in ["/v1.0", "/beta"] {
+        if let Some(rest) = path.strip_prefix(version) {
+            if rest.is_empty() || rest.starts_with('/') {
+                return rest.to_string();
+            }
+        }
+    }
+    path
+}
+
 impl EndpointsConfig {
-    /// Get all enabled endpoints
+    /// Get all enabled endpoints, highest priority first
     pub fn get_enabled_endpoints(&self) -> Vec<&EndpointConfig> {
-        self.endpoints.iter().filter(|e| e.enabled).collect()
+        let mut enabled: Vec<_> = self.endpoints.iter().filter(|e| e.enabled).collect();
+        enabled.sort_by_key(|e| std::cmp::Reverse(e.priority));
+        enabled
     }
 
     /// Get endpoint by name
@@ -117,10 +542,45 @@ impl EndpointsConfig {
         self.endpoints.iter().find(|e| e.name == name)
     }
 
+    /// Item cap and policy for the endpoint, its own settings taking precedence
+    pub fn max_items_for(&self, endpoint: &EndpointConfig) -> Option<(usize, MaxItemsPolicy)> {
+        endpoint.max_items.or(self.max_items)
+            .map(|cap| (cap, endpoint.on_max_items.unwrap_or(self.on_max_items)))
+    }
+
+    /// Why the endpoint's URL is not allowed by `allowedEndpointHosts`/`allowedEndpointPaths`,
+    /// `None` if it is (or no allowlist is configured)
+    pub fn policy_violation(&self, endpoint: &EndpointConfig) -> Option<String> {
+        if self.allowed_endpoint_hosts.is_none() && self.allowed_endpoint_paths.is_none() {
+            return None;
+        }
+
+        let url = match url::Url::parse(&endpoint.endpoint_url) {
+            Ok(url) => url,
+            Err(_) => return Some(format!("URL {} cannot be checked against the allowlist", endpoint.endpoint_url)),
+        };
+
+        if let Some(ref hosts) = self.allowed_endpoint_hosts {
+            let host = url.host_str().unwrap_or_default();
+            if !hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+                return Some(format!("host {} is not in allowedEndpointHosts", host));
+            }
+        }
+
+        if let Some(ref paths) = self.allowed_endpoint_paths {
+            let path = normalize_endpoint_path(url.path());
+            if !paths.iter().any(|p| normalize_endpoint_path(p) == path) {
+                return Some(format!("path {} is not in allowedEndpointPaths", url.path()));
+            }
+        }
+
+        None
+    }
+
     /// Validate endpoint configurations
     pub fn validate(&self) -> Result<()> {
         if self.endpoints.is_empty() {
-            return Err(anyhow::anyhow!("At least one endpoint must be configured"));
+            return Err(ConfigError::InvalidEndpoint("At least one endpoint must be configured".to_string()).into());
         }
 
         let mut names = std::collections::HashSet::new();
@@ -129,27 +589,103 @@ impl EndpointsConfig {
         for endpoint in &self.endpoints {
             // Check for duplicate names
             if !names.insert(&endpoint.name) {
-                return Err(anyhow::anyhow!("Duplicate endpoint name: {}", endpoint.name));
+                return Err(ConfigError::InvalidEndpoint(format!("Duplicate endpoint name: {}", endpoint.name)).into());
             }
 
-            // Check for duplicate table names
-            if !tables.insert(&endpoint.table_name) {
-                return Err(anyhow::anyhow!("Duplicate table name: {}", endpoint.table_name));
+            // Only one endpoint may own a table; the others have to merge into it by key
+            match endpoint.merge_key {
+                Some(ref key) if key.trim().is_empty() => {
+                    return Err(ConfigError::InvalidEndpoint(format!("mergeKey cannot be empty for endpoint: {}", endpoint.name)).into());
+                }
+                // Its records only hold some columns of rows another endpoint owns
+                Some(_) if endpoint.deletion_mode != DeletionMode::None => {
+                    return Err(ConfigError::InvalidEndpoint(format!("deletionMode cannot be used with mergeKey for endpoint: {}", endpoint.name)).into());
+                }
+                Some(_) => {}
+                None => {
+                    if !tables.insert(&endpoint.table_name) {
+                        return Err(ConfigError::InvalidEndpoint(format!(
+                            "Duplicate table name: {} - set mergeKey on the endpoints that augment its rows",
+                            endpoint.table_name
+                        )).into());
+                    }
+                }
             }
 
             // Validate endpoint URL
             if endpoint.endpoint_url.is_empty() {
-                return Err(anyhow::anyhow!("Endpoint URL cannot be empty for endpoint: {}", endpoint.name));
+                return Err(ConfigError::InvalidEndpoint(format!("Endpoint URL cannot be empty for endpoint: {}", endpoint.name)).into());
             }
 
             // Validate table name
             if endpoint.table_name.is_empty() {
-                return Err(anyhow::anyhow!("Table name cannot be empty for endpoint: {}", endpoint.name));
+                return Err(ConfigError::InvalidEndpoint(format!("Table name cannot be empty for endpoint: {}", endpoint.name)).into());
             }
 
             // Validate URL format
             if let Err(_) = url::Url::parse(&endpoint.endpoint_url) {
-                return Err(anyhow::anyhow!("Invalid endpoint URL for {}: {}", endpoint.name, endpoint.endpoint_url));
+                return Err(ConfigError::InvalidEndpoint(format!("Invalid endpoint URL for {}: {}", endpoint.name, endpoint.endpoint_url)).into());
+            }
+
+            if self.max_items_for(endpoint).is_some_and(|(cap, _)| cap == 0) {
+                return Err(ConfigError::InvalidEndpoint(format!("maxItems must be greater than 0 for endpoint: {}", endpoint.name)).into());
+            }
+
+            if self.max_response_bytes == 0 || self.max_items_per_page == 0 {
+                return Err(ConfigError::InvalidEndpoint("maxResponseBytes and maxItemsPerPage must be greater than 0".to_string()).into());
+            }
+
+            if endpoint.http_method == HttpMethod::Get && endpoint.request_body.is_some() {
+                return Err(ConfigError::InvalidEndpoint(format!("requestBody needs httpMethod POST for endpoint: {}", endpoint.name)).into());
+            }
+
+            if let Some(ref template) = endpoint.detail_endpoint {
+                if !template.starts_with('/') || !template.contains("{id}") {
+                    return Err(ConfigError::InvalidEndpoint(format!(
+                        "detailEndpoint for endpoint {} must be a path starting with '/' containing {{id}}: {}",
+                        endpoint.name, template
+                    )).into());
+                }
+            }
+
+            if let Some(ref transform) = endpoint.transform {
+                if let Err(e) = crate::transform::Transform::parse(transform) {
+                    return Err(ConfigError::InvalidEndpoint(format!("Invalid transform for endpoint {}: {:#}", endpoint.name, e)).into());
+                }
+            }
+
+            if let Some(violation) = self.policy_violation(endpoint) {
+                return Err(ConfigError::InvalidEndpoint(format!("Endpoint {} is not allowed: {}", endpoint.name, violation)).into());
+            }
+
+            // Validate page size
+            if let Some(page_size) = endpoint.page_size {
+                if page_size == 0 {
+                    return Err(ConfigError::InvalidEndpoint(format!("Page size must be greater than 0 for endpoint: {}", endpoint.name)).into());
+                }
+
+                let cap = max_page_size(&endpoint.endpoint_url);
+                if page_size > cap {
+                    warn!(
+                        "Page size {} for endpoint {} exceeds the Graph maximum of {} - the request may be rejected",
+                        page_size, endpoint.name, cap
+                    );
+                }
+            }
+
+            let unknown = unknown_select_fields(endpoint);
+            if !unknown.is_empty() {
+                warn!(
+                    "Endpoint {} selects fields not known for this collection: {} - Graph rejects the whole request if one is misspelled",
+                    endpoint.name, unknown.join(", ")
+                );
+            }
+
+            if select_fields_missing_id(endpoint) {
+                warn!(
+                    "Endpoint {} overrides requiredSelectFields without 'id' - records cannot be matched to existing rows",
+                    endpoint.name
+                );
             }
         }
 
@@ -157,12 +693,124 @@ impl EndpointsConfig {
     }
 }
 
+/// Records in one response: the `value` array of a collection, the results of every
+/// request in a `$batch` response, or the response itself for a single entity
+pub(crate) fn page_items(response: &serde_json::Value) -> Result<Vec<serde_json::Value>> {
+    if let Some(values) = response.get("value").and_then(|v| v.as_array()) {
+        return Ok(values.clone());
+    }
+
+    let Some(responses) = batch_responses(response) else {
+        return Ok(vec![response.clone()]);
+    };
+
+    let mut items = Vec::new();
+    for batch_response in responses {
+        let status = batch_response["status"].as_u64().unwrap_or(0) as u16;
+        let body = &batch_response["body"];
+        if !(200..300).contains(&status) {
+            let request_id = batch_response["id"].as_str().unwrap_or_default();
+            return Err(anyhow::Error::new(GraphError::from_response(status, &body.to_string(), batch_retry_after_header(batch_response)))
+                .context(format!("Batch request {} failed", request_id)));
+        }
+        match body.get("value").and_then(|v| v.as_array()) {
+            Some(values) => items.extend(values.iter().cloned()),
+            None if body.is_object() => items.push(body.clone()),
+            None => {}
+        }
+    }
+    Ok(items)
+}
+
+/// The requests of a `$batch` response in request id order, as they come back in any
+/// order; `None` if the response is not a batch
+fn batch_responses(response: &serde_json::Value) -> Option<Vec<&serde_json::Value>> {
+    let mut responses: Vec<&serde_json::Value> = response.get("responses")?.as_array()?.iter().collect();
+    responses.sort_by_key(|r| {
+        let id = r["id"].as_str().unwrap_or_default();
+        (id.parse::<u64>().unwrap_or(u64::MAX), id.to_string())
+    });
+    Some(responses)
+}
+
+/// `@odata.nextLink` of every request in a `$batch` response that has more pages
+pub(crate) fn batch_next_links(response: &serde_json::Value) -> Vec<String> {
+    batch_responses(response)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|r| r["body"]["@odata.nextLink"].as_str().map(str::to_string))
+        .collect()
+}
+
+/// `Retry-After` of one request in a `$batch` response, from its own headers
+fn batch_retry_after(batch_response: &serde_json::Value) -> Option<Duration> {
+    parse_retry_after_header(batch_retry_after_header(batch_response))
+}
+
+/// Raw `Retry-After` header of a `$batch` sub-response
+fn batch_retry_after_header(batch_response: &serde_json::Value) -> Option<&str> {
+    batch_response["headers"].as_object()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))
+        .and_then(|(_, value)| value.as_str())
+}
+
+/// Most requests Graph accepts in one `$batch`
+pub const MAX_BATCH_REQUESTS: usize = 20;
+
+/// `$batch` URL on the same host and API version as the endpoint
+pub fn batch_url(endpoint_url: &str) -> Result<String> {
+    let url = url::Url::parse(endpoint_url)
+        .with_context(|| format!("Invalid endpoint URL: {}", endpoint_url))?;
+    let version = url.path_segments()
+        .and_then(|mut segments| segments.next())
+        .filter(|version| !version.is_empty())
+        .with_context(|| format!("Endpoint URL has no API version: {}", endpoint_url))?;
+    Ok(format!("{}/{}/$batch", url.origin().ascii_serialization(), version))
+}
+
+/// Detail lookup URL for one record, `None` if the record has no `id`
+pub fn detail_url(template: &str, item: &serde_json::Value) -> Option<String> {
+    let id = item.get("id").and_then(|v| v.as_str())?;
+    let id: String = url::form_urlencoded::byte_serialize(id.as_bytes()).collect();
+    Some(template.replace("{id}", &id))
+}
+
+/// One response page with the ETag Graph sent for it, if any
+struct FetchedPage {
+    data: serde_json::Value,
+    etag: Option<String>,
+}
+
+/// All items fetched from an endpoint in one sync
+#[derive(Debug)]
+pub struct FetchedData {
+    pub data: Vec<serde_json::Value>,
+    /// ETag to send as `If-None-Match` next time
+    pub etag: Option<String>,
+    /// Pagination stopped at `maxItems`, so `data` is incomplete
+    pub truncated: bool,
+    /// `@odata.deltaLink` of the last page, to fetch the next changes from
+    pub delta_link: Option<String>,
+    /// Ids of the records a delta query reported as `@removed`
+    pub removed_ids: Vec<String>,
+    /// Only the changes since a delta link were fetched, not every record
+    pub changes_only: bool,
+}
+
 pub struct EndpointManager {
     config: EndpointsConfig,
     auth_client: AuthClient,
     http_client: Client,
     rate_limited_client: Option<RateLimitedClient>,
+    retry_config: RateLimitConfig,
+    request_budget: RequestBudget,
+    retry_budget: RetryBudget,
+    throttle_gate: ThrottleGate,
     mock_api: Option<MockGraphApi>,
+    log_http_bodies: bool,
+    recorder: Option<ResponseRecorder>,
+    replay: Option<ResponseReplay>,
 }
 
 impl EndpointManager {
@@ -175,6 +823,10 @@ impl EndpointManager {
         let http_client = Client::new();
         let mock_api = mock_api_config.map(|config| MockGraphApi::new(config));
 
+        // Mock API retries follow the same backoff settings as the real client
+        let retry_config = rate_limit_config.clone().unwrap_or_default();
+        let request_budget = RequestBudget::new(retry_config.max_requests_per_sync);
+
         // Create rate limited client if config is provided
         let rate_limited_client = rate_limit_config.map(|config| {
             RateLimitedClient::new(http_client.clone(), config)
@@ -185,29 +837,113 @@ impl EndpointManager {
             auth_client,
             http_client,
             rate_limited_client,
+            retry_config,
+            request_budget,
+            retry_budget: RetryBudget::default(),
+            throttle_gate: ThrottleGate::default(),
             mock_api,
+            log_http_bodies: false,
+            recorder: None,
+            replay: None,
         }
     }
 
+    /// Include (truncated, sanitized) response bodies in the trace-level HTTP log
+    pub fn with_http_body_logging(mut self, enabled: bool) -> Self {
+        self.log_http_bodies = enabled;
+        self
+    }
+
+    /// Write every response page to disk for a later replay
+    pub fn with_response_recorder(mut self, recorder: ResponseRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Answer requests from recorded responses instead of Graph or the mock API
+    pub fn with_replay(mut self, replay: ResponseReplay) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    /// Draw retries from a budget shared with the rest of the sync
+    pub fn with_retry_budget(mut self, retry_budget: RetryBudget) -> Self {
+        self.retry_budget = retry_budget;
+        self
+    }
+
     /// Get all enabled endpoints
     pub fn get_enabled_endpoints(&self) -> Vec<&EndpointConfig> {
         self.config.get_enabled_endpoints()
     }
 
+    /// Requests left for the current sync under `maxRequestsPerSync`
+    pub fn request_budget(&self) -> &RequestBudget {
+        &self.request_budget
+    }
+
+    /// Record or replay the next sync's responses from the first page again
+    pub fn reset_capture(&self) {
+        if let Some(ref recorder) = self.recorder {
+            recorder.reset();
+        }
+        if let Some(ref replay) = self.replay {
+            replay.reset();
+        }
+    }
+
+    /// Retries left for the current sync under `sync.maxRetriesPerSync`/`maxRetrySecondsPerSync`
+    pub fn retry_budget(&self) -> &RetryBudget {
+        &self.retry_budget
+    }
+
+    /// The mock Graph API answering requests, if one is configured
+    #[cfg(test)]
+    pub(crate) fn mock_api(&self) -> Option<&MockGraphApi> {
+        self.mock_api.as_ref()
+    }
+
     /// Fetch data from a specific endpoint
     pub async fn fetch_endpoint_data(&self, endpoint: &EndpointConfig) -> Result<serde_json::Value> {
+        match self.fetch_endpoint_page(endpoint, None).await? {
+            Some(page) => Ok(page.data),
+            None => anyhow::bail!("Endpoint {} answered 304 Not Modified to an unconditional request", endpoint.name),
+        }
+    }
+
+    /// Fetch one page; with `if_none_match` set, `None` means Graph answered 304 Not Modified
+    async fn fetch_endpoint_page(&self, endpoint: &EndpointConfig, if_none_match: Option<&str>) -> Result<Option<FetchedPage>> {
         info!("Fetching data from endpoint: {} ({})", endpoint.name, endpoint.endpoint_url);
+        self.request_budget.consume()?;
+
+        if let Some(ref replay) = self.replay {
+            let recorded = replay.next(&endpoint.name)?;
+            debug!("Replaying recorded response {} of endpoint {}", recorded.sequence, endpoint.name);
+            if recorded.status == reqwest::StatusCode::NOT_MODIFIED.as_u16() {
+                return Ok(None);
+            }
+            if !(200..300).contains(&recorded.status) {
+                let body = recorded.body.as_str().map(str::to_string).unwrap_or_else(|| recorded.body.to_string());
+                return Err(GraphError::from_status(recorded.status, &body).into());
+            }
+            return Ok(Some(FetchedPage { data: recorded.body, etag: recorded.etag }));
+        }
 
         // Check if mock API is enabled and handle supported endpoints
         if let Some(ref mock_api) = self.mock_api {
             if mock_api.is_enabled() {
                 info!("Using mock API for {} endpoint", endpoint.name);
 
-                // Extract skip and top parameters from URL
+                // Extract skip and top parameters from URL, falling back to the configured page size
                 let (skip, top) = self.extract_pagination_params(&endpoint.endpoint_url);
+                let top = top.or(endpoint.page_size);
 
                 // Retry logic for mock API with dynamic endpoint support
-                return self.fetch_mock_data_with_retry(mock_api, &endpoint.name, skip, top).await;
+                let data = self.fetch_mock_data_with_retry(mock_api, &endpoint.name, skip, top).await?;
+                if let Some(ref recorder) = self.recorder {
+                    recorder.record(&endpoint.name, &endpoint.endpoint_url, 200, None, &data);
+                }
+                return Ok(Some(FetchedPage { data, etag: None }));
             }
         }
 
@@ -218,8 +954,12 @@ impl EndpointManager {
         // Build query parameters
         let mut query_params = endpoint.query_params.clone();
         
-        // Add select fields if specified
-        if let Some(ref fields) = endpoint.select_fields {
+        // Add select fields if specified, with the fields the sync cannot work without
+        if let Some(fields) = endpoint.effective_select_fields() {
+            let added = endpoint.missing_required_select_fields();
+            if !added.is_empty() {
+                info!("Adding required fields to $select for endpoint {}: {}", endpoint.name, added.join(", "));
+            }
             query_params.insert("$select".to_string(), fields.join(","));
         }
 
@@ -228,69 +968,382 @@ impl EndpointManager {
             query_params.insert("$filter".to_string(), filter.clone());
         }
 
+        // Add page size if specified
+        if let Some(page_size) = endpoint.page_size {
+            query_params.insert("$top".to_string(), page_size.to_string());
+        }
+
         // Make API request
-        let mut request = self.http_client
-            .get(&endpoint.endpoint_url)
-            .bearer_auth(&token)
-            .header("Content-Type", "application/json");
+        let mut request = match endpoint.http_method {
+            HttpMethod::Get => self.http_client.get(&endpoint.endpoint_url),
+            HttpMethod::Post => self.http_client.post(&endpoint.endpoint_url),
+        }
+        .bearer_auth(&token)
+        .header("Content-Type", "application/json");
+
+        if let Some(ref body) = endpoint.request_body {
+            request = request.json(body);
+        }
 
         // Add query parameters
         for (key, value) in &query_params {
             request = request.query(&[(key, value)]);
         }
 
+        if let Some(etag) = if_none_match {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+
         debug!("Making request to: {} with params: {:?}", endpoint.endpoint_url, query_params);
 
-        let response = request.send().await
-            .context("Failed to send request to endpoint")?;
+        let request = request.build()
+            .context("Failed to build request for endpoint")?;
+
+        if log::log_enabled!(log::Level::Trace) {
+            trace!(
+                "HTTP request: {} {} headers: [{}]",
+                request.method(),
+                request.url(),
+                crate::logging::redact_http_headers(request.headers())
+            );
+        }
+
+        // A 429 closes the throttle gate for every request, this one included, and is
+        // retried once the gate opens again
+        let mut attempt = 1;
+        let response = loop {
+            if let Some(resume) = self.throttle_gate.resume_after() {
+                debug!("Graph is throttling, waiting {:?} before requesting {}", resume.saturating_duration_since(std::time::Instant::now()), endpoint.name);
+            }
+            self.throttle_gate.wait().await;
+            let attempt_request = request.try_clone()
+                .context("Failed to clone request for endpoint")?;
+            let response = self.http_client.execute(attempt_request).await
+                .map_err(GraphError::from_reqwest)
+                .context("Failed to send request to endpoint")?;
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                break response;
+            }
+
+            let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after_header(Some(value)));
+            let delay = retry_after.unwrap_or_else(|| self.retry_config.backoff_delay(attempt));
+            self.throttle_gate.close_for(delay);
+
+            let throttled = anyhow::Error::new(GraphError::Throttled { retry_after_seconds: retry_after.map(|d| d.as_secs()) });
+            if attempt > self.retry_config.max_retry_attempts {
+                return Err(throttled.context(format!("Endpoint {} is still throttled after {} attempts", endpoint.name, attempt)));
+            }
+            if let Err(exhausted) = self.retry_budget.spend(delay) {
+                return Err(throttled.context(exhausted));
+            }
+            warn!(
+                "Endpoint {} was throttled (attempt {}), pausing all Graph requests for {:?}",
+                endpoint.name, attempt, delay
+            );
+            attempt += 1;
+        };
+
+        let status = response.status();
+        trace!("HTTP response: {} from {}", status, response.url());
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(anyhow::anyhow!("API request failed with status {}: {}", status, error_text));
+        if if_none_match.is_some() && status == reqwest::StatusCode::NOT_MODIFIED {
+            if let Some(ref recorder) = self.recorder {
+                recorder.record(&endpoint.name, response.url().as_str(), status.as_u16(), None, &serde_json::Value::Null);
+            }
+            return Ok(None);
         }
+        let url = response.url().to_string();
 
-        let data: serde_json::Value = response.json().await
+        let etag = response.headers().get(reqwest::header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let body = read_body_limited(response, self.config.max_response_bytes).await
+            .with_context(|| format!("Failed to read response body from endpoint {}", endpoint.name))?;
+
+        if self.log_http_bodies {
+            trace!("HTTP response body: {}", crate::logging::format_http_body_for_log(&body));
+        }
+
+        if !status.is_success() {
+            if let Some(ref recorder) = self.recorder {
+                let recorded = serde_json::from_str(&body).unwrap_or_else(|_| serde_json::Value::String(body.clone()));
+                recorder.record(&endpoint.name, &url, status.as_u16(), None, &recorded);
+            }
+            return Err(GraphError::from_response(status.as_u16(), &body, retry_after.as_deref()).into());
+        }
+
+        let data: serde_json::Value = serde_json::from_str(&body)
             .context("Failed to parse response JSON")?;
 
+        // Single entities carry their ETag in the body rather than a header
+        let etag = etag.or_else(|| data.get("@odata.etag").and_then(|v| v.as_str()).map(str::to_string));
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(&endpoint.name, &url, status.as_u16(), etag.as_deref(), &data);
+        }
+
         debug!("Successfully fetched data from endpoint: {}", endpoint.name);
-        Ok(data)
+        Ok(Some(FetchedPage { data, etag }))
     }
 
     /// Fetch paginated data from an endpoint
     pub async fn fetch_all_endpoint_data(&self, endpoint: &EndpointConfig) -> Result<Vec<serde_json::Value>> {
+        match self.fetch_all_endpoint_data_if_changed(endpoint, None).await? {
+            Some(fetched) => Ok(fetched.data),
+            None => anyhow::bail!("Endpoint {} answered 304 Not Modified to an unconditional request", endpoint.name),
+        }
+    }
+
+    /// Fetch paginated data, sending `etag` as `If-None-Match` on the first request.
+    /// Returns `None` if Graph answered 304 Not Modified. An ETag is only returned for
+    /// single-page results, since the first page's ETag says nothing about the pages
+    /// after it. Pagination stops once the endpoint's `maxItems` is reached.
+    pub async fn fetch_all_endpoint_data_if_changed(
+        &self,
+        endpoint: &EndpointConfig,
+        etag: Option<&str>,
+    ) -> Result<Option<FetchedData>> {
         let mut all_data = Vec::new();
+        let mut removed_ids = Vec::new();
+        let mut delta_link = None;
         let mut next_url = Some(endpoint.endpoint_url.clone());
+        // Further pages of the requests in a `$batch`, fetched one chain after the other
+        let mut batch_links = std::collections::VecDeque::new();
+        let mut first_page_etag = None;
+        let mut pages = 0;
+        let mut truncated = false;
+        let max_items = self.config.max_items_for(endpoint);
 
         while let Some(url) = next_url {
-            // Create a temporary endpoint config with the current URL
-            let temp_endpoint = EndpointConfig {
-                endpoint_url: url,
-                ..endpoint.clone()
+            // Query options only go on the first request; nextLink already carries
+            // $select, $filter, $top and any skip token forward
+            let temp_endpoint = if url == endpoint.endpoint_url {
+                endpoint.clone()
+            } else {
+                link_endpoint(endpoint, url)
             };
 
-            let response = self.fetch_endpoint_data(&temp_endpoint).await?;
-
-            // Extract data array
-            if let Some(value_array) = response.get("value").and_then(|v| v.as_array()) {
-                all_data.extend(value_array.iter().cloned());
-            } else {
-                // If no "value" array, treat the whole response as a single item
-                all_data.push(response.clone());
+            let if_none_match = if pages == 0 { etag } else { None };
+            let Some(page) = self.fetch_endpoint_page(&temp_endpoint, if_none_match).await? else {
+                info!("Endpoint {} has not changed since the last sync (304 Not Modified)", endpoint.name);
+                return Ok(None);
+            };
+            if pages == 0 {
+                first_page_etag = page.etag;
+            }
+            pages += 1;
+            let response = page.data;
+
+            let items = page_items(&response)
+                .with_context(|| format!("Endpoint {} returned a failed batch response", endpoint.name))?;
+            if items.len() > self.config.max_items_per_page {
+                anyhow::bail!(
+                    "Endpoint {} returned {} items in one page, more than maxItemsPerPage ({})",
+                    endpoint.name, items.len(), self.config.max_items_per_page
+                );
+            }
+            for item in items {
+                // Delta queries return deleted records as a bare id with an `@removed` annotation
+                if item.get("@removed").is_some() {
+                    if let Some(id) = item.get("id").and_then(|id| id.as_str()) {
+                        removed_ids.push(id.to_string());
+                    }
+                } else {
+                    all_data.push(item);
+                }
             }
 
-            // Check for next page
+            // Check for next page; the last page of a delta query links to the next round instead
+            batch_links.extend(batch_next_links(&response));
             next_url = response.get("@odata.nextLink")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .or_else(|| batch_links.pop_front());
+            delta_link = response.get("@odata.deltaLink")
                 .and_then(|v| v.as_str())
                 .map(|s| s.to_string());
 
+            if let Some((cap, policy)) = max_items {
+                if all_data.len() > cap || (all_data.len() == cap && next_url.is_some()) {
+                    if policy == MaxItemsPolicy::Fail {
+                        anyhow::bail!(
+                            "Endpoint {} returned more than maxItems ({}) items after {} pages",
+                            endpoint.name, cap, pages
+                        );
+                    }
+                    warn!(
+                        "Endpoint {} reached maxItems ({}) after {} pages - storing the first {} items and skipping the rest",
+                        endpoint.name, cap, pages, cap
+                    );
+                    all_data.truncate(cap);
+                    truncated = true;
+                    break;
+                }
+            }
+
             if next_url.is_some() {
                 debug!("Found next page for endpoint: {}", endpoint.name);
             }
         }
 
+        if let Some(ref template) = endpoint.detail_endpoint {
+            self.fetch_details(endpoint, template, &mut all_data).await?;
+        }
+
+        for item in &mut all_data {
+            preprocess_odata_record(endpoint, item);
+        }
+
         info!("Fetched {} total items from endpoint: {}", all_data.len(), endpoint.name);
-        Ok(all_data)
+        Ok(Some(FetchedData {
+            data: all_data,
+            etag: if pages == 1 && !truncated { first_page_etag } else { None },
+            truncated,
+            delta_link: delta_link.filter(|_| !truncated),
+            removed_ids,
+            changes_only: false,
+        }))
+    }
+
+    /// Fetch the changes since `delta_link`, or start a new delta round through the
+    /// endpoint's `/delta` function when there is none. Graph answers a delta link it no
+    /// longer accepts with 410 Gone; a new round is started then as well, which returns
+    /// every record again.
+    pub async fn fetch_endpoint_delta(&self, endpoint: &EndpointConfig, delta_link: Option<&str>) -> Result<FetchedData> {
+        if let Some(link) = delta_link {
+            info!("Fetching changes of endpoint {} since its last delta query", endpoint.name);
+            match self.fetch_all_endpoint_data_if_changed(&link_endpoint(endpoint, link.to_string()), None).await {
+                Ok(Some(fetched)) => return Ok(FetchedData { changes_only: true, ..fetched }),
+                Ok(None) => anyhow::bail!("Endpoint {} answered 304 Not Modified to a delta query", endpoint.name),
+                Err(e) if matches!(GraphError::find(&e), Some(GraphError::Http { status: 410, .. })) => {
+                    warn!("Delta link of endpoint {} has expired (410 Gone) - fetching every record again", endpoint.name);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Most delta functions reject $filter and $top
+        let delta_endpoint = EndpointConfig {
+            endpoint_url: delta_url(&endpoint.endpoint_url),
+            filter: None,
+            page_size: None,
+            ..endpoint.clone()
+        };
+        match self.fetch_all_endpoint_data_if_changed(&delta_endpoint, None).await? {
+            Some(fetched) => Ok(fetched),
+            None => anyhow::bail!("Endpoint {} answered 304 Not Modified to a delta query", endpoint.name),
+        }
+    }
+
+    /// Merge each record's `detailEndpoint` lookup into it, sending the lookups as `$batch`
+    /// requests of up to 20. Batches go out one at a time and each counts as one request
+    /// against the request budget. Records whose lookup returns 404 (deleted since the list
+    /// was fetched) are kept as they are; any other failed lookup fails the fetch.
+    async fn fetch_details(&self, endpoint: &EndpointConfig, template: &str, items: &mut [serde_json::Value]) -> Result<()> {
+        if self.mock_api.as_ref().is_some_and(|mock_api| mock_api.is_enabled()) {
+            debug!("Skipping detail lookups for {} endpoint with the mock API", endpoint.name);
+            return Ok(());
+        }
+
+        let batch_endpoint = EndpointConfig {
+            endpoint_url: batch_url(&endpoint.endpoint_url)?,
+            query_params: HashMap::new(),
+            select_fields: None,
+            filter: None,
+            page_size: None,
+            http_method: HttpMethod::Post,
+            request_body: None,
+            ..endpoint.clone()
+        };
+
+        let mut batches = 0;
+        for chunk in items.chunks_mut(MAX_BATCH_REQUESTS) {
+            // Request ids are positions in the chunk, so responses can come back in any order
+            let mut pending: Vec<serde_json::Value> = chunk.iter().enumerate()
+                .filter_map(|(i, item)| detail_url(template, item).map(|url| serde_json::json!({
+                    "id": i.to_string(),
+                    "method": "GET",
+                    "url": url,
+                })))
+                .collect();
+
+            // Lookups answered 429 are sent again once the throttle gate opens; the rest
+            // of the batch is kept
+            let mut attempt = 1;
+            while !pending.is_empty() {
+                let batch = EndpointConfig {
+                    request_body: Some(serde_json::json!({ "requests": pending })),
+                    ..batch_endpoint.clone()
+                };
+                let response = self.fetch_endpoint_data(&batch).await
+                    .with_context(|| format!("Detail lookup batch failed for endpoint {}", endpoint.name))?;
+                batches += 1;
+
+                let mut throttled = HashSet::new();
+                let mut retry_after = None;
+                for batch_response in response["responses"].as_array().into_iter().flatten() {
+                    let Some(id) = batch_response["id"].as_str() else { continue };
+                    let Some(item) = id.parse::<usize>().ok().and_then(|i| chunk.get_mut(i)) else {
+                        continue;
+                    };
+                    let status = batch_response["status"].as_u64().unwrap_or(0) as u16;
+                    let body = &batch_response["body"];
+
+                    if status == 429 {
+                        retry_after = retry_after.max(batch_retry_after(batch_response));
+                        throttled.insert(id.to_string());
+                        continue;
+                    }
+                    if status == 404 {
+                        warn!("Detail lookup for {} record {} returned 404 - keeping the record without details", endpoint.name, item["id"]);
+                        continue;
+                    }
+                    if !(200..300).contains(&status) {
+                        return Err(anyhow::Error::new(GraphError::from_response(status, &body.to_string(), batch_retry_after_header(batch_response)))
+                            .context(format!("Detail lookup failed for {} record {}", endpoint.name, item["id"])));
+                    }
+
+                    if let (Some(record), Some(detail)) = (item.as_object_mut(), body.as_object()) {
+                        for (key, value) in detail {
+                            if key != "@odata.context" {
+                                record.insert(key.clone(), value.clone());
+                            }
+                        }
+                    }
+                }
+
+                pending.retain(|request| request["id"].as_str().is_some_and(|id| throttled.contains(id)));
+                if pending.is_empty() {
+                    break;
+                }
+
+                let delay = retry_after.unwrap_or_else(|| self.retry_config.backoff_delay(attempt));
+                self.throttle_gate.close_for(delay);
+                let throttled = anyhow::Error::new(GraphError::Throttled { retry_after_seconds: retry_after.map(|d| d.as_secs()) });
+                if attempt > self.retry_config.max_retry_attempts {
+                    return Err(throttled.context(format!(
+                        "Detail lookups for endpoint {} are still throttled after {} attempts",
+                        endpoint.name, attempt
+                    )));
+                }
+                if let Err(exhausted) = self.retry_budget.spend(delay) {
+                    return Err(throttled.context(exhausted));
+                }
+                warn!(
+                    "{} detail lookups for endpoint {} were throttled (attempt {}), retrying them after {:?}",
+                    pending.len(), endpoint.name, attempt, delay
+                );
+                attempt += 1;
+            }
+        }
+
+        debug!("Merged details for {} items from endpoint {} in {} batches", items.len(), endpoint.name, batches);
+        Ok(())
     }
 
     /// Apply field mappings to data
@@ -357,14 +1410,11 @@ impl EndpointManager {
         skip: Option<u32>,
         top: Option<u32>
     ) -> Result<serde_json::Value> {
-        const MAX_RETRIES: u32 = 5;
-        const INITIAL_DELAY: Duration = Duration::from_secs(1);
-        const BACKOFF_MULTIPLIER: f64 = 2.0;
-
         let mut attempt = 1;
-        let mut delay = INITIAL_DELAY;
 
         loop {
+            self.throttle_gate.wait().await;
+
             // Get endpoint configuration to pass to mock API
             let endpoint_config = self.config.get_endpoint_by_name(endpoint_name);
             let result = mock_api.get_endpoint_data(endpoint_name, endpoint_config, skip, top).await;
@@ -377,32 +1427,27 @@ impl EndpointManager {
                     return Ok(serde_json::to_value(response)?);
                 }
                 Err(e) => {
-                    let error_msg = e.to_string();
-
-                    // Check if this is a retryable error
-                    let is_retryable = error_msg.contains("Rate limited") ||
-                                     error_msg.contains("429") ||
-                                     error_msg.contains("Network error") ||
-                                     error_msg.contains("timeout");
+                    let is_retryable = GraphError::find(&e).is_some_and(|e| e.is_retryable());
 
-                    if !is_retryable || attempt >= MAX_RETRIES {
+                    if !is_retryable || attempt > self.retry_config.max_retry_attempts {
                         warn!("Mock API request failed after {} attempts: {}", attempt, e);
                         return Err(e);
                     }
 
+                    let delay = self.retry_config.backoff_delay(attempt);
+                    if let Err(exhausted) = self.retry_budget.spend(delay) {
+                        warn!("Mock API request failed (attempt {}), not retrying: {}", attempt, exhausted);
+                        return Err(e.context(exhausted));
+                    }
                     warn!("Mock API request failed (attempt {}), retrying in {:?}: {}",
                           attempt, delay, e);
 
-                    sleep(delay).await;
-
-                    // Exponential backoff with jitter
-                    delay = Duration::from_millis(
-                        (delay.as_millis() as f64 * BACKOFF_MULTIPLIER) as u64 +
-                        (std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap_or_default()
-                            .subsec_millis() % 100) as u64
-                    );
+                    // Throttling pauses every request through the gate, other failures only this one
+                    if matches!(GraphError::find(&e), Some(GraphError::Throttled { .. })) {
+                        self.throttle_gate.close_for(delay);
+                    } else {
+                        sleep(delay).await;
+                    }
 
                     attempt += 1;
                 }
@@ -411,6 +1456,177 @@ impl EndpointManager {
     }
 }
 
+/// Request for a link Graph returned (`@odata.nextLink`, `@odata.deltaLink`), which already
+/// carries the query options of the request it continues
+fn link_endpoint(endpoint: &EndpointConfig, url: String) -> EndpointConfig {
+    EndpointConfig {
+        endpoint_url: url,
+        query_params: HashMap::new(),
+        select_fields: None,
+        filter: None,
+        page_size: None,
+        http_method: HttpMethod::Get,
+        request_body: None,
+        ..endpoint.clone()
+    }
+}
+
+/// URL of a collection's delta function (`.../managedDevices` -> `.../managedDevices/delta`)
+pub fn delta_url(endpoint_url: &str) -> String {
+    let (path, query) = match endpoint_url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (endpoint_url, None),
+    };
+    let path = path.trim_end_matches('/');
+    let path = if path.ends_with("/delta") { path.to_string() } else { format!("{}/delta", path) };
+    match query {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    }
+}
+
+/// Names of the navigation properties requested through `$expand`
+/// (e.g. `"manager($select=id),memberOf"` -> `["manager", "memberOf"]`)
+pub fn expanded_navigation_properties(endpoint: &EndpointConfig) -> Vec<String> {
+    let expand = match endpoint.query_params.get("$expand") {
+        Some(expand) => expand,
+        None => return Vec::new(),
+    };
+
+    let mut names = Vec::new();
+    let mut depth = 0;
+    let mut current = String::new();
+
+    for c in expand.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                names.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ if depth == 0 => current.push(c),
+            _ => {}
+        }
+    }
+    names.push(current);
+
+    names.into_iter()
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+/// Read a response body, failing as soon as it grows past `limit` bytes instead of
+/// buffering whatever the server sends
+async fn read_body_limited(mut response: reqwest::Response, limit: u64) -> Result<String> {
+    if let Some(length) = response.content_length().filter(|length| *length > limit) {
+        anyhow::bail!("Response of {} bytes exceeds maxResponseBytes ({})", length, limit);
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() as u64 + chunk.len() as u64 > limit {
+            anyhow::bail!("Response exceeds maxResponseBytes ({})", limit);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Turn an OData annotation key into something usable as a column name
+/// (`@odata.type` -> `odata_type`, `manager@odata.navigationLink` -> `manager_odata_navigationLink`)
+fn sanitize_annotation_key(key: &str) -> String {
+    key.trim_start_matches('@')
+        .chars()
+        .map(|c| if c == '@' || c == '.' { '_' } else { c })
+        .collect()
+}
+
+/// Apply the endpoint's OData annotation and navigation property settings to a record
+pub fn preprocess_odata_record(endpoint: &EndpointConfig, item: &mut serde_json::Value) {
+    let obj = match item.as_object_mut() {
+        Some(obj) => obj,
+        None => return,
+    };
+
+    let annotation_keys: Vec<String> = obj.keys()
+        .filter(|k| k.contains("@odata."))
+        .cloned()
+        .collect();
+
+    for key in annotation_keys {
+        if let Some(value) = obj.remove(&key) {
+            if endpoint.odata_annotations == ODataAnnotationMode::Keep {
+                obj.insert(sanitize_annotation_key(&key), value);
+            }
+        }
+    }
+
+    if !endpoint.keep_navigation_properties {
+        for property in expanded_navigation_properties(endpoint) {
+            obj.remove(&property);
+        }
+    }
+}
+
+/// Strip fields that the endpoint's `storeFields`/`excludeFields` lists do not allow to be stored
+pub fn apply_field_selection(endpoint: &EndpointConfig, item: &mut serde_json::Value) {
+    if endpoint.store_fields.is_none() && endpoint.exclude_fields.is_empty() {
+        return;
+    }
+
+    if let Some(obj) = item.as_object_mut() {
+        obj.retain(|key, _| {
+            let allowed = key == "id"
+                || endpoint.store_fields.as_ref().map_or(true, |fields| fields.contains(key));
+            allowed && !endpoint.exclude_fields.contains(key)
+        });
+    }
+}
+
+/// Replace the endpoint's `hashFields` with salted SHA256 tokens (nulls are left as they are)
+pub fn apply_field_hashing(endpoint: &EndpointConfig, item: &mut serde_json::Value, salt: &str) {
+    if endpoint.hash_fields.is_empty() {
+        return;
+    }
+
+    if let Some(obj) = item.as_object_mut() {
+        for field in &endpoint.hash_fields {
+            if let Some(value) = obj.get_mut(field) {
+                let plain = match &*value {
+                    serde_json::Value::Null => continue,
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                *value = serde_json::Value::String(crate::fingerprint::pseudonymize_value(&plain, salt));
+            }
+        }
+    }
+}
+
+/// `$filter` fetching only records whose `incrementalField` is newer than the
+/// watermark minus the safety margin, combined with the endpoint's own filter
+pub fn incremental_filter(endpoint: &EndpointConfig, watermark: DateTime<Utc>) -> Option<String> {
+    let field = endpoint.incremental_field.as_deref()?;
+    let since = watermark - chrono::Duration::seconds(endpoint.incremental_safety_margin_seconds as i64);
+    let clause = format!("{} gt {}", field, since.to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    Some(match endpoint.filter.as_deref() {
+        Some(filter) if !filter.trim().is_empty() => format!("({}) and {}", filter, clause),
+        _ => clause,
+    })
+}
+
+/// Newest value of the timestamp field across the fetched records
+pub fn max_incremental_value(field: &str, data: &[serde_json::Value]) -> Option<DateTime<Utc>> {
+    data.iter()
+        .filter_map(|item| item.get(field)?.as_str())
+        .filter_map(|value| DateTime::parse_from_rfc3339(value).ok())
+        .map(|value| value.with_timezone(&Utc))
+        .max()
+}
+
 /// Predefined endpoint configurations for common Microsoft Graph endpoints
 pub struct PredefinedEndpoints;
 
@@ -432,6 +1648,7 @@ impl PredefinedEndpoints {
                 object_count: 30000,
                 enabled: true,
             }),
+            ..Default::default()
         }
     }
 
@@ -463,6 +1680,7 @@ impl PredefinedEndpoints {
                 object_count: 5000,
                 enabled: true,
             }),
+            ..Default::default()
         }
     }
 
@@ -492,6 +1710,7 @@ impl PredefinedEndpoints {
                 object_count: 1000,
                 enabled: true,
             }),
+            ..Default::default()
         }
     }
 
@@ -512,6 +1731,7 @@ impl PredefinedEndpoints {
                 object_count: 100,
                 enabled: true,
             }),
+            ..Default::default()
         }
     }
 
@@ -555,6 +1775,7 @@ mod tests {
                     filter: None,
                     field_mappings: HashMap::new(),
                     mock_config: None,
+                    ..Default::default()
                 },
                 EndpointConfig {
                     name: "users".to_string(),
@@ -568,8 +1789,10 @@ mod tests {
                     filter: None,
                     field_mappings: HashMap::new(),
                     mock_config: None,
+                    ..Default::default()
                 },
             ],
+            ..Default::default()
         };
 
         // Valid configuration should pass
@@ -583,6 +1806,19 @@ mod tests {
         config.endpoints[1].name = "users".to_string();
         config.endpoints[1].table_name = "devices".to_string();
         assert!(config.validate().is_err());
+
+        // A second endpoint may share the table when it merges by key
+        config.endpoints[1].merge_key = Some("deviceId".to_string());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.endpoints[0].hash_column(), "device_hash");
+        assert_eq!(config.endpoints[1].hash_column(), "device_hash_users");
+
+        config.endpoints[1].deletion_mode = DeletionMode::Hard;
+        assert!(config.validate().is_err());
+        config.endpoints[1].deletion_mode = DeletionMode::None;
+
+        config.endpoints[1].merge_key = Some(" ".to_string());
+        assert!(config.validate().is_err());
     }
 
     #[test]
@@ -619,6 +1855,7 @@ mod tests {
                     ..Default::default()
                 },
             ],
+            ..Default::default()
         };
 
         let enabled = config.get_enabled_endpoints();
@@ -626,4 +1863,854 @@ mod tests {
         assert_eq!(enabled[0].name, "devices");
         assert_eq!(enabled[1].name, "groups");
     }
+
+    #[test]
+    fn test_enabled_endpoints_by_priority() {
+        let endpoint = |name: &str, priority| EndpointConfig { name: name.to_string(), priority, ..Default::default() };
+        let config = EndpointsConfig {
+            endpoints: vec![endpoint("groups", 0), endpoint("users", 0), endpoint("devices", 10)],
+            ..Default::default()
+        };
+
+        let names: Vec<_> = config.get_enabled_endpoints().iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["devices", "groups", "users"]);
+    }
+
+    #[test]
+    fn test_page_size_validation() {
+        assert_eq!(max_page_size("https://graph.microsoft.com/v1.0/users"), 999);
+        assert_eq!(max_page_size("https://graph.microsoft.com/v1.0/deviceManagement/managedDevices"), 999);
+        assert_eq!(max_page_size("https://graph.microsoft.com/v1.0/deviceManagement/deviceCompliancePolicies"), 100);
+        assert_eq!(max_page_size("https://graph.microsoft.com/beta/someNewCollection"), DEFAULT_MAX_PAGE_SIZE);
+
+        let mut config = EndpointsConfig::default();
+        config.endpoints[0].page_size = Some(999);
+        assert!(config.validate().is_ok());
+
+        // Above the cap only warns, zero is rejected
+        config.endpoints[0].page_size = Some(5000);
+        assert!(config.validate().is_ok());
+        config.endpoints[0].page_size = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_required_select_fields() {
+        let mut devices = EndpointConfig {
+            select_fields: Some(vec!["deviceName".to_string(), "OperatingSystem".to_string()]),
+            ..Default::default()
+        };
+        let missing = devices.missing_required_select_fields();
+        assert!(missing.contains(&"id") && missing.contains(&"serialNumber"));
+        assert!(!missing.contains(&"operatingSystem"));
+        let fields = devices.effective_select_fields().unwrap();
+        assert_eq!(&fields[..2], ["deviceName", "OperatingSystem"]);
+        assert_eq!(fields.len(), 2 + missing.len());
+
+        // Other collections only need 'id'
+        let mut users = PredefinedEndpoints::users();
+        users.select_fields = Some(vec!["mail".to_string()]);
+        assert_eq!(users.effective_select_fields().unwrap(), ["mail", "id"]);
+
+        // No selectFields means every field comes back, so nothing is added
+        users.select_fields = None;
+        assert!(users.effective_select_fields().is_none());
+
+        // The override replaces the built-in set
+        devices.required_select_fields = Some(vec!["id".to_string()]);
+        assert_eq!(devices.effective_select_fields().unwrap(), ["deviceName", "OperatingSystem", "id"]);
+    }
+
+    #[test]
+    fn test_select_field_validation() {
+        let mut endpoint = PredefinedEndpoints::groups();
+        assert!(unknown_select_fields(&endpoint).is_empty());
+        assert!(!select_fields_missing_id(&endpoint));
+
+        endpoint.select_fields = Some(vec!["displyName".to_string(), "MAIL".to_string()]);
+        assert_eq!(unknown_select_fields(&endpoint), vec!["displyName"]);
+        assert!(!select_fields_missing_id(&endpoint));
+
+        // Only an override without 'id' can drop it
+        endpoint.required_select_fields = Some(Vec::new());
+        assert!(select_fields_missing_id(&endpoint));
+        endpoint.required_select_fields = None;
+
+        // No known list for this collection, so nothing is flagged
+        endpoint.endpoint_url = "https://graph.microsoft.com/beta/someNewCollection".to_string();
+        assert!(unknown_select_fields(&endpoint).is_empty());
+
+        // Unknown fields only warn
+        let config = EndpointsConfig { endpoints: vec![endpoint], ..Default::default() };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_endpoint_allowlist() {
+        let mut config = EndpointsConfig {
+            endpoints: vec![PredefinedEndpoints::managed_devices(), PredefinedEndpoints::users()],
+            ..Default::default()
+        };
+        // No allowlist, no restriction
+        assert!(config.validate().is_ok());
+
+        config.allowed_endpoint_paths = Some(vec!["/deviceManagement/managedDevices/".to_string()]);
+        assert!(config.policy_violation(&config.endpoints[0]).is_none());
+        let violation = config.policy_violation(&config.endpoints[1]).unwrap();
+        assert!(violation.contains("/v1.0/users"));
+        assert!(config.validate().is_err());
+
+        // The API version is ignored on both sides
+        config.allowed_endpoint_paths = Some(vec!["/beta/users".to_string(), "/v1.0/deviceManagement/managedDevices".to_string()]);
+        assert!(config.validate().is_ok());
+
+        config.allowed_endpoint_hosts = Some(vec!["GRAPH.microsoft.com".to_string()]);
+        assert!(config.validate().is_ok());
+        config.endpoints[1].endpoint_url = "https://graph.example.com/v1.0/users".to_string();
+        assert!(config.policy_violation(&config.endpoints[1]).unwrap().contains("allowedEndpointHosts"));
+    }
+
+    #[test]
+    fn test_apply_field_selection() {
+        let record = serde_json::json!({
+            "id": "1",
+            "deviceName": "PC-01",
+            "emailAddress": "ada@example.com",
+            "userPrincipalName": "ada@example.com",
+            "imei": "490154203237518"
+        });
+
+        // No lists configured - record is untouched
+        let mut endpoint = EndpointConfig::default();
+        let mut item = record.clone();
+        apply_field_selection(&endpoint, &mut item);
+        assert_eq!(item, record);
+
+        // Deny list
+        endpoint.exclude_fields = vec!["emailAddress".to_string(), "imei".to_string()];
+        let mut item = record.clone();
+        apply_field_selection(&endpoint, &mut item);
+        assert!(item.get("emailAddress").is_none());
+        assert!(item.get("imei").is_none());
+        assert_eq!(item["userPrincipalName"], "ada@example.com");
+
+        // Allow list keeps id even if not listed, deny list still wins
+        endpoint.store_fields = Some(vec!["deviceName".to_string(), "imei".to_string()]);
+        let mut item = record.clone();
+        apply_field_selection(&endpoint, &mut item);
+        let mut keys: Vec<&String> = item.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["deviceName", "id"]);
+    }
+
+    #[test]
+    fn test_full_sync_due() {
+        let now = Utc::now();
+        let mut endpoint = EndpointConfig::default();
+        assert!(!endpoint.full_sync_due(None, now));
+
+        endpoint.sync_mode = SyncMode::Full;
+        assert!(endpoint.full_sync_due(Some(now), now));
+
+        endpoint.sync_mode = SyncMode::Auto;
+        assert!(endpoint.full_sync_due(None, now));
+        assert!(!endpoint.full_sync_due(Some(now - chrono::Duration::hours(23)), now));
+        assert!(endpoint.full_sync_due(Some(now - chrono::Duration::hours(24)), now));
+
+        endpoint.full_sync_every = Some("7d".to_string());
+        assert!(!endpoint.full_sync_due(Some(now - chrono::Duration::days(6)), now));
+        assert!(endpoint.full_sync_due(Some(now - chrono::Duration::days(7)), now));
+    }
+
+    #[test]
+    fn test_incremental_filter() {
+        let watermark = DateTime::parse_from_rfc3339("2024-05-01T10:00:00Z").unwrap().with_timezone(&Utc);
+        let mut endpoint = EndpointConfig::default();
+        assert_eq!(incremental_filter(&endpoint, watermark), None);
+
+        endpoint.incremental_field = Some("lastModifiedDateTime".to_string());
+        assert_eq!(
+            incremental_filter(&endpoint, watermark).unwrap(),
+            "lastModifiedDateTime gt 2024-05-01T09:55:00Z"
+        );
+
+        endpoint.filter = Some("operatingSystem eq 'Windows'".to_string());
+        endpoint.incremental_safety_margin_seconds = 0;
+        assert_eq!(
+            incremental_filter(&endpoint, watermark).unwrap(),
+            "(operatingSystem eq 'Windows') and lastModifiedDateTime gt 2024-05-01T10:00:00Z"
+        );
+    }
+
+    #[test]
+    fn test_max_incremental_value() {
+        let data = vec![
+            serde_json::json!({"id": "1", "lastModifiedDateTime": "2024-05-01T10:00:00Z"}),
+            serde_json::json!({"id": "2", "lastModifiedDateTime": "2024-05-03T08:30:00+02:00"}),
+            serde_json::json!({"id": "3", "lastModifiedDateTime": null}),
+            serde_json::json!({"id": "4", "lastModifiedDateTime": "not a date"}),
+        ];
+        assert_eq!(
+            max_incremental_value("lastModifiedDateTime", &data).unwrap().to_rfc3339(),
+            "2024-05-03T06:30:00+00:00"
+        );
+        assert_eq!(max_incremental_value("createdDateTime", &data), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_if_changed_with_etag() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/deviceManagement/settings", server.url()),
+            use_etag: true,
+            ..Default::default()
+        };
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+
+        let changed = server.mock("GET", "/v1.0/deviceManagement/settings")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_header("etag", "W/\"1\"")
+            .with_body(r#"{"value": [{"id": "a"}]}"#)
+            .create_async().await;
+        let fetched = manager.fetch_all_endpoint_data_if_changed(&endpoint, None).await.unwrap().unwrap();
+        assert_eq!(fetched.data.len(), 1);
+        assert_eq!(fetched.etag.as_deref(), Some("W/\"1\""));
+        changed.assert_async().await;
+
+        let not_modified = server.mock("GET", "/v1.0/deviceManagement/settings")
+            .match_header("if-none-match", "W/\"1\"")
+            .with_status(304)
+            .create_async().await;
+        assert!(manager.fetch_all_endpoint_data_if_changed(&endpoint, fetched.etag.as_deref()).await.unwrap().is_none());
+        not_modified.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_fetch_endpoint_delta() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/deviceManagement/managedDevices", server.url()),
+            use_delta: true,
+            filter: Some("operatingSystem eq 'Windows'".to_string()),
+            page_size: Some(100),
+            ..Default::default()
+        };
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+        let delta_path = "/v1.0/deviceManagement/managedDevices/delta";
+        let url = server.url();
+        let link = |query: &str| format!("{}{}?{}", url, delta_path, query);
+        let ids = |fetched: &FetchedData| -> Vec<String> {
+            fetched.data.iter().map(|item| item["id"].as_str().unwrap().to_string()).collect()
+        };
+
+        // A new round pages through every record, without $filter and $top, and ends
+        // with the link to the next one
+        let start = server.mock("GET", delta_path)
+            .match_query(mockito::Matcher::Missing)
+            .with_body(serde_json::json!({"value": [{"id": "1"}, {"id": "2"}], "@odata.nextLink": link("$skiptoken=p2")}).to_string())
+            .expect(2)
+            .create_async().await;
+        let page2 = server.mock("GET", delta_path)
+            .match_query(mockito::Matcher::UrlEncoded("$skiptoken".into(), "p2".into()))
+            .with_body(serde_json::json!({
+                "value": [{"id": "3"}, {"id": "4", "@removed": {"reason": "deleted"}}],
+                "@odata.deltaLink": link("$deltatoken=t1"),
+            }).to_string())
+            .expect(2)
+            .create_async().await;
+        let fetched = manager.fetch_endpoint_delta(&endpoint, None).await.unwrap();
+        assert_eq!(ids(&fetched), vec!["1", "2", "3"]);
+        assert_eq!(fetched.removed_ids, vec!["4"]);
+        assert_eq!(fetched.delta_link, Some(link("$deltatoken=t1")));
+        assert!(!fetched.changes_only);
+
+        // Resuming only returns what changed
+        let changes = server.mock("GET", delta_path)
+            .match_query(mockito::Matcher::UrlEncoded("$deltatoken".into(), "t1".into()))
+            .with_body(serde_json::json!({"value": [{"id": "2"}], "@odata.deltaLink": link("$deltatoken=t2")}).to_string())
+            .create_async().await;
+        let fetched = manager.fetch_endpoint_delta(&endpoint, fetched.delta_link.as_deref()).await.unwrap();
+        assert_eq!(ids(&fetched), vec!["2"]);
+        assert_eq!(fetched.delta_link, Some(link("$deltatoken=t2")));
+        assert!(fetched.changes_only);
+
+        // An expired link starts a new round
+        let expired = server.mock("GET", delta_path)
+            .match_query(mockito::Matcher::UrlEncoded("$deltatoken".into(), "t2".into()))
+            .with_status(410)
+            .create_async().await;
+        let fetched = manager.fetch_endpoint_delta(&endpoint, fetched.delta_link.as_deref()).await.unwrap();
+        assert_eq!(ids(&fetched), vec!["1", "2", "3"]);
+        assert_eq!(fetched.delta_link, Some(link("$deltatoken=t1")));
+        assert!(!fetched.changes_only);
+
+        start.assert_async().await;
+        page2.assert_async().await;
+        changes.assert_async().await;
+        expired.assert_async().await;
+        assert_eq!(delta_url("https://graph.microsoft.com/beta/users/?$select=id"), "https://graph.microsoft.com/beta/users/delta?$select=id");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_stops_at_max_items() {
+        let mut server = mockito::Server::new_async().await;
+        let mut endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/users", server.url()),
+            ..Default::default()
+        };
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], max_items: Some(3), ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+
+        let first = server.mock("GET", "/v1.0/users")
+            .with_body(format!(
+                r#"{{"value": [{{"id": "1"}}, {{"id": "2"}}], "@odata.nextLink": "{}/v1.0/users?page=2"}}"#,
+                server.url()
+            ))
+            .expect(2)
+            .create_async().await;
+        let second = server.mock("GET", "/v1.0/users")
+            .match_query(mockito::Matcher::UrlEncoded("page".into(), "2".into()))
+            .with_body(format!(
+                r#"{{"value": [{{"id": "3"}}, {{"id": "4"}}], "@odata.nextLink": "{}/v1.0/users?page=3"}}"#,
+                server.url()
+            ))
+            .expect(2)
+            .create_async().await;
+
+        let fetched = manager.fetch_all_endpoint_data_if_changed(&endpoint, None).await.unwrap().unwrap();
+        let ids: Vec<&str> = fetched.data.iter().map(|item| item["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert!(fetched.truncated);
+
+        endpoint.on_max_items = Some(MaxItemsPolicy::Fail);
+        assert!(manager.fetch_all_endpoint_data_if_changed(&endpoint, None).await.is_err());
+        first.assert_async().await;
+        second.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_fetch_enforces_response_limits() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/users", server.url()),
+            ..Default::default()
+        };
+        let manager = |config: EndpointsConfig| EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..config },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+
+        let body = r#"{"value": [{"id": "1"}, {"id": "2"}, {"id": "3"}]}"#;
+        let mock = server.mock("GET", "/v1.0/users")
+            .with_body(body)
+            .expect(3)
+            .create_async().await;
+
+        let generous = manager(EndpointsConfig::default());
+        assert_eq!(generous.fetch_all_endpoint_data(&endpoint).await.unwrap().len(), 3);
+
+        let small_body = manager(EndpointsConfig { max_response_bytes: body.len() as u64 - 1, ..Default::default() });
+        let err = small_body.fetch_all_endpoint_data(&endpoint).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("maxResponseBytes"));
+
+        let small_page = manager(EndpointsConfig { max_items_per_page: 2, ..Default::default() });
+        let err = small_page.fetch_all_endpoint_data(&endpoint).await.unwrap_err();
+        assert!(err.to_string().contains("maxItemsPerPage"));
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn test_page_items() {
+        let collection = serde_json::json!({"value": [{"id": "1"}, {"id": "2"}]});
+        assert_eq!(page_items(&collection).unwrap().len(), 2);
+
+        let entity = serde_json::json!({"id": "1", "displayName": "PC-01"});
+        assert_eq!(page_items(&entity).unwrap(), vec![entity.clone()]);
+
+        let batch = serde_json::json!({"responses": [
+            {"id": "2", "status": 200, "body": {"value": [{"id": "b"}, {"id": "c"}]}},
+            {"id": "1", "status": 200, "body": {"id": "a"}},
+            {"id": "3", "status": 204},
+        ]});
+        let ids: Vec<_> = page_items(&batch).unwrap().iter().map(|item| item["id"].clone()).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+
+        let failed = serde_json::json!({"responses": [
+            {"id": "1", "status": 200, "body": {"id": "a"}},
+            {"id": "2", "status": 403, "body": {"error": {"code": "Forbidden"}}},
+        ]});
+        let err = page_items(&failed).unwrap_err();
+        assert!(matches!(GraphError::find(&err), Some(GraphError::Unauthorized { status: 403, .. })));
+
+        let throttled = serde_json::json!({"responses": [
+            {"id": "1", "status": 429, "headers": {"Retry-After": "12"}, "body": {"error": {"code": "TooManyRequests"}}},
+        ]});
+        let err = page_items(&throttled).unwrap_err();
+        assert!(matches!(GraphError::find(&err), Some(GraphError::Throttled { retry_after_seconds: Some(12) })));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_with_post_body() {
+        let mut server = mockito::Server::new_async().await;
+        let body = serde_json::json!({"requests": [{"id": "1", "method": "GET", "url": "/users"}]});
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/$batch", server.url()),
+            http_method: HttpMethod::Post,
+            request_body: Some(body.clone()),
+            ..Default::default()
+        };
+        let mock = server.mock("POST", "/v1.0/$batch")
+            .match_body(mockito::Matcher::Json(body))
+            .with_body(r#"{"responses": [{"id": "1", "status": 200, "body": {"value": [{"id": "u1"}]}}]}"#)
+            .create_async().await;
+
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+        let data = manager.fetch_all_endpoint_data(&endpoint).await.unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0]["id"], "u1");
+        mock.assert_async().await;
+
+        // A body on a GET request is a configuration mistake
+        let get_with_body = EndpointConfig { http_method: HttpMethod::Get, ..endpoint };
+        let config = EndpointsConfig { endpoints: vec![get_with_body], ..Default::default() };
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_follows_batch_next_links() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/$batch", server.url()),
+            http_method: HttpMethod::Post,
+            request_body: Some(serde_json::json!({"requests": [
+                {"id": "1", "method": "GET", "url": "/users"},
+                {"id": "2", "method": "GET", "url": "/groups"}
+            ]})),
+            ..Default::default()
+        };
+        let batch = server.mock("POST", "/v1.0/$batch")
+            .with_body(serde_json::json!({"responses": [
+                {"id": "2", "status": 200, "body": {
+                    "value": [{"id": "g1"}],
+                    "@odata.nextLink": format!("{}/v1.0/groups?$skiptoken=a", server.url())
+                }},
+                {"id": "1", "status": 200, "body": {
+                    "value": [{"id": "u1"}],
+                    "@odata.nextLink": format!("{}/v1.0/users?$skiptoken=a", server.url())
+                }}
+            ]}).to_string())
+            .create_async().await;
+        let users = server.mock("GET", "/v1.0/users?$skiptoken=a")
+            .with_body(serde_json::json!({
+                "value": [{"id": "u2"}],
+                "@odata.nextLink": format!("{}/v1.0/users?$skiptoken=b", server.url())
+            }).to_string())
+            .create_async().await;
+        let users_last = server.mock("GET", "/v1.0/users?$skiptoken=b")
+            .with_body(r#"{"value": [{"id": "u3"}]}"#)
+            .create_async().await;
+        let groups = server.mock("GET", "/v1.0/groups?$skiptoken=a")
+            .with_body(r#"{"value": [{"id": "g2"}]}"#)
+            .create_async().await;
+
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+        let data = manager.fetch_all_endpoint_data(&endpoint).await.unwrap();
+        let ids: Vec<_> = data.iter().map(|item| item["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["u1", "g1", "u2", "u3", "g2"]);
+        for mock in [batch, users, users_last, groups] {
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_merges_details_in_batches() {
+        let mut server = mockito::Server::new_async().await;
+        let devices: Vec<_> = (0..25).map(|i| serde_json::json!({"id": format!("d{}", i), "deviceName": "PC"})).collect();
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/deviceManagement/managedDevices", server.url()),
+            detail_endpoint: Some("/deviceManagement/managedDevices/{id}?$select=hardwareInformation".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(batch_url(&endpoint.endpoint_url).unwrap(), format!("{}/v1.0/$batch", server.url()));
+        assert_eq!(
+            detail_url(endpoint.detail_endpoint.as_ref().unwrap(), &devices[3]).unwrap(),
+            "/deviceManagement/managedDevices/d3?$select=hardwareInformation"
+        );
+
+        let list_mock = server.mock("GET", "/v1.0/deviceManagement/managedDevices")
+            .with_body(serde_json::json!({"value": devices}).to_string())
+            .create_async().await;
+        let batch_mock = server.mock("POST", "/v1.0/$batch")
+            .match_body(mockito::Matcher::Regex(r"managedDevices/d\d+\?\$select=hardwareInformation".to_string()))
+            .with_body(r#"{"responses": [
+                {"id": "1", "status": 200, "body": {"hardwareInformation": {"tpmVersion": "2.0"}}},
+                {"id": "0", "status": 200, "body": {"@odata.context": "x", "hardwareInformation": {"tpmVersion": "1.2"}}},
+                {"id": "2", "status": 404, "body": {"error": {"code": "ResourceNotFound"}}}
+            ]}"#)
+            .expect(2)
+            .create_async().await;
+
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+        let data = manager.fetch_all_endpoint_data(&endpoint).await.unwrap();
+        assert_eq!(data.len(), 25);
+        assert_eq!(data[0]["hardwareInformation"]["tpmVersion"], "1.2");
+        assert_eq!(data[1]["hardwareInformation"]["tpmVersion"], "2.0");
+        assert!(data[0].get("@odata.context").is_none());
+        assert!(data[2].get("hardwareInformation").is_none());
+        assert_eq!(data[20]["hardwareInformation"]["tpmVersion"], "1.2");
+        assert_eq!(data[20]["deviceName"], "PC");
+        list_mock.assert_async().await;
+        batch_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_throttled_detail_lookups_are_retried() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/deviceManagement/managedDevices", server.url()),
+            detail_endpoint: Some("/deviceManagement/managedDevices/{id}".to_string()),
+            ..Default::default()
+        };
+        let _list = server.mock("GET", "/v1.0/deviceManagement/managedDevices")
+            .with_body(r#"{"value": [{"id": "d0"}, {"id": "d1"}]}"#)
+            .create_async().await;
+        let first = server.mock("POST", "/v1.0/$batch")
+            .match_body(mockito::Matcher::Regex("managedDevices/d0".to_string()))
+            .with_body(r#"{"responses": [
+                {"id": "0", "status": 200, "body": {"model": "A"}},
+                {"id": "1", "status": 429, "headers": {"retry-after": "0"}, "body": {"error": {"code": "TooManyRequests"}}}
+            ]}"#)
+            .expect(1)
+            .create_async().await;
+        // Only the throttled lookup goes out again
+        let retry = server.mock("POST", "/v1.0/$batch")
+            .match_body(mockito::Matcher::Json(serde_json::json!({"requests": [
+                {"id": "1", "method": "GET", "url": "/deviceManagement/managedDevices/d1"}
+            ]})))
+            .with_body(r#"{"responses": [{"id": "1", "status": 200, "body": {"model": "B"}}]}"#)
+            .expect(1)
+            .create_async().await;
+
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+        let data = manager.fetch_all_endpoint_data(&endpoint).await.unwrap();
+        assert_eq!(data[0]["model"], "A");
+        assert_eq!(data[1]["model"], "B");
+        first.assert_async().await;
+        retry.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_fetch_stops_at_request_budget() {
+        let mut server = mockito::Server::new_async().await;
+        let endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/users", server.url()),
+            ..Default::default()
+        };
+        let next_link = format!("{}/v1.0/users/page2", server.url());
+        let first = server.mock("GET", "/v1.0/users")
+            .with_body(serde_json::json!({"value": [{"id": "1"}], "@odata.nextLink": next_link}).to_string())
+            .expect(2)
+            .create_async().await;
+        let second = server.mock("GET", "/v1.0/users/page2")
+            .with_body(r#"{"value": [{"id": "2"}]}"#)
+            .expect(0)
+            .create_async().await;
+
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            Some(RateLimitConfig { max_requests_per_sync: Some(1), ..Default::default() }),
+        );
+
+        let err = manager.fetch_all_endpoint_data(&endpoint).await.unwrap_err();
+        assert!(crate::rate_limiter::RequestBudgetExhausted::is_cause_of(&err));
+        assert!(manager.request_budget().is_exhausted());
+
+        // A new sync starts with the full budget again
+        manager.request_budget().reset();
+        assert!(manager.fetch_endpoint_data(&endpoint).await.is_ok());
+        first.assert_async().await;
+        second.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_throttled_request_pauses_all_requests() {
+        let mut server = mockito::Server::new_async().await;
+        let throttled = EndpointConfig {
+            name: "devices".to_string(),
+            endpoint_url: format!("{}/v1.0/deviceManagement/managedDevices", server.url()),
+            ..Default::default()
+        };
+        let other = EndpointConfig {
+            name: "users".to_string(),
+            endpoint_url: format!("{}/v1.0/users", server.url()),
+            ..Default::default()
+        };
+        let throttle = server.mock("GET", "/v1.0/deviceManagement/managedDevices")
+            .with_status(429)
+            .with_header("Retry-After", "1")
+            .expect(1)
+            .create_async().await;
+        let devices = server.mock("GET", "/v1.0/deviceManagement/managedDevices")
+            .with_body(r#"{"value": [{"id": "1"}]}"#)
+            .expect(1)
+            .create_async().await;
+        let users = server.mock("GET", "/v1.0/users")
+            .with_body(r#"{"value": [{"id": "2"}]}"#)
+            .expect(1)
+            .create_async().await;
+
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![throttled.clone(), other.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+
+        // The users request starts once devices got its 429 and has to wait for Retry-After
+        let (devices_result, users_wait) = tokio::join!(
+            manager.fetch_endpoint_data(&throttled),
+            async {
+                while manager.throttle_gate.resume_after().is_none() {
+                    sleep(std::time::Duration::from_millis(10)).await;
+                }
+                let start = std::time::Instant::now();
+                manager.fetch_endpoint_data(&other).await.unwrap();
+                start.elapsed()
+            }
+        );
+        assert_eq!(devices_result.unwrap()["value"][0]["id"], "1");
+        assert!(users_wait >= std::time::Duration::from_millis(800));
+        assert!(manager.throttle_gate.resume_after().is_none());
+        throttle.assert_async().await;
+        devices.assert_async().await;
+        users.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay_responses() {
+        let capture = tempfile::TempDir::new().unwrap();
+        let mut server = mockito::Server::new_async().await;
+        let mut endpoint = EndpointConfig {
+            endpoint_url: format!("{}/v1.0/users", server.url()),
+            ..Default::default()
+        };
+        let next_link = format!("{}/v1.0/users/page2", server.url());
+        server.mock("GET", "/v1.0/users")
+            .with_body(serde_json::json!({"value": [{"id": "1"}], "@odata.nextLink": next_link}).to_string())
+            .create_async().await;
+        server.mock("GET", "/v1.0/users/page2")
+            .with_body(r#"{"value": [{"id": "2"}]}"#)
+            .create_async().await;
+
+        let recording = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        ).with_response_recorder(ResponseRecorder::new(capture.path()));
+        let recorded = recording.fetch_all_endpoint_data(&endpoint).await.unwrap();
+        assert_eq!(recorded.len(), 2);
+
+        // The replay needs neither the server nor a token
+        drop(server);
+        endpoint.endpoint_url = "https://graph.microsoft.com/v1.0/users".to_string();
+        let replaying = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::new(crate::config::AppConfig::default()),
+            None,
+            None,
+        ).with_replay(ResponseReplay::new(capture.path()).unwrap());
+        assert_eq!(replaying.fetch_all_endpoint_data(&endpoint).await.unwrap(), recorded);
+    }
+
+    #[test]
+    fn test_apply_field_hashing() {
+        let endpoint = EndpointConfig {
+            hash_fields: vec!["userPrincipalName".to_string(), "managerId".to_string()],
+            ..Default::default()
+        };
+        let mut item = serde_json::json!({
+            "id": "1",
+            "userPrincipalName": "ada@contoso.com",
+            "managerId": null,
+            "deviceName": "PC-01"
+        });
+        apply_field_hashing(&endpoint, &mut item, "salt");
+
+        assert_eq!(item["userPrincipalName"], crate::fingerprint::pseudonymize_value("ada@contoso.com", "salt"));
+        assert!(item["managerId"].is_null());
+        assert_eq!(item["deviceName"], "PC-01");
+    }
+
+    #[test]
+    fn test_preprocess_odata_record() {
+        let mut endpoint = EndpointConfig::default();
+        endpoint.query_params.insert("$expand".to_string(), "manager($select=id,displayName),memberOf".to_string());
+        assert_eq!(expanded_navigation_properties(&endpoint), vec!["manager", "memberOf"]);
+
+        let record = serde_json::json!({
+            "@odata.type": "#microsoft.graph.user",
+            "@odata.etag": "W/\"abc\"",
+            "manager@odata.navigationLink": "users/1/manager",
+            "id": "1",
+            "manager": {"id": "2"},
+            "memberOf": [{"id": "3"}]
+        });
+
+        // Defaults: strip annotations, keep navigation properties
+        let mut item = record.clone();
+        preprocess_odata_record(&endpoint, &mut item);
+        let keys: Vec<&String> = item.as_object().unwrap().keys().collect();
+        assert!(keys.iter().all(|k| !k.contains('@')));
+        assert!(item.get("manager").is_some());
+        assert!(item.get("memberOf").is_some());
+
+        // Keep annotations under sanitized names, drop navigation properties
+        endpoint.odata_annotations = ODataAnnotationMode::Keep;
+        endpoint.keep_navigation_properties = false;
+        let mut item = record.clone();
+        preprocess_odata_record(&endpoint, &mut item);
+        assert_eq!(item["odata_type"], "#microsoft.graph.user");
+        assert_eq!(item["manager_odata_navigationLink"], "users/1/manager");
+        assert!(item.get("odata_etag").is_some());
+        assert!(item.get("manager").is_none());
+        assert!(item.get("memberOf").is_none());
+        assert_eq!(item["id"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_mock_retry_uses_error_kind() {
+        let endpoints = EndpointsConfig {
+            endpoints: vec![EndpointConfig {
+                mock_object_count: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let retry_config = RateLimitConfig {
+            max_retry_attempts: 2,
+            initial_retry_delay_seconds: 0,
+            enable_jitter: false,
+            ..Default::default()
+        };
+        let mock_config = crate::mock_graph_api::MockGraphApiConfig {
+            enabled: true,
+            response_delay_ms: (0, 0),
+            ..Default::default()
+        };
+
+        // Throttling is retried until the attempts run out
+        let manager = EndpointManager::new(
+            endpoints.clone(),
+            AuthClient::new(crate::config::AppConfig::default()),
+            Some(crate::mock_graph_api::MockGraphApiConfig {
+                simulate_rate_limits: true,
+                rate_limit_probability: 1.0,
+                ..mock_config.clone()
+            }),
+            Some(retry_config.clone()),
+        );
+        let mock_api = manager.mock_api.as_ref().unwrap();
+        let err = manager.fetch_mock_data_with_retry(mock_api, "devices", None, None).await.unwrap_err();
+        assert!(matches!(GraphError::find(&err), Some(GraphError::Throttled { .. })));
+        assert_eq!(mock_api.get_request_count().await, 3);
+
+        // Authorization failures are returned on the first attempt
+        let manager = EndpointManager::new(
+            endpoints,
+            AuthClient::new(crate::config::AppConfig::default()),
+            Some(crate::mock_graph_api::MockGraphApiConfig {
+                simulate_auth_failures: true,
+                auth_failure_probability: 1.0,
+                ..mock_config
+            }),
+            Some(retry_config),
+        );
+        let mock_api = manager.mock_api.as_ref().unwrap();
+        let err = manager.fetch_mock_data_with_retry(mock_api, "devices", None, None).await.unwrap_err();
+        assert!(matches!(GraphError::find(&err), Some(GraphError::Unauthorized { .. })));
+        assert_eq!(mock_api.get_request_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_retry_stops_at_retry_budget() {
+        let endpoints = EndpointsConfig {
+            endpoints: vec![EndpointConfig {
+                mock_object_count: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mock_config = crate::mock_graph_api::MockGraphApiConfig {
+            enabled: true,
+            response_delay_ms: (0, 0),
+            simulate_rate_limits: true,
+            rate_limit_probability: 1.0,
+            ..Default::default()
+        };
+        let retry_config = RateLimitConfig {
+            max_retry_attempts: 5,
+            initial_retry_delay_seconds: 0,
+            enable_jitter: false,
+            ..Default::default()
+        };
+
+        // Two retries are left for the whole sync, shared with whatever else retries
+        let retry_budget = RetryBudget::new(Some(2), None);
+        let manager = EndpointManager::new(
+            endpoints,
+            AuthClient::new(crate::config::AppConfig::default()),
+            Some(mock_config),
+            Some(retry_config),
+        ).with_retry_budget(retry_budget.clone());
+        let mock_api = manager.mock_api.as_ref().unwrap();
+
+        let err = manager.fetch_mock_data_with_retry(mock_api, "devices", None, None).await.unwrap_err();
+        assert!(crate::rate_limiter::RetryBudgetExhausted::is_cause_of(&err));
+        assert!(matches!(GraphError::find(&err), Some(GraphError::Throttled { .. })));
+        assert_eq!(mock_api.get_request_count().await, 3);
+        assert!(retry_budget.is_exhausted());
+
+        // The budget is spent, so the next request fails without retrying
+        manager.fetch_mock_data_with_retry(mock_api, "devices", None, None).await.unwrap_err();
+        assert_eq!(mock_api.get_request_count().await, 4);
+    }
 }
@@ -0,0 +1,136 @@
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::config::AppConfig;
+use crate::endpoint::{known_select_fields, EndpointConfig};
+use crate::storage::{sanitize_column_name, DeviceQuery};
+use crate::sync::SyncService;
+use crate::fingerprint::FieldNormalization;
+use crate::uuid_utils::{DeviceInfo, UuidScheme};
+
+/// One page of a device query
+#[derive(Debug, Clone)]
+pub struct DevicePage {
+    pub devices: Vec<DeviceInfo>,
+    /// Devices matching the query across all pages
+    pub total: u64,
+}
+
+/// Library entry point: syncs the configured endpoints and reads the stored devices back.
+///
+/// Wraps the same sync service the binary runs, so configuration, filtering and
+/// change detection behave exactly as with `MSGraphDBSynchronizer sync`.
+pub struct SyncEngine {
+    service: SyncService,
+    uuid_scheme: UuidScheme,
+    normalization: FieldNormalization,
+}
+
+impl SyncEngine {
+    /// Connect to and initialize the configured databases. Graph is not called until
+    /// the first [`sync_once`](Self::sync_once).
+    pub async fn new(config: AppConfig) -> Result<Self> {
+        let uuid_scheme = config.uuid_scheme()?;
+        let normalization = config.normalize_fields.clone();
+        Ok(Self {
+            service: SyncService::new(config).await?,
+            uuid_scheme,
+            normalization,
+        })
+    }
+
+    /// Sync every enabled endpoint once
+    pub async fn sync_once(&mut self) -> Result<()> {
+        self.service.sync_once().await.map(|_| ())
+    }
+
+    /// Stored devices matching `query`; filtering, sorting and paging run in the database
+    pub async fn query_devices(&mut self, query: &DeviceQuery) -> Result<DevicePage> {
+        let endpoint = self.devices_endpoint()?;
+        let (records, total) = self.service.storage_mut().query_devices(&endpoint.table_name, query).await?;
+
+        let mut devices = Vec::with_capacity(records.len());
+        for record in records {
+            let serde_json::Value::Object(row) = record else { continue };
+            let data = graph_field_names(&endpoint, row);
+            devices.push(DeviceInfo::from_normalized_device_data(data, self.uuid_scheme, &self.normalization)?);
+        }
+        Ok(DevicePage { devices, total })
+    }
+
+    /// Number of stored devices
+    pub async fn device_count(&mut self) -> Result<i64> {
+        let endpoint = self.devices_endpoint()?;
+        self.service.storage_mut().get_table_count(&endpoint.table_name).await
+    }
+
+    /// Close the database connections
+    pub async fn close(mut self) -> Result<()> {
+        self.service.cleanup().await
+    }
+
+    fn devices_endpoint(&self) -> Result<EndpointConfig> {
+        self.service.endpoint_manager().get_config()
+            .get_endpoint_by_name("devices")
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No endpoint named 'devices' is configured"))
+    }
+}
+
+/// Give columns back their Graph property names (`devicename` -> `deviceName`) where the
+/// collection's properties are known, so the row reads like the record Graph returned
+fn graph_field_names(endpoint: &EndpointConfig, row: serde_json::Map<String, serde_json::Value>) -> HashMap<String, serde_json::Value> {
+    let known = known_select_fields(&endpoint.endpoint_url).unwrap_or_default();
+    row.into_iter()
+        .map(|(column, value)| {
+            let name = known.iter()
+                .find(|field| sanitize_column_name(field) == column)
+                .map_or(column, |field| field.to_string());
+            (name, value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_query_stored_devices() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let config = AppConfig {
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: temp_file.path().to_string_lossy().into_owned(),
+                    vacuum_into: None,
+                }),
+                postgres: None,
+                mssql: None,
+                ..AppConfig::default().database
+            },
+            ..AppConfig::default()
+        };
+        let mut engine = SyncEngine::new(config).await.unwrap();
+
+        let storage = engine.service.storage_mut();
+        storage.create_table_if_not_exists("devices").await.unwrap();
+        let (records, _) = crate::sync::detect_changes(vec![
+            json!({"id": "0f3c5f3e-7d2a-4c4e-9d7b-1a2b3c4d5e6f", "deviceName": "LAPTOP-1", "operatingSystem": "Windows"}),
+            json!({"id": "6a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d", "deviceName": "MacBook", "operatingSystem": "macOS"}),
+        ], &HashMap::new());
+        storage.store_endpoint_data("devices", &records).await.unwrap();
+
+        assert_eq!(engine.device_count().await.unwrap(), 2);
+        assert_eq!(engine.query_devices(&DeviceQuery::new()).await.unwrap().total, 2);
+
+        let windows = engine.query_devices(&DeviceQuery::new().os("windows")).await.unwrap();
+        assert_eq!(windows.total, 1);
+        assert_eq!(windows.devices[0].name, "LAPTOP-1");
+        assert_eq!(windows.devices[0].os.as_deref(), Some("Windows"));
+        assert_eq!(windows.devices[0].uuid.to_string(), "0f3c5f3e-7d2a-4c4e-9d7b-1a2b3c4d5e6f");
+
+        engine.close().await.unwrap();
+    }
+}
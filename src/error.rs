@@ -0,0 +1,235 @@
+//! Typed errors for the Graph API, authentication, storage and configuration layers.
+//! Callers keep using `anyhow::Result`; these types travel inside `anyhow::Error` and
+//! can be recovered with `downcast_ref` where the kind of failure matters (e.g. retries).
+
+use serde::Deserialize;
+
+/// Failures talking to Microsoft Graph (or the mock API standing in for it)
+#[derive(Debug, thiserror::Error)]
+pub enum GraphError {
+    #[error("Rate limited (429): Too Many Requests")]
+    Throttled { retry_after_seconds: Option<u64> },
+    #[error("Unauthorized ({status}): {message}")]
+    Unauthorized { status: u16, message: String },
+    #[error("Not found (404): {message}")]
+    NotFound { message: String },
+    #[error("Network error: {0}")]
+    Network(String),
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+    #[error("API request failed with status {status}: {message}")]
+    Http { status: u16, message: String },
+}
+
+impl GraphError {
+    /// Map a non-success HTTP status and its body to a typed error
+    pub fn from_status(status: u16, body: &str) -> Self {
+        Self::from_response(status, body, None)
+    }
+
+    /// Like [`from_status`](Self::from_status), with the response's `Retry-After` header
+    /// (seconds or an HTTP date) kept in [`GraphError::Throttled`]
+    pub fn from_response(status: u16, body: &str, retry_after: Option<&str>) -> Self {
+        let message = if body.is_empty() { "Unknown error".to_string() } else { body.to_string() };
+        match status {
+            429 => GraphError::Throttled {
+                retry_after_seconds: crate::rate_limiter::parse_retry_after_header(retry_after).map(|delay| delay.as_secs()),
+            },
+            401 | 403 => GraphError::Unauthorized { status, message },
+            404 => GraphError::NotFound { message },
+            _ => GraphError::Http { status, message },
+        }
+    }
+
+    /// Classify a transport-level failure from reqwest
+    pub fn from_reqwest(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            GraphError::Timeout(error.to_string())
+        } else {
+            GraphError::Network(error.to_string())
+        }
+    }
+
+    /// Whether retrying the same request may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            GraphError::Throttled { .. } | GraphError::Network(_) | GraphError::Timeout(_) => true,
+            GraphError::Http { status, .. } => *status >= 500,
+            GraphError::Unauthorized { .. } | GraphError::NotFound { .. } => false,
+        }
+    }
+
+    /// Find a `GraphError` anywhere in an `anyhow` error chain
+    pub fn find(error: &anyhow::Error) -> Option<&GraphError> {
+        error.chain().find_map(|cause| cause.downcast_ref::<GraphError>())
+    }
+}
+
+/// Error body returned by the Azure AD token endpoint
+#[derive(Debug, Clone, Default, Deserialize)]
+struct TokenErrorResponse {
+    #[serde(default)]
+    error: String,
+    #[serde(default)]
+    error_description: String,
+    #[serde(default)]
+    error_codes: Vec<u64>,
+}
+
+/// Token acquisition failures, with the well-known AADSTS codes mapped to their cause
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("client secret has expired (AADSTS7000222)")]
+    ExpiredSecret { description: String },
+    #[error("invalid client credentials (AADSTS{code})")]
+    InvalidClient { code: u64, description: String },
+    #[error("tenant not found (AADSTS{code})")]
+    TenantNotFound { code: u64, description: String },
+    #[error("account does not exist in the tenant (AADSTS90072)")]
+    AccountNotInTenant { description: String },
+    #[error("blocked by conditional access (AADSTS{code})")]
+    ConditionalAccess { code: u64, description: String },
+    #[error("token request failed with status {status}: {error} {description}")]
+    Other { status: u16, error: String, description: String },
+}
+
+impl AuthError {
+    /// Map a failed token endpoint response to a typed error
+    pub fn from_token_response(status: u16, body: &str) -> Self {
+        let response: TokenErrorResponse = serde_json::from_str(body).unwrap_or_else(|_| TokenErrorResponse {
+            error_description: body.to_string(),
+            ..Default::default()
+        });
+        let description = response.error_description;
+
+        for &code in &response.error_codes {
+            match code {
+                7000222 => return AuthError::ExpiredSecret { description },
+                7000215 | 700016 | 7000218 => return AuthError::InvalidClient { code, description },
+                90002 | 900023 => return AuthError::TenantNotFound { code, description },
+                90072 => return AuthError::AccountNotInTenant { description },
+                53000 | 53001 | 53003 | 50076 | 50079 | 50158 => {
+                    return AuthError::ConditionalAccess { code, description }
+                }
+                _ => {}
+            }
+        }
+
+        AuthError::Other {
+            status,
+            error: response.error,
+            description,
+        }
+    }
+
+    /// Short machine-readable cause, used as the webhook reason code
+    pub fn reason_code(&self) -> &'static str {
+        match self {
+            AuthError::ExpiredSecret { .. } => "expired_secret",
+            AuthError::InvalidClient { .. } => "invalid_client",
+            AuthError::TenantNotFound { .. } => "tenant_not_found",
+            AuthError::AccountNotInTenant { .. } => "account_not_in_tenant",
+            AuthError::ConditionalAccess { .. } => "conditional_access",
+            AuthError::Other { .. } => "token_request_failed",
+        }
+    }
+
+    /// What an administrator should do about it
+    pub fn guidance(&self) -> &'static str {
+        match self {
+            AuthError::ExpiredSecret { .. } => {
+                "Create a new client secret for the app registration and update clientSecret"
+            }
+            AuthError::InvalidClient { .. } => {
+                "Check that clientId and clientSecret belong to the same app registration in this tenant"
+            }
+            AuthError::TenantNotFound { .. } => {
+                "Check tenantId - it must be the directory (tenant) ID or a verified domain of the tenant"
+            }
+            AuthError::AccountNotInTenant { .. } => {
+                "The app or account is not in tenantId's directory - check tenantId, or add it to the tenant as a guest"
+            }
+            AuthError::ConditionalAccess { .. } => {
+                "A conditional access policy blocks this app - exclude the service principal or allow its location"
+            }
+            AuthError::Other { .. } => "See the Azure AD error description for details",
+        }
+    }
+
+    /// Description returned by Azure AD
+    pub fn description(&self) -> &str {
+        match self {
+            AuthError::ExpiredSecret { description }
+            | AuthError::InvalidClient { description, .. }
+            | AuthError::TenantNotFound { description, .. }
+            | AuthError::AccountNotInTenant { description }
+            | AuthError::ConditionalAccess { description, .. }
+            | AuthError::Other { description, .. } => description,
+        }
+    }
+}
+
+/// Storage backend failures
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("No valid storage backends configured")]
+    NoBackends,
+    #[error("Schema conflict in table {table}: {details}")]
+    SchemaConflict { table: String, details: String },
+    #[error("Table {table} does not exist and autoMigrate is off - create it with the statements from `preview-schema`")]
+    TableMissing { table: String },
+    #[error("Table {table} is missing columns {columns} and autoMigrate is off - add them with the statements from `preview-schema`")]
+    MissingColumns { table: String, columns: String },
+    #[error("'{table}' is not a valid table name - use letters, digits and underscores, not starting with a digit")]
+    InvalidTableName { table: String },
+}
+
+/// Configuration problems detected while loading or validating the config
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{0} is required (unless mock API is enabled)")]
+    MissingCredential(&'static str),
+    #[error(
+        "Mock Graph API is enabled while real credentials and a persistent database are configured - \
+         fake devices would be written to that database. Disable mockGraphApi, or set \
+         mockGraphApi.mockConfirm to true if this is intentional"
+    )]
+    UnconfirmedMock,
+    #[error("{0}")]
+    InvalidEndpoint(String),
+    #[error("Config served by {url} failed validation:\n{errors}")]
+    InvalidRemoteConfig { url: String, errors: String },
+    #[error(
+        "No config file found (looked for {searched}). Create one there, point to it with \
+         --config <path>, or set GRAPH_CLIENT_ID, GRAPH_CLIENT_SECRET and GRAPH_TENANT_ID"
+    )]
+    NotFound { searched: String },
+    #[error("Config file {0} does not exist")]
+    FileNotFound(String),
+    #[error("Config profile '{profile}' is not defined in profiles (available: {available})")]
+    UnknownProfile { profile: String, available: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graph_error_classification() {
+        assert!(matches!(GraphError::from_status(429, ""), GraphError::Throttled { retry_after_seconds: None }));
+        assert!(matches!(GraphError::from_response(429, "", Some("30")), GraphError::Throttled { retry_after_seconds: Some(30) }));
+        assert!(matches!(GraphError::from_status(403, "denied"), GraphError::Unauthorized { status: 403, .. }));
+        assert!(matches!(GraphError::from_status(404, ""), GraphError::NotFound { .. }));
+
+        assert!(GraphError::from_status(429, "").is_retryable());
+        assert!(GraphError::from_status(503, "").is_retryable());
+        assert!(GraphError::Network("connection reset".to_string()).is_retryable());
+        assert!(GraphError::Timeout("after 30s".to_string()).is_retryable());
+        assert!(!GraphError::from_status(401, "").is_retryable());
+        assert!(!GraphError::from_status(400, "bad request").is_retryable());
+
+        let wrapped = anyhow::Error::new(GraphError::Network("timeout".to_string())).context("Failed to fetch page");
+        assert!(GraphError::find(&wrapped).is_some_and(|e| e.is_retryable()));
+        assert!(GraphError::find(&anyhow::anyhow!("Rate limited (429)")).is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use log::{debug, info};
 
 use crate::metrics;
@@ -11,6 +12,78 @@ pub fn normalize_filter(raw: &str) -> Vec<String> {
         .collect()
 }
 
+/// Whether a filter entry is a glob pattern such as `windows*` rather than a plain
+/// name. The lone `*` is not, it keeps meaning "all devices".
+pub fn is_glob_pattern(filter: &str) -> bool {
+    filter != "*" && filter.contains(['*', '?', '['])
+}
+
+/// Normalized filter entries split by how they match: the glob entries are compiled once
+/// into a single set instead of per device.
+struct CompiledFilters {
+    all: bool,
+    names: Vec<String>,
+    globs: GlobSet,
+}
+
+impl CompiledFilters {
+    /// Compiles the glob entries of `filters`. A pattern that does not compile is kept as a
+    /// plain name.
+    fn new(filters: &[String]) -> Self {
+        let mut names = Vec::new();
+        let mut builder = GlobSetBuilder::new();
+        for filter in filters {
+            match is_glob_pattern(filter).then(|| Glob::new(filter)) {
+                Some(Ok(glob)) => {
+                    builder.add(glob);
+                }
+                _ => names.push(filter.clone()),
+            }
+        }
+
+        let globs = builder.build().unwrap_or_else(|e| {
+            debug!("Failed to build OS filter glob set, ignoring globs: {}", e);
+            GlobSet::empty()
+        });
+
+        Self {
+            all: filters.iter().any(|f| f == "*"),
+            names,
+            globs,
+        }
+    }
+
+    /// Glob entries must match the whole lowercased OS; plain names match anywhere in it.
+    fn matches(&self, device_os: Option<&str>) -> bool {
+        // If wildcard is present, match everything
+        if self.all {
+            debug!("Wildcard filter found, allowing all devices");
+            return true;
+        }
+
+        // Handle missing/empty OS
+        let os = match device_os {
+            Some(os) if !os.trim().is_empty() => os.trim().to_lowercase(),
+            _ => {
+                debug!("Device has no OS information, treating as 'unknown'");
+                "unknown".to_string()
+            }
+        };
+
+        let matches = self.globs.is_match(&os) || self.names.iter().any(|name| os.contains(name.as_str()));
+
+        if matches {
+            debug!("Device OS '{}' matched filters", os);
+            metrics::DEVICE_FILTER_MATCHED_TOTAL.inc();
+        } else {
+            debug!("Device OS '{}' did not match any filters", os);
+            metrics::DEVICE_FILTER_SKIPPED_TOTAL.inc();
+        }
+
+        matches
+    }
+}
+
 /// Checks if a device OS matches any of the provided filters.
 /// 
 /// # Arguments
@@ -22,40 +95,13 @@ pub fn normalize_filter(raw: &str) -> Vec<String> {
 /// 
 /// # Matching Rules
 /// - If filters contain "*", all devices match
+/// - Entries with `*`, `?` or `[...]` are case-insensitive globs matched against the whole OS
 /// - Otherwise, case-insensitive substring matching is used
 /// - Empty or None device OS is treated as "unknown"
+///
+/// This compiles the globs on every call; [`DeviceOsFilter`] compiles them once.
 pub fn os_matches_filter(device_os: Option<&str>, filters: &[String]) -> bool {
-    // If wildcard is present, match everything
-    if filters.contains(&"*".to_string()) {
-        debug!("Wildcard filter found, allowing all devices");
-        return true;
-    }
-
-    // Handle missing/empty OS
-    let os = match device_os {
-        Some(os) if !os.trim().is_empty() => os.trim().to_lowercase(),
-        _ => {
-            debug!("Device has no OS information, treating as 'unknown'");
-            "unknown".to_string()
-        }
-    };
-
-    // Check if any filter matches
-    let matches = filters.iter().any(|filter| {
-        let result = os.contains(filter);
-        debug!("Checking OS '{}' against filter '{}': {}", os, filter, result);
-        result
-    });
-
-    if matches {
-        debug!("Device OS '{}' matched filters", os);
-        metrics::DEVICE_FILTER_MATCHED_TOTAL.inc();
-    } else {
-        debug!("Device OS '{}' did not match any filters", os);
-        metrics::DEVICE_FILTER_SKIPPED_TOTAL.inc();
-    }
-
-    matches
+    CompiledFilters::new(filters).matches(device_os)
 }
 
 /// Logs information about a device being filtered
@@ -77,6 +123,7 @@ pub fn log_device_filter_result(
 /// Device OS filter configuration and logic
 pub struct DeviceOsFilter {
     filters: Vec<String>,
+    compiled: CompiledFilters,
 }
 
 impl DeviceOsFilter {
@@ -97,6 +144,7 @@ impl DeviceOsFilter {
         info!("Initialized OS filter with rules: {:?}", normalized_filters);
 
         Self {
+            compiled: CompiledFilters::new(&normalized_filters),
             filters: normalized_filters,
         }
     }
@@ -107,7 +155,7 @@ impl DeviceOsFilter {
         device_name: Option<&str>,
         device_os: Option<&str>,
     ) -> bool {
-        let matches = os_matches_filter(device_os, &self.filters);
+        let matches = self.compiled.matches(device_os);
         log_device_filter_result(device_name, device_os, matches);
         matches
     }
@@ -163,6 +211,35 @@ mod tests {
         assert!(!os_matches_filter(Some(""), &filters));
     }
 
+    #[test]
+    fn test_glob_filter() {
+        let filters = normalize_filter("Windows*, *Server*, i?adOS, [AM]ac*");
+
+        assert!(os_matches_filter(Some("Windows"), &filters));
+        assert!(os_matches_filter(Some("Windows 10"), &filters));
+        assert!(os_matches_filter(Some("Windows 11"), &filters));
+        assert!(os_matches_filter(Some("Windows Server 2022"), &filters));
+        assert!(os_matches_filter(Some("Linux Server"), &filters));
+        assert!(os_matches_filter(Some("iPadOS"), &filters));
+        assert!(os_matches_filter(Some("macOS"), &filters));
+        assert!(os_matches_filter(Some("MacMDM"), &filters));
+        assert!(!os_matches_filter(Some("Windows Mobile"), &["windows?".to_string()]));
+        assert!(!os_matches_filter(Some("iOS"), &filters));
+        assert!(!os_matches_filter(Some("AndroidForWork"), &filters));
+        assert!(!os_matches_filter(Some("HoloLens Windows"), &filters));
+
+        // Plain names still match anywhere in the OS
+        assert!(os_matches_filter(Some("AndroidEnterprise"), &["android".to_string(), "win*".to_string()]));
+
+        // A pattern that does not compile falls back to a plain name
+        assert!(os_matches_filter(Some("Legacy [Win"), &["[win".to_string()]));
+        assert!(!os_matches_filter(Some("Windows"), &["[win".to_string()]));
+
+        assert!(is_glob_pattern("windows*"));
+        assert!(!is_glob_pattern("*"));
+        assert!(!is_glob_pattern("windows"));
+    }
+
     #[test]
     fn test_wildcard_filter() {
         let wildcard_filters = vec!["*".to_string()];
@@ -185,6 +262,11 @@ mod tests {
         
         assert!(!filter.allows_all());
         assert_eq!(filter.get_filters(), &["windows", "macos"]);
+
+        let filter = DeviceOsFilter::new(&["Windows*, android".to_string()]);
+        assert!(filter.should_include_device(Some("Test Device"), Some("Windows 11")));
+        assert!(filter.should_include_device(Some("Test Device"), Some("AndroidForWork")));
+        assert!(!filter.should_include_device(Some("Test Device"), Some("HoloLens Windows")));
     }
 
     #[test]
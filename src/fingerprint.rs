@@ -2,7 +2,14 @@ use log::{debug, warn};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
-/// Generates a SHA256 fingerprint from device identifying information
+use crate::config::NormalizeRule;
+
+/// Per-field normalization rules, as configured in `normalizeFields`
+pub type FieldNormalization = HashMap<String, Vec<NormalizeRule>>;
+
+/// Generates a SHA256 fingerprint from device identifying information. Identifiers are
+/// normalized by their field's rules first, so `aa:bb:cc` and `AA-BB-CC` give the same
+/// fingerprint when the field is normalized.
 pub fn generate_device_fingerprint(
     serial_number: Option<&str>,
     imei: Option<&str>,
@@ -10,42 +17,47 @@ pub fn generate_device_fingerprint(
     azure_ad_device_id: Option<&str>,
     model: Option<&str>,
     enrolled_date_time: Option<&str>,
+    normalization: &FieldNormalization,
 ) -> String {
     let mut hasher = Sha256::new();
     
     // Add available identifying information to the hash
     let mut components = Vec::new();
+    let identifier = |field: &str, value: Option<&str>| {
+        value.map(|value| normalize_identifier(normalization, field, value))
+            .filter(|value| !value.is_empty())
+    };
     
-    if let Some(serial) = serial_number.filter(|s| !s.trim().is_empty()) {
-        components.push(format!("serial:{}", serial.trim()));
-        hasher.update(serial.trim().as_bytes());
+    if let Some(serial) = identifier("serialNumber", serial_number) {
+        components.push(format!("serial:{}", serial));
+        hasher.update(serial.as_bytes());
     }
     
-    if let Some(imei) = imei.filter(|s| !s.trim().is_empty()) {
-        components.push(format!("imei:{}", imei.trim()));
-        hasher.update(imei.trim().as_bytes());
+    if let Some(imei) = identifier("imei", imei) {
+        components.push(format!("imei:{}", imei));
+        hasher.update(imei.as_bytes());
     }
     
-    if let Some(hw_id) = hardware_id.filter(|s| !s.trim().is_empty()) {
-        components.push(format!("hardware_id:{}", hw_id.trim()));
-        hasher.update(hw_id.trim().as_bytes());
+    if let Some(hw_id) = identifier("hardwareId", hardware_id) {
+        components.push(format!("hardware_id:{}", hw_id));
+        hasher.update(hw_id.as_bytes());
     }
     
-    if let Some(azure_id) = azure_ad_device_id.filter(|s| !s.trim().is_empty()) {
-        components.push(format!("azure_ad_device_id:{}", azure_id.trim()));
-        hasher.update(azure_id.trim().as_bytes());
+    if let Some(azure_id) = identifier("azureADDeviceId", azure_ad_device_id) {
+        components.push(format!("azure_ad_device_id:{}", azure_id));
+        hasher.update(azure_id.as_bytes());
     }
     
     // Fallback to model + enrollment date if no other identifiers
     if components.is_empty() {
-        if let Some(model) = model.filter(|s| !s.trim().is_empty()) {
-            components.push(format!("model:{}", model.trim()));
-            hasher.update(model.trim().as_bytes());
+        if let Some(model) = identifier("model", model) {
+            components.push(format!("model:{}", model));
+            hasher.update(model.as_bytes());
         }
         
-        if let Some(enrolled) = enrolled_date_time.filter(|s| !s.trim().is_empty()) {
-            components.push(format!("enrolled:{}", enrolled.trim()));
-            hasher.update(enrolled.trim().as_bytes());
+        if let Some(enrolled) = identifier("enrolledDateTime", enrolled_date_time) {
+            components.push(format!("enrolled:{}", enrolled));
+            hasher.update(enrolled.as_bytes());
         }
     }
     
@@ -64,6 +76,39 @@ pub fn generate_device_fingerprint(
     fingerprint
 }
 
+/// An identifier normalized by its field's rules, then trimmed as fingerprints always were
+fn normalize_identifier(normalization: &FieldNormalization, field: &str, value: &str) -> String {
+    match normalization.get(field) {
+        Some(rules) => normalize_value(rules, value).trim().to_string(),
+        None => value.trim().to_string(),
+    }
+}
+
+/// Apply `rules` to `value` in order
+pub fn normalize_value(rules: &[NormalizeRule], value: &str) -> String {
+    rules.iter().fold(value.to_string(), |value, rule| match rule {
+        NormalizeRule::Trim => value.trim().to_string(),
+        NormalizeRule::Uppercase => value.to_uppercase(),
+        NormalizeRule::Lowercase => value.to_lowercase(),
+        NormalizeRule::StripSeparators => value.chars().filter(|c| !matches!(c, ':' | '-' | '.' | ' ')).collect(),
+    })
+}
+
+/// Normalize the string values of the fields in `normalization` (other values are left as they are)
+pub fn apply_field_normalization(normalization: &FieldNormalization, item: &mut serde_json::Value) {
+    if normalization.is_empty() {
+        return;
+    }
+
+    if let Some(obj) = item.as_object_mut() {
+        for (field, rules) in normalization {
+            if let Some(serde_json::Value::String(value)) = obj.get_mut(field) {
+                *value = normalize_value(rules, value);
+            }
+        }
+    }
+}
+
 /// Calculates a hash of device data for change detection
 pub fn calculate_device_hash(device_data: &HashMap<String, serde_json::Value>) -> String {
     let mut hasher = Sha256::new();
@@ -85,6 +130,16 @@ pub fn calculate_device_hash(device_data: &HashMap<String, serde_json::Value>) -
     hex::encode(result)
 }
 
+/// Replaces a value with a salted SHA256 token so it can still be joined on
+/// without storing it in clear text. The same value and salt always give the same token.
+pub fn pseudonymize_value(value: &str, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(b":");
+    hasher.update(value.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
 /// Extracts identifying information from device data for fingerprinting
 pub fn extract_device_identifiers(
     device_data: &HashMap<String, serde_json::Value>,
@@ -144,6 +199,7 @@ mod tests {
             None,
             None,
             None,
+            &HashMap::new(),
         );
         assert!(!fingerprint1.is_empty());
         assert_eq!(fingerprint1.len(), 64); // SHA256 hex length
@@ -156,6 +212,7 @@ mod tests {
             None,
             None,
             None,
+            &HashMap::new(),
         );
         assert!(!fingerprint2.is_empty());
         assert_ne!(fingerprint1, fingerprint2);
@@ -168,6 +225,7 @@ mod tests {
             None,
             Some("iPhone"),
             Some("2023-01-01T00:00:00Z"),
+            &HashMap::new(),
         );
         assert!(!fingerprint3.is_empty());
         
@@ -179,10 +237,50 @@ mod tests {
             None,
             None,
             None,
+            &HashMap::new(),
         );
         assert!(!fingerprint4.is_empty());
     }
     
+    #[test]
+    fn test_field_normalization() {
+        let normalization: FieldNormalization = HashMap::from([
+            ("serialNumber".to_string(), vec![NormalizeRule::Trim, NormalizeRule::Uppercase, NormalizeRule::StripSeparators]),
+        ]);
+
+        let mut first = json!({"serialNumber": "aa:bb:cc", "model": "aa:bb:cc"});
+        let mut second = json!({"serialNumber": " AA-BB-CC ", "model": 42});
+        apply_field_normalization(&normalization, &mut first);
+        apply_field_normalization(&normalization, &mut second);
+        assert_eq!(first["serialNumber"], "AABBCC");
+        assert_eq!(first["serialNumber"], second["serialNumber"]);
+        // Fields without rules and non-string values are left alone
+        assert_eq!(first["model"], "aa:bb:cc");
+        assert_eq!(second["model"], 42);
+
+        let fingerprint = |serial| generate_device_fingerprint(Some(serial), None, None, None, None, None, &normalization);
+        assert_eq!(fingerprint("aa:bb:cc"), fingerprint("AA-BB-CC"));
+        assert_ne!(fingerprint("aa:bb:cc"), fingerprint("AA-BB-CD"));
+        // Without rules the formatting still matters, as before
+        assert_ne!(
+            generate_device_fingerprint(Some("aa:bb:cc"), None, None, None, None, None, &HashMap::new()),
+            generate_device_fingerprint(Some("AA-BB-CC"), None, None, None, None, None, &HashMap::new())
+        );
+    }
+
+    #[test]
+    fn test_pseudonymize_value() {
+        let token1 = pseudonymize_value("ada@contoso.com", "deployment-salt");
+        let token2 = pseudonymize_value("ada@contoso.com", "deployment-salt");
+        assert_eq!(token1, token2);
+        assert_eq!(token1.len(), 64);
+        assert!(!token1.contains("ada"));
+
+        // Different input or salt gives a different token
+        assert_ne!(token1, pseudonymize_value("bob@contoso.com", "deployment-salt"));
+        assert_ne!(token1, pseudonymize_value("ada@contoso.com", "other-salt"));
+    }
+
     #[test]
     fn test_calculate_device_hash() {
         let mut device_data = HashMap::new();
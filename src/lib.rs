@@ -0,0 +1,65 @@
+//! Microsoft Graph to database synchronization.
+//!
+//! The [`SyncEngine`] runs the same sync as the `MSGraphDBSynchronizer` binary and reads
+//! the synced devices back, for embedding in other Rust services:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use msgraph_db_synchronizer::{AppConfig, ConfigSource, DeviceQuery, SyncEngine};
+//!
+//! let config = AppConfig::load(&ConfigSource::default()).await?;
+//! let mut engine = SyncEngine::new(config).await?;
+//! engine.sync_once().await?;
+//! println!("{} devices", engine.device_count().await?);
+//!
+//! let page = engine.query_devices(&DeviceQuery::new().os("Windows").limit(50)).await?;
+//! println!("showing {} of {} Windows devices", page.devices.len(), page.total);
+//! engine.close().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything apart from the items re-exported here is internal and may change at any time.
+
+mod audit;
+mod auth;
+mod backup;
+mod bench;
+mod capture;
+mod circuit_breaker;
+mod config;
+mod config_validator;
+mod endpoint;
+mod engine;
+mod error;
+mod filter;
+mod fingerprint;
+mod lock;
+mod logging;
+mod metrics;
+mod mock_graph_api;
+mod mock_server;
+mod path_utils;
+mod permissions;
+mod rate_limiter;
+mod service_manager;
+mod storage;
+mod sync;
+mod transform;
+mod uuid_utils;
+mod version;
+mod webhook;
+
+#[doc(hidden)]
+pub mod cli;
+
+pub use config::{
+    AppConfig, ConfigSource, ConnectionFields, DatabaseConfig, MissingColumnPolicy, MssqlConfig, OverlapPolicy,
+    PostgresConfig, SchemaConflictPolicy, SqliteConfig, SyncConfig, TlsConfig, TlsMode,
+    UuidCollisionPolicy, UuidStrategy,
+};
+pub use endpoint::{EndpointConfig, EndpointsConfig, MaxItemsPolicy, ODataAnnotationMode};
+pub use engine::{DevicePage, SyncEngine};
+pub use storage::{DeviceQuery, DeviceSort};
+pub use uuid_utils::{DeviceInfo, UuidScheme};
+pub use webhook::WebhookConfig;
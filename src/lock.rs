@@ -0,0 +1,116 @@
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Exclusive lock that keeps a second instance from syncing into the same database.
+///
+/// Backed by an OS advisory lock (`flock`/`LockFileEx`) on a file that also holds the
+/// owner's PID. The OS drops the lock when the process dies, so a file left behind by a
+/// crash is detected as stale and taken over. Released (and the PID cleared) when dropped.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Take the lock, or fail with the PID of the instance holding it
+    pub fn acquire(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory for lock file: {}", parent.display()))?;
+        }
+
+        // Not truncated on open: the holder's PID must survive a failed attempt
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file: {}", path.display()))?;
+
+        let previous_pid = read_pid(&mut file);
+
+        if file.try_lock_exclusive().is_err() {
+            let holder = previous_pid.map_or_else(|| "unknown".to_string(), |pid| pid.to_string());
+            anyhow::bail!(
+                "Another instance is already running (PID {}, lock file {}). Stop it first or set lockFile to a different path",
+                holder,
+                path.display()
+            );
+        }
+
+        if let Some(pid) = previous_pid.filter(|&pid| pid != std::process::id()) {
+            warn!("Taking over stale lock file {} left by PID {} that is no longer running", path.display(), pid);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+
+        info!("Acquired instance lock {}", path.display());
+        Ok(Self { file, path: path.to_path_buf() })
+    }
+
+    /// PID of the running instance holding the lock at `path`, `None` if nobody holds it.
+    /// For commands that must not run alongside the service.
+    pub fn holder(path: &Path) -> Option<u32> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).ok()?;
+        if file.try_lock_exclusive().is_ok() {
+            let _ = file.unlock();
+            return None;
+        }
+        read_pid(&mut file).or(Some(0))
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Clear the PID rather than deleting the file: an open file cannot be removed on
+        // Windows, and an empty file marks a clean shutdown instead of a stale lock
+        if let Err(e) = self.file.set_len(0) {
+            warn!("Failed to clear lock file {}: {}", self.path.display(), e);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_lock() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("sync.lock");
+
+        let lock = InstanceLock::acquire(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), std::process::id().to_string());
+        assert_eq!(InstanceLock::holder(&path), Some(std::process::id()));
+
+        let err = InstanceLock::acquire(&path).unwrap_err();
+        assert!(err.to_string().contains("Another instance is already running"));
+
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(InstanceLock::holder(&path), None);
+
+        // A file left behind by a dead process is not held and gets taken over
+        std::fs::write(&path, "999999").unwrap();
+        assert_eq!(InstanceLock::holder(&path), None);
+        let _lock = InstanceLock::acquire(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), std::process::id().to_string());
+    }
+}
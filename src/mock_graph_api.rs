@@ -7,6 +7,8 @@ use anyhow::Result;
 use log::{info, debug, warn};
 use uuid::Uuid;
 
+use crate::error::GraphError;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MockGraphApiConfig {
     /// Enable mock mode instead of real Graph API
@@ -36,6 +38,13 @@ pub struct MockGraphApiConfig {
     /// Device update frequency (how often devices change)
     #[serde(rename = "deviceUpdateFrequency")]
     pub device_update_frequency: f64,
+    /// Confirms that mock data may be written while real credentials and a
+    /// persistent database are configured
+    #[serde(rename = "mockConfirm", default)]
+    pub mock_confirm: bool,
+    /// Port `mock-serve` serves the mock API on over HTTP
+    #[serde(rename = "servePort")]
+    pub serve_port: Option<u16>,
 }
 
 impl Default for MockGraphApiConfig {
@@ -50,6 +59,8 @@ impl Default for MockGraphApiConfig {
             network_error_probability: 0.02,
             response_delay_ms: (100, 500),
             device_update_frequency: 0.1,
+            mock_confirm: false,
+            serve_port: None,
         }
     }
 }
@@ -115,6 +126,8 @@ pub struct MockGraphApi {
     config: MockGraphApiConfig,
     devices: Arc<RwLock<HashMap<String, MockDevice>>>,
     request_count: Arc<RwLock<u64>>,
+    /// Device count of the last generation, so devices added or removed since survive
+    generated_count: Arc<RwLock<Option<u32>>>,
 }
 
 impl MockGraphApi {
@@ -123,6 +136,7 @@ impl MockGraphApi {
             config: config.clone(),
             devices: Arc::new(RwLock::new(HashMap::new())),
             request_count: Arc::new(RwLock::new(0)),
+            generated_count: Arc::new(RwLock::new(None)),
         };
 
         // Generate initial mock devices
@@ -214,7 +228,7 @@ impl MockGraphApi {
         let devices = self.devices.read().await;
         devices.get(device_id)
             .cloned()
-            .ok_or_else(|| anyhow::anyhow!("Device not found: {}", device_id))
+            .ok_or_else(|| GraphError::NotFound { message: format!("Device not found: {}", device_id) }.into())
     }
 
     pub async fn get_request_count(&self) -> u64 {
@@ -244,15 +258,7 @@ impl MockGraphApi {
     /// Regenerate devices with a specific count
     async fn regenerate_devices_with_count(&self, count: u32) {
         info!("Regenerating {} mock devices", count);
-
-        // Clear existing devices
-        {
-            let mut devices = self.devices.write().await;
-            devices.clear();
-        }
-
-        // Generate new devices with the specified count
-        self.generate_mock_devices_internal(count).await;
+        self.generate_mock_devices_internal(count, true).await;
     }
 
     /// Dynamic endpoint data generation - supports any enabled endpoint
@@ -267,16 +273,20 @@ impl MockGraphApi {
             return Err(anyhow::anyhow!("Mock API is not enabled"));
         }
 
-        // For devices endpoint, use the existing implementation but check if we need to regenerate
-        if endpoint_name == "devices" {
+        // For devices endpoint, use the existing implementation but check if we need to regenerate.
+        // Endpoints under a name the mock has no generator for that list managed devices get devices too.
+        let lists_devices = !matches!(endpoint_name.to_lowercase().as_str(), "users" | "groups" | "compliance_policies")
+            && endpoint_config
+                .is_some_and(|config| config.endpoint_url.trim_end_matches('/').ends_with("/deviceManagement/managedDevices"));
+        if endpoint_name == "devices" || lists_devices {
             // Check if we need to regenerate devices based on endpoint config
             let expected_count = endpoint_config
                 .and_then(|config| config.mock_object_count)
                 .unwrap_or(30000);
 
-            let current_count = self.get_device_count().await;
-            if current_count != expected_count as usize {
-                info!("Regenerating devices: current={}, expected={}", current_count, expected_count);
+            let generated_count = *self.generated_count.read().await;
+            if generated_count != Some(expected_count) {
+                info!("Regenerating devices: generated={:?}, expected={}", generated_count, expected_count);
                 self.regenerate_devices_with_count(expected_count).await;
             }
 
@@ -525,8 +535,7 @@ impl MockGraphApi {
 
         // Get select fields from endpoint config or use defaults
         let select_fields = endpoint_config
-            .and_then(|config| config.select_fields.as_ref())
-            .cloned()
+            .and_then(|config| config.effective_select_fields())
             .unwrap_or_else(|| vec![
                 "id".to_string(), "userPrincipalName".to_string(), "displayName".to_string(),
                 "mail".to_string(), "jobTitle".to_string(), "department".to_string(),
@@ -564,8 +573,7 @@ impl MockGraphApi {
 
         // Get select fields from endpoint config or use defaults
         let select_fields = endpoint_config
-            .and_then(|config| config.select_fields.as_ref())
-            .cloned()
+            .and_then(|config| config.effective_select_fields())
             .unwrap_or_else(|| vec![
                 "id".to_string(), "displayName".to_string(), "description".to_string(),
                 "groupTypes".to_string(), "mail".to_string(), "mailEnabled".to_string(),
@@ -610,8 +618,7 @@ impl MockGraphApi {
 
         // Get select fields from endpoint config or use defaults
         let select_fields = endpoint_config
-            .and_then(|config| config.select_fields.as_ref())
-            .cloned()
+            .and_then(|config| config.effective_select_fields())
             .unwrap_or_else(|| vec![
                 "id".to_string(), "displayName".to_string(), "description".to_string(),
                 "platformType".to_string(), "createdDateTime".to_string(), "lastModifiedDateTime".to_string()
@@ -649,10 +656,14 @@ impl MockGraphApi {
     async fn generate_mock_devices(&self) {
         // Use default device count since it's now per-endpoint
         let count = 30000; // Default fallback
-        self.generate_mock_devices_internal(count).await;
+        // Runs in the background, so an endpoint may already have generated its own count
+        self.generate_mock_devices_internal(count, false).await;
     }
 
-    async fn generate_mock_devices_internal(&self, device_count: u32) {
+    /// Generate `device_count` devices in place of the current ones, or only if there are
+    /// none yet when `replace` is false. One write lock covers the check and the inserts,
+    /// so concurrent generations never mix.
+    async fn generate_mock_devices_internal(&self, device_count: u32, replace: bool) {
         info!("Generating {} mock devices", device_count);
 
         let operating_systems = vec!["Windows", "macOS", "Android", "iOS"];
@@ -676,6 +687,11 @@ impl MockGraphApi {
 
         let tenant_id = Uuid::new_v4().to_string(); // Single tenant for all devices
         let mut devices = self.devices.write().await;
+        if !replace && !devices.is_empty() {
+            return;
+        }
+        devices.clear();
+        *self.generated_count.write().await = Some(device_count);
 
         for i in 0..device_count {
             let os = operating_systems[i as usize % operating_systems.len()];
@@ -811,19 +827,19 @@ impl MockGraphApi {
         // Simulate rate limiting
         if self.config.simulate_rate_limits && random_value < self.config.rate_limit_probability {
             warn!("Mock API: Simulating rate limit response");
-            return Err(anyhow::anyhow!("Rate limited (429): Too Many Requests"));
+            return Err(GraphError::Throttled { retry_after_seconds: None }.into());
         }
 
         // Simulate authentication failures
         if self.config.simulate_auth_failures && random_value < self.config.auth_failure_probability {
             warn!("Mock API: Simulating authentication failure");
-            return Err(anyhow::anyhow!("Authentication failed (401): Unauthorized"));
+            return Err(GraphError::Unauthorized { status: 401, message: "Authentication failed".to_string() }.into());
         }
 
         // Simulate network errors
         if self.config.simulate_network_errors && random_value < self.config.network_error_probability {
             warn!("Mock API: Simulating network error");
-            return Err(anyhow::anyhow!("Network error: Connection timeout"));
+            return Err(GraphError::Timeout("Connection timeout".to_string()).into());
         }
 
         Ok(())
@@ -878,6 +894,7 @@ impl Clone for MockGraphApi {
             config: self.config.clone(),
             devices: Arc::clone(&self.devices),
             request_count: Arc::clone(&self.request_count),
+            generated_count: Arc::clone(&self.generated_count),
         }
     }
 }
@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use log::info;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::endpoint::EndpointConfig;
+use crate::error::GraphError;
+use crate::mock_graph_api::{MockGraphApi, MockGraphApiConfig};
+
+/// Port `mock-serve` listens on when neither `--port` nor `servePort` is set
+pub const DEFAULT_MOCK_SERVE_PORT: u16 = 8089;
+
+struct MockServer {
+    api: MockGraphApi,
+    /// Configured endpoints by name, for their `mockObjectCount` and `selectFields`
+    endpoints: HashMap<String, EndpointConfig>,
+}
+
+/// Routes serving the mock API the way Graph does, so the real HTTP client, rate
+/// limiter and retries can be pointed at it through each endpoint's `endpointUrl`
+pub fn router(config: MockGraphApiConfig, endpoints: Vec<EndpointConfig>) -> Router {
+    // The mock only answers while enabled; serving it is what enabling means here
+    let api = MockGraphApi::new(MockGraphApiConfig { enabled: true, ..config });
+    let server = MockServer {
+        api,
+        endpoints: endpoints.into_iter().map(|e| (e.name.clone(), e)).collect(),
+    };
+
+    Router::new()
+        .route("/:version/deviceManagement/managedDevices", get(managed_devices_handler))
+        .route("/:version/users", get(users_handler))
+        .route("/:version/groups", get(groups_handler))
+        .with_state(Arc::new(server))
+}
+
+/// Serve the mock API on `addr` until the process stops
+pub async fn serve(config: MockGraphApiConfig, endpoints: Vec<EndpointConfig>, addr: SocketAddr) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await
+        .with_context(|| format!("Failed to bind mock Graph API to {}", addr))?;
+    info!("Serving mock Graph API on http://{}", listener.local_addr()?);
+
+    axum::serve(listener, router(config, endpoints)).await
+        .context("Mock Graph API server failed")
+}
+
+async fn managed_devices_handler(
+    State(server): State<Arc<MockServer>>,
+    Path(version): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    collection_response(&server, "devices", &version, &headers, &params).await
+}
+
+async fn users_handler(
+    State(server): State<Arc<MockServer>>,
+    Path(version): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    collection_response(&server, "users", &version, &headers, &params).await
+}
+
+async fn groups_handler(
+    State(server): State<Arc<MockServer>>,
+    Path(version): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    collection_response(&server, "groups", &version, &headers, &params).await
+}
+
+async fn collection_response(
+    server: &MockServer,
+    endpoint_name: &str,
+    version: &str,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Response {
+    let skip = params.get("$skip").and_then(|v| v.parse().ok());
+    let top = params.get("$top").and_then(|v| v.parse().ok());
+
+    let result = server.api
+        .get_endpoint_data(endpoint_name, server.endpoints.get(endpoint_name), skip, top)
+        .await;
+    let mut response = match result {
+        Ok(response) => response,
+        Err(e) => return error_response(e),
+    };
+
+    // Next links must come back here rather than go to the real Graph
+    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok()).unwrap_or("localhost");
+    response.odata_next_link = response.odata_next_link.map(|link| {
+        link.replace("https://graph.microsoft.com/v1.0", &format!("http://{}/{}", host, version))
+    });
+
+    Json(response).into_response()
+}
+
+/// Graph-style error body with the status the real API would answer
+fn error_response(error: anyhow::Error) -> Response {
+    let retry_after = match error.downcast_ref::<GraphError>() {
+        Some(GraphError::Throttled { retry_after_seconds }) => retry_after_seconds.unwrap_or(1),
+        _ => 1,
+    };
+    let (status, code) = match error.downcast_ref::<GraphError>() {
+        Some(GraphError::Throttled { .. }) => (StatusCode::TOO_MANY_REQUESTS, "TooManyRequests"),
+        Some(GraphError::Unauthorized { status, .. }) => (
+            StatusCode::from_u16(*status).unwrap_or(StatusCode::UNAUTHORIZED),
+            "InvalidAuthenticationToken",
+        ),
+        Some(GraphError::NotFound { .. }) => (StatusCode::NOT_FOUND, "ResourceNotFound"),
+        Some(GraphError::Network(_)) | Some(GraphError::Timeout(_)) => (StatusCode::SERVICE_UNAVAILABLE, "ServiceUnavailable"),
+        Some(GraphError::Http { status, .. }) => (
+            StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            "generalException",
+        ),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, "generalException"),
+    };
+
+    let body = Json(serde_json::json!({
+        "error": { "code": code, "message": error.to_string() }
+    }));
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return (status, [(header::RETRY_AFTER, retry_after.to_string())], body).into_response();
+    }
+    (status, body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthClient;
+    use crate::endpoint::{EndpointManager, EndpointsConfig};
+
+    async fn start(config: MockGraphApiConfig, endpoints: Vec<EndpointConfig>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(config, endpoints)).await });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_real_client_pages_through_mock_server() {
+        let quiet = MockGraphApiConfig { response_delay_ms: (0, 0), ..Default::default() };
+        let mut endpoint = EndpointConfig {
+            name: "users".to_string(),
+            mock_object_count: Some(25),
+            page_size: Some(10),
+            ..Default::default()
+        };
+        let addr = start(quiet, vec![endpoint.clone()]).await;
+        endpoint.endpoint_url = format!("http://{}/v1.0/users", addr);
+
+        let manager = EndpointManager::new(
+            EndpointsConfig { endpoints: vec![endpoint.clone()], ..Default::default() },
+            AuthClient::with_static_token(crate::config::AppConfig::default(), "token"),
+            None,
+            None,
+        );
+        let data = manager.fetch_all_endpoint_data(&endpoint).await.unwrap();
+        assert_eq!(data.len(), 25);
+        assert!(data.iter().all(|user| user["userPrincipalName"].is_string()));
+    }
+
+    #[tokio::test]
+    async fn test_mock_server_simulated_failures() {
+        let failing = MockGraphApiConfig {
+            response_delay_ms: (0, 0),
+            simulate_auth_failures: true,
+            auth_failure_probability: 1.0,
+            ..Default::default()
+        };
+        let addr = start(failing, Vec::new()).await;
+
+        let response = reqwest::get(format!("http://{}/v1.0/groups", addr)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "InvalidAuthenticationToken");
+    }
+}
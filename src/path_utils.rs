@@ -39,6 +39,50 @@ pub fn resolve_path(path: &str) -> Result<PathBuf> {
     }
 }
 
+/// Resolve a path against a base directory; absolute paths are used as-is
+pub fn resolve_path_in(base: &Path, path: &str) -> PathBuf {
+    let path_buf = PathBuf::from(normalize_path_separators(path));
+    if path_buf.is_absolute() {
+        path_buf
+    } else {
+        base.join(path_buf)
+    }
+}
+
+/// Directory name under the platform data location, matching the service name
+const DATA_DIR_NAME: &str = "msgraph-db-synchronizer";
+
+/// Where the database, logs and lock file live when `dataDir` is not configured:
+/// `%PROGRAMDATA%` on Windows, `/var/lib` on Linux (or the user's data directory
+/// when not running as root and the service has not been installed) and
+/// `~/Library/Application Support` on macOS. Installs that already keep a `data`
+/// folder next to the executable keep using the executable directory.
+pub fn default_data_dir() -> Result<PathBuf> {
+    let exe_dir = get_executable_dir()?;
+    if exe_dir.join("data").is_dir() {
+        return Ok(exe_dir);
+    }
+
+    #[cfg(windows)]
+    {
+        if let Some(program_data) = env::var_os("PROGRAMDATA") {
+            return Ok(PathBuf::from(program_data).join(crate::version::get_product_name()));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let system_dir = Path::new("/var/lib").join(DATA_DIR_NAME);
+        if system_dir.is_dir() || unsafe { libc::geteuid() == 0 } {
+            return Ok(system_dir);
+        }
+    }
+
+    dirs::data_dir()
+        .map(|dir| dir.join(DATA_DIR_NAME))
+        .context("Failed to determine the platform data directory; set dataDir in config.json")
+}
+
 /// Ensure a directory exists, creating it if necessary
 pub async fn ensure_directory_exists<P: AsRef<Path>>(path: P) -> Result<()> {
     let path = path.as_ref();
@@ -74,11 +118,6 @@ pub fn resolve_database_path(configured_path: &str) -> Result<PathBuf> {
     resolve_path(configured_path)
 }
 
-/// Resolve and sanitize a logs directory path from configuration
-pub fn resolve_logs_path(configured_path: &str) -> Result<PathBuf> {
-    resolve_path(configured_path)
-}
-
 /// Resolve and sanitize a backup directory path from configuration
 pub fn resolve_backup_path(configured_path: &str) -> Result<PathBuf> {
     resolve_path(configured_path)
@@ -100,6 +139,16 @@ mod tests {
         assert_eq!(normalized, "./data/test.db");
     }
 
+    #[test]
+    fn test_resolve_path_in() {
+        let base = std::env::temp_dir().join("sync-data");
+        assert_eq!(resolve_path_in(&base, "logs"), base.join("logs"));
+        assert_eq!(resolve_path_in(&base, "./data/test.db"), base.join(".").join("data").join("test.db"));
+
+        let absolute = std::env::temp_dir().join("elsewhere.db");
+        assert_eq!(resolve_path_in(&base, &absolute.to_string_lossy()), absolute);
+    }
+
     #[test]
     fn test_normalize_mixed_separators() {
         let path = "./data\\subdir/test.db";
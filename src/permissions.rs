@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use anyhow::{Context, Result};
+use base64::Engine;
+
+use crate::endpoint::EndpointConfig;
+
+/// Graph permissions that grant read access to common collections, matched against the end
+/// of the URL path like the page size caps. Any one of them is enough; the least privileged
+/// comes first and is the one reported as missing.
+const KNOWN_REQUIRED_PERMISSIONS: &[(&str, &[&str])] = &[
+    ("/devicemanagement/manageddevices", &[
+        "DeviceManagementManagedDevices.Read.All", "DeviceManagementManagedDevices.ReadWrite.All",
+    ]),
+    ("/devicemanagement/devicecompliancepolicies", &[
+        "DeviceManagementConfiguration.Read.All", "DeviceManagementConfiguration.ReadWrite.All",
+    ]),
+    ("/users", &[
+        "User.Read.All", "User.ReadWrite.All", "Directory.Read.All", "Directory.ReadWrite.All",
+    ]),
+    ("/groups", &[
+        "GroupMember.Read.All", "Group.Read.All", "Group.ReadWrite.All", "Directory.Read.All",
+        "Directory.ReadWrite.All",
+    ]),
+    ("/devices", &[
+        "Device.Read.All", "Directory.Read.All", "Directory.ReadWrite.All",
+    ]),
+];
+
+/// Permissions that each grant read access to the endpoint URL, `None` for collections we
+/// have no mapping for
+pub fn required_permissions(endpoint_url: &str) -> Option<&'static [&'static str]> {
+    let path = url::Url::parse(endpoint_url)
+        .map(|u| u.path().trim_end_matches('/').to_lowercase())
+        .unwrap_or_default();
+
+    KNOWN_REQUIRED_PERMISSIONS.iter()
+        .find(|(suffix, _)| path.ends_with(suffix))
+        .map(|(_, permissions)| *permissions)
+}
+
+/// Application roles (`roles`) and delegated scopes (`scp`) granted by an access token.
+/// Only the payload is decoded; the signature is not verified.
+pub fn token_permissions(token: &str) -> Result<Vec<String>> {
+    let payload = token.split('.').nth(1)
+        .context("Access token is not a JWT")?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .context("Access token payload is not valid base64")?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload)
+        .context("Access token payload is not valid JSON")?;
+
+    let roles = claims["roles"].as_array().into_iter().flatten()
+        .filter_map(|role| role.as_str())
+        .map(|role| role.to_string());
+    let scopes = claims["scp"].as_str().unwrap_or_default()
+        .split_whitespace()
+        .map(|scope| scope.to_string());
+
+    Ok(roles.chain(scopes).collect())
+}
+
+/// Whether an endpoint can be read with the granted permissions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointPermission {
+    pub endpoint: String,
+    /// Permissions that would each grant access, `None` when the collection is not mapped
+    pub accepted: Option<&'static [&'static str]>,
+    /// The accepted permission the token has, if any
+    pub granted: Option<&'static str>,
+}
+
+impl EndpointPermission {
+    /// Least privileged permission to add when none is granted
+    pub fn missing(&self) -> Option<&'static str> {
+        match (self.accepted, self.granted) {
+            (Some(accepted), None) => accepted.first().copied(),
+            _ => None,
+        }
+    }
+}
+
+/// Compare granted permissions (matched case-insensitively) against what each endpoint needs
+pub fn check_endpoints(granted: &[String], endpoints: &[&EndpointConfig]) -> Vec<EndpointPermission> {
+    endpoints.iter()
+        .map(|endpoint| {
+            let accepted = required_permissions(&endpoint.endpoint_url);
+            let granted = accepted.and_then(|accepted| {
+                accepted.iter().copied().find(|permission| granted.iter().any(|g| g.eq_ignore_ascii_case(permission)))
+            });
+            EndpointPermission { endpoint: endpoint.name.clone(), accepted, granted }
+        })
+        .collect()
+}
+
+/// Missing permissions with the endpoints each one blocks
+pub fn missing_permissions(checks: &[EndpointPermission]) -> BTreeMap<&'static str, Vec<&str>> {
+    let mut missing: BTreeMap<&'static str, Vec<&str>> = BTreeMap::new();
+    for check in checks {
+        if let Some(permission) = check.missing() {
+            missing.entry(permission).or_default().push(&check.endpoint);
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::PredefinedEndpoints;
+
+    fn token(claims: serde_json::Value) -> String {
+        let encode = |value: &[u8]| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value);
+        format!("{}.{}.signature", encode(br#"{"alg":"RS256","typ":"JWT"}"#), encode(claims.to_string().as_bytes()))
+    }
+
+    #[test]
+    fn test_token_permissions() {
+        let app_token = token(serde_json::json!({"roles": ["User.Read.All", "Group.Read.All"]}));
+        assert_eq!(token_permissions(&app_token).unwrap(), ["User.Read.All", "Group.Read.All"]);
+
+        let delegated_token = token(serde_json::json!({"scp": "User.Read Directory.Read.All"}));
+        assert_eq!(token_permissions(&delegated_token).unwrap(), ["User.Read", "Directory.Read.All"]);
+
+        assert!(token_permissions(&token(serde_json::json!({}))).unwrap().is_empty());
+        assert!(token_permissions("not-a-jwt").is_err());
+    }
+
+    #[test]
+    fn test_check_endpoints() {
+        let devices = PredefinedEndpoints::managed_devices();
+        let users = PredefinedEndpoints::users();
+        let groups = PredefinedEndpoints::groups();
+        let custom = EndpointConfig {
+            name: "apps".to_string(),
+            endpoint_url: "https://graph.microsoft.com/v1.0/deviceAppManagement/mobileApps".to_string(),
+            ..Default::default()
+        };
+        let granted = vec!["user.read.all".to_string(), "Directory.Read.All".to_string()];
+
+        let checks = check_endpoints(&granted, &[&devices, &users, &groups, &custom]);
+        assert_eq!(checks[0].missing(), Some("DeviceManagementManagedDevices.Read.All"));
+        assert_eq!(checks[1].granted, Some("User.Read.All"));
+        assert_eq!(checks[2].granted, Some("Directory.Read.All"));
+        assert_eq!(checks[3].accepted, None);
+        assert_eq!(checks[3].missing(), None);
+
+        let missing = missing_permissions(&checks);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing["DeviceManagementManagedDevices.Read.All"], [devices.name.as_str()]);
+    }
+}
@@ -1,11 +1,15 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 use tokio::time::sleep;
 use anyhow::{Result, Context};
 use log::{debug, warn, info};
 use serde::{Deserialize, Serialize};
 
+use crate::error::GraphError;
+use crate::metrics;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RateLimitConfig {
     /// Maximum requests per minute
@@ -26,6 +30,9 @@ pub struct RateLimitConfig {
     /// Enable jitter to avoid thundering herd
     #[serde(rename = "enableJitter")]
     pub enable_jitter: bool,
+    /// Graph requests one sync may make across all endpoints (optional, unlimited if unset)
+    #[serde(rename = "maxRequestsPerSync", default)]
+    pub max_requests_per_sync: Option<u32>,
 }
 
 impl Default for RateLimitConfig {
@@ -37,10 +44,244 @@ impl Default for RateLimitConfig {
             max_retry_attempts: 5,
             backoff_multiplier: 2.0,
             enable_jitter: true,
+            max_requests_per_sync: None,
+        }
+    }
+}
+
+/// Raised once a sync has used up `maxRequestsPerSync`
+#[derive(Debug, thiserror::Error)]
+#[error("Request budget of {limit} requests per sync is exhausted")]
+pub struct RequestBudgetExhausted {
+    pub limit: u32,
+}
+
+impl RequestBudgetExhausted {
+    /// Whether an `anyhow` error chain stopped on the request budget
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| cause.is::<RequestBudgetExhausted>())
+    }
+}
+
+/// Graph requests left in the current sync, shared by all endpoints so the budget
+/// goes to higher-priority endpoints first
+#[derive(Debug, Clone, Default)]
+pub struct RequestBudget {
+    limit: Option<u32>,
+    used: Arc<AtomicU32>,
+}
+
+impl RequestBudget {
+    pub fn new(limit: Option<u32>) -> Self {
+        Self { limit, used: Arc::new(AtomicU32::new(0)) }
+    }
+
+    /// Start a new sync with the full budget
+    pub fn reset(&self) {
+        self.used.store(0, Ordering::SeqCst);
+    }
+
+    /// Take one request from the budget, failing once it is spent
+    pub fn consume(&self) -> Result<(), RequestBudgetExhausted> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| (used < limit).then_some(used + 1))
+            .map(|_| ())
+            .map_err(|_| RequestBudgetExhausted { limit })
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.limit.is_some_and(|limit| self.used.load(Ordering::SeqCst) >= limit)
+    }
+}
+
+/// Raised once a sync has used up `sync.maxRetriesPerSync` or `sync.maxRetrySecondsPerSync`
+#[derive(Debug, thiserror::Error)]
+#[error("Retry budget of this sync is exhausted after {retries} retries and {waited:?} of waiting")]
+pub struct RetryBudgetExhausted {
+    pub retries: u32,
+    pub waited: Duration,
+}
+
+impl RetryBudgetExhausted {
+    /// Whether an `anyhow` error chain stopped on the retry budget, as its cause or as
+    /// context added to the error of the last attempt
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<RetryBudgetExhausted>().is_some()
+            || error.chain().any(|cause| cause.is::<RetryBudgetExhausted>())
+    }
+}
+
+#[derive(Debug, Default)]
+struct RetryUsage {
+    retries: u32,
+    waited: Duration,
+    exhausted: bool,
+}
+
+/// Retries left in the current sync, shared by every retry loop (Graph fetches and
+/// webhooks) so that together they cannot stall a sync for longer than configured
+#[derive(Debug, Clone, Default)]
+pub struct RetryBudget {
+    max_retries: Option<u32>,
+    max_wait: Option<Duration>,
+    used: Arc<StdMutex<RetryUsage>>,
+}
+
+impl RetryBudget {
+    pub fn new(max_retries: Option<u32>, max_wait: Option<Duration>) -> Self {
+        let budget = Self { max_retries, max_wait, used: Arc::default() };
+        budget.report(&RetryUsage::default());
+        budget
+    }
+
+    /// Start a new sync with the full budget
+    pub fn reset(&self) {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        *used = RetryUsage::default();
+        self.report(&used);
+    }
+
+    /// Take one retry that waits `delay` first from the budget. Fails once the retry
+    /// count is spent or the wait would pass the time limit; the first failure is logged.
+    pub fn spend(&self, delay: Duration) -> Result<(), RetryBudgetExhausted> {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        let out_of_retries = self.max_retries.is_some_and(|max| used.retries >= max);
+        let out_of_time = self.max_wait.is_some_and(|max| used.waited + delay > max);
+
+        if out_of_retries || out_of_time || used.exhausted {
+            if !used.exhausted {
+                used.exhausted = true;
+                warn!(
+                    "Retry budget exhausted after {} retries and {:?} of waiting - failing further retries in this sync",
+                    used.retries, used.waited
+                );
+                metrics::RETRY_BUDGET_EXHAUSTED_TOTAL.inc();
+            }
+            return Err(RetryBudgetExhausted { retries: used.retries, waited: used.waited });
+        }
+
+        used.retries += 1;
+        used.waited += delay;
+        self.report(&used);
+        Ok(())
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.used.lock().unwrap_or_else(|e| e.into_inner()).exhausted
+    }
+
+    fn report(&self, used: &RetryUsage) {
+        if let Some(max) = self.max_retries {
+            metrics::RETRY_BUDGET_REMAINING
+                .with_label_values(&["retries"])
+                .set(max.saturating_sub(used.retries) as f64);
+        }
+        if let Some(max) = self.max_wait {
+            metrics::RETRY_BUDGET_REMAINING
+                .with_label_values(&["seconds"])
+                .set(max.saturating_sub(used.waited).as_secs_f64());
+        }
+    }
+}
+
+/// Pause shared by every Graph request of an endpoint manager. Graph throttles the whole
+/// app, not one request, so once any request is answered 429 all others (in flight or
+/// new) hold until its `Retry-After` has passed instead of collecting more 429s that
+/// extend the penalty.
+#[derive(Debug, Clone, Default)]
+pub struct ThrottleGate {
+    inner: Arc<ThrottleGateState>,
+}
+
+#[derive(Debug, Default)]
+struct ThrottleGateState {
+    resume_after: StdMutex<Option<Instant>>,
+    /// Wakes waiters when the resume time moves or the gate opens
+    changed: Notify,
+}
+
+impl ThrottleGate {
+    /// Hold all requests for `delay` from now, unless the gate already stays closed longer
+    pub fn close_for(&self, delay: Duration) {
+        let resume = Instant::now() + delay;
+        {
+            let mut resume_after = self.inner.resume_after.lock().unwrap_or_else(|e| e.into_inner());
+            if resume_after.is_some_and(|current| current >= resume) {
+                return;
+            }
+            *resume_after = Some(resume);
+        }
+        metrics::GRAPH_THROTTLE_GATE_CLOSED.set(1.0);
+        self.inner.changed.notify_waiters();
+
+        // Open the gate on time even if no request is waiting for it
+        let gate = self.clone();
+        tokio::spawn(async move { gate.wait().await });
+    }
+
+    /// When requests may go out again, `None` while the gate is open
+    pub fn resume_after(&self) -> Option<Instant> {
+        let resume_after = self.inner.resume_after.lock().unwrap_or_else(|e| e.into_inner());
+        resume_after.filter(|resume| *resume > Instant::now())
+    }
+
+    /// Wait until the gate is open
+    pub async fn wait(&self) {
+        loop {
+            // Registered before reading the resume time so a change in between is not missed
+            let changed = self.inner.changed.notified();
+            let resume = {
+                let mut resume_after = self.inner.resume_after.lock().unwrap_or_else(|e| e.into_inner());
+                match *resume_after {
+                    Some(resume) if resume > Instant::now() => resume,
+                    Some(_) => {
+                        *resume_after = None;
+                        metrics::GRAPH_THROTTLE_GATE_CLOSED.set(0.0);
+                        self.inner.changed.notify_waiters();
+                        return;
+                    }
+                    None => return,
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(resume.into()) => {}
+                _ = changed => {}
+            }
         }
     }
 }
 
+impl RateLimitConfig {
+    /// Delay before retry number `attempt` (1-based): exponential backoff capped at
+    /// `maxRetryDelaySeconds`, with jitter if enabled
+    pub fn backoff_delay(&self, attempt: u32) -> Duration {
+        let base_delay = Duration::from_secs(self.initial_retry_delay_seconds);
+        let multiplier = self.backoff_multiplier.powi(attempt.saturating_sub(1) as i32);
+        let delay_secs = (base_delay.as_secs_f64() * multiplier) as u64;
+
+        let delay = Duration::from_secs(delay_secs.min(self.max_retry_delay_seconds));
+
+        if self.enable_jitter {
+            add_jitter(delay)
+        } else {
+            delay
+        }
+    }
+}
+
+fn add_jitter(delay: Duration) -> Duration {
+    // Simple jitter using system time microseconds
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let jitter_factor = 0.8 + (now.subsec_micros() % 400) as f64 / 1000.0; // 0.8 to 1.2
+    let jittered_secs = delay.as_secs_f64() * jitter_factor;
+    Duration::from_secs_f64(jittered_secs)
+}
+
 #[derive(Debug)]
 struct RateLimitState {
     requests: Vec<Instant>,
@@ -172,27 +413,7 @@ impl RateLimiter {
     }
 
     fn calculate_backoff_delay(&self, attempt: u32) -> Duration {
-        let base_delay = Duration::from_secs(self.config.initial_retry_delay_seconds);
-        let multiplier = self.config.backoff_multiplier.powi(attempt.saturating_sub(1) as i32);
-        let delay_secs = (base_delay.as_secs_f64() * multiplier) as u64;
-        
-        let delay = Duration::from_secs(delay_secs.min(self.config.max_retry_delay_seconds));
-        
-        if self.config.enable_jitter {
-            self.add_jitter(delay)
-        } else {
-            delay
-        }
-    }
-
-    fn add_jitter(&self, delay: Duration) -> Duration {
-        // Simple jitter using system time microseconds
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default();
-        let jitter_factor = 0.8 + (now.subsec_micros() % 400) as f64 / 1000.0; // 0.8 to 1.2
-        let jittered_secs = delay.as_secs_f64() * jitter_factor;
-        Duration::from_secs_f64(jittered_secs)
+        self.config.backoff_delay(attempt)
     }
 
     /// Get current rate limit statistics
@@ -228,14 +449,14 @@ pub struct RateLimitStats {
 /// Extract retry-after duration from HTTP response headers
 pub fn parse_retry_after_header(retry_after: Option<&str>) -> Option<Duration> {
     retry_after.and_then(|value| {
+        let value = value.trim();
         // Try parsing as seconds (most common)
         if let Ok(seconds) = value.parse::<u64>() {
             Some(Duration::from_secs(seconds))
         } else {
-            // Try parsing as HTTP date (less common)
-            // For now, just return None for date format
-            // Could implement HTTP date parsing if needed
-            None
+            // An HTTP date (`Wed, 21 Oct 2015 07:28:00 GMT`); one already past means now
+            let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            Some((at.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().unwrap_or_default())
         }
     })
 }
@@ -267,6 +488,7 @@ impl RateLimitedClient {
             let response = request_fn()
                 .send()
                 .await
+                .map_err(GraphError::from_reqwest)
                 .context("Failed to send HTTP request")?;
 
             match response.status() {
@@ -279,17 +501,19 @@ impl RateLimitedClient {
                     return Ok(result);
                 }
                 status if status == 429 => {
-                    // Rate limited - check if we should retry
-                    if !self.rate_limiter.should_retry().await {
-                        return Err(anyhow::anyhow!("Maximum retry attempts exceeded for rate limiting"));
-                    }
-
                     // Parse retry-after header
                     let retry_after = response.headers()
                         .get("retry-after")
                         .and_then(|h| h.to_str().ok())
                         .and_then(|s| parse_retry_after_header(Some(s)));
 
+                    // Rate limited - check if we should retry
+                    if !self.rate_limiter.should_retry().await {
+                        let throttled = GraphError::Throttled { retry_after_seconds: retry_after.map(|delay| delay.as_secs()) };
+                        return Err(anyhow::Error::new(throttled)
+                            .context("Maximum retry attempts exceeded for rate limiting"));
+                    }
+
                     // Handle rate limit and get delay
                     let delay = self.rate_limiter.handle_rate_limit_response(retry_after).await?;
                     
@@ -298,8 +522,11 @@ impl RateLimitedClient {
                     continue;
                 }
                 status => {
-                    let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-                    return Err(anyhow::anyhow!("HTTP request failed with status {}: {}", status, error_text));
+                    let retry_after = response.headers().get("retry-after")
+                        .and_then(|h| h.to_str().ok())
+                        .map(str::to_string);
+                    let error_text = response.text().await.unwrap_or_default();
+                    return Err(GraphError::from_response(status.as_u16(), &error_text, retry_after.as_deref()).into());
                 }
             }
         }
@@ -336,6 +563,58 @@ mod tests {
         assert!(elapsed > Duration::from_millis(50));
     }
 
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(Some(3), Some(Duration::from_secs(10)));
+        let shared = budget.clone();
+
+        budget.spend(Duration::from_secs(4)).unwrap();
+        shared.spend(Duration::from_secs(4)).unwrap();
+        // A third retry is allowed by count but would wait past the 10s limit
+        let exhausted = budget.spend(Duration::from_secs(4)).unwrap_err();
+        assert_eq!(exhausted.retries, 2);
+        assert_eq!(exhausted.waited, Duration::from_secs(8));
+        assert!(shared.is_exhausted());
+        // Once spent, even a short retry fails fast
+        assert!(shared.spend(Duration::ZERO).is_err());
+
+        let err = anyhow::anyhow!("Graph request failed").context(exhausted);
+        assert!(RetryBudgetExhausted::is_cause_of(&err));
+        assert!(!RetryBudgetExhausted::is_cause_of(&anyhow::anyhow!("Graph request failed")));
+
+        budget.reset();
+        assert!(!shared.is_exhausted());
+        for _ in 0..3 {
+            shared.spend(Duration::from_secs(1)).unwrap();
+        }
+        assert!(shared.spend(Duration::from_secs(1)).is_err());
+
+        let unlimited = RetryBudget::default();
+        for _ in 0..100 {
+            unlimited.spend(Duration::from_secs(60)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_request_budget() {
+        let unlimited = RequestBudget::default();
+        for _ in 0..1000 {
+            unlimited.consume().unwrap();
+        }
+        assert!(!unlimited.is_exhausted());
+
+        let budget = RequestBudget::new(Some(2));
+        budget.consume().unwrap();
+        budget.clone().consume().unwrap();
+        assert!(budget.is_exhausted());
+        let err = anyhow::Error::new(budget.consume().unwrap_err()).context("Failed to fetch page");
+        assert!(RequestBudgetExhausted::is_cause_of(&err));
+
+        budget.reset();
+        assert!(!budget.is_exhausted());
+        budget.consume().unwrap();
+    }
+
     #[tokio::test]
     async fn test_backoff_calculation() {
         let config = RateLimitConfig {
@@ -353,6 +632,28 @@ mod tests {
         assert_eq!(delay1, Duration::from_secs(1));
         assert_eq!(delay2, Duration::from_secs(2));
         assert_eq!(delay3, Duration::from_secs(4));
+
+        // Capped at maxRetryDelaySeconds
+        assert_eq!(limiter.calculate_backoff_delay(20), Duration::from_secs(300));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_gate() {
+        let gate = ThrottleGate::default();
+        assert!(gate.resume_after().is_none());
+        gate.wait().await;
+
+        // A shorter pause does not cut a longer one short
+        gate.close_for(Duration::from_millis(300));
+        gate.close_for(Duration::from_millis(50));
+        let resume = gate.resume_after().unwrap();
+        assert!(resume >= Instant::now() + Duration::from_millis(200));
+
+        let start = Instant::now();
+        let shared = gate.clone();
+        let (_, _) = tokio::join!(gate.wait(), shared.wait());
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(gate.resume_after().is_none());
     }
 
     #[test]
@@ -360,6 +661,10 @@ mod tests {
         assert_eq!(parse_retry_after_header(Some("60")), Some(Duration::from_secs(60)));
         assert_eq!(parse_retry_after_header(Some("0")), Some(Duration::from_secs(0)));
         assert_eq!(parse_retry_after_header(Some("invalid")), None);
+        let at = (chrono::Utc::now() + chrono::Duration::seconds(120)).to_rfc2822();
+        let delay = parse_retry_after_header(Some(&at)).unwrap();
+        assert!(delay > Duration::from_secs(110) && delay <= Duration::from_secs(120));
+        assert_eq!(parse_retry_after_header(Some("Wed, 21 Oct 2015 07:28:00 GMT")), Some(Duration::ZERO));
         assert_eq!(parse_retry_after_header(None), None);
     }
 }
@@ -13,6 +13,7 @@ use std::process::Command;
 #[cfg(windows)]
 use log::warn;
 
+use crate::config::ConfigSource;
 use crate::version;
 
 /// Service management for different platforms
@@ -20,18 +21,18 @@ pub struct ServiceManager;
 
 impl ServiceManager {
     /// Install service on the current platform
-    pub async fn install() -> Result<()> {
+    pub async fn install(source: &ConfigSource) -> Result<()> {
         #[cfg(target_os = "linux")]
         {
-            Self::install_systemd_service().await
+            Self::install_systemd_service(source).await
         }
         #[cfg(target_os = "macos")]
         {
-            Self::install_launchd_service().await
+            Self::install_launchd_service(source).await
         }
         #[cfg(windows)]
         {
-            Self::install_windows_service().await
+            Self::install_windows_service(source).await
         }
         #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
         {
@@ -142,6 +143,78 @@ impl ServiceManager {
             .context("Failed to get current executable path")
     }
 
+    /// Config file given to `install --config`, resolved to the absolute path the
+    /// service will use. It has to exist, so a typo fails the install and not the service.
+    /// A URL is passed on as given.
+    fn service_config_path(source: &ConfigSource) -> Result<Option<PathBuf>> {
+        let Some(path) = source.explicit() else {
+            return Ok(None);
+        };
+        if crate::config::is_config_url(&path) {
+            return Ok(Some(PathBuf::from(path)));
+        }
+        let path = std::fs::canonicalize(&path)
+            .with_context(|| format!("Config file {} does not exist", path))?;
+        Ok(Some(path))
+    }
+
+    /// Arguments the installed service starts with: `run`, plus the config file given
+    /// to `install --config`, so the service loads the file that was validated
+    fn service_arguments(config_path: Option<&std::path::Path>) -> Vec<String> {
+        let mut arguments = vec!["run".to_string()];
+        if let Some(path) = config_path {
+            arguments.push("--config".to_string());
+            arguments.push(path.display().to_string());
+        }
+        arguments
+    }
+
+    /// Let the service user's group read the config file without handing it the file;
+    /// it holds credentials, so it is not made readable for everyone. Directories on the
+    /// way to it that the service cannot enter, or that others can write to, are reported.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn grant_config_read_access(config_path: &Path, group: &str) {
+        use std::os::unix::fs::MetadataExt;
+
+        let service_gid = group_id(group);
+        for problem in config_directory_problems(config_path, service_gid) {
+            warn!("{}", problem);
+        }
+
+        let current_gid = fs::metadata(config_path).ok().map(|metadata| metadata.gid());
+        let change_group = current_gid.is_none() || current_gid != service_gid;
+        let granted = (|| {
+            if change_group {
+                let output = Command::new("chgrp").args([group, &config_path.to_string_lossy()]).output()?;
+                if !output.status.success() {
+                    return Ok(output);
+                }
+            }
+            Command::new("chmod").args(["g+r", &config_path.to_string_lossy()]).output()
+        })();
+
+        match granted {
+            Ok(output) if output.status.success() => {
+                if change_group {
+                    warn!(
+                        "Changed the group of {} from {} to {} so the service can read it",
+                        config_path.display(),
+                        current_gid.map(group_name).unwrap_or_else(|| "unknown".to_string()),
+                        group
+                    );
+                }
+                info!("Made {} readable for group {}", config_path.display(), group)
+            }
+            Ok(output) => warn!(
+                "Failed to give {} read access to {}: {}",
+                group,
+                config_path.display(),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => warn!("Failed to give {} read access to {}: {}", group, config_path.display(), e),
+        }
+    }
+
     /// Check if running as root/administrator
     fn is_elevated() -> bool {
         #[cfg(unix)]
@@ -168,20 +241,30 @@ impl ServiceManager {
 
     // Linux systemd implementation
     #[cfg(target_os = "linux")]
-    async fn install_systemd_service() -> Result<()> {
+    async fn install_systemd_service(source: &ConfigSource) -> Result<()> {
         Self::ensure_elevated()?;
 
         let service_name = Self::get_service_name();
         let service_file_path = format!("/etc/systemd/system/{}.service", service_name);
         let executable_path = Self::get_executable_path()?;
+        let config_path = Self::service_config_path(source)?;
 
         info!("Installing systemd service: {}", service_name);
 
         // Create service user if it doesn't exist
         Self::create_service_user().await?;
 
-        // Ensure log directory exists and has proper permissions
-        Self::setup_log_directory().await?;
+        // Ensure the data directory exists and belongs to the service user
+        let data_dir = Self::service_data_dir(source).await?;
+        Self::setup_data_directory(&data_dir).await?;
+        if let Some(ref config_path) = config_path {
+            Self::grant_config_read_access(config_path, service_name);
+        }
+        // ProtectHome hides home directories entirely; a config kept in one stays readable
+        let protect_home = match config_path {
+            Some(ref path) if ["/home", "/root", "/run/user"].iter().any(|home| path.starts_with(home)) => "read-only",
+            _ => "true",
+        };
 
         // Create service file content
         let service_content = format!(
@@ -195,9 +278,11 @@ Type=simple
 User={}
 Group={}
 WorkingDirectory={}
-ExecStart={} run
+ExecStart={}
 Restart=always
 RestartSec=10
+# Stopping drains: the sync in progress is finished first
+TimeoutStopSec=3600
 StandardOutput=journal
 StandardError=journal
 SyslogIdentifier={}
@@ -206,8 +291,8 @@ SyslogIdentifier={}
 NoNewPrivileges=true
 PrivateTmp=true
 ProtectSystem=strict
-ProtectHome=true
-ReadWritePaths={}
+ProtectHome={}
+ReadWritePaths={} {}
 
 [Install]
 WantedBy=multi-user.target
@@ -216,9 +301,11 @@ WantedBy=multi-user.target
             service_name,
             service_name,
             executable_path.parent().unwrap().display(),
-            executable_path.display(),
+            systemd_command_line(&executable_path, &Self::service_arguments(config_path.as_deref())),
             service_name,
-            executable_path.parent().unwrap().display()
+            protect_home,
+            executable_path.parent().unwrap().display(),
+            data_dir.display()
         );
 
         // Write service file
@@ -252,6 +339,10 @@ WantedBy=multi-user.target
         println!("✅ Service installed and enabled successfully");
         println!("   Service name: {}", service_name);
         println!("   Service file: {}", service_file_path);
+        println!("   Data directory: {}", data_dir.display());
+        if let Some(ref config_path) = config_path {
+            println!("   Config file: {}", config_path.display());
+        }
         println!("   To start: sudo systemctl start {}", service_name);
         println!("   To check status: sudo systemctl status {}", service_name);
 
@@ -298,30 +389,42 @@ WantedBy=multi-user.target
         Ok(())
     }
 
+    /// Data directory the service will run with: `dataDir` from the config next to
+    /// the executable, otherwise the platform default
+    #[cfg(any(target_os = "linux", windows))]
+    async fn service_data_dir(source: &ConfigSource) -> Result<PathBuf> {
+        match crate::config::AppConfig::load(source).await {
+            Ok(config) => config.data_dir(),
+            Err(_) => crate::path_utils::default_data_dir(),
+        }
+    }
+
     #[cfg(target_os = "linux")]
-    async fn setup_log_directory() -> Result<()> {
+    async fn setup_data_directory(data_dir: &Path) -> Result<()> {
         let service_name = Self::get_service_name();
-        let executable_path = Self::get_executable_path()?;
-        let log_dir = executable_path.parent().unwrap().join("logs");
+        let executable_dir = Self::get_executable_path()?.parent().unwrap().to_path_buf();
+        let log_dir = data_dir.join("logs");
 
-        // Create logs directory
+        // Create data and logs directories
         if !log_dir.exists() {
             fs::create_dir_all(&log_dir)
                 .with_context(|| format!("Failed to create log directory: {}", log_dir.display()))?;
-            info!("Created log directory: {}", log_dir.display());
+            info!("Created data directory: {}", data_dir.display());
         }
 
-        // Set ownership to service user
+        // Set ownership to service user; a data directory shared with the executable
+        // only hands over its logs
+        let owned_dir = if data_dir == executable_dir { log_dir } else { data_dir.to_path_buf() };
         let output = Command::new("chown")
-            .args(&["-R", &format!("{}:{}", service_name, service_name), &log_dir.to_string_lossy()])
+            .args(&["-R", &format!("{}:{}", service_name, service_name), &owned_dir.to_string_lossy()])
             .output()
-            .context("Failed to set log directory ownership")?;
+            .context("Failed to set data directory ownership")?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            warn!("Failed to set log directory ownership: {}", stderr);
+            warn!("Failed to set data directory ownership: {}", stderr);
         } else {
-            info!("Set log directory ownership to {}", service_name);
+            info!("Set ownership of {} to {}", owned_dir.display(), service_name);
         }
 
         Ok(())
@@ -455,15 +558,33 @@ WantedBy=multi-user.target
 
     // macOS launchd implementation
     #[cfg(target_os = "macos")]
-    async fn install_launchd_service() -> Result<()> {
+    async fn install_launchd_service(source: &ConfigSource) -> Result<()> {
         Self::ensure_elevated()?;
 
         let service_name = format!("com.gracesolutions.{}", Self::get_service_name());
         let plist_path = format!("/Library/LaunchDaemons/{}.plist", service_name);
         let executable_path = Self::get_executable_path()?;
+        let config_path = Self::service_config_path(source)?;
 
         info!("Installing launchd service: {}", service_name);
 
+        // The service user has no home directory, so unless the config or an existing
+        // install says otherwise, data goes to the system-wide Application Support
+        let executable_dir = executable_path.parent().unwrap().to_path_buf();
+        let configured_dir = crate::config::AppConfig::load(source).await.ok().and_then(|config| config.data_dir);
+        let (data_dir, data_dir_env) = match configured_dir {
+            Some(dir) => (crate::path_utils::resolve_path(&dir)?, String::new()),
+            None if executable_dir.join("data").is_dir() => (executable_dir.clone(), String::new()),
+            None => {
+                let dir = PathBuf::from("/Library/Application Support").join(Self::get_service_name());
+                let env = format!(
+                    "    <key>EnvironmentVariables</key>\n    <dict>\n        <key>DATA_DIR</key>\n        <string>{}</string>\n    </dict>\n",
+                    dir.display()
+                );
+                (dir, env)
+            }
+        };
+
         // Create plist content
         let plist_content = format!(
             r#"<?xml version="1.0" encoding="UTF-8"?>
@@ -475,14 +596,15 @@ WantedBy=multi-user.target
     <key>ProgramArguments</key>
     <array>
         <string>{}</string>
-        <string>run</string>
-    </array>
+{}    </array>
     <key>WorkingDirectory</key>
     <string>{}</string>
     <key>RunAtLoad</key>
     <true/>
     <key>KeepAlive</key>
     <true/>
+    <key>ExitTimeOut</key>
+    <integer>3600</integer>
     <key>StandardOutPath</key>
     <string>/var/log/msgraph-db-synchronizer.log</string>
     <key>StandardErrorPath</key>
@@ -491,20 +613,31 @@ WantedBy=multi-user.target
     <string>_msgraphsync</string>
     <key>GroupName</key>
     <string>_msgraphsync</string>
-</dict>
+{}</dict>
 </plist>
 "#,
             service_name,
-            executable_path.display(),
-            executable_path.parent().unwrap().display()
+            xml_escape(&executable_path.display().to_string()),
+            Self::service_arguments(config_path.as_deref()).iter()
+                .map(|argument| format!("        <string>{}</string>\n", xml_escape(argument)))
+                .collect::<String>(),
+            executable_path.parent().unwrap().display(),
+            data_dir_env
         );
 
         // Create service user if it doesn't exist
         Self::create_macos_service_user().await?;
 
+        // Create the data directory and hand it to the service user
+        Self::setup_macos_data_directory(&data_dir, &executable_dir).await?;
+
         // Setup log files with proper permissions
         Self::setup_macos_log_files().await?;
 
+        if let Some(ref config_path) = config_path {
+            Self::grant_config_read_access(config_path, "_msgraphsync");
+        }
+
         // Write plist file
         fs::write(&plist_path, plist_content)
             .with_context(|| format!("Failed to write plist file: {}", plist_path))?;
@@ -544,6 +677,10 @@ WantedBy=multi-user.target
         println!("✅ Service installed and loaded successfully");
         println!("   Service name: {}", service_name);
         println!("   Plist file: {}", plist_path);
+        println!("   Data directory: {}", data_dir.display());
+        if let Some(ref config_path) = config_path {
+            println!("   Config file: {}", config_path.display());
+        }
         println!("   To start: sudo launchctl start {}", service_name);
         println!("   To check status: sudo launchctl list | grep {}", Self::get_service_name());
 
@@ -613,6 +750,34 @@ WantedBy=multi-user.target
         Ok(())
     }
 
+    #[cfg(target_os = "macos")]
+    async fn setup_macos_data_directory(data_dir: &Path, executable_dir: &Path) -> Result<()> {
+        let username = "_msgraphsync";
+        let log_dir = data_dir.join("logs");
+
+        if !log_dir.exists() {
+            fs::create_dir_all(&log_dir)
+                .with_context(|| format!("Failed to create log directory: {}", log_dir.display()))?;
+            info!("Created data directory: {}", data_dir.display());
+        }
+
+        // A data directory shared with the executable only hands over its logs
+        let owned_dir = if data_dir == executable_dir { log_dir } else { data_dir.to_path_buf() };
+        let output = Command::new("chown")
+            .args(&["-R", &format!("{}:{}", username, username), &owned_dir.to_string_lossy()])
+            .output()
+            .context("Failed to set data directory ownership")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            warn!("Failed to set data directory ownership: {}", stderr);
+        } else {
+            info!("Set ownership of {} to {}", owned_dir.display(), username);
+        }
+
+        Ok(())
+    }
+
     #[cfg(target_os = "macos")]
     async fn setup_macos_log_files() -> Result<()> {
         let username = "_msgraphsync";
@@ -778,13 +943,14 @@ WantedBy=multi-user.target
 
     // Windows service implementation (delegated to existing code)
     #[cfg(windows)]
-    async fn install_windows_service() -> Result<()> {
+    async fn install_windows_service(source: &ConfigSource) -> Result<()> {
         use std::ffi::OsString;
         use windows_service::{
             service::{ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceType},
             service_manager::{ServiceManager, ServiceManagerAccess},
         };
 
+        let config_path = Self::service_config_path(source)?;
         let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
 
         let service_info = ServiceInfo {
@@ -794,14 +960,22 @@ WantedBy=multi-user.target
             start_type: ServiceStartType::AutoStart,
             error_control: ServiceErrorControl::Normal,
             executable_path: std::env::current_exe()?,
-            launch_arguments: vec![OsString::from("run")],
+            // Runs as LocalSystem, which can read the config wherever it is
+            launch_arguments: Self::service_arguments(config_path.as_deref()).into_iter().map(OsString::from).collect(),
             dependencies: vec![],
             account_name: None,
             account_password: None,
         };
 
+        // LocalSystem can write anywhere, but create the directory up front so it is
+        // obvious where the database and logs will appear
+        let data_dir = Self::service_data_dir(source).await?;
+        std::fs::create_dir_all(data_dir.join("logs"))
+            .with_context(|| format!("Failed to create data directory: {}", data_dir.display()))?;
+
         let _service = manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
         println!("✅ Service installed successfully");
+        println!("   Data directory: {}", data_dir.display());
         Ok(())
     }
 
@@ -888,3 +1062,132 @@ WantedBy=multi-user.target
         Ok(())
     }
 }
+
+/// `ExecStart` value for the executable and its arguments, quoting those systemd would
+/// otherwise split or expand
+#[cfg(target_os = "linux")]
+fn systemd_command_line(executable: &Path, arguments: &[String]) -> String {
+    std::iter::once(executable.display().to_string())
+        .chain(arguments.iter().cloned())
+        .map(|word| {
+            if word.is_empty() || word.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\\' | '\'' | '$' | '%')) {
+                let escaped = word.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "$$").replace('%', "%%");
+                format!("\"{}\"", escaped)
+            } else {
+                word
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Problems with the directories leading to the config file: ones the service group
+/// cannot enter, and ones others can write to, which would let them swap the file out.
+/// Sticky directories such as `/tmp` only let owners rename their entries and are fine.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn config_directory_problems(config_path: &Path, service_gid: Option<u32>) -> Vec<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut problems = Vec::new();
+    for dir in config_path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()) {
+        let Ok(metadata) = fs::metadata(dir) else { continue };
+        let mode = metadata.mode();
+
+        let searchable = mode & 0o001 != 0 || (Some(metadata.gid()) == service_gid && mode & 0o010 != 0);
+        if !searchable {
+            problems.push(format!(
+                "The service cannot enter {} ({:o}), so it will not be able to read {}",
+                dir.display(),
+                mode & 0o777,
+                config_path.display()
+            ));
+        }
+
+        if mode & 0o022 != 0 && mode & 0o1000 == 0 {
+            problems.push(format!(
+                "{} ({:o}) is writable by other users, who could replace {}; consider chmod go-w",
+                dir.display(),
+                mode & 0o777,
+                config_path.display()
+            ));
+        }
+    }
+    problems
+}
+
+/// Id of the named group, if it exists
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // getgrnam returns a pointer into static storage, read before any other lookup
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    (!group.is_null()).then(|| unsafe { (*group).gr_gid })
+}
+
+/// Name of the group with the given id, or the id itself when it has none
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn group_name(gid: u32) -> String {
+    let group = unsafe { libc::getgrgid(gid) };
+    if group.is_null() {
+        return gid.to_string();
+    }
+    unsafe { std::ffi::CStr::from_ptr((*group).gr_name) }.to_string_lossy().into_owned()
+}
+
+#[cfg(target_os = "macos")]
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_systemd_command_line() {
+        let arguments = vec!["run".to_string(), "--config".to_string(), "/etc/msgraph sync/config.json".to_string()];
+        assert_eq!(
+            systemd_command_line(Path::new("/opt/msgraph/MSGraphDBSynchronizer"), &arguments),
+            r#"/opt/msgraph/MSGraphDBSynchronizer run --config "/etc/msgraph sync/config.json""#
+        );
+        assert_eq!(systemd_command_line(Path::new("/opt/app"), &["100%$".to_string()]), r#"/opt/app "100%%$$""#);
+
+        assert_eq!(ServiceManager::service_arguments(None), ["run"]);
+        assert_eq!(
+            ServiceManager::service_arguments(Some(Path::new("/etc/msgraph/config.json"))),
+            ["run", "--config", "/etc/msgraph/config.json"]
+        );
+    }
+
+    #[test]
+    fn test_config_directory_problems() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config_dir = temp_dir.path().join("config");
+        fs::create_dir(&config_dir).unwrap();
+        let config_path = config_dir.join("config.json");
+        fs::write(&config_path, "{}").unwrap();
+        fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let problems = config_directory_problems(&config_path, None);
+        assert!(!problems.iter().any(|p| p.contains(config_dir.to_str().unwrap())), "{:?}", problems);
+
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o700)).unwrap();
+        let problems = config_directory_problems(&config_path, None);
+        assert!(problems.iter().any(|p| p.starts_with(&format!("The service cannot enter {}", config_dir.display()))));
+
+        // The service group can enter a directory that only its group may search
+        let gid = std::os::unix::fs::MetadataExt::gid(&fs::metadata(&config_dir).unwrap());
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o750)).unwrap();
+        assert!(!config_directory_problems(&config_path, Some(gid)).iter().any(|p| p.contains(config_dir.to_str().unwrap())));
+
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o777)).unwrap();
+        let problems = config_directory_problems(&config_path, None);
+        assert!(problems.iter().any(|p| p.starts_with(&format!("{} (777) is writable by other users", config_dir.display()))));
+
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        assert!(!config_directory_problems(&config_path, None).iter().any(|p| p.contains(config_dir.to_str().unwrap())));
+    }
+}
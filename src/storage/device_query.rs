@@ -0,0 +1,223 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::HashSet;
+
+use super::sanitize_column_name;
+
+/// Device column a [`DeviceQuery`] can sort by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceSort {
+    #[default]
+    DeviceName,
+    OperatingSystem,
+    EnrolledDateTime,
+    LastSyncDateTime,
+}
+
+impl DeviceSort {
+    fn column(self) -> String {
+        match self {
+            DeviceSort::DeviceName => sanitize_column_name("deviceName"),
+            DeviceSort::OperatingSystem => sanitize_column_name("operatingSystem"),
+            DeviceSort::EnrolledDateTime => sanitize_column_name("enrolledDateTime"),
+            DeviceSort::LastSyncDateTime => "last_sync_date_time".to_string(),
+        }
+    }
+}
+
+/// Filters, sort order and paging for reading stored devices back.
+///
+/// Text filters match case-insensitively; `name_contains` matches anywhere in the
+/// device name. Every filter is sent as a bound parameter, never spliced into SQL.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceQuery {
+    pub os: Option<String>,
+    pub compliance_state: Option<String>,
+    pub manufacturer: Option<String>,
+    pub enrolled_after: Option<DateTime<Utc>>,
+    pub enrolled_before: Option<DateTime<Utc>>,
+    pub name_contains: Option<String>,
+    pub sort: DeviceSort,
+    pub descending: bool,
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+/// A [`DeviceQuery`] rendered for one backend
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceQuerySql {
+    /// Empty, or ` WHERE ...` with placeholders for `params`
+    pub where_clause: String,
+    pub params: Vec<String>,
+    /// Column list for `ORDER BY`, ending in `id` so pages are stable
+    pub order_by: String,
+}
+
+impl DeviceQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only devices with this operating system, e.g. `Windows`
+    pub fn os(mut self, os: impl Into<String>) -> Self {
+        self.os = Some(os.into());
+        self
+    }
+
+    /// Only devices in this compliance state, e.g. `compliant` or `noncompliant`
+    pub fn compliance_state(mut self, state: impl Into<String>) -> Self {
+        self.compliance_state = Some(state.into());
+        self
+    }
+
+    pub fn manufacturer(mut self, manufacturer: impl Into<String>) -> Self {
+        self.manufacturer = Some(manufacturer.into());
+        self
+    }
+
+    /// Only devices enrolled at or after `from`
+    pub fn enrolled_after(mut self, from: DateTime<Utc>) -> Self {
+        self.enrolled_after = Some(from);
+        self
+    }
+
+    /// Only devices enrolled before `until`
+    pub fn enrolled_before(mut self, until: DateTime<Utc>) -> Self {
+        self.enrolled_before = Some(until);
+        self
+    }
+
+    /// Only devices whose name contains `text`
+    pub fn name_contains(mut self, text: impl Into<String>) -> Self {
+        self.name_contains = Some(text.into());
+        self
+    }
+
+    pub fn sort_by(mut self, sort: DeviceSort, descending: bool) -> Self {
+        self.sort = sort;
+        self.descending = descending;
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Render the filters and sort order against a table with the given columns.
+    ///
+    /// `placeholder` turns a 1-based parameter number into the backend's placeholder
+    /// and `timestamp` wraps an expression so it compares as a point in time. Returns
+    /// `None` if a filter needs a column the table does not have, since no row can match.
+    pub fn to_sql(
+        &self,
+        columns: &HashSet<String>,
+        placeholder: impl Fn(usize) -> String,
+        timestamp: impl Fn(&str) -> String,
+    ) -> Option<DeviceQuerySql> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+
+        let equals = [
+            ("operatingSystem", &self.os),
+            ("complianceState", &self.compliance_state),
+            ("manufacturer", &self.manufacturer),
+        ];
+        for (field, value) in equals {
+            let Some(value) = value else { continue };
+            let column = existing_column(columns, field)?;
+            params.push(value.to_lowercase());
+            conditions.push(format!("LOWER({}) = {}", column, placeholder(params.len())));
+        }
+
+        let ranges = [(">=", self.enrolled_after), ("<", self.enrolled_before)];
+        for (operator, value) in ranges {
+            let Some(value) = value else { continue };
+            let column = existing_column(columns, "enrolledDateTime")?;
+            params.push(value.to_rfc3339_opts(SecondsFormat::Secs, true));
+            conditions.push(format!(
+                "{} {} {}",
+                timestamp(&column),
+                operator,
+                timestamp(&placeholder(params.len()))
+            ));
+        }
+
+        if let Some(ref text) = self.name_contains {
+            let column = existing_column(columns, "deviceName")?;
+            params.push(format!("%{}%", escape_like(&text.to_lowercase())));
+            conditions.push(format!("LOWER({}) LIKE {} ESCAPE '\\'", column, placeholder(params.len())));
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        };
+
+        // Sorting by a column the table lacks would fail the query, so fall back to id
+        let direction = if self.descending { " DESC" } else { "" };
+        let sort_column = self.sort.column();
+        let order_by = if sort_column != "id" && columns.contains(&sort_column) {
+            format!("{}{}, id{}", sort_column, direction, direction)
+        } else {
+            format!("id{}", direction)
+        };
+
+        Some(DeviceQuerySql { where_clause, params, order_by })
+    }
+}
+
+fn existing_column(columns: &HashSet<String>, field: &str) -> Option<String> {
+    let column = sanitize_column_name(field);
+    columns.contains(&column).then_some(column)
+}
+
+/// Escape `LIKE` wildcards so user text only matches literally
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_query_sql() {
+        let columns: HashSet<String> = ["id", "devicename", "operatingsystem", "enrolleddatetime"]
+            .iter().map(|c| c.to_string()).collect();
+        let from = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+
+        let sql = DeviceQuery::new()
+            .os("Windows")
+            .enrolled_after(from)
+            .name_contains("50%_off")
+            .sort_by(DeviceSort::EnrolledDateTime, true)
+            .to_sql(&columns, |n| format!("${}", n), |expr| format!("CAST({} AS TIMESTAMPTZ)", expr))
+            .unwrap();
+        assert_eq!(
+            sql.where_clause,
+            " WHERE LOWER(operatingsystem) = $1 AND CAST(enrolleddatetime AS TIMESTAMPTZ) >= CAST($2 AS TIMESTAMPTZ) AND LOWER(devicename) LIKE $3 ESCAPE '\\'"
+        );
+        assert_eq!(sql.params, vec!["windows", "2024-01-01T00:00:00Z", "%50\\%\\_off%"]);
+        assert_eq!(sql.order_by, "enrolleddatetime DESC, id DESC");
+
+        // No column, no match; unknown sort columns fall back to id
+        assert!(DeviceQuery::new().manufacturer("Dell").to_sql(&columns, |_| "?".into(), str::to_string).is_none());
+        let sql = DeviceQuery::new().sort_by(DeviceSort::LastSyncDateTime, false)
+            .to_sql(&columns, |_| "?".into(), str::to_string).unwrap();
+        assert_eq!(sql.where_clause, "");
+        assert_eq!(sql.order_by, "id");
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+
+use super::{DeviceQuery, StorageBackend};
+
+/// Rows copied for one table by [`migrate_tables`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableMigration {
+    pub table_name: String,
+    /// Rows in the source table
+    pub source_rows: u64,
+    /// Rows the destination accepted
+    pub copied: usize,
+    /// Rows in the destination table afterwards
+    pub destination_rows: i64,
+}
+
+impl TableMigration {
+    /// Whether both tables hold the same number of rows
+    pub fn verified(&self) -> bool {
+        self.destination_rows >= 0 && self.destination_rows as u64 == self.source_rows
+    }
+}
+
+/// Copy every row of `tables` from one backend to another, `batch_size` rows at a time,
+/// then the sync state of `endpoint_names`. Rows keep their id, hash and raw JSON, so the
+/// next sync against the destination only writes what changed in Graph. Tables the source
+/// does not have are skipped.
+pub async fn migrate_tables(
+    source: &mut dyn StorageBackend,
+    destination: &mut dyn StorageBackend,
+    tables: &[String],
+    endpoint_names: &[String],
+    batch_size: u32,
+) -> Result<Vec<TableMigration>> {
+    let batch_size = batch_size.max(1);
+    let mut migrations = Vec::new();
+
+    for table_name in tables {
+        let (first_batch, source_rows) = source.query_devices(table_name, &DeviceQuery::new().limit(batch_size)).await
+            .with_context(|| format!("Failed to read table {} from {} backend", table_name, source.backend_name()))?;
+        if source_rows == 0 {
+            log::info!("Skipping table {}: no rows in {} backend", table_name, source.backend_name());
+            continue;
+        }
+
+        let schema = destination.default_table_schema(table_name);
+        destination.create_table_if_not_exists(table_name, &schema).await
+            .with_context(|| format!("Failed to create table {} in {} backend", table_name, destination.backend_name()))?;
+
+        let mut copied = 0;
+        let mut offset = 0;
+        let mut batch = first_batch;
+        while !batch.is_empty() {
+            let rows: Vec<serde_json::Value> = batch.into_iter().map(without_nulls).collect();
+            copied += destination.store_endpoint_data(table_name, &rows).await
+                .with_context(|| format!("Failed to write table {} to {} backend", table_name, destination.backend_name()))?
+                .stored();
+            log::info!("Copied {}/{} rows of table {}", copied, source_rows, table_name);
+
+            offset += rows.len() as u32;
+            let query = DeviceQuery::new().limit(batch_size).offset(offset);
+            batch = source.query_devices(table_name, &query).await?.0;
+        }
+
+        let destination_rows = destination.get_table_count(table_name).await?;
+        migrations.push(TableMigration { table_name: table_name.clone(), source_rows, copied, destination_rows });
+    }
+
+    for endpoint_name in endpoint_names {
+        if let Some(state) = source.get_sync_state(endpoint_name).await? {
+            destination.set_sync_state(&state).await
+                .with_context(|| format!("Failed to copy sync state of endpoint {}", endpoint_name))?;
+        }
+    }
+
+    Ok(migrations)
+}
+
+/// Leave out columns that are NULL in the source, so the destination fills its own
+/// defaults instead of storing the text "null"
+fn without_nulls(row: serde_json::Value) -> serde_json::Value {
+    match row {
+        serde_json::Value::Object(map) => map.into_iter().filter(|(_, value)| !value.is_null()).collect(),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sqlite::SqliteBackend;
+    use crate::storage::{ConnectRetry, SyncState, HASH_COLUMN};
+    use tempfile::NamedTempFile;
+
+    async fn sqlite_backend(file: &NamedTempFile) -> SqliteBackend {
+        let mut backend = SqliteBackend::new(file.path().to_str().unwrap(), ConnectRetry::default()).await.unwrap();
+        backend.initialize().await.unwrap();
+        backend
+    }
+
+    #[tokio::test]
+    async fn test_migrate_tables() {
+        let (source_file, destination_file) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let mut source = sqlite_backend(&source_file).await;
+        let mut destination = sqlite_backend(&destination_file).await;
+
+        let schema = source.default_table_schema("devices");
+        source.create_table_if_not_exists("devices", &schema).await.unwrap();
+        let devices: Vec<serde_json::Value> = (1..=5)
+            .map(|i| serde_json::json!({"id": i.to_string(), "deviceName": format!("PC-{}", i), "device_hash": format!("hash-{}", i)}))
+            .collect();
+        source.store_endpoint_data("devices", &devices).await.unwrap();
+        let state = SyncState {
+            endpoint_name: "devices".to_string(),
+            watermark: Some("2024-05-01T10:00:00+00:00".to_string()),
+            ..Default::default()
+        };
+        source.set_sync_state(&state).await.unwrap();
+
+        let tables = vec!["devices".to_string(), "users".to_string()];
+        let endpoints = vec!["devices".to_string(), "users".to_string()];
+        let migrations = migrate_tables(&mut source, &mut destination, &tables, &endpoints, 2).await.unwrap();
+
+        // The users table does not exist in the source
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(migrations[0].source_rows, 5);
+        assert_eq!(migrations[0].copied, 5);
+        assert!(migrations[0].verified());
+
+        assert_eq!(destination.get_all_hashes("devices", HASH_COLUMN).await.unwrap(), source.get_all_hashes("devices", HASH_COLUMN).await.unwrap());
+        assert_eq!(destination.get_sync_state("devices").await.unwrap(), Some(state));
+        assert_eq!(destination.get_sync_state("users").await.unwrap(), None);
+    }
+}
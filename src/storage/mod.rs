@@ -1,11 +1,698 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub mod sqlite;
 pub mod postgres;
 pub mod mssql;
+mod device_query;
+mod migrate;
 
-use crate::config::DatabaseConfig;
+pub use device_query::{DeviceQuery, DeviceSort};
+pub use migrate::migrate_tables;
+
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+use crate::config::{DatabaseConfig, MissingColumnPolicy, SchemaConflictPolicy};
+use crate::endpoint::ColumnTypeOverride;
+use crate::error::StorageError;
+use crate::webhook::{BackendCircuitData, WebhookManager};
+
+/// Column holding the change-detection hash of each stored record
+pub const HASH_COLUMN: &str = "device_hash";
+
+/// Column reserved in every endpoint table for the record's raw JSON
+pub const RAW_JSON_COLUMN: &str = "data";
+
+/// Column holding when this service last wrote the row
+pub const SYNCED_AT_COLUMN: &str = "synced_at";
+
+/// Column holding when a soft-deleted row stopped being returned by Graph
+pub const DELETED_AT_COLUMN: &str = "deleted_at";
+
+/// Ids per `IN (...)` list, well below every backend's bind parameter limit
+pub(crate) const ID_BATCH_SIZE: usize = 500;
+
+/// `lastSyncDateTime` Graph reports for a device that never checked in
+pub const NEVER_CHECKED_IN: &str = "0001-01-01T00:00:00Z";
+
+/// Fill the timestamp columns of a record about to be written. `last_sync_date_time`
+/// is the record's own Graph `lastSyncDateTime` (when the device last checked in with
+/// Intune) and stays empty for records without one; `synced_at` is the time of writing,
+/// left out when the table has no such column.
+pub(crate) fn add_sync_timestamps(record: &mut HashMap<String, String>, synced_at: bool) {
+    if !record.contains_key("last_sync_date_time") {
+        let graph_value = record.get(&sanitize_column_name("lastSyncDateTime"))
+            .filter(|value| !value.is_empty())
+            .cloned();
+        if let Some(value) = graph_value {
+            record.insert("last_sync_date_time".to_string(), value);
+        }
+    }
+
+    if synced_at {
+        record.entry(SYNCED_AT_COLUMN.to_string())
+            .or_insert_with(|| chrono::Utc::now().to_rfc3339());
+    }
+}
+
+/// Turn a JSON key into a valid SQL column name.
+///
+/// Lowercases the key, replaces anything that is not ASCII alphanumeric with `_`
+/// and prefixes names starting with a digit, e.g. `@odata.type` -> `_odata_type`.
+/// Applied to both schema evolution and inserts so the mapping is stable.
+pub fn sanitize_column_name(key: &str) -> String {
+    let mut name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+
+    name
+}
+
+/// Whether `name` is a plain identifier (letters, digits and underscores, not starting
+/// with a digit) that can be formatted into SQL as is
+pub fn is_valid_table_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Refuse a table name that is not a plain identifier, before it ends up in SQL
+pub(crate) fn validate_table_name(name: &str) -> Result<()> {
+    if is_valid_table_name(name) {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidTableName { table: name.to_string() }.into())
+    }
+}
+
+/// Original JSON key -> sanitized column name for every key that gets renamed
+pub fn column_name_mapping<'a, I>(keys: I) -> BTreeMap<String, String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    keys.into_iter()
+        .map(|key| (key.clone(), sanitize_column_name(key)))
+        .filter(|(key, column)| key != column)
+        .collect()
+}
+
+/// Longest identifier every backend accepts (PostgreSQL's limit)
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// Name of the index on `columns` of a table, e.g. `idx_devices_compliancestate`.
+/// Names over 63 characters are cut short and end in a hash of the full name, so
+/// they stay unique.
+pub fn index_name(table_name: &str, columns: &[String]) -> String {
+    let name = format!("idx_{}_{}", table_name, columns.join("_")).to_lowercase();
+    if name.len() <= MAX_IDENTIFIER_LENGTH {
+        return name;
+    }
+
+    use sha2::{Digest, Sha256};
+    let hash = hex::encode(Sha256::digest(name.as_bytes()));
+    format!("{}_{}", &name[..MAX_IDENTIFIER_LENGTH - 9], &hash[..8])
+}
+
+/// Columns a record needs that the table does not have yet, with the value each
+/// one's type is inferred from. Always includes `id`, `last_sync_date_time` and `synced_at`.
+/// Existing names are compared case-insensitively, since tables created before
+/// sanitization may hold mixed-case column names.
+pub fn missing_columns<'a>(
+    existing: &HashSet<String>,
+    sample: &'a serde_json::Map<String, serde_json::Value>,
+) -> Vec<(String, Option<&'a serde_json::Value>)> {
+    let existing: HashSet<String> = existing.iter().map(|c| c.to_lowercase()).collect();
+
+    let mut required: BTreeMap<String, Option<&serde_json::Value>> = BTreeMap::new();
+    required.insert("id".to_string(), None);
+    required.insert("last_sync_date_time".to_string(), None);
+    required.insert(SYNCED_AT_COLUMN.to_string(), None);
+    for (key, value) in sample {
+        required.insert(sanitize_column_name(key), Some(value));
+    }
+
+    required.into_iter()
+        .filter(|(column, _)| !existing.contains(column))
+        .collect()
+}
+
+/// Whether `existing` has `column`, compared case-insensitively like [`missing_columns`]
+pub(crate) fn has_column(existing: &HashSet<String>, column: &str) -> bool {
+    existing.iter().any(|c| c.eq_ignore_ascii_case(column))
+}
+
+/// Make records fit a table whose schema may not be changed (`autoMigrate` off):
+/// fields without a column are dropped or fail the batch, depending on the policy.
+/// `id` and `last_sync_date_time` are written with every record, so missing those
+/// always fails. `synced_at` is optional, since tables created before it was
+/// introduced lack it; records are then written without it.
+pub fn fit_to_existing_columns(
+    table_name: &str,
+    existing: &HashSet<String>,
+    data: &[serde_json::Value],
+    policy: MissingColumnPolicy,
+) -> Result<Vec<serde_json::Value>> {
+    if existing.is_empty() {
+        return Err(StorageError::TableMissing { table: table_name.to_string() }.into());
+    }
+
+    let mut missing = std::collections::BTreeSet::new();
+    for item in data {
+        if let Some(obj) = item.as_object() {
+            missing.extend(missing_columns(existing, obj).into_iter()
+                .map(|(column, _)| column)
+                .filter(|column| column != SYNCED_AT_COLUMN));
+        }
+    }
+
+    let required_missing = missing.iter().any(|c| c == "id" || c == "last_sync_date_time");
+    if missing.is_empty() {
+        return Ok(data.to_vec());
+    }
+    if required_missing || policy == MissingColumnPolicy::Fail {
+        return Err(StorageError::MissingColumns {
+            table: table_name.to_string(),
+            columns: missing.into_iter().collect::<Vec<_>>().join(", "),
+        }.into());
+    }
+
+    log::debug!(
+        "Dropping fields without a column in table {} (autoMigrate is off): {}",
+        table_name,
+        missing.iter().cloned().collect::<Vec<_>>().join(", ")
+    );
+    let existing: HashSet<String> = existing.iter().map(|c| c.to_lowercase()).collect();
+    Ok(data.iter()
+        .cloned()
+        .map(|mut item| {
+            if let Some(obj) = item.as_object_mut() {
+                obj.retain(|key, _| existing.contains(&sanitize_column_name(key)));
+            }
+            item
+        })
+        .collect())
+}
+
+/// Records sampled by default to infer the types of new columns (`schemaSampleSize`)
+pub const DEFAULT_SCHEMA_SAMPLE_SIZE: usize = 100;
+
+/// One record holding every field of the first `sample_size` records (at least one), for
+/// inferring column types. Each field keeps a value whose inferred type fits all of the
+/// sampled values: the first non-null one when they agree, a float when integers and
+/// floats mix, a string that is not a timestamp when only some strings are, and an empty
+/// string (a text column) when the kinds differ.
+pub fn schema_sample(records: &[serde_json::Value], sample_size: usize) -> serde_json::Value {
+    let mut sample = serde_json::Map::new();
+    for record in records.iter().take(sample_size.max(1)).filter_map(|r| r.as_object()) {
+        for (key, value) in record {
+            match sample.get_mut(key) {
+                Some(slot) => {
+                    if let Some(wider) = widen_sample_value(slot, value) {
+                        *slot = wider;
+                    }
+                }
+                None => {
+                    sample.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+    serde_json::Value::Object(sample)
+}
+
+/// The value that replaces `current` once `value` has been seen too, if it has to change
+fn widen_sample_value(current: &serde_json::Value, value: &serde_json::Value) -> Option<serde_json::Value> {
+    use serde_json::Value;
+
+    let is_timestamp = |s: &str| chrono::DateTime::parse_from_rfc3339(s).is_ok();
+    match (current, value) {
+        (_, Value::Null) => None,
+        (Value::Null, _) => Some(value.clone()),
+        (Value::Number(a), Value::Number(b)) => (!a.is_f64() && b.is_f64()).then(|| value.clone()),
+        (Value::String(a), Value::String(b)) => (is_timestamp(a) && !is_timestamp(b)).then(|| value.clone()),
+        (Value::Bool(_), Value::Bool(_)) | (Value::Array(_), Value::Array(_)) | (Value::Object(_), Value::Object(_)) => None,
+        (Value::String(a), _) if a.is_empty() => None,
+        _ => Some(Value::String(String::new())),
+    }
+}
+
+/// A column schema inference adds to a table, with the statement that adds it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnAddition {
+    pub column: String,
+    pub column_type: String,
+    pub sql: String,
+}
+
+/// How a backend types the columns schema inference adds
+pub(crate) trait ColumnTypeBackend {
+    /// `ALTER TABLE` clause that adds a column in the backend's dialect
+    const ADD_COLUMN: &'static str;
+
+    fn column_types(&self) -> &ColumnTypes;
+
+    /// Type a new column holding `value` would get
+    fn inferred_column_type(&self, column: &str, value: Option<&serde_json::Value>) -> &'static str;
+}
+
+/// `ALTER TABLE` statements adding the columns the sample record needs, typed by the
+/// endpoint's `columnTypes` or else inferred from the sample
+pub(crate) fn column_additions<B: ColumnTypeBackend>(
+    backend: &B,
+    table_name: &str,
+    existing: &HashSet<String>,
+    sample_data: &serde_json::Value,
+) -> Vec<ColumnAddition> {
+    let Some(obj) = sample_data.as_object() else {
+        return Vec::new();
+    };
+
+    let mapping = column_name_mapping(obj.keys());
+    if !mapping.is_empty() {
+        log::debug!("Column name mapping for table {}: {:?}", table_name, mapping);
+    }
+
+    missing_columns(existing, obj)
+        .into_iter()
+        .map(|(column, value)| {
+            let column_type = match backend.column_types().get(table_name, &column) {
+                Some(column_type) => column_type.to_string(),
+                None => backend.inferred_column_type(&column, value).to_string(),
+            };
+            let sql = format!("ALTER TABLE {} {} {} {}", table_name, B::ADD_COLUMN, column, column_type);
+            ColumnAddition { column, column_type, sql }
+        })
+        .collect()
+}
+
+/// Statements `preview_schema_changes` reports: the table's `schema` if it has no
+/// columns yet, then the columns the sample would add
+pub(crate) fn preview_statements<B: ColumnTypeBackend>(
+    backend: &B,
+    table_name: &str,
+    schema: &str,
+    existing: HashSet<String>,
+    sample: &serde_json::Value,
+) -> Vec<String> {
+    let mut statements = Vec::new();
+    let existing = if existing.is_empty() {
+        statements.push(schema.trim().to_string());
+        schema_columns(schema)
+    } else {
+        existing
+    };
+
+    statements.extend(
+        column_additions(backend, table_name, &existing, sample)
+            .into_iter()
+            .map(|addition| addition.sql),
+    );
+    statements
+}
+
+/// Column names declared by a `CREATE TABLE` statement (lowercased, constraints skipped)
+pub fn schema_columns(schema: &str) -> HashSet<String> {
+    // Skip a guard in front of the statement, like MSSQL's `IF OBJECT_ID(...) IS NULL`
+    let create = schema.to_lowercase().find("create table").unwrap_or(0);
+    let (Some(start), Some(end)) = (schema[create..].find('(').map(|i| create + i), schema.rfind(')')) else {
+        return HashSet::new();
+    };
+
+    // Split the column list on commas outside of type arguments like DECIMAL(10,2)
+    let mut definitions = Vec::new();
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in schema[start + 1..end].chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                definitions.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    definitions.push(current);
+
+    definitions.iter()
+        .filter_map(|definition| definition.split_whitespace().next())
+        .map(|name| name.trim_matches(|c| c == '"' || c == '[' || c == ']').to_lowercase())
+        .filter(|name| !matches!(name.as_str(), "primary" | "foreign" | "unique" | "constraint" | "check"))
+        .collect()
+}
+
+/// Failed inserts in one batch after which the table's column types are checked
+pub const SCHEMA_CONFLICT_THRESHOLD: usize = 3;
+
+/// A column whose existing type cannot hold the values now arriving for it,
+/// e.g. after `selectFields` changed what a field contains
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnConflict {
+    pub column: String,
+    pub existing_type: String,
+    pub expected_type: String,
+}
+
+impl std::fmt::Display for ColumnConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "column {} is {} but incoming values need {}", self.column, self.existing_type, self.expected_type)
+    }
+}
+
+/// Coarse type family of a SQL column type, across the three backends
+fn type_family(sql_type: &str) -> &'static str {
+    let sql_type = sql_type.to_lowercase();
+    if sql_type.contains("char") || sql_type.contains("text") {
+        "text"
+    } else if sql_type.contains("json") {
+        "json"
+    } else if sql_type.contains("date") || sql_type.contains("time") {
+        "timestamp"
+    } else if sql_type.contains("int") {
+        "integer"
+    } else if ["real", "double", "float", "numeric", "decimal"].iter().any(|t| sql_type.contains(t)) {
+        "real"
+    } else if sql_type.contains("bool") || sql_type == "bit" {
+        "boolean"
+    } else {
+        "other"
+    }
+}
+
+/// Whether a column of `existing_type` can store values inferred as `expected_type`
+pub fn column_accepts(existing_type: &str, expected_type: &str) -> bool {
+    let existing = type_family(existing_type);
+    let expected = type_family(expected_type);
+
+    // Text holds anything; types we do not know are not second-guessed
+    existing == "text" || existing == "other" || existing == expected
+        || (existing == "real" && expected == "integer")
+}
+
+/// Compare existing column types with the types inferred from incoming data.
+/// Both maps are keyed by sanitized column name; columns missing on either side are ignored.
+pub fn find_type_conflicts(
+    existing: &HashMap<String, String>,
+    expected: &HashMap<String, String>,
+) -> Vec<ColumnConflict> {
+    let mut conflicts: Vec<ColumnConflict> = expected.iter()
+        .filter_map(|(column, expected_type)| {
+            let existing_type = existing.get(column)?;
+            (!column_accepts(existing_type, expected_type)).then(|| ColumnConflict {
+                column: column.clone(),
+                existing_type: existing_type.clone(),
+                expected_type: expected_type.clone(),
+            })
+        })
+        .collect();
+
+    conflicts.sort_by(|a, b| a.column.cmp(&b.column));
+    conflicts
+}
+
+/// Column types from `columnTypes`, by table and sanitized column name, for one dialect
+#[derive(Debug, Clone, Default)]
+pub struct ColumnTypes {
+    tables: HashMap<String, HashMap<String, String>>,
+}
+
+impl ColumnTypes {
+    /// Add the overrides `dialect` (`sqlite`, `postgres` or `mssql`) gets to the table's,
+    /// replacing only those for the same columns: endpoints merged into one table with
+    /// `mergeKey` each bring their own. Types the dialect does not accept are logged and
+    /// left to inference.
+    pub fn set(&mut self, dialect: &str, table_name: &str, overrides: &HashMap<String, ColumnTypeOverride>) {
+        let columns = self.tables.entry(table_name.to_string()).or_default();
+        for (field, column_type) in overrides {
+            let Some(sql_type) = column_type.for_backend(dialect) else { continue };
+            if valid_column_type(dialect, sql_type) {
+                columns.insert(sanitize_column_name(field), sql_type.trim().to_string());
+            } else {
+                log::warn!("Ignoring column type '{}' for {}.{}: not a {} type", sql_type, table_name, field, dialect);
+            }
+        }
+    }
+
+    pub fn get(&self, table_name: &str, column: &str) -> Option<&str> {
+        self.tables.get(table_name)?.get(column).map(String::as_str)
+    }
+}
+
+/// Whether `sql_type` is a column type of `dialect` that `columnTypes` may use: a known
+/// type name, optionally with a length or precision such as `NVARCHAR(64)` or `NUMERIC(10,2)`.
+/// The type ends up in DDL as written, so nothing else is allowed.
+pub fn valid_column_type(dialect: &str, sql_type: &str) -> bool {
+    const SQLITE: &[&str] = &["TEXT", "INTEGER", "REAL", "NUMERIC", "BLOB", "DATETIME", "DATE", "BOOLEAN", "VARCHAR"];
+    const POSTGRES: &[&str] = &[
+        "TEXT", "VARCHAR", "CHARACTER VARYING", "CHAR", "INTEGER", "INT", "BIGINT", "SMALLINT", "NUMERIC", "DECIMAL",
+        "REAL", "DOUBLE PRECISION", "BOOLEAN", "TIMESTAMP", "TIMESTAMPTZ", "TIMESTAMP WITH TIME ZONE", "DATE", "UUID",
+        "JSON", "JSONB",
+    ];
+    const MSSQL: &[&str] = &[
+        "NVARCHAR", "VARCHAR", "NCHAR", "CHAR", "INT", "BIGINT", "SMALLINT", "TINYINT", "BIT", "DECIMAL", "NUMERIC",
+        "FLOAT", "REAL", "DATETIME", "DATETIME2", "DATETIMEOFFSET", "DATE", "UNIQUEIDENTIFIER",
+    ];
+
+    let known = match dialect {
+        "sqlite" => SQLITE,
+        "postgres" => POSTGRES,
+        "mssql" => MSSQL,
+        _ => return false,
+    };
+    let (name, arguments) = match sql_type.trim().split_once('(') {
+        Some((name, rest)) => match rest.strip_suffix(')') {
+            Some(arguments) => (name, Some(arguments)),
+            None => return false,
+        },
+        None => (sql_type.trim(), None),
+    };
+    let arguments_valid = arguments.is_none_or(|arguments| {
+        arguments.trim().eq_ignore_ascii_case("max")
+            || arguments.split(',').all(|n| !n.trim().is_empty() && n.trim().chars().all(|c| c.is_ascii_digit()))
+    });
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ").to_uppercase();
+    arguments_valid && known.contains(&name.as_str())
+}
+
+/// Name a conflicting column is renamed to before it is recreated with the new type
+pub fn conflict_backup_column_name(column: &str) -> String {
+    format!("{}_old_{}", column, chrono::Utc::now().format("%Y%m%d%H%M%S"))
+}
+
+/// Backend-specific steps of [`handle_schema_conflicts`]
+#[async_trait]
+pub(crate) trait SchemaConflictBackend: ColumnTypeBackend + Send {
+    /// Backend the recorded schema changes are attributed to
+    const BACKEND_NAME: &'static str;
+
+    /// Existing columns of the table and their types
+    async fn existing_column_types(&mut self, table_name: &str) -> Result<HashMap<String, String>>;
+
+    /// Rename the conflicting column to `backup` and add it again with the expected
+    /// type, returning the statements run
+    async fn recreate_column(&mut self, table_name: &str, conflict: &ColumnConflict, backup: &str) -> Result<Vec<String>>;
+
+    /// Store a record again after its columns were recreated
+    async fn retry_record(&mut self, table_name: &str, item: &serde_json::Value) -> Result<StorageResult>;
+
+    fn schema_changes(&mut self) -> &mut Vec<SchemaChange>;
+}
+
+/// Column types the given records would get if their tables were created today,
+/// leaving out columns with a configured type.
+/// Null values say nothing about the type and are skipped.
+fn expected_column_types<B: SchemaConflictBackend>(backend: &B, table_name: &str, items: &[&serde_json::Value]) -> HashMap<String, String> {
+    let mut expected = HashMap::new();
+    for obj in items.iter().filter_map(|item| item.as_object()) {
+        for (key, value) in obj.iter().filter(|(_, v)| !v.is_null()) {
+            let column = sanitize_column_name(key);
+            // A configured type is what the operator wants, not a conflict
+            if backend.column_types().get(table_name, &column).is_some() {
+                continue;
+            }
+            let column_type = backend.inferred_column_type(&column, Some(value));
+            expected.entry(column).or_insert_with(|| column_type.to_string());
+        }
+    }
+    expected
+}
+
+/// Look for columns whose type no longer fits the failed records and apply the
+/// configured policy. Returns the records stored on retry.
+pub(crate) async fn handle_schema_conflicts<B: SchemaConflictBackend>(
+    backend: &mut B,
+    policy: SchemaConflictPolicy,
+    table_name: &str,
+    failed: &[&serde_json::Value],
+) -> Result<ChangeCounts> {
+    let existing = backend.existing_column_types(table_name).await?;
+    let conflicts = find_type_conflicts(&existing, &expected_column_types(backend, table_name, failed));
+
+    if conflicts.is_empty() {
+        log::warn!(
+            "{} inserts into table {} failed, but no column type conflicts were found",
+            failed.len(), table_name
+        );
+        return Ok(ChangeCounts::default());
+    }
+
+    for conflict in &conflicts {
+        log::error!("Schema conflict in table {}: {}", table_name, conflict);
+    }
+
+    match policy {
+        SchemaConflictPolicy::Log => {
+            log::warn!(
+                "{} records were not stored in table {} - set onSchemaConflict to \"recreate\" or migrate the columns manually",
+                failed.len(), table_name
+            );
+            Ok(ChangeCounts::default())
+        }
+        SchemaConflictPolicy::Fail => Err(StorageError::SchemaConflict {
+            table: table_name.to_string(),
+            details: conflicts.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("; "),
+        }
+        .into()),
+        SchemaConflictPolicy::Recreate => {
+            for conflict in conflicts.iter().filter(|c| c.column != "id") {
+                let backup = conflict_backup_column_name(&conflict.column);
+                let statements = backend.recreate_column(table_name, conflict, &backup).await?;
+                backend.schema_changes().extend(statements.into_iter().map(|statement| SchemaChange {
+                    backend: B::BACKEND_NAME,
+                    table: table_name.to_string(),
+                    statement,
+                }));
+                log::warn!(
+                    "Recreated column {} in table {} as {} - old values kept in {}",
+                    conflict.column, table_name, conflict.expected_type, backup
+                );
+            }
+
+            let mut stored = ChangeCounts::default();
+            for item in failed {
+                match backend.retry_record(table_name, item).await {
+                    Ok(result) => stored.record(&result),
+                    Err(e) => log::warn!("Failed to store item in table {} after recreating columns: {}", table_name, e),
+                }
+            }
+            Ok(stored)
+        }
+    }
+}
+
+/// Upper bound for the delay between two connection attempts
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How often and how long to wait for a database that is not reachable yet.
+/// The default connects once and gives up on failure.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectRetry {
+    /// Retries after the first failed attempt
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every further retry
+    pub delay: Duration,
+}
+
+impl ConnectRetry {
+    /// Delay before the given retry (1-based), capped at one minute
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.delay.saturating_mul(factor).min(MAX_CONNECT_RETRY_DELAY)
+    }
+}
+
+/// Run `connect` until it succeeds or the retries are used up, e.g. while a
+/// database container is still starting next to the service
+pub async fn connect_with_retry<T, F, Fut>(backend_name: &str, retry: ConnectRetry, mut connect: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match connect().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < retry.retries => {
+                attempt += 1;
+                let delay = retry.delay_for(attempt);
+                log::warn!(
+                    "Connecting to {} failed (attempt {} of {}): {} - retrying in {:?}",
+                    backend_name, attempt, retry.retries + 1, e, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Table holding the sync progress of every endpoint
+pub const SYNC_STATE_TABLE: &str = "sync_state";
+
+/// Progress of one endpoint that has to survive restarts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncState {
+    pub endpoint_name: String,
+    /// `@odata.deltaLink` to resume a delta query from
+    pub delta_link: Option<String>,
+    /// Highest `incrementalField` value stored so far (RFC 3339)
+    pub watermark: Option<String>,
+    /// ETag of the last response, sent as `If-None-Match` when `useEtag` is on
+    pub etag: Option<String>,
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Why the endpoint's last sync failed, cleared by its next success
+    pub last_error: Option<String>,
+    pub last_error_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the last sync that fetched everything finished, for `syncMode: auto`
+    pub last_full_sync_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Combine the state read from several backends, primary first. A field is only kept
+/// if every backend holds the same value, so a backend that missed a sync makes the
+/// next one start over instead of skipping what it never received. The last error is
+/// the first backend's.
+pub fn merge_sync_states(states: Vec<Option<SyncState>>) -> Option<SyncState> {
+    let mut states = states.into_iter();
+    let mut merged = states.next()??;
+
+    for state in states {
+        let state = state?;
+        if merged.delta_link != state.delta_link {
+            merged.delta_link = None;
+        }
+        if merged.watermark != state.watermark {
+            merged.watermark = None;
+        }
+        if merged.etag != state.etag {
+            merged.etag = None;
+        }
+        merged.updated_at = merged.updated_at.min(state.updated_at);
+        // A backend that missed the last full pull makes the next sync a full one
+        merged.last_full_sync_at = merged.last_full_sync_at.min(state.last_full_sync_at);
+        // Errors are not progress; the first backend's, normally the primary's, stands, so a
+        // replica still holding a failure the primary has since cleared does not resurface it
+    }
+
+    Some(merged)
+}
+
+/// DDL a backend ran on its own while storing: columns added by schema evolution and
+/// columns recreated after a type conflict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaChange {
+    pub backend: &'static str,
+    pub table: String,
+    pub statement: String,
+}
 
 /// Represents the result of a storage operation
 #[derive(Debug, Clone)]
@@ -15,135 +702,867 @@ pub enum StorageResult {
     Skipped, // No changes detected
 }
 
-/// Trait for database storage backends
-#[async_trait]
-pub trait StorageBackend: Send + Sync {
-    /// Initialize the storage backend (create tables, etc.)
-    async fn initialize(&mut self) -> Result<()>;
+/// Tally of how records compare to what is already stored: per sync from the stored
+/// hashes, or per write from the rows a backend inserted and updated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeCounts {
+    pub inserted: usize,
+    pub updated: usize,
+    /// Unchanged records, or records a backend failed to write
+    pub skipped: usize,
+}
+
+impl ChangeCounts {
+    pub(crate) fn record(&mut self, result: &StorageResult) {
+        match result {
+            StorageResult::Inserted => self.inserted += 1,
+            StorageResult::Updated => self.updated += 1,
+            StorageResult::Skipped => self.skipped += 1,
+        }
+    }
+
+    /// Records written, as opposed to skipped
+    pub fn stored(&self) -> usize {
+        self.inserted + self.updated
+    }
+}
+
+impl std::ops::AddAssign for ChangeCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.skipped += other.skipped;
+    }
+}
+
+/// Trait for database storage backends
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Initialize the storage backend (create tables, etc.)
+    async fn initialize(&mut self) -> Result<()>;
+
+    /// `CREATE TABLE` statement for a new endpoint table in this backend's SQL dialect,
+    /// a no-op if the table exists. Columns for the data are added as it arrives.
+    fn default_table_schema(&self, table_name: &str) -> String;
+
+    /// Create a table if it doesn't exist with the given schema
+    async fn create_table_if_not_exists(&mut self, table_name: &str, schema: &str) -> Result<()>;
+
+    /// Store generic endpoint data in a specified table, counting the rows inserted and
+    /// updated; records that could not be written are counted as skipped
+    async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<ChangeCounts>;
+
+    /// Load every `id -> hash` pair of a table's hash column (`device_hash`, or the
+    /// column of an endpoint merging into the table) in a single query.
+    /// Returns an empty map if the table has no such column yet.
+    async fn get_all_hashes(&mut self, table_name: &str, column: &str) -> Result<HashMap<String, String>>;
+
+    /// Number of rows in a table
+    async fn get_table_count(&mut self, table_name: &str) -> Result<i64>;
+
+    /// Non-empty raw JSON documents stored with each row. Empty if the table
+    /// has no raw JSON column or nothing was stored in it.
+    async fn get_raw_records(&mut self, table_name: &str) -> Result<Vec<String>>;
+
+    /// One page of the devices matching `query`, as JSON objects keyed by column name,
+    /// and the number of matching devices across all pages. Empty if the table does
+    /// not exist yet.
+    async fn query_devices(&mut self, table_name: &str, query: &DeviceQuery) -> Result<(Vec<serde_json::Value>, u64)>;
+
+    /// DDL that storing `sample` into the table would run, without running it:
+    /// `schema` if the table does not exist yet, then any columns it would gain
+    async fn preview_schema_changes(&mut self, table_name: &str, schema: &str, sample: &serde_json::Value) -> Result<Vec<String>>;
+
+    /// Ids of the rows whose `last_sync_date_time` is before `cutoff`. Rows without one,
+    /// with [`NEVER_CHECKED_IN`] or already marked deleted are left out. Empty if the
+    /// table or the column does not exist.
+    async fn stale_row_ids(&mut self, table_name: &str, cutoff: chrono::DateTime<chrono::Utc>) -> Result<Vec<String>>;
+
+    /// Name of each of the rows with these ids: the first of `name_columns` that the
+    /// table has and the row holds a non-empty value in. Rows without one are left out.
+    async fn row_names(&mut self, table_name: &str, ids: &[String], name_columns: &[String]) -> Result<HashMap<String, String>>;
+
+    /// Delete the rows with these ids. Returns the number of rows deleted.
+    async fn delete_rows(&mut self, table_name: &str, ids: &[String]) -> Result<u64>;
+
+    /// Set [`DELETED_AT_COLUMN`] of the rows with these ids and clear their `hash_column`,
+    /// so a row that comes back is written again. The column is added like any new
+    /// column. With `deleted_at` `None` the marks are cleared instead, which does nothing
+    /// if the table has no such column. Returns the number of rows changed.
+    async fn mark_rows_deleted(&mut self, table_name: &str, hash_column: &str, ids: &[String], deleted_at: Option<&str>) -> Result<u64>;
+
+    /// Stored sync progress of an endpoint, if any
+    async fn get_sync_state(&mut self, endpoint_name: &str) -> Result<Option<SyncState>>;
+
+    /// Insert or replace the sync progress of an endpoint
+    async fn set_sync_state(&mut self, state: &SyncState) -> Result<()>;
+
+    /// Reclaim free space and refresh planner statistics for the given tables.
+    /// Returns the bytes reclaimed, or `None` if the backend does no maintenance.
+    async fn run_maintenance(&mut self, _tables: &[String]) -> Result<Option<i64>> {
+        Ok(None)
+    }
+
+    /// Create an index on `columns` (sanitized column names) unless it exists. Does
+    /// nothing while the table lacks one of the columns, so it can be retried after
+    /// schema evolution has added them.
+    async fn create_index_if_not_exists(&mut self, _table_name: &str, _columns: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Bytes the table takes up on disk including its indexes, `None` if the backend
+    /// cannot tell or the table does not exist. SQLite reports the whole database file.
+    async fn table_size_bytes(&mut self, _table_name: &str) -> Result<Option<i64>> {
+        Ok(None)
+    }
+
+    /// Drop the table if it exists
+    async fn drop_table(&mut self, _table_name: &str) -> Result<()> {
+        anyhow::bail!("The {} backend cannot drop tables", self.backend_name())
+    }
+
+    /// Move everything written so far into the database file itself, so a copy of
+    /// the file is complete. Nothing to do for server databases.
+    async fn checkpoint(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Schema changes applied since the last call, oldest first
+    fn take_schema_changes(&mut self) -> Vec<SchemaChange> {
+        Vec::new()
+    }
+
+    /// Use these types instead of the inferred ones when columns of the table are added,
+    /// on top of the ones already set for its other columns
+    fn set_column_types(&mut self, _table_name: &str, _column_types: &HashMap<String, ColumnTypeOverride>) {}
+
+    /// Health check for the storage backend
+    async fn health_check(&mut self) -> Result<()>;
+
+    /// Get backend name for logging
+    fn backend_name(&self) -> &'static str;
+
+    /// Clean up resources and close connections
+    async fn cleanup(&mut self) -> Result<()>;
+}
+
+/// Backends a database config can hold, in the order they are written
+pub const BACKEND_KINDS: &[&str] = &["sqlite", "postgres", "mssql"];
+
+/// Name a backend kind the way its [`StorageBackend::backend_name`] does
+pub(crate) fn backend_kind_name(kind: &str) -> &'static str {
+    match kind {
+        "sqlite" => "SQLite",
+        "postgres" => "PostgreSQL",
+        "mssql" => "MSSQL",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn backend_enabled(config: &DatabaseConfig, kind: &str) -> bool {
+    match kind {
+        "sqlite" => config.sqlite.as_ref().is_some_and(|c| c.enabled),
+        "postgres" => config.postgres.as_ref().is_some_and(|c| c.enabled),
+        "mssql" => config.mssql.as_ref().is_some_and(|c| c.enabled),
+        _ => false,
+    }
+}
+
+/// Connect to one backend of the config by kind (`sqlite`, `postgres` or `mssql`),
+/// whether or not it is enabled. `None` if the config has no section for it.
+pub async fn connect_backend(config: &DatabaseConfig, kind: &str) -> Result<Option<Box<dyn StorageBackend>>> {
+    let retry = ConnectRetry {
+        retries: config.connect_retries,
+        delay: config.parse_connect_retry_delay()?,
+    };
+
+    let backend: Box<dyn StorageBackend> = match kind {
+        "sqlite" => {
+            let Some(ref sqlite_config) = config.sqlite else { return Ok(None) };
+            Box::new(sqlite::SqliteBackend::new(&sqlite_config.database_path, retry).await?
+                .with_vacuum_into(sqlite_config.vacuum_into.clone())
+                .with_auto_migrate(config.auto_migrate, config.on_missing_column)
+                .with_schema_sample_size(config.schema_sample_size))
+        }
+        "postgres" => {
+            let Some(ref postgres_config) = config.postgres else { return Ok(None) };
+            Box::new(postgres::PostgresBackend::new(&postgres_config.resolve_connection_string()?, postgres_config.tls.as_ref(), postgres_config.max_connections, retry).await?
+                .with_schema_conflict_policy(config.on_schema_conflict)
+                .with_auto_migrate(config.auto_migrate, config.on_missing_column)
+                .with_schema_sample_size(config.schema_sample_size)
+                .with_copy_threshold(postgres_config.copy_threshold.unwrap_or(postgres::DEFAULT_COPY_THRESHOLD)))
+        }
+        "mssql" => {
+            let Some(ref mssql_config) = config.mssql else { return Ok(None) };
+            Box::new(mssql::MssqlBackend::new(&mssql_config.resolve_connection_string()?, mssql_config.tls.as_ref(), retry).await?
+                .with_schema_conflict_policy(config.on_schema_conflict)
+                .with_auto_migrate(config.auto_migrate, config.on_missing_column)
+                .with_schema_sample_size(config.schema_sample_size))
+        }
+        other => anyhow::bail!("Unknown backend '{}', expected one of: {}", other, BACKEND_KINDS.join(", ")),
+    };
+    Ok(Some(backend))
+}
+
+/// Open one backend kind without creating its database, file or tables, for commands
+/// that only read
+pub async fn connect_backend_read_only(config: &DatabaseConfig, kind: &str) -> Result<Option<Box<dyn StorageBackend>>> {
+    let retry = ConnectRetry {
+        retries: config.connect_retries,
+        delay: config.parse_connect_retry_delay()?,
+    };
+
+    let backend: Box<dyn StorageBackend> = match kind {
+        "sqlite" => {
+            let Some(ref sqlite_config) = config.sqlite else { return Ok(None) };
+            Box::new(sqlite::SqliteBackend::open_read_only(&sqlite_config.database_path, retry).await?)
+        }
+        "postgres" => {
+            let Some(ref postgres_config) = config.postgres else { return Ok(None) };
+            Box::new(postgres::PostgresBackend::connect_existing(&postgres_config.resolve_connection_string()?, postgres_config.tls.as_ref(), postgres_config.max_connections, retry).await?)
+        }
+        "mssql" => {
+            let Some(ref mssql_config) = config.mssql else { return Ok(None) };
+            Box::new(mssql::MssqlBackend::connect_existing(&mssql_config.resolve_connection_string()?, mssql_config.tls.as_ref(), retry).await?)
+        }
+        other => anyhow::bail!("Unknown backend '{}', expected one of: {}", other, BACKEND_KINDS.join(", ")),
+    };
+    Ok(Some(backend))
+}
+
+/// Connect to the read replica of one backend kind, if it has one configured. A replica
+/// that cannot be reached is logged and left out, so reads fall back to the primary.
+async fn connect_read_replica(config: &DatabaseConfig, kind: &str) -> Result<Option<Box<dyn StorageBackend>>> {
+    let replica = match kind {
+        "postgres" => config.postgres.as_ref()
+            .and_then(|c| c.read_replica_connection_string.as_deref().filter(|s| !s.is_empty()).map(|s| (s, c.tls.as_ref(), c.max_connections))),
+        _ => None,
+    };
+    let Some((connection_string, tls, max_connections)) = replica else { return Ok(None) };
+
+    let retry = ConnectRetry {
+        retries: config.connect_retries,
+        delay: config.parse_connect_retry_delay()?,
+    };
+    match postgres::PostgresBackend::connect_read_replica(connection_string, tls, max_connections, retry).await {
+        Ok(backend) => Ok(Some(Box::new(backend))),
+        Err(e) => {
+            log::warn!("Failed to connect to the {} read replica, reading from the primary instead: {}", kind, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Storage manager that handles multiple backends
+pub struct StorageManager {
+    backends: Vec<Box<dyn StorageBackend>>,
+    /// One breaker per backend, same order as `backends`
+    breakers: Vec<CircuitBreaker>,
+    /// Read replica per backend, same order as `backends`; queries go here when set
+    read_replicas: Vec<Option<Box<dyn StorageBackend>>>,
+    breaker_config: CircuitBreakerConfig,
+    webhooks: Option<Arc<WebhookManager>>,
+    concurrent_writes: bool,
+}
+
+impl StorageManager {
+    /// Create a new storage manager from configuration
+    pub async fn new(config: &DatabaseConfig) -> Result<Self> {
+        let mut backends: Vec<Box<dyn StorageBackend>> = Vec::new();
+        let mut read_replicas = Vec::new();
+        for kind in BACKEND_KINDS {
+            if backend_enabled(config, kind) {
+                if let Some(backend) = connect_backend(config, kind).await? {
+                    backends.push(backend);
+                    read_replicas.push(connect_read_replica(config, kind).await?);
+                }
+            }
+        }
+        
+        if backends.is_empty() {
+            return Err(StorageError::NoBackends.into());
+        }
+        
+        let mut manager = Self::from_backends(backends, config.circuit_breaker.clone())
+            .with_concurrent_writes(config.concurrent_writes);
+        manager.read_replicas = read_replicas;
+        Ok(manager)
+    }
+
+    /// Open the configured backends for reading only. Unlike [`Self::new`] nothing is
+    /// created, so a database that does not exist yet is an error.
+    pub async fn open_read_only(config: &DatabaseConfig) -> Result<Self> {
+        let mut backends: Vec<Box<dyn StorageBackend>> = Vec::new();
+        let mut read_replicas = Vec::new();
+        for kind in BACKEND_KINDS {
+            if backend_enabled(config, kind) {
+                if let Some(backend) = connect_backend_read_only(config, kind).await? {
+                    backends.push(backend);
+                    read_replicas.push(connect_read_replica(config, kind).await?);
+                }
+            }
+        }
+
+        if backends.is_empty() {
+            return Err(StorageError::NoBackends.into());
+        }
+
+        let mut manager = Self::from_backends(backends, config.circuit_breaker.clone());
+        manager.read_replicas = read_replicas;
+        Ok(manager)
+    }
+
+    /// Like [`Self::open_read_only`], but a backend that cannot be opened is returned with
+    /// its error instead of failing the rest. `None` when no backend could be opened.
+    pub async fn open_read_only_available(config: &DatabaseConfig) -> (Option<Self>, Vec<(&'static str, anyhow::Error)>) {
+        let mut backends: Vec<Box<dyn StorageBackend>> = Vec::new();
+        let mut read_replicas = Vec::new();
+        let mut failed = Vec::new();
+        for kind in BACKEND_KINDS {
+            if !backend_enabled(config, kind) {
+                continue;
+            }
+            match connect_backend_read_only(config, kind).await {
+                Ok(Some(backend)) => {
+                    backends.push(backend);
+                    read_replicas.push(connect_read_replica(config, kind).await.unwrap_or(None));
+                }
+                Ok(None) => {}
+                Err(e) => failed.push((backend_kind_name(kind), e)),
+            }
+        }
+
+        if backends.is_empty() {
+            return (None, failed);
+        }
+
+        let mut manager = Self::from_backends(backends, config.circuit_breaker.clone());
+        manager.read_replicas = read_replicas;
+        (Some(manager), failed)
+    }
+
+    fn from_backends(backends: Vec<Box<dyn StorageBackend>>, breaker_config: CircuitBreakerConfig) -> Self {
+        let breakers = vec![CircuitBreaker::default(); backends.len()];
+        let read_replicas = std::iter::repeat_with(|| None).take(backends.len()).collect();
+        Self {
+            backends,
+            breakers,
+            read_replicas,
+            breaker_config,
+            webhooks: None,
+            concurrent_writes: true,
+        }
+    }
+
+    /// Write to all backends at once; when off, backends are written one after the other
+    /// and the first failure stops the rest
+    pub fn with_concurrent_writes(mut self, concurrent_writes: bool) -> Self {
+        self.concurrent_writes = concurrent_writes;
+        self
+    }
+
+    /// Send a webhook whenever a backend circuit opens or closes
+    pub fn with_webhooks(mut self, webhooks: Option<Arc<WebhookManager>>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Whether the backend at `index` may be written to. A backend whose cooldown
+    /// has ended is probed with a health check first.
+    async fn backend_available(&mut self, index: usize) -> bool {
+        match self.breakers[index].state(chrono::Utc::now()) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => match self.backends[index].health_check().await {
+                Ok(()) => {
+                    self.record_backend_success(index);
+                    true
+                }
+                Err(e) => {
+                    log::warn!("Health check of {} backend still failing: {}", self.backends[index].backend_name(), e);
+                    self.record_backend_failure(index, &e);
+                    false
+                }
+            },
+        }
+    }
+
+    fn record_backend_success(&mut self, index: usize) {
+        if self.breakers[index].record_success() {
+            let name = self.backends[index].backend_name();
+            log::info!("{} backend is healthy again, resuming writes", name);
+            self.publish_backend_circuit(index, None);
+        }
+    }
+
+    fn record_backend_failure(&mut self, index: usize, error: &anyhow::Error) {
+        if let Some(until) = self.breakers[index].record_failure(&self.breaker_config, chrono::Utc::now()) {
+            log::warn!(
+                "Suspending writes to {} backend until {} after {} consecutive failures: {}",
+                self.backends[index].backend_name(),
+                until.to_rfc3339(),
+                self.breakers[index].consecutive_failures(),
+                error
+            );
+            self.publish_backend_circuit(index, Some(error.to_string()));
+        }
+    }
+
+    /// Update the gauge and send the webhook after a circuit state transition
+    fn publish_backend_circuit(&self, index: usize, error: Option<String>) {
+        let name = self.backends[index].backend_name();
+        let status = self.breakers[index].status(chrono::Utc::now());
+        let open = status.state != CircuitState::Closed;
+        crate::metrics::BACKEND_CIRCUIT_OPEN
+            .with_label_values(&[name])
+            .set(if open { 1.0 } else { 0.0 });
+
+        if let Some(webhooks) = &self.webhooks {
+            let webhooks = Arc::clone(webhooks);
+            let data = BackendCircuitData {
+                backend: name.to_string(),
+                state: if open { "open" } else { "closed" }.to_string(),
+                consecutive_failures: status.consecutive_failures,
+                open_until: status.open_until,
+                error,
+            };
+            tokio::spawn(async move {
+                if let Err(e) = webhooks.send_backend_circuit_changed(data).await {
+                    log::warn!("Failed to send backend circuit webhook: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Indexes of the backends that may be used right now
+    async fn available_backends(&mut self) -> Result<Vec<usize>> {
+        let mut available = Vec::with_capacity(self.backends.len());
+        for index in 0..self.backends.len() {
+            if self.backend_available(index).await {
+                available.push(index);
+            } else {
+                log::debug!("Skipping {} backend while its circuit is open", self.backends[index].backend_name());
+            }
+        }
+
+        if available.is_empty() {
+            return Err(anyhow::anyhow!("No storage backend is available - every backend circuit is open"));
+        }
+        Ok(available)
+    }
+    
+    /// Initialize all backends
+    pub async fn initialize(&mut self) -> Result<()> {
+        for backend in &mut self.backends {
+            log::info!("Initializing {} backend", backend.backend_name());
+            backend.initialize().await?;
+        }
+        Ok(())
+    }
+    
+    /// Perform health check on all backends
+    pub async fn health_check(&mut self) -> Result<()> {
+        for backend in &mut self.backends {
+            backend.health_check().await.map_err(|e| {
+                anyhow::anyhow!(
+                    "Health check failed for {} backend: {}",
+                    backend.backend_name(),
+                    e
+                )
+            })?;
+        }
+        Ok(())
+    }
+    
+    /// Create table in all backends if it doesn't exist, each with its default schema
+    pub async fn create_table_if_not_exists(&mut self, table_name: &str) -> Result<()> {
+        for index in self.available_backends().await? {
+            let schema = self.backends[index].default_table_schema(table_name);
+            let result = self.backends[index].create_table_if_not_exists(table_name, &schema).await;
+            if let Err(e) = result {
+                self.record_backend_failure(index, &e);
+                return Err(anyhow::anyhow!(
+                    "Failed to create table {} in {} backend: {}",
+                    table_name,
+                    self.backends[index].backend_name(),
+                    e
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Store endpoint data in all backends. Concurrent writes let every backend finish,
+    /// so a failing replica does not leave the primary behind; the first error is returned.
+    pub async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<ChangeCounts> {
+        let available = self.available_backends().await?;
+
+        let results: Vec<(usize, Result<ChangeCounts>)> = if self.concurrent_writes {
+            let writes = self.backends.iter_mut()
+                .enumerate()
+                .filter(|(index, _)| available.contains(index))
+                .map(|(index, backend)| async move { (index, backend.store_endpoint_data(table_name, data).await) });
+            futures::future::join_all(writes).await
+        } else {
+            let mut results = Vec::with_capacity(available.len());
+            for index in available {
+                let result = self.backends[index].store_endpoint_data(table_name, data).await;
+                let failed = result.is_err();
+                results.push((index, result));
+                if failed {
+                    break;
+                }
+            }
+            results
+        };
+
+        let mut total_stored = ChangeCounts::default();
+        let mut first_error = None;
+        for (index, result) in results {
+            let backend_name = self.backends[index].backend_name();
+            match result {
+                Ok(counts) => {
+                    log::debug!(
+                        "Stored {} items in table {} using {} backend ({} inserted, {} updated, {} skipped)",
+                        counts.stored(),
+                        table_name,
+                        backend_name,
+                        counts.inserted,
+                        counts.updated,
+                        counts.skipped
+                    );
+                    self.record_backend_success(index);
+                    total_stored = counts; // Use the counts from the last successful backend
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to store endpoint data in table {} using {} backend: {}",
+                        table_name,
+                        backend_name,
+                        e
+                    );
+                    crate::metrics::DB_ERROR_TOTAL.inc();
+                    self.record_backend_failure(index, &e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(total_stored),
+        }
+    }
+
+    /// Column types every backend uses for the table instead of inferring them
+    pub fn set_column_types(&mut self, table_name: &str, column_types: &HashMap<String, ColumnTypeOverride>) {
+        for backend in &mut self.backends {
+            backend.set_column_types(table_name, column_types);
+        }
+    }
+
+    /// Schema changes every backend applied since the last call
+    pub fn take_schema_changes(&mut self) -> Vec<SchemaChange> {
+        self.backends.iter_mut().flat_map(|backend| backend.take_schema_changes()).collect()
+    }
+
+    /// Raw JSON of a table, read from the first backend
+    pub async fn get_raw_records(&mut self, table_name: &str) -> Result<Vec<String>> {
+        let backend = &mut self.backends[0];
+        backend.get_raw_records(table_name).await.map_err(|e| anyhow::anyhow!(
+            "Failed to read raw JSON from table {} in {} backend: {}",
+            table_name,
+            backend.backend_name(),
+            e
+        ))
+    }
+
+    /// Devices matching `query` and their total count, read from the first available
+    /// backend, or from its read replica when one is configured
+    pub async fn query_devices(&mut self, table_name: &str, query: &DeviceQuery) -> Result<(Vec<serde_json::Value>, u64)> {
+        let index = *self.available_backends().await?.first()
+            .ok_or_else(|| anyhow::anyhow!("No storage backend is available to read table {}", table_name))?;
+        if let Some(replica) = self.read_replicas[index].as_mut() {
+            match replica.query_devices(table_name, query).await {
+                Ok(result) => return Ok(result),
+                Err(e) => log::warn!(
+                    "Failed to read table {} from the {} read replica, reading from the primary: {}",
+                    table_name,
+                    replica.backend_name(),
+                    e
+                ),
+            }
+        }
+        match self.backends[index].query_devices(table_name, query).await {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                self.record_backend_failure(index, &e);
+                Err(anyhow::anyhow!(
+                    "Failed to read table {} from {} backend: {}",
+                    table_name,
+                    self.backends[index].backend_name(),
+                    e
+                ))
+            }
+        }
+    }
+
+    /// Row count of a table in the first available backend
+    pub async fn get_table_count(&mut self, table_name: &str) -> Result<i64> {
+        let index = *self.available_backends().await?.first()
+            .ok_or_else(|| anyhow::anyhow!("No storage backend is available to count table {}", table_name))?;
+        self.backends[index].get_table_count(table_name).await.map_err(|e| anyhow::anyhow!(
+            "Failed to count rows of table {} in {} backend: {}",
+            table_name,
+            self.backends[index].backend_name(),
+            e
+        ))
+    }
 
-    /// Create a table if it doesn't exist with the given schema
-    async fn create_table_if_not_exists(&mut self, table_name: &str, schema: &str) -> Result<()>;
+    /// Load existing record hashes for change detection.
+    ///
+    /// A hash is only returned if every backend holds the same hash for that id,
+    /// so a record missing from (or stale in) any backend is written again. Backends
+    /// with an open circuit are left out; once one recovers, whatever it missed
+    /// differs from the others and is rewritten.
+    pub async fn get_all_hashes(&mut self, table_name: &str, column: &str) -> Result<HashMap<String, String>> {
+        let mut merged: Option<HashMap<String, String>> = None;
 
-    /// Store generic endpoint data in a specified table
-    async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<usize>;
+        for index in self.available_backends().await? {
+            let hashes = match self.backends[index].get_all_hashes(table_name, column).await {
+                Ok(hashes) => hashes,
+                Err(e) => {
+                    self.record_backend_failure(index, &e);
+                    return Err(anyhow::anyhow!(
+                        "Failed to load hashes for table {} from {} backend: {}",
+                        table_name,
+                        self.backends[index].backend_name(),
+                        e
+                    ));
+                }
+            };
 
-    /// Health check for the storage backend
-    async fn health_check(&mut self) -> Result<()>;
+            merged = Some(match merged {
+                None => hashes,
+                Some(mut existing) => {
+                    existing.retain(|id, hash| hashes.get(id) == Some(hash));
+                    existing
+                }
+            });
+        }
 
-    /// Get backend name for logging
-    fn backend_name(&self) -> &'static str;
+        Ok(merged.unwrap_or_default())
+    }
 
-    /// Clean up resources and close connections
-    async fn cleanup(&mut self) -> Result<()>;
-}
+    /// Schema changes each backend would make for the sample record
+    pub async fn preview_schema_changes(
+        &mut self,
+        table_name: &str,
+        sample: &serde_json::Value,
+    ) -> Vec<(&'static str, Result<Vec<String>>)> {
+        let mut previews = Vec::with_capacity(self.backends.len());
+        for backend in &mut self.backends {
+            let schema = backend.default_table_schema(table_name);
+            previews.push((backend.backend_name(), backend.preview_schema_changes(table_name, &schema, sample).await));
+        }
+        previews
+    }
 
-/// Storage manager that handles multiple backends
-pub struct StorageManager {
-    backends: Vec<Box<dyn StorageBackend>>,
-}
+    /// Stale rows of every available backend, sorted
+    pub async fn stale_row_ids(&mut self, table_name: &str, cutoff: chrono::DateTime<chrono::Utc>) -> Result<Vec<String>> {
+        let mut stale = std::collections::BTreeSet::new();
+        for index in self.available_backends().await? {
+            match self.backends[index].stale_row_ids(table_name, cutoff).await {
+                Ok(ids) => stale.extend(ids),
+                Err(e) => {
+                    self.record_backend_failure(index, &e);
+                    return Err(anyhow::anyhow!(
+                        "Failed to find stale rows of table {} in {} backend: {}",
+                        table_name,
+                        self.backends[index].backend_name(),
+                        e
+                    ));
+                }
+            }
+        }
+        Ok(stale.into_iter().collect())
+    }
 
-impl StorageManager {
-    /// Create a new storage manager from configuration
-    pub async fn new(config: &DatabaseConfig) -> Result<Self> {
-        let mut backends: Vec<Box<dyn StorageBackend>> = Vec::new();
+    /// Names of the rows with these ids, read from the first available backend
+    pub async fn row_names(&mut self, table_name: &str, ids: &[String], name_columns: &[String]) -> Result<HashMap<String, String>> {
+        let index = *self.available_backends().await?.first()
+            .ok_or_else(|| anyhow::anyhow!("No storage backend is available to read table {}", table_name))?;
+        let backend = &mut self.backends[index];
+        backend.row_names(table_name, ids, name_columns).await.map_err(|e| anyhow::anyhow!(
+            "Failed to read row names from table {} in {} backend: {}",
+            table_name,
+            backend.backend_name(),
+            e
+        ))
+    }
 
-        // Check SQLite backend
-        if let Some(ref sqlite_config) = config.sqlite {
-            if sqlite_config.enabled {
-                let backend = sqlite::SqliteBackend::new(&sqlite_config.database_path).await?;
-                backends.push(Box::new(backend));
+    /// Delete the rows with these ids from every available backend. Returns the most
+    /// rows any one backend deleted.
+    pub async fn delete_rows(&mut self, table_name: &str, ids: &[String]) -> Result<u64> {
+        let mut deleted = 0;
+        for index in self.available_backends().await? {
+            match self.backends[index].delete_rows(table_name, ids).await {
+                Ok(count) => deleted = deleted.max(count),
+                Err(e) => {
+                    self.record_backend_failure(index, &e);
+                    return Err(anyhow::anyhow!(
+                        "Failed to delete rows from table {} in {} backend: {}",
+                        table_name,
+                        self.backends[index].backend_name(),
+                        e
+                    ));
+                }
             }
         }
+        Ok(deleted)
+    }
 
-        // Check PostgreSQL backend
-        if let Some(ref postgres_config) = config.postgres {
-            if postgres_config.enabled {
-                let backend = postgres::PostgresBackend::new(&postgres_config.connection_string).await?;
-                backends.push(Box::new(backend));
+    /// Mark rows deleted (or clear the marks) in every available backend. Returns the
+    /// most rows any one backend changed.
+    pub async fn mark_rows_deleted(&mut self, table_name: &str, hash_column: &str, ids: &[String], deleted_at: Option<&str>) -> Result<u64> {
+        let mut marked = 0;
+        for index in self.available_backends().await? {
+            match self.backends[index].mark_rows_deleted(table_name, hash_column, ids, deleted_at).await {
+                Ok(count) => marked = marked.max(count),
+                Err(e) => {
+                    self.record_backend_failure(index, &e);
+                    return Err(anyhow::anyhow!(
+                        "Failed to mark rows deleted in table {} in {} backend: {}",
+                        table_name,
+                        self.backends[index].backend_name(),
+                        e
+                    ));
+                }
             }
         }
+        Ok(marked)
+    }
 
-        // Check MSSQL backend
-        if let Some(ref mssql_config) = config.mssql {
-            if mssql_config.enabled {
-                let backend = mssql::MssqlBackend::new(&mssql_config.connection_string).await?;
-                backends.push(Box::new(backend));
+    /// Sync progress of an endpoint as agreed on by every available backend
+    pub async fn get_sync_state(&mut self, endpoint_name: &str) -> Result<Option<SyncState>> {
+        let mut states = Vec::new();
+        for index in self.available_backends().await? {
+            match self.backends[index].get_sync_state(endpoint_name).await {
+                Ok(state) => states.push(state),
+                Err(e) => {
+                    self.record_backend_failure(index, &e);
+                    return Err(anyhow::anyhow!(
+                        "Failed to read sync state of {} from {} backend: {}",
+                        endpoint_name,
+                        self.backends[index].backend_name(),
+                        e
+                    ));
+                }
             }
         }
-        
-        if backends.is_empty() {
-            return Err(anyhow::anyhow!("No valid storage backends configured"));
+        Ok(merge_sync_states(states))
+    }
+
+    /// Save the sync progress of an endpoint in every available backend
+    pub async fn set_sync_state(&mut self, state: &SyncState) -> Result<()> {
+        for index in self.available_backends().await? {
+            if let Err(e) = self.backends[index].set_sync_state(state).await {
+                self.record_backend_failure(index, &e);
+                return Err(anyhow::anyhow!(
+                    "Failed to save sync state of {} in {} backend: {}",
+                    state.endpoint_name,
+                    self.backends[index].backend_name(),
+                    e
+                ));
+            }
         }
-        
-        Ok(Self { backends })
+        Ok(())
     }
-    
-    /// Initialize all backends
-    pub async fn initialize(&mut self) -> Result<()> {
+
+    /// Health of every backend, without stopping at the first unhealthy one
+    pub async fn backend_health(&mut self) -> Vec<(&'static str, Result<()>)> {
+        let mut results = Vec::with_capacity(self.backends.len());
         for backend in &mut self.backends {
-            log::info!("Initializing {} backend", backend.backend_name());
-            backend.initialize().await?;
+            results.push((backend.backend_name(), backend.health_check().await));
         }
-        Ok(())
+        results
     }
-    
-    /// Perform health check on all backends
-    pub async fn health_check(&mut self) -> Result<()> {
+
+    /// Health of each backend's read replica, in backend order; `None` for backends
+    /// without one
+    pub async fn read_replica_health(&mut self) -> Vec<(&'static str, Option<Result<()>>)> {
+        let mut results = Vec::with_capacity(self.read_replicas.len());
+        for (backend, replica) in self.backends.iter().zip(&mut self.read_replicas) {
+            let health = match replica {
+                Some(replica) => Some(replica.health_check().await),
+                None => None,
+            };
+            results.push((backend.backend_name(), health));
+        }
+        results
+    }
+
+    /// Row count of a table in every backend
+    pub async fn get_table_counts(&mut self, table_name: &str) -> Vec<(&'static str, Result<i64>)> {
+        let mut results = Vec::with_capacity(self.backends.len());
         for backend in &mut self.backends {
-            backend.health_check().await.map_err(|e| {
-                anyhow::anyhow!(
-                    "Health check failed for {} backend: {}",
-                    backend.backend_name(),
-                    e
-                )
-            })?;
+            results.push((backend.backend_name(), backend.get_table_count(table_name).await));
         }
-        Ok(())
+        results
     }
-    
-    /// Create table in all backends if it doesn't exist
-    pub async fn create_table_if_not_exists(&mut self, table_name: &str, schema: &str) -> Result<()> {
+
+    /// Sync state of an endpoint in every backend, unmerged
+    pub async fn get_sync_states(&mut self, endpoint_name: &str) -> Vec<(&'static str, Result<Option<SyncState>>)> {
+        let mut results = Vec::with_capacity(self.backends.len());
         for backend in &mut self.backends {
-            backend.create_table_if_not_exists(table_name, schema).await
-                .map_err(|e| anyhow::anyhow!(
-                    "Failed to create table {} in {} backend: {}",
-                    table_name,
-                    backend.backend_name(),
-                    e
-                ))?;
+            results.push((backend.backend_name(), backend.get_sync_state(endpoint_name).await));
         }
-        Ok(())
+        results
     }
 
-    /// Store endpoint data in all backends
-    pub async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<usize> {
-        let mut total_stored = 0;
+    /// Run maintenance on every backend, logging the outcome; a failing backend
+    /// does not stop the others
+    pub async fn run_maintenance(&mut self, tables: &[String]) {
+        for backend in &mut self.backends {
+            let started = std::time::Instant::now();
+            match backend.run_maintenance(tables).await {
+                Ok(Some(reclaimed)) => log::info!(
+                    "Maintenance of {} backend finished in {:?}, reclaimed {} bytes",
+                    backend.backend_name(),
+                    started.elapsed(),
+                    reclaimed
+                ),
+                Ok(None) => log::debug!("{} backend has no maintenance to run", backend.backend_name()),
+                Err(e) => log::warn!("Maintenance of {} backend failed: {}", backend.backend_name(), e),
+            }
+        }
+    }
 
+    /// Create an endpoint's configured indexes in every backend. Field names are
+    /// sanitized like columns; a failing index is logged and does not stop the others.
+    pub async fn create_indexes(&mut self, table_name: &str, indexes: &[Vec<String>]) {
         for backend in &mut self.backends {
-            match backend.store_endpoint_data(table_name, data).await {
-                Ok(count) => {
-                    log::debug!(
-                        "Stored {} items in table {} using {} backend",
-                        count,
-                        table_name,
-                        backend.backend_name()
-                    );
-                    total_stored = count; // Use the count from the last successful backend
-                }
-                Err(e) => {
-                    log::error!(
-                        "Failed to store endpoint data in table {} using {} backend: {}",
-                        table_name,
+            for index in indexes {
+                let columns: Vec<String> = index.iter().map(|field| sanitize_column_name(field)).collect();
+                if let Err(e) = backend.create_index_if_not_exists(table_name, &columns).await {
+                    log::warn!(
+                        "Failed to create index {} in {} backend: {}",
+                        index_name(table_name, &columns),
                         backend.backend_name(),
                         e
                     );
-                    crate::metrics::DB_ERROR_TOTAL.inc();
-                    return Err(e);
                 }
             }
         }
+    }
 
-        Ok(total_stored)
+    /// Checkpoint every backend before its files are copied
+    pub async fn checkpoint(&mut self) -> Result<()> {
+        for backend in &mut self.backends {
+            backend.checkpoint().await
+                .map_err(|e| anyhow::anyhow!("Failed to checkpoint {} backend: {}", backend.backend_name(), e))?;
+        }
+        Ok(())
     }
 
     /// Get list of active backend names
@@ -160,6 +1579,629 @@ impl StorageManager {
                 log::info!("Successfully cleaned up backend: {}", backend.backend_name());
             }
         }
+        for replica in self.read_replicas.iter_mut().flatten() {
+            if let Err(e) = replica.cleanup().await {
+                log::warn!("Failed to cleanup {} read replica: {}", replica.backend_name(), e);
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_type_conflicts() {
+        let existing: HashMap<String, String> = [
+            ("id", "text"),
+            ("devicename", "nvarchar"),
+            ("storage", "bigint"),
+            ("ratio", "double precision"),
+            ("enrolleddatetime", "timestamp with time zone"),
+        ].iter().map(|(c, t)| (c.to_string(), t.to_string())).collect();
+
+        let expected: HashMap<String, String> = [
+            ("devicename", "BIGINT"),           // text holds anything
+            ("storage", "TEXT"),                // was a number, now a string
+            ("ratio", "BIGINT"),                // integers fit a floating point column
+            ("enrolleddatetime", "TIMESTAMPTZ"),
+            ("newcolumn", "BOOLEAN"),           // not created yet, not a conflict
+        ].iter().map(|(c, t)| (c.to_string(), t.to_string())).collect();
+
+        let conflicts = find_type_conflicts(&existing, &expected);
+        assert_eq!(conflicts, vec![ColumnConflict {
+            column: "storage".to_string(),
+            existing_type: "bigint".to_string(),
+            expected_type: "TEXT".to_string(),
+        }]);
+        assert_eq!(conflicts[0].to_string(), "column storage is bigint but incoming values need TEXT");
+
+        assert!(!column_accepts("bit", "NVARCHAR(MAX)"));
+        assert!(column_accepts("bit", "BIT"));
+        assert!(!column_accepts("jsonb", "TEXT"));
+        assert!(conflict_backup_column_name("storage").starts_with("storage_old_"));
+    }
+
+    /// Table whose `score` column is an integer while records now send strings
+    struct ConflictingBackend {
+        columns: HashMap<String, String>,
+        column_types: ColumnTypes,
+        schema_changes: Vec<SchemaChange>,
+    }
+
+    impl ConflictingBackend {
+        fn new() -> Self {
+            Self {
+                columns: [("id", "TEXT"), ("score", "INTEGER")].iter()
+                    .map(|(c, t)| (c.to_string(), t.to_string()))
+                    .collect(),
+                column_types: ColumnTypes::default(),
+                schema_changes: Vec::new(),
+            }
+        }
+    }
+
+    impl ColumnTypeBackend for ConflictingBackend {
+        const ADD_COLUMN: &'static str = "ADD COLUMN";
+
+        fn column_types(&self) -> &ColumnTypes {
+            &self.column_types
+        }
+
+        fn inferred_column_type(&self, _: &str, value: Option<&serde_json::Value>) -> &'static str {
+            if value.is_some_and(|value| value.is_i64()) { "INTEGER" } else { "TEXT" }
+        }
+    }
+
+    #[async_trait]
+    impl SchemaConflictBackend for ConflictingBackend {
+        const BACKEND_NAME: &'static str = "Test";
+
+        async fn existing_column_types(&mut self, _: &str) -> Result<HashMap<String, String>> {
+            Ok(self.columns.clone())
+        }
+
+        async fn recreate_column(&mut self, table_name: &str, conflict: &ColumnConflict, backup: &str) -> Result<Vec<String>> {
+            let old_type = self.columns.remove(&conflict.column).unwrap();
+            self.columns.insert(backup.to_string(), old_type);
+            self.columns.insert(conflict.column.clone(), conflict.expected_type.clone());
+            Ok(vec![format!("RECREATE {}.{}", table_name, conflict.column)])
+        }
+
+        async fn retry_record(&mut self, _: &str, _: &serde_json::Value) -> Result<StorageResult> {
+            match self.columns["score"].as_str() {
+                "TEXT" => Ok(StorageResult::Inserted),
+                other => anyhow::bail!("score is still {}", other),
+            }
+        }
+
+        fn schema_changes(&mut self) -> &mut Vec<SchemaChange> {
+            &mut self.schema_changes
+        }
+    }
+
+    fn failed_records() -> Vec<serde_json::Value> {
+        vec![
+            serde_json::json!({"id": "1", "score": "high"}),
+            serde_json::json!({"id": "2", "score": "low"}),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_schema_conflict_log() {
+        let mut backend = ConflictingBackend::new();
+        let records = failed_records();
+        let failed: Vec<&serde_json::Value> = records.iter().collect();
+
+        let stored = handle_schema_conflicts(&mut backend, SchemaConflictPolicy::Log, "devices", &failed).await.unwrap();
+        assert_eq!(stored, ChangeCounts::default());
+        assert_eq!(backend.columns["score"], "INTEGER");
+        assert!(backend.schema_changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_schema_conflict_fail() {
+        let mut backend = ConflictingBackend::new();
+        let records = failed_records();
+        let failed: Vec<&serde_json::Value> = records.iter().collect();
+
+        let error = handle_schema_conflicts(&mut backend, SchemaConflictPolicy::Fail, "devices", &failed).await.unwrap_err();
+        match error.downcast_ref::<StorageError>() {
+            Some(StorageError::SchemaConflict { table, details }) => {
+                assert_eq!(table, "devices");
+                assert_eq!(details, "column score is INTEGER but incoming values need TEXT");
+            }
+            other => panic!("expected a schema conflict, got {:?}", other),
+        }
+        assert!(backend.schema_changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_schema_conflict_recreate() {
+        let mut backend = ConflictingBackend::new();
+        let records = failed_records();
+        let failed: Vec<&serde_json::Value> = records.iter().collect();
+
+        let stored = handle_schema_conflicts(&mut backend, SchemaConflictPolicy::Recreate, "devices", &failed).await.unwrap();
+        assert_eq!(stored.inserted, 2);
+        assert_eq!(backend.columns["score"], "TEXT");
+        // The old values are kept in a renamed column
+        assert!(backend.columns.iter().any(|(column, column_type)| column.starts_with("score_old_") && column_type == "INTEGER"));
+        assert_eq!(backend.schema_changes.len(), 1);
+        assert_eq!(backend.schema_changes[0].backend, "Test");
+        assert_eq!(backend.schema_changes[0].statement, "RECREATE devices.score");
+    }
+
+    #[tokio::test]
+    async fn test_schema_conflict_with_configured_type() {
+        let mut backend = ConflictingBackend::new();
+        let overrides = [("score".to_string(), ColumnTypeOverride::All("INTEGER".to_string()))].into_iter().collect();
+        backend.column_types.set("sqlite", "devices", &overrides);
+        let records = failed_records();
+        let failed: Vec<&serde_json::Value> = records.iter().collect();
+
+        // A configured type is never treated as a conflict
+        let stored = handle_schema_conflicts(&mut backend, SchemaConflictPolicy::Fail, "devices", &failed).await.unwrap();
+        assert_eq!(stored, ChangeCounts::default());
+    }
+
+    #[test]
+    fn test_column_types_merge_per_column() {
+        let mut column_types = ColumnTypes::default();
+        let devices = [
+            ("complianceGracePeriodExpirationDateTime".to_string(), ColumnTypeOverride::All("DATETIME".to_string())),
+            ("totalStorageSpaceInBytes".to_string(), ColumnTypeOverride::All("INTEGER".to_string())),
+        ].into_iter().collect();
+        let compliance = [
+            ("totalStorageSpaceInBytes".to_string(), ColumnTypeOverride::All("REAL".to_string())),
+            ("complianceState".to_string(), ColumnTypeOverride::All("TEXT".to_string())),
+        ].into_iter().collect();
+
+        // A second endpoint merged into the table keeps the first one's other columns
+        column_types.set("sqlite", "devices", &devices);
+        column_types.set("sqlite", "devices", &compliance);
+        assert_eq!(column_types.get("devices", "compliancegraceperiodexpirationdatetime"), Some("DATETIME"));
+        assert_eq!(column_types.get("devices", "totalstoragespaceinbytes"), Some("REAL"));
+        assert_eq!(column_types.get("devices", "compliancestate"), Some("TEXT"));
+        assert_eq!(column_types.get("users", "compliancestate"), None);
+    }
+
+    #[test]
+    fn test_sanitize_column_name() {
+        assert_eq!(sanitize_column_name("deviceName"), "devicename");
+        assert_eq!(sanitize_column_name("@odata.type"), "_odata_type");
+        assert_eq!(sanitize_column_name("manager@odata.navigationLink"), "manager_odata_navigationlink");
+        assert_eq!(sanitize_column_name("extension_39c8b2e1_customField"), "extension_39c8b2e1_customfield");
+        assert_eq!(sanitize_column_name("Display Name"), "display_name");
+        assert_eq!(sanitize_column_name("hardware-id"), "hardware_id");
+        assert_eq!(sanitize_column_name("2faEnabled"), "_2faenabled");
+        assert_eq!(sanitize_column_name(""), "_");
+        assert_eq!(sanitize_column_name("last_sync_date_time"), "last_sync_date_time");
+    }
+
+    #[test]
+    fn test_connect_retry_backoff() {
+        let retry = ConnectRetry { retries: 10, delay: Duration::from_secs(2) };
+        assert_eq!(retry.delay_for(1), Duration::from_secs(2));
+        assert_eq!(retry.delay_for(2), Duration::from_secs(4));
+        assert_eq!(retry.delay_for(3), Duration::from_secs(8));
+        assert_eq!(retry.delay_for(10), MAX_CONNECT_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_retry() {
+        let retry = ConnectRetry { retries: 3, delay: Duration::from_millis(1) };
+
+        // Succeeds once the "database" comes up on the third attempt
+        let mut attempts = 0;
+        let result = connect_with_retry("test", retry, || {
+            attempts += 1;
+            let current = attempts;
+            async move {
+                if current < 3 { Err(anyhow::anyhow!("connection refused")) } else { Ok(current) }
+            }
+        }).await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Gives up after the configured retries
+        let mut attempts = 0;
+        let result: Result<()> = connect_with_retry("test", retry, || {
+            attempts += 1;
+            async { Err(anyhow::anyhow!("connection refused")) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn test_column_name_mapping() {
+        let keys = vec![
+            "id".to_string(),
+            "@odata.type".to_string(),
+            "Display Name".to_string(),
+        ];
+        let mapping = column_name_mapping(&keys);
+
+        // Unchanged keys are left out of the mapping
+        assert_eq!(mapping.len(), 2);
+        assert_eq!(mapping.get("@odata.type").map(String::as_str), Some("_odata_type"));
+        assert_eq!(mapping.get("Display Name").map(String::as_str), Some("display_name"));
+    }
+
+    /// Backend that takes a fixed time to store anything and records how many stores
+    /// were running at once across every backend sharing the counters
+    struct SlowBackend {
+        delay: Duration,
+        in_flight: Arc<std::sync::atomic::AtomicUsize>,
+        max_in_flight: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl StorageBackend for SlowBackend {
+        async fn initialize(&mut self) -> Result<()> { Ok(()) }
+        fn default_table_schema(&self, _: &str) -> String { String::new() }
+        async fn create_table_if_not_exists(&mut self, _: &str, _: &str) -> Result<()> { Ok(()) }
+        async fn store_endpoint_data(&mut self, _: &str, data: &[serde_json::Value]) -> Result<ChangeCounts> {
+            use std::sync::atomic::Ordering;
+
+            let running = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(ChangeCounts { inserted: data.len(), ..Default::default() })
+        }
+        async fn get_all_hashes(&mut self, _: &str, _: &str) -> Result<HashMap<String, String>> { Ok(HashMap::new()) }
+        async fn get_table_count(&mut self, _: &str) -> Result<i64> { Ok(0) }
+        async fn get_raw_records(&mut self, _: &str) -> Result<Vec<String>> { Ok(Vec::new()) }
+        async fn query_devices(&mut self, _: &str, _: &DeviceQuery) -> Result<(Vec<serde_json::Value>, u64)> { Ok((Vec::new(), 0)) }
+        async fn preview_schema_changes(&mut self, _: &str, _: &str, _: &serde_json::Value) -> Result<Vec<String>> { Ok(Vec::new()) }
+        async fn stale_row_ids(&mut self, _: &str, _: chrono::DateTime<chrono::Utc>) -> Result<Vec<String>> { Ok(Vec::new()) }
+        async fn row_names(&mut self, _: &str, _: &[String], _: &[String]) -> Result<HashMap<String, String>> { Ok(HashMap::new()) }
+        async fn delete_rows(&mut self, _: &str, _: &[String]) -> Result<u64> { Ok(0) }
+        async fn mark_rows_deleted(&mut self, _: &str, _: &str, _: &[String], _: Option<&str>) -> Result<u64> { Ok(0) }
+        async fn get_sync_state(&mut self, _: &str) -> Result<Option<SyncState>> { Ok(None) }
+        async fn set_sync_state(&mut self, _: &SyncState) -> Result<()> { Ok(()) }
+        async fn health_check(&mut self) -> Result<()> { Ok(()) }
+        fn backend_name(&self) -> &'static str { "Slow" }
+        async fn cleanup(&mut self) -> Result<()> { Ok(()) }
+    }
+
+    /// Backend whose every call succeeds or fails depending on a shared switch
+    struct FlakyBackend {
+        up: Arc<std::sync::atomic::AtomicBool>,
+        stored: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl FlakyBackend {
+        fn check(&self) -> Result<()> {
+            if self.up.load(std::sync::atomic::Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("connection refused"))
+            }
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for FlakyBackend {
+        async fn initialize(&mut self) -> Result<()> { self.check() }
+        fn default_table_schema(&self, _: &str) -> String { String::new() }
+        async fn create_table_if_not_exists(&mut self, _: &str, _: &str) -> Result<()> { self.check() }
+        async fn store_endpoint_data(&mut self, _: &str, data: &[serde_json::Value]) -> Result<ChangeCounts> {
+            self.check()?;
+            self.stored.fetch_add(data.len(), std::sync::atomic::Ordering::SeqCst);
+            Ok(ChangeCounts { inserted: data.len(), ..Default::default() })
+        }
+        async fn get_all_hashes(&mut self, _: &str, _: &str) -> Result<HashMap<String, String>> {
+            self.check().map(|_| HashMap::new())
+        }
+        async fn get_table_count(&mut self, _: &str) -> Result<i64> { self.check().map(|_| 0) }
+        async fn get_raw_records(&mut self, _: &str) -> Result<Vec<String>> { self.check().map(|_| Vec::new()) }
+        async fn query_devices(&mut self, _: &str, _: &DeviceQuery) -> Result<(Vec<serde_json::Value>, u64)> { self.check().map(|_| (Vec::new(), 0)) }
+        async fn preview_schema_changes(&mut self, _: &str, _: &str, _: &serde_json::Value) -> Result<Vec<String>> {
+            self.check().map(|_| Vec::new())
+        }
+        async fn stale_row_ids(&mut self, _: &str, _: chrono::DateTime<chrono::Utc>) -> Result<Vec<String>> { self.check().map(|_| Vec::new()) }
+        async fn row_names(&mut self, _: &str, _: &[String], _: &[String]) -> Result<HashMap<String, String>> {
+            self.check().map(|_| HashMap::new())
+        }
+        async fn delete_rows(&mut self, _: &str, _: &[String]) -> Result<u64> { self.check().map(|_| 0) }
+        async fn mark_rows_deleted(&mut self, _: &str, _: &str, _: &[String], _: Option<&str>) -> Result<u64> { self.check().map(|_| 0) }
+        async fn get_sync_state(&mut self, _: &str) -> Result<Option<SyncState>> { self.check().map(|_| None) }
+        async fn set_sync_state(&mut self, _: &SyncState) -> Result<()> { self.check() }
+        async fn health_check(&mut self) -> Result<()> { self.check() }
+        fn backend_name(&self) -> &'static str { "Flaky" }
+        async fn cleanup(&mut self) -> Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_missing_columns() {
+        let sample = serde_json::json!({"id": "1", "deviceName": "PC-01", "@odata.type": "#device"});
+        let existing: HashSet<String> = ["ID", "devicename"].iter().map(|c| c.to_string()).collect();
+
+        let missing: Vec<String> = missing_columns(&existing, sample.as_object().unwrap())
+            .into_iter()
+            .map(|(column, _)| column)
+            .collect();
+        assert_eq!(missing, vec!["_odata_type", "last_sync_date_time", "synced_at"]);
+    }
+
+    #[test]
+    fn test_schema_sample() {
+        let records = vec![
+            serde_json::json!({"id": "1", "ownerType": null, "storage": 64, "note": 3, "enrolled": "2024-05-01T10:00:00Z"}),
+            serde_json::json!({"id": "2", "ownerType": "company", "storage": 64.5, "note": "spare", "enrolled": "unknown"}),
+            serde_json::json!({"id": "3", "ownerType": "personal", "storage": 128, "note": true, "extra": [1]}),
+        ];
+        assert_eq!(schema_sample(&records, 3), serde_json::json!({
+            "id": "1",
+            "ownerType": "company",
+            "storage": 64.5,
+            "note": "",
+            "enrolled": "unknown",
+            "extra": [1],
+        }));
+
+        // Only the first record, as inference used to work
+        assert_eq!(schema_sample(&records, 0), records[0]);
+    }
+
+    #[test]
+    fn test_fit_to_existing_columns() {
+        let existing: HashSet<String> = ["id", "last_sync_date_time", "synced_at", "DeviceName"].iter().map(|c| c.to_string()).collect();
+        let data = vec![serde_json::json!({"id": "1", "deviceName": "PC-01", "model": "Surface"})];
+
+        let fitted = fit_to_existing_columns("devices", &existing, &data, MissingColumnPolicy::Drop).unwrap();
+        assert_eq!(fitted, vec![serde_json::json!({"id": "1", "deviceName": "PC-01"})]);
+
+        let err = fit_to_existing_columns("devices", &existing, &data, MissingColumnPolicy::Fail).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::MissingColumns { columns, .. }) if columns == "model"
+        ));
+
+        // Columns written with every record cannot be dropped
+        let without_sync_time: HashSet<String> = ["id", "devicename"].iter().map(|c| c.to_string()).collect();
+        assert!(fit_to_existing_columns("devices", &without_sync_time, &data, MissingColumnPolicy::Drop).is_err());
+
+        // Tables from before synced_at are written without it
+        let without_synced_at: HashSet<String> = ["id", "last_sync_date_time", "devicename", "model"].iter().map(|c| c.to_string()).collect();
+        assert_eq!(fit_to_existing_columns("devices", &without_synced_at, &data, MissingColumnPolicy::Fail).unwrap(), data);
+        assert!(!has_column(&without_synced_at, SYNCED_AT_COLUMN));
+        assert!(has_column(&existing, "devicename"));
+
+        assert!(matches!(
+            fit_to_existing_columns("devices", &HashSet::new(), &data, MissingColumnPolicy::Drop).unwrap_err().downcast_ref::<StorageError>(),
+            Some(StorageError::TableMissing { .. })
+        ));
+    }
+
+    #[test]
+    fn test_schema_columns() {
+        let columns = schema_columns(
+            "CREATE TABLE IF NOT EXISTS devices (
+                id TEXT PRIMARY KEY,
+                amount DECIMAL(10,2),
+                [Data] TEXT,
+                PRIMARY KEY (id)
+            )",
+        );
+        let mut columns: Vec<String> = columns.into_iter().collect();
+        columns.sort();
+        assert_eq!(columns, vec!["amount", "data", "id"]);
+        assert!(schema_columns("DROP TABLE devices").is_empty());
+
+        let guarded = schema_columns(
+            "IF OBJECT_ID(N'devices', N'U') IS NULL
+             CREATE TABLE devices (id NVARCHAR(450) NOT NULL PRIMARY KEY, data NVARCHAR(MAX))",
+        );
+        assert_eq!(guarded, HashSet::from(["id".to_string(), "data".to_string()]));
+    }
+
+    #[test]
+    fn test_index_name() {
+        let columns = vec!["compliancestate".to_string(), "manufacturer".to_string()];
+        assert_eq!(index_name("devices", &columns), "idx_devices_compliancestate_manufacturer");
+
+        let long_table = "a".repeat(60);
+        let long = index_name(&long_table, &columns);
+        assert_eq!(long.len(), 63);
+        assert_ne!(long, index_name(&long_table, &columns[..1]));
+    }
+
+    #[test]
+    fn test_valid_column_type() {
+        assert!(valid_column_type("postgres", "timestamptz"));
+        assert!(valid_column_type("postgres", "double  precision"));
+        assert!(valid_column_type("postgres", "NUMERIC(10, 2)"));
+        assert!(valid_column_type("mssql", "NVARCHAR(MAX)"));
+        assert!(valid_column_type("sqlite", "TEXT"));
+
+        assert!(!valid_column_type("sqlite", "TIMESTAMPTZ"));
+        assert!(!valid_column_type("mssql", "JSONB"));
+        assert!(!valid_column_type("postgres", "TEXT; DROP TABLE devices"));
+        assert!(!valid_column_type("postgres", "VARCHAR(64"));
+        assert!(!valid_column_type("postgres", "VARCHAR(x)"));
+        assert!(!valid_column_type("oracle", "TEXT"));
+    }
+
+    #[test]
+    fn test_merge_sync_states() {
+        let state = SyncState {
+            endpoint_name: "users".to_string(),
+            delta_link: Some("https://graph.microsoft.com/v1.0/users/delta?$deltatoken=abc".to_string()),
+            watermark: Some("2024-05-01T10:00:00+00:00".to_string()),
+            updated_at: Some(chrono::Utc::now()),
+            ..Default::default()
+        };
+
+        assert_eq!(merge_sync_states(vec![]), None);
+        assert_eq!(merge_sync_states(vec![Some(state.clone())]), Some(state.clone()));
+        assert_eq!(merge_sync_states(vec![Some(state.clone()), Some(state.clone())]), Some(state.clone()));
+
+        // A backend without state means starting over
+        assert_eq!(merge_sync_states(vec![Some(state.clone()), None]), None);
+
+        // Only the fields both backends agree on survive
+        let behind = SyncState {
+            watermark: Some("2024-04-01T10:00:00+00:00".to_string()),
+            ..state.clone()
+        };
+        let merged = merge_sync_states(vec![Some(state.clone()), Some(behind)]).unwrap();
+        assert_eq!(merged.watermark, None);
+        assert_eq!(merged.delta_link, state.delta_link);
+
+        let failed = SyncState {
+            last_error: Some("403 Forbidden".to_string()),
+            last_error_at: Some(chrono::Utc::now()),
+            ..state.clone()
+        };
+        let merged = merge_sync_states(vec![Some(failed.clone()), Some(state.clone())]).unwrap();
+        assert_eq!(merged.last_error, failed.last_error);
+        assert_eq!(merged.last_error_at, failed.last_error_at);
+        // A replica's failure does not override a primary that has none
+        let merged = merge_sync_states(vec![Some(state.clone()), Some(failed.clone())]).unwrap();
+        assert_eq!(merged.last_error, None);
+        assert_eq!(merged.last_error_at, None);
+
+        // The older full sync wins, a backend that never had one forces the next
+        let full = SyncState { last_full_sync_at: Some(chrono::Utc::now()), ..state.clone() };
+        assert_eq!(merge_sync_states(vec![Some(full.clone()), Some(full.clone())]).unwrap().last_full_sync_at, full.last_full_sync_at);
+        assert_eq!(merge_sync_states(vec![Some(full), Some(state.clone())]).unwrap().last_full_sync_at, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_backend_writes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let data = vec![serde_json::json!({"id": "1"})];
+
+        // Concurrent writes have both backends storing at once, sequential ones never do
+        for (concurrent, expected_max_in_flight) in [(true, 2), (false, 1)] {
+            let in_flight = Arc::new(AtomicUsize::new(0));
+            let max_in_flight = Arc::new(AtomicUsize::new(0));
+            let backends: Vec<Box<dyn StorageBackend>> = (0..2)
+                .map(|_| -> Box<dyn StorageBackend> {
+                    Box::new(SlowBackend {
+                        delay: Duration::from_millis(200),
+                        in_flight: in_flight.clone(),
+                        max_in_flight: max_in_flight.clone(),
+                    })
+                })
+                .collect();
+            let mut storage = StorageManager::from_backends(backends, CircuitBreakerConfig::default())
+                .with_concurrent_writes(concurrent);
+
+            assert_eq!(storage.store_endpoint_data("devices", &data).await.unwrap().stored(), 1);
+            assert_eq!(max_in_flight.load(Ordering::SeqCst), expected_max_in_flight);
+            assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writes_reach_every_backend() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        // A failing first backend stops sequential writes, but not concurrent ones
+        for (concurrent, replica_writes) in [(true, 1), (false, 0)] {
+            let replica_stored = Arc::new(AtomicUsize::new(0));
+            let backends: Vec<Box<dyn StorageBackend>> = vec![
+                Box::new(FlakyBackend { up: Arc::new(AtomicBool::new(false)), stored: Arc::new(AtomicUsize::new(0)) }),
+                Box::new(FlakyBackend { up: Arc::new(AtomicBool::new(true)), stored: replica_stored.clone() }),
+            ];
+            let mut storage = StorageManager::from_backends(backends, CircuitBreakerConfig::default())
+                .with_concurrent_writes(concurrent);
+
+            assert!(storage.store_endpoint_data("devices", &[serde_json::json!({"id": "1"})]).await.is_err());
+            assert_eq!(replica_stored.load(Ordering::SeqCst), replica_writes);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_circuit_breaker() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        let primary_stored = Arc::new(AtomicUsize::new(0));
+        let replica_up = Arc::new(AtomicBool::new(false));
+        let replica_stored = Arc::new(AtomicUsize::new(0));
+        let backends: Vec<Box<dyn StorageBackend>> = vec![
+            Box::new(FlakyBackend { up: Arc::new(AtomicBool::new(true)), stored: primary_stored.clone() }),
+            Box::new(FlakyBackend { up: replica_up.clone(), stored: replica_stored.clone() }),
+        ];
+        let config = CircuitBreakerConfig { max_consecutive_failures: 2, ..Default::default() };
+        let mut storage = StorageManager::from_backends(backends, config);
+        let data = vec![serde_json::json!({"id": "1"})];
+
+        // The replica fails writes until its circuit opens
+        assert!(storage.store_endpoint_data("devices", &data).await.is_err());
+        assert!(storage.store_endpoint_data("devices", &data).await.is_err());
+        assert_eq!(storage.breakers[1].state(chrono::Utc::now()), CircuitState::Open);
+
+        // Then writes go to the primary only
+        assert_eq!(storage.store_endpoint_data("devices", &data).await.unwrap().stored(), 1);
+        assert_eq!(primary_stored.load(Ordering::SeqCst), 3);
+        assert_eq!(replica_stored.load(Ordering::SeqCst), 0);
+
+        // Once the cooldown is over a passing health check closes the circuit
+        replica_up.store(true, Ordering::SeqCst);
+        storage.breakers[1] = {
+            let mut breaker = CircuitBreaker::default();
+            let past = chrono::Utc::now() - chrono::Duration::hours(1);
+            breaker.record_failure(&CircuitBreakerConfig { max_consecutive_failures: 1, cooldown_seconds: 1, ..Default::default() }, past);
+            breaker
+        };
+        assert_eq!(storage.store_endpoint_data("devices", &data).await.unwrap().stored(), 1);
+        assert_eq!(replica_stored.load(Ordering::SeqCst), 1);
+        assert_eq!(storage.breakers[1].state(chrono::Utc::now()), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_get_table_count_per_table() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let backend = sqlite::SqliteBackend::new(file.path().to_str().unwrap(), ConnectRetry::default()).await.unwrap();
+        let mut storage = StorageManager::from_backends(vec![Box::new(backend)], CircuitBreakerConfig::default());
+        for (table, rows) in [("devices", 3), ("users", 5)] {
+            storage.create_table_if_not_exists(table).await.unwrap();
+            let data: Vec<_> = (0..rows).map(|i| serde_json::json!({"id": format!("{}-{}", table, i)})).collect();
+            storage.store_endpoint_data(table, &data).await.unwrap();
+        }
+        assert_eq!(storage.get_table_count("devices").await.unwrap(), 3);
+        assert_eq!(storage.get_table_count("users").await.unwrap(), 5);
+
+        // Names that are not plain identifiers never reach the database
+        for table in ["users; DROP TABLE devices", "1users", "dbo.users", "user-groups", ""] {
+            let err = storage.get_table_count(table).await.unwrap_err();
+            assert!(err.to_string().contains("not a valid table name"), "{}", err);
+        }
+        assert!(is_valid_table_name("_device_configurations2"));
+        assert_eq!(storage.get_table_count("devices").await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_query_devices_reads_from_replica() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+        async fn sqlite_with_devices(file: &tempfile::NamedTempFile, count: usize) -> Box<dyn StorageBackend> {
+            let mut backend = sqlite::SqliteBackend::new(file.path().to_str().unwrap(), ConnectRetry::default()).await.unwrap();
+            backend.initialize().await.unwrap();
+            let schema = backend.default_table_schema("devices");
+            backend.create_table_if_not_exists("devices", &schema).await.unwrap();
+            let devices: Vec<serde_json::Value> = (0..count).map(|i| serde_json::json!({"id": i.to_string()})).collect();
+            backend.store_endpoint_data("devices", &devices).await.unwrap();
+            Box::new(backend)
+        }
+
+        let (primary_file, replica_file) = (tempfile::NamedTempFile::new().unwrap(), tempfile::NamedTempFile::new().unwrap());
+        let mut storage = StorageManager::from_backends(vec![sqlite_with_devices(&primary_file, 1).await], CircuitBreakerConfig::default());
+        storage.read_replicas[0] = Some(sqlite_with_devices(&replica_file, 2).await);
+        assert_eq!(storage.query_devices("devices", &DeviceQuery::new()).await.unwrap().1, 2);
+        assert!(storage.read_replica_health().await[0].1.as_ref().unwrap().is_ok());
+
+        // A failing replica falls back to the primary
+        storage.read_replicas[0] = Some(Box::new(FlakyBackend { up: Arc::new(AtomicBool::new(false)), stored: Arc::new(AtomicUsize::new(0)) }));
+        assert_eq!(storage.query_devices("devices", &DeviceQuery::new()).await.unwrap().1, 1);
+        assert!(storage.read_replica_health().await[0].1.as_ref().unwrap().is_err());
+    }
+}
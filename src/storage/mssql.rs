@@ -1,25 +1,112 @@
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use tiberius::{Client, Config, Row};
+use tiberius::{Client, Config, EncryptionLevel, Row};
 use tokio::net::TcpStream;
 use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
 use std::collections::{HashMap, HashSet};
 use chrono::{TimeZone, Utc};
 
-use super::StorageBackend;
+use futures::TryStreamExt;
+
+use super::{connect_with_retry, ChangeCounts, ColumnConflict, ColumnTypeBackend, ColumnTypes, ConnectRetry, DeviceQuery, DEFAULT_SCHEMA_SAMPLE_SIZE, SchemaChange, SchemaConflictBackend, StorageBackend, StorageResult, SyncState, DELETED_AT_COLUMN, ID_BATCH_SIZE, NEVER_CHECKED_IN, RAW_JSON_COLUMN, SCHEMA_CONFLICT_THRESHOLD, SYNC_STATE_TABLE};
+use crate::config::{MissingColumnPolicy, SchemaConflictPolicy, TlsConfig, TlsMode};
+use crate::endpoint::ColumnTypeOverride;
 
 pub struct MssqlBackend {
     client: Client<Compat<TcpStream>>,
+    schema_conflict: SchemaConflictPolicy,
+    auto_migrate: bool,
+    on_missing_column: MissingColumnPolicy,
+    /// Whether the table being written has a `synced_at` column; tables created before
+    /// it existed may lack it while `autoMigrate` is off
+    write_synced_at: bool,
+    schema_sample_size: usize,
+    schema_changes: Vec<SchemaChange>,
+    column_types: ColumnTypes,
 }
 
 impl MssqlBackend {
-    pub async fn new(connection_string: &str) -> Result<Self> {
+    /// Connect (creating the database if needed), retrying while the server is unreachable
+    pub async fn new(connection_string: &str, tls: Option<&TlsConfig>, retry: ConnectRetry) -> Result<Self> {
+        let config = Self::parse_config(connection_string, tls)?;
+        let db_name = Self::extract_database_name(connection_string);
+
+        connect_with_retry("MSSQL", retry, || Self::connect_or_create(&config, db_name.as_deref())).await
+    }
+
+    /// Connect to a database that must already exist, for commands that only read
+    pub async fn connect_existing(connection_string: &str, tls: Option<&TlsConfig>, retry: ConnectRetry) -> Result<Self> {
+        let config = Self::parse_config(connection_string, tls)?;
+
+        connect_with_retry("MSSQL", retry, || async {
+            let client = Self::connect_with_config(&config).await?;
+            log::info!("Connected to MSSQL database successfully");
+            Ok(Self::from_client(client))
+        }).await
+    }
+
+    fn parse_config(connection_string: &str, tls: Option<&TlsConfig>) -> Result<Config> {
+        // Settings of the tls block replace the string's own, so they cannot conflict
+        let settings = match tls {
+            Some(tls) => Self::strip_tls_settings(connection_string, tls),
+            None => connection_string.to_string(),
+        };
+
         // Parse connection string using tiberius Config
-        let config = Config::from_ado_string(connection_string)
-            .with_context(|| format!("Failed to parse MSSQL connection string: {}", connection_string))?;
+        let mut config = if settings.trim_start().to_lowercase().starts_with("jdbc:") {
+            Config::from_jdbc_string(&settings)
+        } else {
+            Config::from_ado_string(&settings)
+        }
+        .context("Failed to parse MSSQL connection string")?;
+
+        if let Some(tls) = tls {
+            Self::apply_tls(&mut config, tls)?;
+        }
+        Ok(config)
+    }
+
+    /// Remove the `Encrypt` token from a connection string when the tls block sets a mode,
+    /// and the `TrustServerCertificate` ones when it sets how the certificate is trusted
+    fn strip_tls_settings(connection_string: &str, tls: &TlsConfig) -> String {
+        let sets_trust = tls.trust_server_certificate || tls.ca_cert_path.is_some();
+        connection_string
+            .split(';')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or("").trim().to_lowercase();
+                match key.as_str() {
+                    "encrypt" => tls.mode.is_none(),
+                    "trustservercertificate" | "trustservercertificateca" => !sets_trust,
+                    _ => true,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Set encryption level and certificate trust from the tls block
+    fn apply_tls(config: &mut Config, tls: &TlsConfig) -> Result<()> {
+        match tls.mode {
+            None => {}
+            Some(TlsMode::Disable) => config.encryption(EncryptionLevel::NotSupported),
+            // TDS has no level that falls back to plaintext without failing against servers
+            // that cannot encrypt, so nothing would honor prefer
+            Some(TlsMode::Prefer) => anyhow::bail!("tls mode prefer is not supported on MSSQL - use require or disable"),
+            Some(TlsMode::Require | TlsMode::VerifyCa | TlsMode::VerifyFull) => config.encryption(EncryptionLevel::Required),
+        }
+
+        // tiberius panics if both are set; the validator rejects that combination
+        if tls.trust_server_certificate {
+            config.trust_cert();
+        } else if let Some(ref ca_cert_path) = tls.ca_cert_path {
+            config.trust_cert_ca(ca_cert_path);
+        }
+        Ok(())
+    }
 
+    async fn connect_or_create(config: &Config, db_name: Option<&str>) -> Result<Self> {
         // Try to connect to the specified database
-        let client = match Self::connect_with_config(&config).await {
+        let client = match Self::connect_with_config(config).await {
             Ok(client) => {
                 log::info!("Connected to MSSQL database successfully");
                 client
@@ -27,13 +114,13 @@ impl MssqlBackend {
             Err(e) => {
                 log::warn!("Failed to connect to MSSQL database: {}", e);
 
-                // Try to extract database name and create it if it doesn't exist
-                if let Some(db_name) = Self::extract_database_name(&config) {
+                // Create the database named in the connection string if it doesn't exist
+                if let Some(db_name) = db_name {
                     log::info!("Attempting to create database: {}", db_name);
-                    Self::create_database_if_not_exists(&config, &db_name).await?;
+                    Self::create_database_if_not_exists(config, db_name).await?;
 
                     // Retry connection
-                    Self::connect_with_config(&config).await
+                    Self::connect_with_config(config).await
                         .context("Failed to connect to MSSQL after creating database")?
                 } else {
                     return Err(e);
@@ -41,9 +128,40 @@ impl MssqlBackend {
             }
         };
 
-        Ok(Self {
+        Ok(Self::from_client(client))
+    }
+
+    fn from_client(client: Client<Compat<TcpStream>>) -> Self {
+        Self {
             client,
-        })
+            schema_conflict: SchemaConflictPolicy::default(),
+            auto_migrate: true,
+            on_missing_column: MissingColumnPolicy::default(),
+            write_synced_at: true,
+            schema_sample_size: DEFAULT_SCHEMA_SAMPLE_SIZE,
+            schema_changes: Vec::new(),
+            column_types: ColumnTypes::default(),
+        }
+    }
+
+    /// How to react when inserts fail because a column's type no longer fits the data
+    pub fn with_schema_conflict_policy(mut self, policy: SchemaConflictPolicy) -> Self {
+        self.schema_conflict = policy;
+        self
+    }
+
+    /// Whether schema evolution may add columns; when off, records are fitted to the
+    /// existing columns according to `on_missing_column`
+    pub fn with_auto_migrate(mut self, auto_migrate: bool, on_missing_column: MissingColumnPolicy) -> Self {
+        self.auto_migrate = auto_migrate;
+        self.on_missing_column = on_missing_column;
+        self
+    }
+
+    /// Records sampled to pick the type of each new column
+    pub fn with_schema_sample_size(mut self, schema_sample_size: usize) -> Self {
+        self.schema_sample_size = schema_sample_size;
+        self
     }
 
     async fn connect_with_config(config: &Config) -> Result<Client<Compat<TcpStream>>> {
@@ -59,11 +177,29 @@ impl MssqlBackend {
         Ok(client)
     }
 
-    fn extract_database_name(_config: &Config) -> Option<String> {
-        // Extract database name from tiberius Config
-        // This is a simplified approach - in practice you might need to access private fields
-        // For now, we'll return None and rely on manual database creation
-        None
+    /// Database name from the `Database=` / `Initial Catalog=` token of an ADO connection
+    /// string, or `databaseName=` of a JDBC one. Keys are case-insensitive and values may
+    /// be wrapped in quotes or braces.
+    fn extract_database_name(connection_string: &str) -> Option<String> {
+        let settings = connection_string
+            .trim()
+            .strip_prefix("jdbc:sqlserver://")
+            .unwrap_or(connection_string);
+
+        settings
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| {
+                let key = key.trim().to_lowercase();
+                key == "database" || key == "initial catalog" || key == "databasename"
+            })
+            .map(|(_, value)| {
+                value
+                    .trim()
+                    .trim_matches(|c| c == '{' || c == '}' || c == '\'' || c == '"')
+                    .to_string()
+            })
+            .filter(|name| !name.is_empty())
     }
 
     async fn create_database_if_not_exists(config: &Config, db_name: &str) -> Result<()> {
@@ -73,7 +209,11 @@ impl MssqlBackend {
 
         match Self::connect_with_config(&master_config).await {
             Ok(mut client) => {
-                let create_db_sql = format!("IF NOT EXISTS (SELECT name FROM sys.databases WHERE name = '{}') CREATE DATABASE [{}]", db_name, db_name);
+                let create_db_sql = format!(
+                    "IF NOT EXISTS (SELECT name FROM sys.databases WHERE name = '{}') CREATE DATABASE [{}]",
+                    db_name.replace('\'', "''"),
+                    db_name.replace(']', "]]")
+                );
                 match client.simple_query(&create_db_sql).await {
                     Ok(_) => log::info!("Created or verified database: {}", db_name),
                     Err(e) => {
@@ -113,7 +253,12 @@ impl MssqlBackend {
                     }
                 };
 
-                record.insert(key.clone(), string_value);
+                let column = super::sanitize_column_name(key);
+                if record.contains_key(&column) {
+                    log::debug!("Skipping key {} - column {} is already populated by another key", key, column);
+                    continue;
+                }
+                record.insert(column, string_value);
             }
         }
 
@@ -123,16 +268,50 @@ impl MssqlBackend {
             record.insert("id".to_string(), uuid::Uuid::new_v4().to_string());
         }
 
-        if !record.contains_key("last_sync_date_time") {
-            record.insert("last_sync_date_time".to_string(), chrono::Utc::now().to_rfc3339());
-        }
+        super::add_sync_timestamps(&mut record, self.write_synced_at);
 
         Ok(record)
     }
 
     async fn create_tables(&mut self) -> Result<()> {
-        // No default tables are created - tables are created dynamically via create_table_if_not_exists
-        log::info!("MSSQL backend initialized - tables will be created dynamically");
+        // Endpoint tables are created dynamically via create_table_if_not_exists;
+        // only the sync progress table is fixed
+        let sql = format!(
+            "IF OBJECT_ID(N'{0}', N'U') IS NULL
+             CREATE TABLE {0} (
+                endpoint_name NVARCHAR(255) NOT NULL PRIMARY KEY,
+                delta_link NVARCHAR(MAX),
+                watermark NVARCHAR(64),
+                etag NVARCHAR(512),
+                updated_at DATETIMEOFFSET,
+                last_error NVARCHAR(MAX),
+                last_error_at DATETIMEOFFSET,
+                last_full_sync_at DATETIMEOFFSET
+             )",
+            SYNC_STATE_TABLE
+        );
+        self.client.simple_query(sql).await?.into_results().await
+            .context("Failed to create sync state table")?;
+
+        // Tables created before the last error was tracked
+        let sql = format!(
+            "IF COL_LENGTH(N'{0}', N'last_error') IS NULL
+             ALTER TABLE {0} ADD last_error NVARCHAR(MAX), last_error_at DATETIMEOFFSET",
+            SYNC_STATE_TABLE
+        );
+        self.client.simple_query(sql).await?.into_results().await
+            .context("Failed to add last error to sync state table")?;
+
+        // Tables created before the last full sync was tracked
+        let sql = format!(
+            "IF COL_LENGTH(N'{0}', N'last_full_sync_at') IS NULL
+             ALTER TABLE {0} ADD last_full_sync_at DATETIMEOFFSET",
+            SYNC_STATE_TABLE
+        );
+        self.client.simple_query(sql).await?.into_results().await
+            .context("Failed to add last full sync to sync state table")?;
+
+        log::info!("MSSQL backend initialized - endpoint tables will be created dynamically");
         Ok(())
     }
 
@@ -235,43 +414,109 @@ impl MssqlBackend {
         Ok(columns)
     }
 
+    /// Existing column name (lowercased) -> data type
+    async fn get_column_types(&mut self, table_name: &str) -> Result<HashMap<String, String>> {
+        let query = format!(
+            "SELECT COLUMN_NAME, DATA_TYPE FROM INFORMATION_SCHEMA.COLUMNS WHERE TABLE_NAME = '{}'",
+            table_name
+        );
+
+        let stream = self.client.simple_query(&query).await?;
+        let rows = stream.into_first_result().await?;
+
+        Ok(rows.iter()
+            .filter_map(|row| Some((row.get::<&str, _>(0)?.to_lowercase(), row.get::<&str, _>(1)?.to_string())))
+            .collect())
+    }
+
+    /// Fail for an `NVARCHAR(MAX)` column, which SQL Server cannot use as an index key.
+    /// Narrowing it would cut off or reject longer values, so that is left to the user.
+    async fn check_index_key_column(&mut self, table_name: &str, column: &str) -> Result<()> {
+        let mut query = tiberius::Query::new(
+            "SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS
+             WHERE TABLE_NAME = @P1 AND LOWER(COLUMN_NAME) = @P2
+               AND DATA_TYPE IN ('nvarchar', 'varchar') AND CHARACTER_MAXIMUM_LENGTH = -1",
+        );
+        query.bind(table_name);
+        query.bind(column);
+        let row = query.query(&mut self.client).await?.into_row().await?;
+        let Some(column) = row.and_then(|row| row.get::<&str, _>(0).map(str::to_string)) else {
+            return Ok(());
+        };
+
+        Err(anyhow::anyhow!(
+            "column {} of {} is NVARCHAR(MAX), which SQL Server cannot index - give it a type such as NVARCHAR(450) \
+             with columnTypes, or narrow it after checking its values: ALTER TABLE {} ALTER COLUMN {} NVARCHAR(450) NULL",
+            column, table_name, table_name, column
+        ))
+    }
+
+    /// Upsert a single record
+    async fn upsert_record(&mut self, table_name: &str, item: &serde_json::Value) -> Result<StorageResult> {
+        // Convert JSON to a generic record format
+        let record = self.json_to_generic_record(item)?;
+
+        let field_names: Vec<String> = record.keys().cloned().collect();
+        let placeholders: Vec<String> = (1..=field_names.len())
+            .map(|i| format!("@P{}", i))
+            .collect();
+
+        // MERGE on id updates an existing row and inserts a new one, reporting which in
+        // $action. Only the record's columns are set, so columns another endpoint merged in are kept.
+        let updates: Vec<String> = field_names.iter()
+            .filter(|field| *field != "id")
+            .map(|field| format!("{} = source.{}", field, field))
+            .collect();
+        let when_matched = if updates.is_empty() {
+            String::new()
+        } else {
+            format!("WHEN MATCHED THEN UPDATE SET {}", updates.join(", "))
+        };
+        let sql = format!(
+            "MERGE {} WITH (HOLDLOCK) AS target
+             USING (VALUES ({})) AS source ({})
+             ON target.id = source.id
+             {}
+             WHEN NOT MATCHED THEN INSERT ({}) VALUES ({})
+             OUTPUT $action;",
+            table_name,
+            placeholders.join(", "),
+            field_names.join(", "),
+            when_matched,
+            field_names.join(", "),
+            field_names.iter().map(|field| format!("source.{}", field)).collect::<Vec<_>>().join(", ")
+        );
+
+        let mut query = tiberius::Query::new(sql);
+        for field in &field_names {
+            query.bind(record.get(field).unwrap().as_str());
+        }
+
+        let row = query.query(&mut self.client).await?.into_row().await?;
+        // A record with only an id has nothing to update
+        Ok(match row.as_ref().and_then(|row| row.get::<&str, _>(0)) {
+            Some("INSERT") => StorageResult::Inserted,
+            Some("UPDATE") => StorageResult::Updated,
+            _ => StorageResult::Skipped,
+        })
+    }
+
     /// Ensure the table schema matches the data structure by analyzing the JSON object
     async fn ensure_table_schema_matches(&mut self, table_name: &str, sample_data: &serde_json::Value) -> Result<()> {
-        if let Some(obj) = sample_data.as_object() {
-            // Get current table schema
-            let existing_columns = self.get_table_columns(table_name).await?;
-
-            // Determine required columns from the sample data
-            let mut required_columns = HashSet::new();
-            for key in obj.keys() {
-                required_columns.insert(key.clone());
-            }
+        let existing_columns = self.get_table_columns(table_name).await?;
 
-            // Add standard columns
-            required_columns.insert("id".to_string());
-            required_columns.insert("last_sync_date_time".to_string());
-
-            // Find missing columns
-            let missing_columns: Vec<String> = required_columns
-                .difference(&existing_columns)
-                .cloned()
-                .collect();
-
-            // Add missing columns
-            for column in missing_columns {
-                let column_type = self.determine_column_type_by_name(&column, obj.get(&column));
-                let alter_sql = format!(
-                    "ALTER TABLE {} ADD {} {}",
-                    table_name, column, column_type
-                );
-
-                match self.client.simple_query(&alter_sql).await {
-                    Ok(_) => {
-                        log::info!("Added column {} ({}) to table {}", column, column_type, table_name);
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to add column {} to table {}: {}", column, table_name, e);
-                    }
+        for addition in super::column_additions(self, table_name, &existing_columns, sample_data) {
+            match self.client.simple_query(&addition.sql).await {
+                Ok(_) => {
+                    log::info!("Added column {} ({}) to table {}", addition.column, addition.column_type, table_name);
+                    self.schema_changes.push(SchemaChange {
+                        backend: "MSSQL",
+                        table: table_name.to_string(),
+                        statement: addition.sql,
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Failed to add column {} to table {}: {}", addition.column, table_name, e);
                 }
             }
         }
@@ -289,6 +534,48 @@ impl MssqlBackend {
     }
 }
 
+impl ColumnTypeBackend for MssqlBackend {
+    const ADD_COLUMN: &'static str = "ADD";
+
+    fn column_types(&self) -> &ColumnTypes {
+        &self.column_types
+    }
+
+    fn inferred_column_type(&self, column: &str, value: Option<&serde_json::Value>) -> &'static str {
+        self.determine_column_type_by_name(column, value)
+    }
+}
+
+#[async_trait]
+impl SchemaConflictBackend for MssqlBackend {
+    const BACKEND_NAME: &'static str = "MSSQL";
+
+    async fn existing_column_types(&mut self, table_name: &str) -> Result<HashMap<String, String>> {
+        self.get_column_types(table_name).await
+    }
+
+    async fn recreate_column(&mut self, table_name: &str, conflict: &ColumnConflict, backup: &str) -> Result<Vec<String>> {
+        let rename_sql = format!(
+            "EXEC sp_rename '{}.{}', '{}', 'COLUMN'",
+            table_name, conflict.column, backup
+        );
+        self.client.simple_query(&rename_sql).await?.into_results().await
+            .with_context(|| format!("Failed to rename column {} in table {}", conflict.column, table_name))?;
+        let add_sql = format!("ALTER TABLE {} ADD {} {}", table_name, conflict.column, conflict.expected_type);
+        self.client.simple_query(&add_sql).await?.into_results().await
+            .with_context(|| format!("Failed to recreate column {} in table {}", conflict.column, table_name))?;
+        Ok(vec![rename_sql, add_sql])
+    }
+
+    async fn retry_record(&mut self, table_name: &str, item: &serde_json::Value) -> Result<StorageResult> {
+        self.upsert_record(table_name, item).await
+    }
+
+    fn schema_changes(&mut self) -> &mut Vec<SchemaChange> {
+        &mut self.schema_changes
+    }
+}
+
 #[async_trait]
 impl StorageBackend for MssqlBackend {
     async fn initialize(&mut self) -> Result<()> {
@@ -297,79 +584,404 @@ impl StorageBackend for MssqlBackend {
 
 
 
+    async fn get_table_count(&mut self, table_name: &str) -> Result<i64> {
+        super::validate_table_name(table_name)?;
+        let sql = format!("SELECT COUNT_BIG(*) FROM {}", table_name);
+        let row = self.client.simple_query(&sql).await?.into_row().await?;
+        Ok(row.and_then(|row| row.get::<i64, _>(0)).unwrap_or(0))
+    }
+
+    async fn table_size_bytes(&mut self, table_name: &str) -> Result<Option<i64>> {
+        // Reading sys.dm_db_partition_stats needs VIEW DATABASE STATE
+        let sql = format!(
+            "SELECT SUM(reserved_page_count) * 8192 FROM sys.dm_db_partition_stats WHERE object_id = OBJECT_ID(N'{}')",
+            table_name
+        );
+        let row = self.client.simple_query(&sql).await?.into_row().await?;
+        Ok(row.and_then(|row| row.get::<i64, _>(0)))
+    }
+
+    async fn drop_table(&mut self, table_name: &str) -> Result<()> {
+        let sql = format!("IF OBJECT_ID(N'{0}', N'U') IS NOT NULL DROP TABLE {0}", table_name);
+        self.client.simple_query(&sql).await?.into_results().await?;
+        Ok(())
+    }
+
+    /// Not done here: index rebuilds and statistics updates on SQL Server belong to
+    /// its own maintenance plans or Agent jobs, which run with the right permissions
+    async fn run_maintenance(&mut self, _tables: &[String]) -> Result<Option<i64>> {
+        log::debug!("Skipping MSSQL maintenance - use SQL Server maintenance plans instead");
+        Ok(None)
+    }
+
     async fn health_check(&mut self) -> Result<()> {
         let stream = self.client.simple_query("SELECT 1").await?;
         let _ = stream.into_row().await?;
         Ok(())
     }
 
+    fn default_table_schema(&self, table_name: &str) -> String {
+        // NVARCHAR(MAX) cannot be a key; 450 characters is the longest key that fits an index
+        format!(
+            "IF OBJECT_ID(N'{0}', N'U') IS NULL
+             CREATE TABLE {0} (
+                id NVARCHAR(450) NOT NULL PRIMARY KEY,
+                data NVARCHAR(MAX),
+                last_sync_date_time NVARCHAR(MAX),
+                synced_at NVARCHAR(MAX),
+                created_at DATETIMEOFFSET DEFAULT SYSDATETIMEOFFSET(),
+                updated_at DATETIMEOFFSET DEFAULT SYSDATETIMEOFFSET()
+             )",
+            table_name
+        )
+    }
+
     async fn create_table_if_not_exists(&mut self, table_name: &str, schema: &str) -> Result<()> {
-        // Execute the schema directly - it should include CREATE TABLE IF NOT EXISTS equivalent
-        self.client.simple_query(schema).await
+        let created = self.get_table_columns(table_name).await?.is_empty();
+        // Execute the schema directly - it should be guarded with IF OBJECT_ID(...) IS NULL
+        self.client.simple_query(schema).await?.into_results().await
             .context("Failed to create table")?;
 
         log::info!("Created/verified table: {}", table_name);
+        if created {
+            self.schema_changes.push(SchemaChange {
+                backend: "MSSQL",
+                table: table_name.to_string(),
+                statement: schema.trim().to_string(),
+            });
+        }
         Ok(())
     }
 
-    async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<usize> {
+    async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<ChangeCounts> {
         if data.is_empty() {
-            return Ok(0);
+            return Ok(ChangeCounts::default());
         }
 
-        // Ensure table schema matches the data structure using the first item as a sample
-        if let Some(first_item) = data.first() {
-            if let Err(e) = self.ensure_table_schema_matches(table_name, first_item).await {
+        let fitted;
+        let data = if self.auto_migrate {
+            self.write_synced_at = true;
+            // Ensure table schema matches the data structure using the first records as a sample
+            let sample = super::schema_sample(data, self.schema_sample_size);
+            if let Err(e) = self.ensure_table_schema_matches(table_name, &sample).await {
                 log::warn!("Failed to update table schema for {}: {}", table_name, e);
                 // Continue anyway - might work with existing schema
             }
-        }
+            data
+        } else {
+            let existing = self.get_table_columns(table_name).await?;
+            fitted = super::fit_to_existing_columns(table_name, &existing, data, self.on_missing_column)?;
+            self.write_synced_at = super::has_column(&existing, super::SYNCED_AT_COLUMN);
+            &fitted[..]
+        };
 
-        let mut stored_count = 0;
+        let mut counts = ChangeCounts::default();
+        let mut failed = Vec::new();
 
         for item in data {
-            // Convert JSON to a generic record format
-            let record = self.json_to_generic_record(item)?;
+            match self.upsert_record(table_name, item).await {
+                Ok(result) => counts.record(&result),
+                Err(e) => {
+                    log::warn!("Failed to store item in table {}: {}", table_name, e);
+                    // Continue with other items rather than failing completely
+                    failed.push(item);
+                }
+            }
+        }
+
+        if failed.len() >= SCHEMA_CONFLICT_THRESHOLD.min(data.len()) && !failed.is_empty() {
+            let policy = self.schema_conflict;
+            counts += super::handle_schema_conflicts(self, policy, table_name, &failed).await?;
+        }
+        counts.skipped = data.len() - counts.stored();
+
+        log::debug!("Stored {} items in table {}", counts.stored(), table_name);
+        Ok(counts)
+    }
+
+    async fn get_all_hashes(&mut self, table_name: &str, column: &str) -> Result<HashMap<String, String>> {
+        let mut hashes = HashMap::new();
+
+        // Tables created before change detection existed have no hash column yet
+        if !self.get_table_columns(table_name).await?.contains(column) {
+            return Ok(hashes);
+        }
+
+        let sql = format!(
+            "SELECT CAST(id AS NVARCHAR(450)), CAST({} AS NVARCHAR(MAX)) FROM {} WHERE {} IS NOT NULL",
+            column, table_name, column
+        );
+
+        // Stream rows instead of buffering the whole result set for large tables
+        let mut rows = self.client.simple_query(&sql).await?.into_row_stream();
+        while let Some(row) = rows.try_next().await? {
+            if let (Some(id), Some(hash)) = (row.get::<&str, _>(0), row.get::<&str, _>(1)) {
+                hashes.insert(id.to_string(), hash.to_string());
+            }
+        }
+
+        log::debug!("Loaded {} existing hashes from table {}", hashes.len(), table_name);
+        Ok(hashes)
+    }
+
+    async fn get_raw_records(&mut self, table_name: &str) -> Result<Vec<String>> {
+        if !self.get_table_columns(table_name).await?.contains(RAW_JSON_COLUMN) {
+            return Ok(Vec::new());
+        }
+
+        let sql = format!(
+            "SELECT CAST({} AS NVARCHAR(MAX)) FROM {} WHERE {} IS NOT NULL AND CAST({} AS NVARCHAR(MAX)) <> ''",
+            RAW_JSON_COLUMN, table_name, RAW_JSON_COLUMN, RAW_JSON_COLUMN
+        );
+        let rows = self.client.simple_query(&sql).await?.into_first_result().await?;
+        Ok(rows.iter().filter_map(|row| row.get::<&str, _>(0).map(str::to_string)).collect())
+    }
+
+    async fn query_devices(&mut self, table_name: &str, query: &DeviceQuery) -> Result<(Vec<serde_json::Value>, u64)> {
+        let columns = self.get_table_columns(table_name).await?;
+        if columns.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+        let Some(sql) = query.to_sql(&columns, |n| format!("@P{}", n), |expr| format!("TRY_CAST({} AS DATETIMEOFFSET)", expr)) else {
+            return Ok((Vec::new(), 0));
+        };
+
+        let mut count = tiberius::Query::new(format!("SELECT COUNT_BIG(*) FROM {}{}", table_name, sql.where_clause));
+        for param in &sql.params {
+            count.bind(param.as_str());
+        }
+        let total = count.query(&mut self.client).await?.into_row().await?
+            .and_then(|row| row.get::<i64, _>(0))
+            .unwrap_or(0);
 
-            // For simplicity, use a basic INSERT with ON DUPLICATE KEY UPDATE equivalent
-            // In MSSQL, we'll use a simple INSERT and handle conflicts
-            let field_names: Vec<String> = record.keys().cloned().collect();
-            let placeholders: Vec<String> = (1..=field_names.len())
-                .map(|i| format!("@P{}", i))
-                .collect();
+        // One JSON document per row; NULL columns are included so every record has every key
+        let mut select = format!(
+            "SELECT (SELECT t.* FOR JSON PATH, WITHOUT_ARRAY_WRAPPER, INCLUDE_NULL_VALUES) FROM {} t{} ORDER BY {} OFFSET {} ROWS",
+            table_name, sql.where_clause, sql.order_by, query.offset
+        );
+        if let Some(limit) = query.limit {
+            select.push_str(&format!(" FETCH NEXT {} ROWS ONLY", limit));
+        }
+        let mut rows = tiberius::Query::new(select);
+        for param in &sql.params {
+            rows.bind(param.as_str());
+        }
+        let devices = rows.query(&mut self.client).await?.into_first_result().await?
+            .iter()
+            .filter_map(|row| row.get::<&str, _>(0))
+            .map(|record| Ok(serde_json::from_str(record)?))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((devices, total as u64))
+    }
+
+    async fn preview_schema_changes(&mut self, table_name: &str, schema: &str, sample: &serde_json::Value) -> Result<Vec<String>> {
+        let existing_columns = self.get_table_columns(table_name).await?;
+
+        Ok(super::preview_statements(self, table_name, schema, existing_columns, sample))
+    }
+
+    async fn stale_row_ids(&mut self, table_name: &str, cutoff: chrono::DateTime<Utc>) -> Result<Vec<String>> {
+        let columns = self.get_table_columns(table_name).await?;
+        let has_column = |name: &str| columns.iter().any(|column| column.eq_ignore_ascii_case(name));
+        if !has_column("last_sync_date_time") {
+            return Ok(Vec::new());
+        }
+
+        let sql = format!(
+            "SELECT CAST(id AS NVARCHAR(450)) FROM {} WHERE last_sync_date_time < @P1 AND last_sync_date_time > @P2{}",
+            table_name,
+            if has_column(DELETED_AT_COLUMN) { format!(" AND {} IS NULL", DELETED_AT_COLUMN) } else { String::new() }
+        );
+        let mut query = tiberius::Query::new(sql);
+        query.bind(cutoff.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+        query.bind(NEVER_CHECKED_IN);
+        let rows = query.query(&mut self.client).await?.into_first_result().await?;
+        Ok(rows.iter()
+            .filter_map(|row| row.get::<&str, _>(0).map(str::to_string))
+            .collect())
+    }
+
+    async fn row_names(&mut self, table_name: &str, ids: &[String], name_columns: &[String]) -> Result<HashMap<String, String>> {
+        let columns = self.get_table_columns(table_name).await?;
+        let names: Vec<String> = name_columns.iter()
+            .filter(|name| columns.iter().any(|column| column.eq_ignore_ascii_case(name)))
+            .map(|column| format!("NULLIF(CAST({} AS NVARCHAR(MAX)), '')", column))
+            .collect();
+        let mut found = HashMap::new();
+        if names.is_empty() {
+            return Ok(found);
+        }
 
-            // Simple INSERT statement - table should have appropriate constraints
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            let placeholders: Vec<String> = (1..=chunk.len()).map(|i| format!("@P{}", i)).collect();
             let sql = format!(
-                "INSERT INTO {} ({}) VALUES ({})",
-                table_name,
-                field_names.join(", "),
-                placeholders.join(", ")
+                "SELECT CAST(id AS NVARCHAR(450)), COALESCE({}, NULL) FROM {} WHERE id IN ({})",
+                names.join(", "), table_name, placeholders.join(", ")
             );
+            let mut query = tiberius::Query::new(sql);
+            for id in chunk {
+                query.bind(id.as_str());
+            }
+            for row in query.query(&mut self.client).await?.into_first_result().await? {
+                if let (Some(id), Some(name)) = (row.get::<&str, _>(0), row.get::<&str, _>(1)) {
+                    found.insert(id.to_string(), name.to_string());
+                }
+            }
+        }
+        Ok(found)
+    }
 
+    async fn delete_rows(&mut self, table_name: &str, ids: &[String]) -> Result<u64> {
+        let mut deleted = 0;
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            let placeholders: Vec<String> = (1..=chunk.len()).map(|i| format!("@P{}", i)).collect();
+            let sql = format!("DELETE FROM {} WHERE id IN ({})", table_name, placeholders.join(", "));
             let mut query = tiberius::Query::new(sql);
-            for field in &field_names {
-                query.bind(record.get(field).unwrap().as_str());
+            for id in chunk {
+                query.bind(id.as_str());
             }
+            deleted += query.execute(&mut self.client).await?.total();
+        }
+        Ok(deleted)
+    }
 
-            match query.execute(&mut self.client).await {
-                Ok(_) => {
-                    stored_count += 1;
-                }
-                Err(e) => {
-                    log::warn!("Failed to store item in table {}: {}", table_name, e);
-                    // Continue with other items rather than failing completely
+    async fn mark_rows_deleted(&mut self, table_name: &str, hash_column: &str, ids: &[String], deleted_at: Option<&str>) -> Result<u64> {
+        let has_column = self.get_table_columns(table_name).await?
+            .iter()
+            .any(|column| column.eq_ignore_ascii_case(DELETED_AT_COLUMN));
+        let assignments = match deleted_at {
+            Some(deleted_at) => {
+                if !has_column {
+                    if !self.auto_migrate {
+                        anyhow::bail!("Table {} has no {} column and autoMigrate is off", table_name, DELETED_AT_COLUMN);
+                    }
+                    self.ensure_table_schema_matches(table_name, &serde_json::json!({ DELETED_AT_COLUMN: deleted_at })).await?;
                 }
+                format!("{} = @P1, {} = NULL", DELETED_AT_COLUMN, hash_column)
+            }
+            None if !has_column => return Ok(0),
+            None => format!("{} = NULL", DELETED_AT_COLUMN),
+        };
+
+        let offset = usize::from(deleted_at.is_some());
+        let mut marked = 0;
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            let placeholders: Vec<String> = (1..=chunk.len()).map(|i| format!("@P{}", i + offset)).collect();
+            // Rows already marked keep the time they were first found missing
+            let sql = format!(
+                "UPDATE {} SET {} WHERE {} {} AND id IN ({})",
+                table_name,
+                assignments,
+                DELETED_AT_COLUMN,
+                if deleted_at.is_some() { "IS NULL" } else { "IS NOT NULL" },
+                placeholders.join(", ")
+            );
+            let mut query = tiberius::Query::new(sql);
+            if let Some(deleted_at) = deleted_at {
+                query.bind(deleted_at);
             }
+            for id in chunk {
+                query.bind(id.as_str());
+            }
+            marked += query.execute(&mut self.client).await?.total();
         }
+        Ok(marked)
+    }
+
+    async fn get_sync_state(&mut self, endpoint_name: &str) -> Result<Option<SyncState>> {
+        let sql = format!(
+            "SELECT delta_link, watermark, etag, updated_at, last_error, last_error_at, last_full_sync_at
+             FROM {} WHERE endpoint_name = @P1",
+            SYNC_STATE_TABLE
+        );
+        let mut query = tiberius::Query::new(sql);
+        query.bind(endpoint_name);
+        let row = query.query(&mut self.client).await?.into_row().await?;
+
+        Ok(row.map(|row| SyncState {
+            endpoint_name: endpoint_name.to_string(),
+            delta_link: row.get::<&str, _>(0).map(str::to_string),
+            watermark: row.get::<&str, _>(1).map(str::to_string),
+            etag: row.get::<&str, _>(2).map(str::to_string),
+            updated_at: row.get::<chrono::DateTime<Utc>, _>(3),
+            last_error: row.get::<&str, _>(4).map(str::to_string),
+            last_error_at: row.get::<chrono::DateTime<Utc>, _>(5),
+            last_full_sync_at: row.get::<chrono::DateTime<Utc>, _>(6),
+        }))
+    }
+
+    async fn set_sync_state(&mut self, state: &SyncState) -> Result<()> {
+        let sql = format!(
+            "MERGE {} WITH (HOLDLOCK) AS target
+             USING (SELECT @P1 AS endpoint_name) AS source
+             ON target.endpoint_name = source.endpoint_name
+             WHEN MATCHED THEN UPDATE SET delta_link = @P2, watermark = @P3, etag = @P4, updated_at = @P5,
+                last_error = @P6, last_error_at = @P7, last_full_sync_at = @P8
+             WHEN NOT MATCHED THEN INSERT (endpoint_name, delta_link, watermark, etag, updated_at, last_error, last_error_at, last_full_sync_at)
+                VALUES (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8);",
+            SYNC_STATE_TABLE
+        );
+        let mut query = tiberius::Query::new(sql);
+        query.bind(state.endpoint_name.as_str());
+        query.bind(state.delta_link.as_deref());
+        query.bind(state.watermark.as_deref());
+        query.bind(state.etag.as_deref());
+        query.bind(state.updated_at);
+        query.bind(state.last_error.as_deref());
+        query.bind(state.last_error_at);
+        query.bind(state.last_full_sync_at);
+        query.execute(&mut self.client).await?;
+        Ok(())
+    }
 
-        log::debug!("Stored {} items in table {}", stored_count, table_name);
-        Ok(stored_count)
+    fn set_column_types(&mut self, table_name: &str, column_types: &HashMap<String, ColumnTypeOverride>) {
+        self.column_types.set("mssql", table_name, column_types);
     }
 
     fn backend_name(&self) -> &'static str {
         "MSSQL"
     }
 
+    /// Text columns are created as `NVARCHAR(MAX)`, which SQL Server cannot use as an
+    /// index key, so an index on one fails until the column is given a shorter type.
+    async fn create_index_if_not_exists(&mut self, table_name: &str, columns: &[String]) -> Result<()> {
+        let existing: HashSet<String> = self.get_table_columns(table_name).await?
+            .iter()
+            .map(|column| column.to_lowercase())
+            .collect();
+        if let Some(missing) = columns.iter().find(|column| !existing.contains(*column)) {
+            log::debug!("Not indexing {} yet - it has no column {}", table_name, missing);
+            return Ok(());
+        }
+
+        let name = super::index_name(table_name, columns);
+        let mut query = tiberius::Query::new("SELECT 1 FROM sys.indexes WHERE name = @P1 AND object_id = OBJECT_ID(@P2)");
+        query.bind(name.as_str());
+        query.bind(table_name);
+        if query.query(&mut self.client).await?.into_row().await?.is_some() {
+            return Ok(());
+        }
+
+        for column in columns {
+            self.check_index_key_column(table_name, column).await?;
+        }
+
+        let sql = format!("CREATE INDEX {} ON {} ({})", name, table_name, columns.join(", "));
+        self.client.simple_query(sql.as_str()).await?.into_results().await?;
+        log::info!("Created index {} on {}", name, table_name);
+        self.schema_changes.push(SchemaChange {
+            backend: "MSSQL",
+            table: table_name.to_string(),
+            statement: sql,
+        });
+        Ok(())
+    }
+
+    fn take_schema_changes(&mut self) -> Vec<SchemaChange> {
+        std::mem::take(&mut self.schema_changes)
+    }
+
     async fn cleanup(&mut self) -> Result<()> {
         // MSSQL connections are automatically closed when dropped
         // The close() method takes ownership, so we just log the cleanup
@@ -382,6 +994,123 @@ impl StorageBackend for MssqlBackend {
 mod tests {
     use super::*;
 
+    /// The guarded default schema creates the table once and has the columns it declares
+    #[cfg(feature = "mssql-integration")]
+    #[tokio::test]
+    async fn test_default_table_schema() {
+        let connection_string = std::env::var("MSSQL_TEST_CONNECTION_STRING")
+            .expect("MSSQL_TEST_CONNECTION_STRING must point at a test database");
+        let mut backend = MssqlBackend::new(&connection_string, None, ConnectRetry::default()).await.unwrap();
+        let table = format!("schema_test_{}", uuid::Uuid::new_v4().simple());
+        let schema = backend.default_table_schema(&table);
+        backend.create_table_if_not_exists(&table, &schema).await.unwrap();
+        let recreated = backend.create_table_if_not_exists(&table, &schema).await;
+        let columns = backend.get_table_columns(&table).await;
+        let stored = backend.store_endpoint_data(&table, &[serde_json::json!({"id": "device-1", "deviceName": "PC-1"})]).await;
+        backend.client.simple_query(format!("DROP TABLE {}", table)).await.unwrap().into_results().await.unwrap();
+
+        recreated.unwrap();
+        assert_eq!(columns.unwrap(), crate::storage::schema_columns(&schema));
+        assert_eq!(stored.unwrap().inserted, 1);
+    }
+
+    /// Runs against the server in `MSSQL_TEST_CONNECTION_STRING`, in a table of its own
+    #[cfg(feature = "mssql-integration")]
+    #[tokio::test]
+    async fn test_store_same_batch_twice() {
+        let connection_string = std::env::var("MSSQL_TEST_CONNECTION_STRING")
+            .expect("MSSQL_TEST_CONNECTION_STRING must point at a test database");
+        let mut backend = MssqlBackend::new(&connection_string, None, ConnectRetry::default()).await.unwrap();
+        let table = format!("upsert_test_{}", uuid::Uuid::new_v4().simple());
+        let schema = backend.default_table_schema(&table);
+        backend.create_table_if_not_exists(&table, &schema).await.unwrap();
+
+        let data: Vec<serde_json::Value> = (0..100)
+            .map(|i| serde_json::json!({"id": format!("device-{}", i), "deviceName": format!("PC-{}", i)}))
+            .collect();
+        let stored = backend.store_endpoint_data(&table, &data).await;
+        let restored = backend.store_endpoint_data(&table, &data).await;
+        let count = backend.get_table_count(&table).await;
+        backend.client.simple_query(format!("DROP TABLE {}", table)).await.unwrap().into_results().await.unwrap();
+
+        assert_eq!(stored.unwrap(), ChangeCounts { inserted: 100, updated: 0, skipped: 0 });
+        // Existing rows are updated, not inserted again
+        assert_eq!(restored.unwrap(), ChangeCounts { inserted: 0, updated: 100, skipped: 0 });
+        assert_eq!(count.unwrap(), 100);
+    }
+
+    /// A merging endpoint's record lands on the existing row and keeps its other columns
+    #[cfg(feature = "mssql-integration")]
+    #[tokio::test]
+    async fn test_store_merged_record() {
+        let connection_string = std::env::var("MSSQL_TEST_CONNECTION_STRING")
+            .expect("MSSQL_TEST_CONNECTION_STRING must point at a test database");
+        let mut backend = MssqlBackend::new(&connection_string, None, ConnectRetry::default()).await.unwrap();
+        let table = format!("merge_test_{}", uuid::Uuid::new_v4().simple());
+        let schema = backend.default_table_schema(&table);
+        backend.create_table_if_not_exists(&table, &schema).await.unwrap();
+
+        let device = serde_json::json!({"id": "device-1", "deviceName": "PC-1"});
+        let compliance = serde_json::json!({"id": "device-1", "complianceState": "compliant"});
+        let stored = backend.store_endpoint_data(&table, &[device]).await;
+        let merged = backend.store_endpoint_data(&table, &[compliance]).await;
+        let row = backend.client
+            .simple_query(format!("SELECT devicename, compliancestate FROM {}", table)).await.unwrap()
+            .into_first_result().await.unwrap()
+            .into_iter()
+            .map(|row| (row.get::<&str, _>(0).map(str::to_string), row.get::<&str, _>(1).map(str::to_string)))
+            .collect::<Vec<_>>();
+        backend.client.simple_query(format!("DROP TABLE {}", table)).await.unwrap().into_results().await.unwrap();
+
+        assert_eq!(stored.unwrap(), ChangeCounts { inserted: 1, updated: 0, skipped: 0 });
+        assert_eq!(merged.unwrap(), ChangeCounts { inserted: 0, updated: 1, skipped: 0 });
+        assert_eq!(row, vec![(Some("PC-1".to_string()), Some("compliant".to_string()))]);
+    }
+
+    #[test]
+    fn test_strip_tls_settings() {
+        let tls = TlsConfig { mode: Some(TlsMode::Require), ca_cert_path: None, trust_server_certificate: true };
+        assert_eq!(
+            MssqlBackend::strip_tls_settings("server=sql;database=intune;Encrypt=true;TrustServerCertificate=true;uid=sa", &tls),
+            "server=sql;database=intune;uid=sa"
+        );
+        assert_eq!(
+            MssqlBackend::strip_tls_settings("server=sql;database=intune", &tls),
+            "server=sql;database=intune"
+        );
+
+        // Only what the block sets is replaced
+        let tls = TlsConfig { mode: None, ca_cert_path: Some("/etc/ssl/certs/db-ca.pem".to_string()), trust_server_certificate: false };
+        assert_eq!(
+            MssqlBackend::strip_tls_settings("server=sql;Encrypt=strict;TrustServerCertificate=true", &tls),
+            "server=sql;Encrypt=strict"
+        );
+        let tls = TlsConfig { mode: Some(TlsMode::Prefer), ..tls };
+        assert!(MssqlBackend::parse_config("server=sql", Some(&tls)).is_err());
+    }
+
+    #[test]
+    fn test_extract_database_name() {
+        let cases = [
+            ("server=localhost;database=intune_devices;trusted_connection=true", Some("intune_devices")),
+            ("Server=tcp:sql.internal,1433;Database=DeviceInventory;User Id=sync;Password=p@ss;", Some("DeviceInventory")),
+            ("Data Source=sql.internal;Initial Catalog=Intune;Integrated Security=SSPI", Some("Intune")),
+            ("server=localhost; DATABASE = {My Devices} ;uid=sa;pwd=secret", Some("My Devices")),
+            ("jdbc:sqlserver://sql.internal:1433;databaseName=intune;user=sa;password=secret", Some("intune")),
+            ("server=localhost;uid=sa;pwd=secret", None),
+            ("server=localhost;database=;uid=sa", None),
+        ];
+
+        for (connection_string, expected) in cases {
+            assert_eq!(
+                MssqlBackend::extract_database_name(connection_string).as_deref(),
+                expected,
+                "{}",
+                connection_string
+            );
+        }
+    }
+
     #[test]
     fn test_parse_timestamp() {
         let valid_timestamp = "2023-01-01T00:00:00Z";
@@ -1,22 +1,39 @@
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use chrono::TimeZone;
 
-use super::StorageBackend;
+use super::{connect_with_retry, ChangeCounts, ColumnTypeBackend, ColumnTypes, ConnectRetry, DeviceQuery, DEFAULT_SCHEMA_SAMPLE_SIZE, SchemaChange, StorageBackend, StorageResult, SyncState, DELETED_AT_COLUMN, ID_BATCH_SIZE, NEVER_CHECKED_IN, RAW_JSON_COLUMN, SYNC_STATE_TABLE};
+use crate::config::MissingColumnPolicy;
+use crate::endpoint::ColumnTypeOverride;
 use crate::path_utils;
 
 pub struct SqliteBackend {
     connection: Arc<Mutex<Connection>>,
     db_path: String,
+    vacuum_into: Option<String>,
+    auto_migrate: bool,
+    on_missing_column: MissingColumnPolicy,
+    /// Whether the table being written has a `synced_at` column; tables created before
+    /// it existed may lack it while `autoMigrate` is off
+    write_synced_at: bool,
+    schema_sample_size: usize,
+    schema_changes: Vec<SchemaChange>,
+    column_types: ColumnTypes,
 }
 
 impl SqliteBackend {
-    pub async fn new(db_path: &str) -> Result<Self> {
+    /// Open the database, retrying while it cannot be opened (e.g. a network share not mounted yet)
+    pub async fn new(db_path: &str, retry: ConnectRetry) -> Result<Self> {
+        connect_with_retry("SQLite", retry, || Self::open(db_path)).await
+    }
+
+    async fn open(db_path: &str) -> Result<Self> {
         // Resolve the database path (handles relative/absolute paths and OS-specific separators)
         let resolved_path = path_utils::resolve_path(db_path)
             .with_context(|| format!("Failed to resolve database path: {}", db_path))?;
@@ -42,18 +59,77 @@ impl SqliteBackend {
         } // stmt is dropped here
 
         // Set synchronous mode to NORMAL for better performance with WAL
-        conn.execute("PRAGMA synchronous = NORMAL", [])?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
 
-        // Additional WAL optimizations
-        conn.execute("PRAGMA wal_autocheckpoint = 1000", [])?; // Checkpoint every 1000 pages
-        conn.execute("PRAGMA cache_size = -64000", [])?; // 64MB cache
+        // Additional WAL optimizations (these PRAGMAs echo a row back, so they
+        // must go through pragma_update rather than execute)
+        conn.pragma_update(None, "wal_autocheckpoint", 1000)?; // Checkpoint every 1000 pages
+        conn.pragma_update(None, "cache_size", -64000)?; // 64MB cache
 
         Ok(Self {
             connection: Arc::new(Mutex::new(conn)),
             db_path: resolved_path.to_string_lossy().to_string(),
+            vacuum_into: None,
+            auto_migrate: true,
+            on_missing_column: MissingColumnPolicy::default(),
+            write_synced_at: true,
+            schema_sample_size: DEFAULT_SCHEMA_SAMPLE_SIZE,
+            schema_changes: Vec::new(),
+            column_types: ColumnTypes::default(),
         })
     }
 
+    /// Open an existing database without creating it or changing its settings
+    pub async fn open_read_only(db_path: &str, retry: ConnectRetry) -> Result<Self> {
+        connect_with_retry("SQLite", retry, || async {
+            let resolved_path = path_utils::resolve_path(db_path)
+                .with_context(|| format!("Failed to resolve database path: {}", db_path))?;
+            let conn = Connection::open_with_flags(
+                &resolved_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+            )
+            .with_context(|| format!("Failed to open SQLite database at {} read-only", resolved_path.display()))?;
+
+            Ok(Self {
+                connection: Arc::new(Mutex::new(conn)),
+                db_path: resolved_path.to_string_lossy().to_string(),
+                vacuum_into: None,
+                auto_migrate: false,
+                on_missing_column: MissingColumnPolicy::default(),
+                write_synced_at: true,
+                schema_sample_size: DEFAULT_SCHEMA_SAMPLE_SIZE,
+                schema_changes: Vec::new(),
+                column_types: ColumnTypes::default(),
+            })
+        }).await
+    }
+
+    /// Have maintenance write a compacted copy to this path instead of vacuuming in place
+    pub fn with_vacuum_into(mut self, path: Option<String>) -> Self {
+        self.vacuum_into = path;
+        self
+    }
+
+    /// Whether schema evolution may add columns; when off, records are fitted to the
+    /// existing columns according to `on_missing_column`
+    pub fn with_auto_migrate(mut self, auto_migrate: bool, on_missing_column: MissingColumnPolicy) -> Self {
+        self.auto_migrate = auto_migrate;
+        self.on_missing_column = on_missing_column;
+        self
+    }
+
+    /// Records sampled to pick the type of each new column
+    pub fn with_schema_sample_size(mut self, schema_sample_size: usize) -> Self {
+        self.schema_sample_size = schema_sample_size;
+        self
+    }
+
+    fn database_size(connection: &Connection) -> Result<i64> {
+        let page_count: i64 = connection.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page_size: i64 = connection.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok(page_count * page_size)
+    }
+
     /// Convert JSON value to a generic record for database storage
     fn json_to_generic_record(&self, json: &serde_json::Value) -> Result<std::collections::HashMap<String, String>> {
         let mut record = std::collections::HashMap::new();
@@ -79,7 +155,12 @@ impl SqliteBackend {
                     }
                 };
 
-                record.insert(key.clone(), string_value);
+                let column = super::sanitize_column_name(key);
+                if record.contains_key(&column) {
+                    log::debug!("Skipping key {} - column {} is already populated by another key", key, column);
+                    continue;
+                }
+                record.insert(column, string_value);
             }
         }
 
@@ -89,52 +170,28 @@ impl SqliteBackend {
             record.insert("id".to_string(), uuid::Uuid::new_v4().to_string());
         }
 
-        if !record.contains_key("last_sync_date_time") {
-            record.insert("last_sync_date_time".to_string(), chrono::Utc::now().to_rfc3339());
-        }
+        super::add_sync_timestamps(&mut record, self.write_synced_at);
 
         Ok(record)
     }
 
     /// Ensure the table schema matches the data structure by analyzing the JSON object
     async fn ensure_table_schema_matches(&mut self, table_name: &str, sample_data: &serde_json::Value) -> Result<()> {
-        if let Some(obj) = sample_data.as_object() {
-            let connection = self.connection.lock().await;
-
-            // Get current table schema
-            let existing_columns = self.get_table_columns(&connection, table_name)?;
-
-            // Determine required columns from the sample data
-            let mut required_columns = std::collections::HashSet::new();
-            for key in obj.keys() {
-                required_columns.insert(key.clone());
-            }
+        let connection = self.connection.lock().await;
+        let existing_columns = self.get_table_columns(&connection, table_name)?;
 
-            // Add standard columns
-            required_columns.insert("id".to_string());
-            required_columns.insert("last_sync_date_time".to_string());
-
-            // Find missing columns
-            let missing_columns: Vec<String> = required_columns
-                .difference(&existing_columns)
-                .cloned()
-                .collect();
-
-            // Add missing columns
-            for column in missing_columns {
-                let column_type = self.determine_column_type_by_name(&column, obj.get(&column));
-                let alter_sql = format!(
-                    "ALTER TABLE {} ADD COLUMN {} {}",
-                    table_name, column, column_type
-                );
-
-                match connection.execute(&alter_sql, []) {
-                    Ok(_) => {
-                        log::info!("Added column {} ({}) to table {}", column, column_type, table_name);
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to add column {} to table {}: {}", column, table_name, e);
-                    }
+        for addition in super::column_additions(self, table_name, &existing_columns, sample_data) {
+            match connection.execute(&addition.sql, []) {
+                Ok(_) => {
+                    log::info!("Added column {} ({}) to table {}", addition.column, addition.column_type, table_name);
+                    self.schema_changes.push(SchemaChange {
+                        backend: "SQLite",
+                        table: table_name.to_string(),
+                        statement: addition.sql,
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Failed to add column {} to table {}: {}", addition.column, table_name, e);
                 }
             }
         }
@@ -249,9 +306,50 @@ impl SqliteBackend {
 
 }
 
+impl ColumnTypeBackend for SqliteBackend {
+    const ADD_COLUMN: &'static str = "ADD COLUMN";
+
+    fn column_types(&self) -> &ColumnTypes {
+        &self.column_types
+    }
+
+    fn inferred_column_type(&self, column: &str, value: Option<&serde_json::Value>) -> &'static str {
+        self.determine_column_type_by_name(column, value)
+    }
+}
+
 #[async_trait]
 impl StorageBackend for SqliteBackend {
     async fn initialize(&mut self) -> Result<()> {
+        let connection = self.connection.lock().await;
+        connection.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    endpoint_name TEXT PRIMARY KEY,
+                    delta_link TEXT,
+                    watermark TEXT,
+                    etag TEXT,
+                    updated_at TEXT,
+                    last_error TEXT,
+                    last_error_at TEXT,
+                    last_full_sync_at TEXT
+                )",
+                SYNC_STATE_TABLE
+            ),
+            [],
+        ).context("Failed to create sync state table")?;
+
+        // Tables created before the last error and full sync were tracked
+        for column in ["last_error", "last_error_at", "last_full_sync_at"] {
+            let exists = connection
+                .prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1", SYNC_STATE_TABLE))?
+                .exists([column])?;
+            if !exists {
+                connection.execute(&format!("ALTER TABLE {} ADD COLUMN {} TEXT", SYNC_STATE_TABLE, column), [])
+                    .with_context(|| format!("Failed to add {} to sync state table", column))?;
+            }
+        }
+
         log::info!("SQLite backend initialized successfully");
         Ok(())
     }
@@ -265,8 +363,23 @@ impl StorageBackend for SqliteBackend {
         Ok(())
     }
 
+    fn default_table_schema(&self, table_name: &str) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id TEXT PRIMARY KEY,
+                data TEXT,
+                last_sync_date_time TEXT,
+                synced_at TEXT,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )",
+            table_name
+        )
+    }
+
     async fn create_table_if_not_exists(&mut self, table_name: &str, schema: &str) -> Result<()> {
         let connection = self.connection.lock().await;
+        let created = self.get_table_columns(&connection, table_name)?.is_empty();
 
         // Log the schema for debugging
         log::debug!("Executing schema for table {}: {}", table_name, schema);
@@ -278,80 +391,498 @@ impl StorageBackend for SqliteBackend {
         })?;
 
         log::info!("Created/verified table: {}", table_name);
+        if created {
+            self.schema_changes.push(SchemaChange {
+                backend: "SQLite",
+                table: table_name.to_string(),
+                statement: schema.trim().to_string(),
+            });
+        }
         Ok(())
     }
 
-    async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<usize> {
+    async fn store_endpoint_data(&mut self, table_name: &str, data: &[serde_json::Value]) -> Result<ChangeCounts> {
         if data.is_empty() {
-            return Ok(0);
+            return Ok(ChangeCounts::default());
         }
 
-        // Analyze the first object to determine required schema
-        if let Some(first_item) = data.first() {
-            self.ensure_table_schema_matches(table_name, first_item).await?;
+        let fitted;
+        let data = if self.auto_migrate {
+            self.write_synced_at = true;
+            // Analyze the first records to determine required schema
+            let sample = super::schema_sample(data, self.schema_sample_size);
+            self.ensure_table_schema_matches(table_name, &sample).await?;
+            data
+        } else {
+            let existing = {
+                let connection = self.connection.lock().await;
+                self.get_table_columns(&connection, table_name)?
+            };
+            fitted = super::fit_to_existing_columns(table_name, &existing, data, self.on_missing_column)?;
+            self.write_synced_at = super::has_column(&existing, super::SYNCED_AT_COLUMN);
+            &fitted[..]
+        };
+
+        // One transaction for the whole batch, so rows are not synced to disk one by one.
+        // The first failing row (with autoMigrate on) rolls it back, updates the schema
+        // from that row and restarts the batch; rows failing after that are skipped.
+        let mut schema_repaired = !self.auto_migrate;
+        // An upsert changes one row either way, so a lookup beforehand tells inserts from updates
+        let exists_sql = format!("SELECT 1 FROM {} WHERE id = ?", table_name);
+        let counts = loop {
+            let failed = {
+                let mut connection = self.connection.lock().await;
+                let transaction = connection.transaction()?;
+                let mut counts = ChangeCounts::default();
+                let mut failed = None;
+
+                for (index, item) in data.iter().enumerate() {
+                    // Convert JSON to a generic record format
+                    let record = self.json_to_generic_record(item)?;
+
+                    // Create dynamic INSERT statement based on available fields. An upsert only
+                    // touches the record's columns, so columns another endpoint merged in are kept.
+                    let field_names: Vec<&String> = record.keys().collect();
+                    let placeholders: Vec<&str> = field_names.iter().map(|_| "?").collect();
+
+                    let sql = format!(
+                        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT (id) DO UPDATE SET {}",
+                        table_name,
+                        field_names.iter().map(|field| field.as_str()).collect::<Vec<_>>().join(", "),
+                        placeholders.join(", "),
+                        field_names.iter()
+                            .map(|field| format!("{} = excluded.{}", field, field))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+
+                    // Records with the same fields share one prepared statement; a missing
+                    // column already fails preparing it
+                    let values = field_names.iter().map(|field| record[*field].as_str());
+                    let result = transaction.prepare_cached(&exists_sql)
+                        .and_then(|mut statement| statement.exists([record["id"].as_str()]))
+                        .and_then(|exists| {
+                            let changed = transaction.prepare_cached(&sql)
+                                .and_then(|mut statement| statement.execute(rusqlite::params_from_iter(values)))?;
+                            Ok(match changed {
+                                0 => StorageResult::Skipped,
+                                _ if exists => StorageResult::Updated,
+                                _ => StorageResult::Inserted,
+                            })
+                        });
+                    match result {
+                        Ok(result) => counts.record(&result),
+                        Err(e) if !schema_repaired => {
+                            failed = Some((index, e));
+                            break;
+                        }
+                        Err(e) => {
+                            log::warn!("Failed to store item in table {}: {}", table_name, e);
+                            counts.skipped += 1;
+                        }
+                    }
+                }
+
+                match failed {
+                    None => {
+                        transaction.commit()?;
+                        break counts;
+                    }
+                    // Dropping the transaction rolls the batch back
+                    Some(failed) => failed,
+                }
+            };
+
+            let (index, e) = failed;
+            log::warn!("Failed to store item in table {}: {} - updating the schema and retrying the batch", table_name, e);
+            schema_repaired = true;
+            if let Err(schema_err) = self.ensure_table_schema_matches(table_name, &data[index]).await {
+                log::error!("Failed to update schema for table {}: {}", table_name, schema_err);
+            }
+        };
+
+        log::debug!("Stored {} items in table {}", counts.stored(), table_name);
+        Ok(counts)
+    }
+
+    async fn get_all_hashes(&mut self, table_name: &str, column: &str) -> Result<HashMap<String, String>> {
+        let connection = self.connection.lock().await;
+        let mut hashes = HashMap::new();
+
+        // Tables created before change detection existed have no hash column yet
+        if !self.get_table_columns(&connection, table_name)?.contains(column) {
+            return Ok(hashes);
         }
 
-        let mut stored_count = 0;
+        let sql = format!(
+            "SELECT id, {} FROM {} WHERE {} IS NOT NULL",
+            column, table_name, column
+        );
+        let mut stmt = connection.prepare(&sql)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
 
-        for item in data {
-            // Convert JSON to a generic record format
-            let record = self.json_to_generic_record(item)?;
+        // Rows are read one at a time straight into the map
+        for row in rows {
+            let (id, hash) = row?;
+            hashes.insert(id, hash);
+        }
 
-            // Create dynamic INSERT statement based on available fields
-            let field_names: Vec<String> = record.keys().cloned().collect();
-            let placeholders: Vec<String> = field_names.iter().map(|_| "?".to_string()).collect();
+        log::debug!("Loaded {} existing hashes from table {}", hashes.len(), table_name);
+        Ok(hashes)
+    }
 
-            let sql = format!(
-                "INSERT OR REPLACE INTO {} ({}) VALUES ({})",
-                table_name,
-                field_names.join(", "),
-                placeholders.join(", ")
-            );
+    async fn get_table_count(&mut self, table_name: &str) -> Result<i64> {
+        super::validate_table_name(table_name)?;
+        let connection = self.connection.lock().await;
+        let sql = format!("SELECT COUNT(*) FROM {}", table_name);
+        let count: i64 = connection.query_row(&sql, [], |row| row.get(0))?;
+        Ok(count)
+    }
+
+    async fn get_raw_records(&mut self, table_name: &str) -> Result<Vec<String>> {
+        let connection = self.connection.lock().await;
+        if !self.get_table_columns(&connection, table_name)?.contains(RAW_JSON_COLUMN) {
+            return Ok(Vec::new());
+        }
 
-            let values: Vec<String> = field_names.iter()
-                .map(|field| record.get(field).unwrap().clone())
-                .collect();
+        let sql = format!(
+            "SELECT {} FROM {} WHERE {} IS NOT NULL AND {} <> ''",
+            RAW_JSON_COLUMN, table_name, RAW_JSON_COLUMN, RAW_JSON_COLUMN
+        );
+        let mut stmt = connection.prepare(&sql)?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    async fn query_devices(&mut self, table_name: &str, query: &DeviceQuery) -> Result<(Vec<serde_json::Value>, u64)> {
+        let connection = self.connection.lock().await;
+        let columns = self.get_table_columns(&connection, table_name)?;
+        if columns.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+        let Some(sql) = query.to_sql(&columns, |_| "?".to_string(), |expr| format!("datetime({})", expr)) else {
+            return Ok((Vec::new(), 0));
+        };
+
+        let total: i64 = connection.query_row(
+            &format!("SELECT COUNT(*) FROM {}{}", table_name, sql.where_clause),
+            rusqlite::params_from_iter(&sql.params),
+            |row| row.get(0),
+        )?;
+
+        // LIMIT -1 is SQLite for no limit
+        let select = format!(
+            "SELECT * FROM {}{} ORDER BY {} LIMIT {} OFFSET {}",
+            table_name,
+            sql.where_clause,
+            sql.order_by,
+            query.limit.map_or(-1, i64::from),
+            query.offset
+        );
+        let mut stmt = connection.prepare(&select)?;
+        let names: Vec<String> = stmt.column_names().into_iter().map(str::to_string).collect();
+        let rows = stmt.query_map(rusqlite::params_from_iter(&sql.params), |row| {
+            let mut record = serde_json::Map::with_capacity(names.len());
+            for (i, name) in names.iter().enumerate() {
+                let value = match row.get_ref(i)? {
+                    rusqlite::types::ValueRef::Null => serde_json::Value::Null,
+                    rusqlite::types::ValueRef::Integer(n) => serde_json::Value::from(n),
+                    rusqlite::types::ValueRef::Real(n) => serde_json::Value::from(n),
+                    rusqlite::types::ValueRef::Text(text) => serde_json::Value::String(String::from_utf8_lossy(text).into_owned()),
+                    rusqlite::types::ValueRef::Blob(_) => serde_json::Value::Null,
+                };
+                record.insert(name.clone(), value);
+            }
+            Ok(serde_json::Value::Object(record))
+        })?;
+        let devices = rows.collect::<rusqlite::Result<Vec<_>>>()?;
 
-            let values_refs: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+        Ok((devices, total as u64))
+    }
 
+    async fn preview_schema_changes(&mut self, table_name: &str, schema: &str, sample: &serde_json::Value) -> Result<Vec<String>> {
+        let existing_columns = {
             let connection = self.connection.lock().await;
-            match connection.execute(&sql, rusqlite::params_from_iter(values_refs.iter())) {
-                Ok(_) => {
-                    stored_count += 1;
+            self.get_table_columns(&connection, table_name)?
+        };
+
+        Ok(super::preview_statements(self, table_name, schema, existing_columns, sample))
+    }
+
+    async fn stale_row_ids(&mut self, table_name: &str, cutoff: chrono::DateTime<chrono::Utc>) -> Result<Vec<String>> {
+        let connection = self.connection.lock().await;
+        let columns = self.get_table_columns(&connection, table_name)?;
+        if !columns.contains("last_sync_date_time") {
+            return Ok(Vec::new());
+        }
+
+        // Values are stored normalized or as RFC 3339, which datetime() both reads
+        let sql = format!(
+            "SELECT id FROM {} WHERE datetime(last_sync_date_time) < datetime(?1) AND datetime(last_sync_date_time) > datetime(?2){}",
+            table_name,
+            if columns.contains(DELETED_AT_COLUMN) { format!(" AND {} IS NULL", DELETED_AT_COLUMN) } else { String::new() }
+        );
+        let cutoff = cutoff.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        let mut statement = connection.prepare(&sql)?;
+        let ids = statement.query_map(rusqlite::params![cutoff, NEVER_CHECKED_IN], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(ids)
+    }
+
+    async fn row_names(&mut self, table_name: &str, ids: &[String], name_columns: &[String]) -> Result<HashMap<String, String>> {
+        let connection = self.connection.lock().await;
+        let columns = self.get_table_columns(&connection, table_name)?;
+        let names: Vec<String> = name_columns.iter()
+            .filter(|column| columns.contains(column.as_str()))
+            .map(|column| format!("NULLIF(CAST({} AS TEXT), '')", column))
+            .collect();
+        let mut found = HashMap::new();
+        if names.is_empty() {
+            return Ok(found);
+        }
+
+        // SQLite's COALESCE takes at least two arguments
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            let sql = format!(
+                "SELECT id, COALESCE({}, NULL) FROM {} WHERE id IN ({})",
+                names.join(", "), table_name, vec!["?"; chunk.len()].join(", ")
+            );
+            let mut statement = connection.prepare(&sql)?;
+            let rows = statement.query_map(rusqlite::params_from_iter(chunk), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })?;
+            for row in rows {
+                if let (id, Some(name)) = row? {
+                    found.insert(id, name);
                 }
-                Err(e) => {
-                    log::warn!("Failed to store item in table {}: {}", table_name, e);
-                    // Drop the connection lock before trying to update schema
-                    drop(connection);
-
-                    // Try to add missing columns and retry once
-                    if let Err(schema_err) = self.ensure_table_schema_matches(table_name, item).await {
-                        log::error!("Failed to update schema for table {}: {}", table_name, schema_err);
-                    } else {
-                        // Retry the insert after schema update
-                        let connection = self.connection.lock().await;
-                        match connection.execute(&sql, rusqlite::params_from_iter(values_refs.iter())) {
-                            Ok(_) => {
-                                stored_count += 1;
-                                log::debug!("Successfully stored item after schema update");
-                            }
-                            Err(retry_err) => {
-                                log::warn!("Failed to store item even after schema update: {}", retry_err);
-                            }
-                        }
+            }
+        }
+        Ok(found)
+    }
+
+    async fn delete_rows(&mut self, table_name: &str, ids: &[String]) -> Result<u64> {
+        let mut connection = self.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let mut deleted = 0;
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            let sql = format!("DELETE FROM {} WHERE id IN ({})", table_name, vec!["?"; chunk.len()].join(", "));
+            deleted += transaction.execute(&sql, rusqlite::params_from_iter(chunk))? as u64;
+        }
+        transaction.commit()?;
+        Ok(deleted)
+    }
+
+    async fn mark_rows_deleted(&mut self, table_name: &str, hash_column: &str, ids: &[String], deleted_at: Option<&str>) -> Result<u64> {
+        let has_column = {
+            let connection = self.connection.lock().await;
+            self.get_table_columns(&connection, table_name)?.contains(DELETED_AT_COLUMN)
+        };
+        let assignments = match deleted_at {
+            Some(deleted_at) => {
+                if !has_column {
+                    if !self.auto_migrate {
+                        anyhow::bail!("Table {} has no {} column and autoMigrate is off", table_name, DELETED_AT_COLUMN);
                     }
+                    self.ensure_table_schema_matches(table_name, &serde_json::json!({ DELETED_AT_COLUMN: deleted_at })).await?;
                 }
+                format!("{} = ?, {} = NULL", DELETED_AT_COLUMN, hash_column)
             }
+            None if !has_column => return Ok(0),
+            None => format!("{} = NULL", DELETED_AT_COLUMN),
+        };
+
+        let mut connection = self.connection.lock().await;
+        let transaction = connection.transaction()?;
+        let mut marked = 0;
+        for chunk in ids.chunks(ID_BATCH_SIZE) {
+            // Rows already marked keep the time they were first found missing
+            let sql = format!(
+                "UPDATE {} SET {} WHERE {} {} AND id IN ({})",
+                table_name,
+                assignments,
+                DELETED_AT_COLUMN,
+                if deleted_at.is_some() { "IS NULL" } else { "IS NOT NULL" },
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let values = deleted_at.into_iter().chain(chunk.iter().map(String::as_str));
+            marked += transaction.execute(&sql, rusqlite::params_from_iter(values))? as u64;
         }
+        transaction.commit()?;
+        Ok(marked)
+    }
+
+    async fn get_sync_state(&mut self, endpoint_name: &str) -> Result<Option<SyncState>> {
+        let connection = self.connection.lock().await;
+        let sql = format!(
+            "SELECT delta_link, watermark, etag, updated_at, last_error, last_error_at, last_full_sync_at
+             FROM {} WHERE endpoint_name = ?1",
+            SYNC_STATE_TABLE
+        );
+        let row = connection.query_row(&sql, [endpoint_name], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, Option<String>>(6)?,
+            ))
+        });
+        let parse_timestamp = |value: Option<String>| value
+            .and_then(|value| chrono::DateTime::parse_from_rfc3339(&value).ok())
+            .map(|value| value.with_timezone(&chrono::Utc));
+
+        match row {
+            Ok((delta_link, watermark, etag, updated_at, last_error, last_error_at, last_full_sync_at)) => Ok(Some(SyncState {
+                endpoint_name: endpoint_name.to_string(),
+                delta_link,
+                watermark,
+                etag,
+                updated_at: parse_timestamp(updated_at),
+                last_error,
+                last_error_at: parse_timestamp(last_error_at),
+                last_full_sync_at: parse_timestamp(last_full_sync_at),
+            })),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn set_sync_state(&mut self, state: &SyncState) -> Result<()> {
+        let connection = self.connection.lock().await;
+        let sql = format!(
+            "INSERT OR REPLACE INTO {} (endpoint_name, delta_link, watermark, etag, updated_at, last_error, last_error_at, last_full_sync_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            SYNC_STATE_TABLE
+        );
+        connection.execute(
+            &sql,
+            rusqlite::params![
+                state.endpoint_name,
+                state.delta_link,
+                state.watermark,
+                state.etag,
+                state.updated_at.map(|value| value.to_rfc3339()),
+                state.last_error,
+                state.last_error_at.map(|value| value.to_rfc3339()),
+                state.last_full_sync_at.map(|value| value.to_rfc3339()),
+            ],
+        )?;
+        Ok(())
+    }
 
-        log::debug!("Stored {} items in table {}", stored_count, table_name);
-        Ok(stored_count)
+    fn set_column_types(&mut self, table_name: &str, column_types: &HashMap<String, ColumnTypeOverride>) {
+        self.column_types.set("sqlite", table_name, column_types);
     }
 
     fn backend_name(&self) -> &'static str {
         "SQLite"
     }
 
+    /// The database runs in WAL mode, so recent writes live in the `-wal` file until
+    /// a checkpoint copies them into the main file
+    async fn checkpoint(&mut self) -> Result<()> {
+        let connection = self.connection.lock().await;
+        connection.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .context("WAL checkpoint failed")?;
+        Ok(())
+    }
+
+    async fn create_index_if_not_exists(&mut self, table_name: &str, columns: &[String]) -> Result<()> {
+        let connection = self.connection.lock().await;
+        let existing: std::collections::HashSet<String> = self.get_table_columns(&connection, table_name)?
+            .iter()
+            .map(|column| column.to_lowercase())
+            .collect();
+        if let Some(missing) = columns.iter().find(|column| !existing.contains(*column)) {
+            log::debug!("Not indexing {} yet - it has no column {}", table_name, missing);
+            return Ok(());
+        }
+
+        let name = super::index_name(table_name, columns);
+        let exists = connection
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?1")?
+            .exists([&name])?;
+        if exists {
+            return Ok(());
+        }
+
+        let sql = format!("CREATE INDEX IF NOT EXISTS {} ON {} ({})", name, table_name, columns.join(", "));
+        connection.execute(&sql, [])?;
+        log::info!("Created index {} on {}", name, table_name);
+        self.schema_changes.push(SchemaChange {
+            backend: "SQLite",
+            table: table_name.to_string(),
+            statement: sql,
+        });
+        Ok(())
+    }
+
+    fn take_schema_changes(&mut self) -> Vec<SchemaChange> {
+        std::mem::take(&mut self.schema_changes)
+    }
+
+    async fn run_maintenance(&mut self, tables: &[String]) -> Result<Option<i64>> {
+        let connection = self.connection.lock().await;
+        let size_before = Self::database_size(&connection)?;
+
+        let size_after = match &self.vacuum_into {
+            Some(target) => {
+                // VACUUM INTO refuses to overwrite, so build the copy next to the target and swap it in
+                let target = path_utils::resolve_path(target)
+                    .with_context(|| format!("Failed to resolve vacuumInto path: {}", target))?;
+                let staging = target.with_extension("vacuum.tmp");
+                if staging.exists() {
+                    std::fs::remove_file(&staging)
+                        .with_context(|| format!("Failed to remove leftover {}", staging.display()))?;
+                }
+                connection.execute("VACUUM INTO ?1", [staging.to_string_lossy()])
+                    .context("VACUUM INTO failed")?;
+                std::fs::rename(&staging, &target)
+                    .with_context(|| format!("Failed to move compacted copy to {}", target.display()))?;
+                log::info!("Wrote compacted copy of {} to {}", self.db_path, target.display());
+                std::fs::metadata(&target)?.len() as i64
+            }
+            None => {
+                connection.execute_batch("VACUUM").context("VACUUM failed")?;
+                Self::database_size(&connection)?
+            }
+        };
+
+        for table in tables {
+            let exists: bool = connection.query_row(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+                [table],
+                |row| row.get(0),
+            )?;
+            if exists {
+                connection.execute_batch(&format!("ANALYZE {}", table))
+                    .with_context(|| format!("ANALYZE failed for table {}", table))?;
+            }
+        }
+
+        Ok(Some(size_before - size_after))
+    }
+
+    async fn table_size_bytes(&mut self, _table_name: &str) -> Result<Option<i64>> {
+        let connection = self.connection.lock().await;
+        let size = connection.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(Some(size))
+    }
+
+    async fn drop_table(&mut self, table_name: &str) -> Result<()> {
+        let connection = self.connection.lock().await;
+        connection.execute(&format!("DROP TABLE IF EXISTS {}", table_name), [])?;
+        Ok(())
+    }
+
     async fn cleanup(&mut self) -> Result<()> {
         // SQLite connections are automatically closed when dropped
         // But we can explicitly close the connection for cleaner shutdown
@@ -363,6 +894,7 @@ impl StorageBackend for SqliteBackend {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::HASH_COLUMN;
     use tempfile::NamedTempFile;
 
     #[tokio::test]
@@ -370,7 +902,7 @@ mod tests {
         let temp_file = NamedTempFile::new().unwrap();
         let db_path = temp_file.path().to_str().unwrap();
         
-        let mut backend = SqliteBackend::new(db_path).await.unwrap();
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
         backend.initialize().await.unwrap();
 
         // Test health check
@@ -378,4 +910,542 @@ mod tests {
 
         // Test completed successfully
     }
+
+    #[tokio::test]
+    async fn test_query_devices() {
+        use crate::storage::DeviceSort;
+        use chrono::{TimeZone, Utc};
+
+        let mut backend = SqliteBackend::new(":memory:", ConnectRetry::default()).await.unwrap();
+        backend.initialize().await.unwrap();
+        backend.create_table_if_not_exists("devices", "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, last_sync_date_time TEXT)").await.unwrap();
+
+        let devices: Vec<serde_json::Value> = [
+            ("1", "LAPTOP-001", "Windows", "compliant", "Dell", "2024-01-10T08:00:00Z"),
+            ("2", "LAPTOP-002", "Windows", "noncompliant", "Lenovo", "2024-02-15T12:30:00Z"),
+            ("3", "MacBook-Pro", "macOS", "compliant", "Apple", "2024-03-01T09:00:00Z"),
+            ("4", "iPhone_15", "iOS", "compliant", "Apple", "2024-04-20T18:45:00Z"),
+            ("5", "DESKTOP-100%", "Windows", "compliant", "HP", "2023-12-31T23:59:59Z"),
+        ].iter().map(|(id, name, os, compliance, manufacturer, enrolled)| serde_json::json!({
+            "id": id,
+            "deviceName": name,
+            "operatingSystem": os,
+            "complianceState": compliance,
+            "manufacturer": manufacturer,
+            "enrolledDateTime": enrolled,
+        })).collect();
+        backend.store_endpoint_data("devices", &devices).await.unwrap();
+
+        async fn ids(backend: &mut SqliteBackend, query: DeviceQuery) -> (Vec<String>, u64) {
+            let (rows, total) = backend.query_devices("devices", &query).await.unwrap();
+            (rows.iter().map(|row| row["id"].as_str().unwrap().to_string()).collect(), total)
+        }
+
+        assert_eq!(ids(&mut backend, DeviceQuery::new()).await, (vec!["5".into(), "1".into(), "2".into(), "3".into(), "4".into()], 5));
+        assert_eq!(ids(&mut backend, DeviceQuery::new().os("WINDOWS")).await.1, 3);
+        assert_eq!(ids(&mut backend, DeviceQuery::new().compliance_state("noncompliant")).await.0, vec!["2"]);
+        assert_eq!(ids(&mut backend, DeviceQuery::new().manufacturer("apple")).await.0, vec!["3", "4"]);
+        assert_eq!(ids(&mut backend, DeviceQuery::new().name_contains("laptop")).await.0, vec!["1", "2"]);
+        // LIKE wildcards in the search text match literally
+        assert_eq!(ids(&mut backend, DeviceQuery::new().name_contains("_")).await.0, vec!["4"]);
+        assert_eq!(ids(&mut backend, DeviceQuery::new().name_contains("100%")).await.0, vec!["5"]);
+
+        let range = DeviceQuery::new()
+            .enrolled_after(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+            .enrolled_before(Utc.with_ymd_and_hms(2024, 3, 1, 9, 0, 0).unwrap())
+            .sort_by(DeviceSort::EnrolledDateTime, false);
+        assert_eq!(ids(&mut backend, range).await.0, vec!["1", "2"]);
+
+        // Paging keeps the total of the whole result
+        let page = DeviceQuery::new().sort_by(DeviceSort::EnrolledDateTime, true).limit(2).offset(1);
+        assert_eq!(ids(&mut backend, page).await, (vec!["3".into(), "2".into()], 5));
+        assert_eq!(ids(&mut backend, DeviceQuery::new().offset(10)).await, (vec![], 5));
+
+        // A table that has not been synced yet holds no devices
+        let (rows, total) = backend.query_devices("users", &DeviceQuery::new()).await.unwrap();
+        assert!(rows.is_empty() && total == 0);
+    }
+
+    #[tokio::test]
+    async fn test_stale_row_ids() {
+        use chrono::Utc;
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.initialize().await.unwrap();
+        let cutoff = Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        assert!(backend.stale_row_ids("devices", cutoff).await.unwrap().is_empty());
+
+        backend.create_table_if_not_exists("devices", "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, last_sync_date_time TEXT, device_hash TEXT)").await.unwrap();
+        backend.store_endpoint_data("devices", &[
+            serde_json::json!({"id": "1", "lastSyncDateTime": "2024-05-02T10:00:00Z"}),
+            serde_json::json!({"id": "2", "lastSyncDateTime": "2024-04-30T10:00:00.123Z"}),
+            serde_json::json!({"id": "3", "lastSyncDateTime": NEVER_CHECKED_IN}),
+            serde_json::json!({"id": "4"}),
+        ]).await.unwrap();
+
+        assert_eq!(backend.stale_row_ids("devices", cutoff).await.unwrap(), vec!["2"]);
+
+        // A row marked deleted is not reported again
+        backend.mark_rows_deleted("devices", HASH_COLUMN, &["2".to_string()], Some("2024-05-01T00:00:00Z")).await.unwrap();
+        assert!(backend.stale_row_ids("devices", cutoff).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_timestamps() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.initialize().await.unwrap();
+        backend.create_table_if_not_exists("devices", "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, last_sync_date_time TEXT)").await.unwrap();
+
+        let before = chrono::Utc::now();
+        backend.store_endpoint_data("devices", &[
+            serde_json::json!({"id": "1", "deviceName": "PC-01", "lastSyncDateTime": "2024-05-01T10:00:00Z"}),
+            serde_json::json!({"id": "2", "deviceName": "PC-02"}),
+        ]).await.unwrap();
+
+        let connection = Connection::open(db_path).unwrap();
+        let rows: Vec<(String, Option<String>, String, Option<String>)> = connection
+            .prepare("SELECT id, last_sync_date_time, synced_at, lastsyncdatetime FROM devices ORDER BY id").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+
+        // The device's own check-in time, not the time we stored it
+        let (_, last_sync, synced_at, graph_last_sync) = &rows[0];
+        assert_eq!(last_sync, graph_last_sync);
+        assert!(last_sync.as_deref().unwrap().starts_with("2024-05-01"));
+        let synced_at = chrono::DateTime::parse_from_rfc3339(synced_at).unwrap();
+        assert!(synced_at >= before);
+        assert_ne!(last_sync.as_deref(), Some(synced_at.to_rfc3339().as_str()));
+
+        // Without a Graph value the column stays empty rather than holding the sync time
+        assert_eq!(rows[1].1, None);
+        assert!(!rows[1].2.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_index_if_not_exists() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        let schema = backend.default_table_schema("devices");
+        backend.create_table_if_not_exists("devices", &schema).await.unwrap();
+        // Creating the table is recorded, verifying it again is not
+        let changes = backend.take_schema_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].statement, schema.trim());
+        backend.create_table_if_not_exists("devices", &schema).await.unwrap();
+        assert!(backend.take_schema_changes().is_empty());
+
+        // The column does not exist yet, so nothing happens until data adds it
+        let columns = vec!["compliancestate".to_string(), "manufacturer".to_string()];
+        backend.create_index_if_not_exists("devices", &columns).await.unwrap();
+        assert!(backend.take_schema_changes().is_empty());
+
+        backend.store_endpoint_data("devices", &[serde_json::json!({
+            "id": "device-1", "complianceState": "compliant", "manufacturer": "Contoso"
+        })]).await.unwrap();
+        backend.take_schema_changes();
+
+        backend.create_index_if_not_exists("devices", &columns).await.unwrap();
+        backend.create_index_if_not_exists("devices", &columns).await.unwrap();
+        let changes = backend.take_schema_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            changes[0].statement,
+            "CREATE INDEX IF NOT EXISTS idx_devices_compliancestate_manufacturer ON devices (compliancestate, manufacturer)"
+        );
+
+        let connection = backend.connection.lock().await;
+        let exists = connection
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'idx_devices_compliancestate_manufacturer'")
+            .unwrap()
+            .exists([])
+            .unwrap();
+        assert!(exists);
+    }
+
+    #[tokio::test]
+    async fn test_preview_schema_changes() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+
+        let schema = "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, last_sync_date_time TEXT)";
+        let sample = serde_json::json!({"id": "device-1", "deviceName": "PC-01", "isEncrypted": true});
+
+        // A missing table is created first, then given the sample's columns
+        let statements = backend.preview_schema_changes("devices", schema, &sample).await.unwrap();
+        assert_eq!(statements, vec![
+            schema.to_string(),
+            "ALTER TABLE devices ADD COLUMN devicename TEXT".to_string(),
+            "ALTER TABLE devices ADD COLUMN isencrypted INTEGER".to_string(),
+            "ALTER TABLE devices ADD COLUMN synced_at DATETIME".to_string(),
+        ]);
+        assert!(backend.get_table_count("devices").await.is_err(), "preview must not create the table");
+
+        backend.create_table_if_not_exists("devices", schema).await.unwrap();
+        backend.store_endpoint_data("devices", std::slice::from_ref(&sample)).await.unwrap();
+        assert!(backend.preview_schema_changes("devices", schema, &sample).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_column_type_overrides() {
+        use crate::endpoint::ColumnTypeOverride;
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let mut backend = SqliteBackend::new(temp_file.path().to_str().unwrap(), ConnectRetry::default()).await.unwrap();
+        let column_types = HashMap::from([
+            ("serialNumber".to_string(), ColumnTypeOverride::All("TEXT".to_string())),
+            ("enrolledDateTime".to_string(), ColumnTypeOverride::PerBackend(
+                [("postgres".to_string(), "TIMESTAMPTZ".to_string())].into(),
+            )),
+            ("model".to_string(), ColumnTypeOverride::All("TEXT; DROP TABLE devices".to_string())),
+        ]);
+        backend.set_column_types("devices", &column_types);
+
+        let schema = "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY)";
+        let sample = serde_json::json!({"id": "device-1", "serialNumber": 12345, "enrolledDateTime": "2024-05-01T10:00:00Z", "model": 7});
+        let statements = backend.preview_schema_changes("devices", schema, &sample).await.unwrap();
+        // The override wins over the inferred INTEGER; a type for another backend or an
+        // invalid one leaves the column to inference
+        assert!(statements.contains(&"ALTER TABLE devices ADD COLUMN serialnumber TEXT".to_string()));
+        assert!(statements.contains(&"ALTER TABLE devices ADD COLUMN enrolleddatetime DATETIME".to_string()));
+        assert!(statements.contains(&"ALTER TABLE devices ADD COLUMN model INTEGER".to_string()));
+
+        // Other tables keep inferring
+        assert!(backend.preview_schema_changes("users", "CREATE TABLE IF NOT EXISTS users (id TEXT PRIMARY KEY)", &sample)
+            .await.unwrap()
+            .contains(&"ALTER TABLE users ADD COLUMN serialnumber INTEGER".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_schema_sample_size() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let mut backend = SqliteBackend::new(temp_file.path().to_str().unwrap(), ConnectRetry::default()).await.unwrap()
+            .with_schema_sample_size(2);
+        backend.create_table_if_not_exists("devices", "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY)").await.unwrap();
+        let data = vec![
+            serde_json::json!({"id": "1", "assetTag": 1001, "storage": null}),
+            serde_json::json!({"id": "2", "assetTag": "A-1002", "storage": 64.5}),
+            serde_json::json!({"id": "3", "assetTag": 1003, "storage": 128, "wipe": true}),
+        ];
+        assert_eq!(backend.store_endpoint_data("devices", &data).await.unwrap().stored(), 3);
+
+        let connection = backend.connection.lock().await;
+        let column_type = |column: &str| -> String {
+            connection.query_row("SELECT type FROM pragma_table_info('devices') WHERE name = ?1", [column], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(column_type("assettag"), "TEXT");
+        assert_eq!(column_type("storage"), "REAL");
+        // Outside the sample, added when its record is stored
+        assert_eq!(column_type("wipe"), "INTEGER");
+    }
+
+    #[tokio::test]
+    async fn test_store_without_auto_migrate() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap()
+            .with_auto_migrate(false, MissingColumnPolicy::Drop);
+
+        let item = serde_json::json!({"id": "device-1", "deviceName": "PC-01", "model": "Surface"});
+        assert!(backend.store_endpoint_data("devices", std::slice::from_ref(&item)).await.is_err());
+
+        backend.create_table_if_not_exists(
+            "devices",
+            "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, devicename TEXT, last_sync_date_time TEXT, synced_at TEXT)",
+        ).await.unwrap();
+        assert_eq!(backend.store_endpoint_data("devices", std::slice::from_ref(&item)).await.unwrap().stored(), 1);
+        {
+            let connection = backend.connection.lock().await;
+            assert!(!backend.get_table_columns(&connection, "devices").unwrap().contains("model"));
+        }
+
+        let mut backend = backend.with_auto_migrate(false, MissingColumnPolicy::Fail);
+        let err = backend.store_endpoint_data("devices", &[item]).await.unwrap_err();
+        assert!(err.to_string().contains("model"));
+    }
+
+    #[tokio::test]
+    async fn test_store_without_synced_at_column() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap()
+            .with_auto_migrate(false, MissingColumnPolicy::Fail);
+
+        // A table created before synced_at was introduced
+        backend.create_table_if_not_exists(
+            "devices",
+            "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, devicename TEXT, last_sync_date_time TEXT)",
+        ).await.unwrap();
+        let item = serde_json::json!({"id": "device-1", "deviceName": "PC-01"});
+        assert_eq!(backend.store_endpoint_data("devices", &[item]).await.unwrap().stored(), 1);
+        let connection = backend.connection.lock().await;
+        assert!(!backend.get_table_columns(&connection, "devices").unwrap().contains("synced_at"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_state_round_trip() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.initialize().await.unwrap();
+        assert_eq!(backend.get_sync_state("devices").await.unwrap(), None);
+
+        let updated_at = chrono::Utc.timestamp_opt(1_714_557_600, 0).unwrap();
+        let mut state = SyncState {
+            endpoint_name: "devices".to_string(),
+            watermark: Some("2024-05-01T10:00:00+00:00".to_string()),
+            etag: Some("W/\"abc\"".to_string()),
+            updated_at: Some(updated_at),
+            ..Default::default()
+        };
+        backend.set_sync_state(&state).await.unwrap();
+        assert_eq!(backend.get_sync_state("devices").await.unwrap(), Some(state.clone()));
+
+        // Saving again replaces the row
+        state.watermark = Some("2024-05-02T10:00:00+00:00".to_string());
+        state.last_error = Some("403 Forbidden".to_string());
+        state.last_error_at = Some(updated_at);
+        state.last_full_sync_at = Some(updated_at);
+        backend.set_sync_state(&state).await.unwrap();
+        assert_eq!(backend.get_sync_state("devices").await.unwrap(), Some(state.clone()));
+        assert_eq!(backend.get_table_count(SYNC_STATE_TABLE).await.unwrap(), 1);
+
+        // Links are kept per endpoint
+        let users = SyncState {
+            endpoint_name: "users".to_string(),
+            delta_link: Some("https://graph.microsoft.com/v1.0/users/delta?$deltatoken=abc".to_string()),
+            updated_at: Some(updated_at),
+            ..Default::default()
+        };
+        backend.set_sync_state(&users).await.unwrap();
+        assert_eq!(backend.get_sync_state("users").await.unwrap(), Some(users));
+        assert_eq!(backend.get_sync_state("devices").await.unwrap(), Some(state));
+        assert_eq!(backend.get_table_count(SYNC_STATE_TABLE).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_all_hashes() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.create_table_if_not_exists(
+            "hash_test",
+            "CREATE TABLE IF NOT EXISTS hash_test (id TEXT PRIMARY KEY, last_sync_date_time TEXT)",
+        ).await.unwrap();
+
+        // No hash column yet - should return an empty map rather than fail
+        assert!(backend.get_all_hashes("hash_test", HASH_COLUMN).await.unwrap().is_empty());
+
+        let data = vec![
+            serde_json::json!({"id": "a", "deviceName": "one", "device_hash": "hash-a"}),
+            serde_json::json!({"id": "b", "deviceName": "two", "device_hash": "hash-b"}),
+        ];
+        backend.store_endpoint_data("hash_test", &data).await.unwrap();
+
+        let hashes = backend.get_all_hashes("hash_test", HASH_COLUMN).await.unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes.get("a").map(String::as_str), Some("hash-a"));
+        assert_eq!(hashes.get("b").map(String::as_str), Some("hash-b"));
+    }
+
+    #[tokio::test]
+    async fn test_store_batch_in_one_transaction() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap()
+            .with_schema_sample_size(1);
+        backend.create_table_if_not_exists("devices", &backend.default_table_schema("devices")).await.unwrap();
+        backend.store_endpoint_data("devices", &[serde_json::json!({"id": "seed", "deviceName": "PC"})]).await.unwrap();
+
+        let commits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&commits);
+        backend.connection.lock().await.commit_hook(Some(move || {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            false
+        }));
+
+        let mut data: Vec<serde_json::Value> = (0..5000)
+            .map(|i| serde_json::json!({"id": format!("device-{}", i), "deviceName": format!("PC-{}", i)}))
+            .collect();
+        assert_eq!(
+            backend.store_endpoint_data("devices", &data).await.unwrap(),
+            ChangeCounts { inserted: 5000, updated: 0, skipped: 0 }
+        );
+        assert_eq!(backend.get_table_count("devices").await.unwrap(), 5001);
+        assert_eq!(commits.swap(0, std::sync::atomic::Ordering::SeqCst), 1);
+
+        // A column the sample did not have fails the last row, so the column is added
+        // and the batch is written again, counted once
+        data.push(serde_json::json!({"id": "late", "deviceName": "PC-late", "serialNumber": "123"}));
+        assert_eq!(
+            backend.store_endpoint_data("devices", &data).await.unwrap(),
+            ChangeCounts { inserted: 1, updated: 5000, skipped: 0 }
+        );
+        assert_eq!(backend.get_table_count("devices").await.unwrap(), 5002);
+        assert_eq!(commits.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_store_keeps_merged_columns() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.create_table_if_not_exists("devices", &backend.default_table_schema("devices")).await.unwrap();
+        backend.store_endpoint_data("devices", &[
+            serde_json::json!({"id": "a", "deviceName": "one", "device_hash": "hash-a"}),
+        ]).await.unwrap();
+
+        // A merging endpoint only writes its own columns of the row
+        backend.store_endpoint_data("devices", &[
+            serde_json::json!({"id": "a", "complianceState": "compliant", "device_hash_compliance": "merged-a"}),
+        ]).await.unwrap();
+        assert_eq!(backend.get_table_count("devices").await.unwrap(), 1);
+        assert_eq!(backend.get_all_hashes("devices", HASH_COLUMN).await.unwrap().get("a").map(String::as_str), Some("hash-a"));
+        assert_eq!(
+            backend.get_all_hashes("devices", "device_hash_compliance").await.unwrap().get("a").map(String::as_str),
+            Some("merged-a")
+        );
+
+        // Rewriting the row keeps the merged columns too
+        backend.store_endpoint_data("devices", &[
+            serde_json::json!({"id": "a", "deviceName": "renamed", "device_hash": "hash-a2"}),
+        ]).await.unwrap();
+        assert_eq!(backend.get_all_hashes("devices", HASH_COLUMN).await.unwrap().get("a").map(String::as_str), Some("hash-a2"));
+        assert_eq!(backend.get_all_hashes("devices", "device_hash_compliance").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_store_sanitizes_graph_keys() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.create_table_if_not_exists(
+            "users",
+            "CREATE TABLE IF NOT EXISTS users (id TEXT PRIMARY KEY, last_sync_date_time TEXT)",
+        ).await.unwrap();
+
+        let data = vec![serde_json::json!({
+            "id": "user-1",
+            "@odata.type": "#microsoft.graph.user",
+            "displayName": "Ada",
+            "extension_39c8b2e1_costCenter": "CC-42",
+            "Employee Number": "1001",
+        })];
+        assert_eq!(backend.store_endpoint_data("users", &data).await.unwrap().stored(), 1);
+
+        let connection = backend.connection.lock().await;
+        let columns = backend.get_table_columns(&connection, "users").unwrap();
+        for expected in ["_odata_type", "displayname", "extension_39c8b2e1_costcenter", "employee_number"] {
+            assert!(columns.contains(expected), "missing column {}", expected);
+        }
+
+        let cost_center: String = connection
+            .query_row("SELECT extension_39c8b2e1_costcenter FROM users WHERE id = 'user-1'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(cost_center, "CC-42");
+    }
+
+    #[tokio::test]
+    async fn test_excluded_fields_produce_no_column() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.create_table_if_not_exists(
+            "devices",
+            "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, last_sync_date_time TEXT)",
+        ).await.unwrap();
+
+        let endpoint = crate::endpoint::EndpointConfig {
+            exclude_fields: vec!["emailAddress".to_string(), "imei".to_string()],
+            ..Default::default()
+        };
+        let mut item = serde_json::json!({
+            "id": "device-1",
+            "deviceName": "PC-01",
+            "emailAddress": "ada@example.com",
+            "imei": "490154203237518",
+        });
+        crate::endpoint::apply_field_selection(&endpoint, &mut item);
+        backend.store_endpoint_data("devices", &[item]).await.unwrap();
+
+        let connection = backend.connection.lock().await;
+        let columns = backend.get_table_columns(&connection, "devices").unwrap();
+        assert!(columns.contains("devicename"));
+        assert!(!columns.contains("emailaddress"));
+        assert!(!columns.contains("imei"));
+    }
+
+    #[tokio::test]
+    async fn test_hashed_fields_stored_as_tokens() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.create_table_if_not_exists(
+            "devices",
+            "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, last_sync_date_time TEXT)",
+        ).await.unwrap();
+
+        let endpoint = crate::endpoint::EndpointConfig {
+            hash_fields: vec!["userPrincipalName".to_string()],
+            ..Default::default()
+        };
+        let mut item = serde_json::json!({
+            "id": "device-1",
+            "userPrincipalName": "ada@example.com",
+        });
+        crate::endpoint::apply_field_hashing(&endpoint, &mut item, "salt");
+        backend.store_endpoint_data("devices", &[item]).await.unwrap();
+
+        let connection = backend.connection.lock().await;
+        let stored: String = connection
+            .query_row("SELECT userprincipalname FROM devices WHERE id = 'device-1'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, crate::fingerprint::pseudonymize_value("ada@example.com", "salt"));
+        assert!(!stored.contains("ada"));
+    }
+
+    #[tokio::test]
+    async fn test_run_maintenance() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db_path = temp_file.path().to_str().unwrap();
+        let copy_dir = tempfile::tempdir().unwrap();
+        let copy_path = copy_dir.path().join("compact.db");
+
+        let mut backend = SqliteBackend::new(db_path, ConnectRetry::default()).await.unwrap();
+        backend.create_table_if_not_exists(
+            "devices",
+            "CREATE TABLE IF NOT EXISTS devices (id TEXT PRIMARY KEY, last_sync_date_time TEXT)",
+        ).await.unwrap();
+        let data: Vec<_> = (0..50)
+            .map(|i| serde_json::json!({"id": i.to_string(), "notes": "x".repeat(2000)}))
+            .collect();
+        backend.store_endpoint_data("devices", &data).await.unwrap();
+        backend.connection.lock().await.execute("DELETE FROM devices", []).unwrap();
+
+        // Tables that do not exist yet are skipped
+        let tables = vec!["devices".to_string(), "groups".to_string()];
+        let reclaimed = backend.run_maintenance(&tables).await.unwrap().unwrap();
+        assert!(reclaimed > 0, "in-place VACUUM should free the deleted rows");
+
+        // A compacted copy can be written (and rewritten) without touching the database
+        backend = backend.with_vacuum_into(Some(copy_path.to_string_lossy().to_string()));
+        backend.run_maintenance(&tables).await.unwrap();
+        backend.run_maintenance(&tables).await.unwrap();
+        assert!(copy_path.exists());
+        let copy = Connection::open(&copy_path).unwrap();
+        let count: i64 = copy.query_row("SELECT COUNT(*) FROM devices", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
 }
@@ -1,17 +1,33 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use log::{error, info, warn, debug};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::Duration;
-use tokio::time::{interval, sleep};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::{interval_at, sleep, MissedTickBehavior};
 
+use crate::audit::{AuditEntry, AuditEvent, AuditLog};
 use crate::auth::AuthClient;
-use crate::config::AppConfig;
-use crate::endpoint::{EndpointManager, EndpointConfig};
+use crate::backup::{BackupType, SqliteBackupManager};
+use crate::capture::{ResponseRecorder, ResponseReplay};
+use crate::circuit_breaker::{CircuitBreaker, CircuitState, LastError, SharedCircuits};
+use crate::config::{AppConfig, OverlapPolicy, UuidCollisionPolicy};
+use crate::endpoint::{
+    apply_field_hashing, apply_field_selection, incremental_filter, max_incremental_value, preprocess_odata_record,
+    DeletionMode, EndpointManager, EndpointConfig, SyncMode,
+};
 use crate::filter::DeviceOsFilter;
+use crate::fingerprint::{apply_field_normalization, calculate_device_hash, FieldNormalization};
 use crate::metrics;
-use crate::storage::StorageManager;
-use crate::uuid_utils::{get_device_name, get_device_os};
+use crate::rate_limiter::{RequestBudgetExhausted, RetryBudgetExhausted};
+use crate::storage::{sanitize_column_name, ChangeCounts, StorageManager, StorageResult, SyncState, HASH_COLUMN, NEVER_CHECKED_IN, RAW_JSON_COLUMN};
+use crate::transform::Transform;
+use crate::uuid_utils::{get_device_name, get_device_os, get_or_generate_device_uuid, UuidScheme};
+use crate::webhook::{DeletesRefusedData, SyncAbortedData, WebhookManager};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct GraphDeviceResponse {
@@ -22,318 +38,2714 @@ struct GraphDeviceResponse {
     value: Vec<serde_json::Value>,
 }
 
-pub struct SyncService {
-    config: AppConfig,
-    auth_client: AuthClient,
-    storage: StorageManager,
-    os_filter: DeviceOsFilter,
-    endpoint_manager: EndpointManager,
-}
-
-impl SyncService {
-    pub async fn new(config: AppConfig) -> Result<Self> {
-        log::debug!("Creating auth client");
-        let auth_client = AuthClient::new(config.clone());
-        log::debug!("Creating storage manager");
-        let mut storage = StorageManager::new(&config.database).await?;
-        log::debug!("Initializing storage");
-        storage.initialize().await?;
-        log::debug!("Storage initialized");
+/// Why rows found by a complete fetch or delta query are deleted, for the log
+const MISSING_ROWS: &str = "that Graph no longer returns";
 
-        log::debug!("Creating OS filter");
-        let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+/// What an endpoint sync fetched from Graph, before any filter, and what it wrote
+#[derive(Debug, Default, Clone, Copy)]
+struct EndpointCounts {
+    fetched: usize,
+    changes: ChangeCounts,
+}
 
-        // Get endpoints configuration
-        log::debug!("Getting endpoints configuration");
-        let endpoints_config = config.get_endpoints_config();
-        log::debug!("Validating endpoints configuration");
-        endpoints_config.validate().context("Invalid endpoints configuration")?;
-        log::debug!("Endpoints configuration validated");
+/// Stored rows Graph no longer returns, which reconciliation would delete
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeletionReport {
+    /// Rows of the endpoint stored before the sync
+    pub stored: usize,
+    /// Ids of the stored rows missing from the fetch, sorted
+    pub missing: Vec<String>,
+    /// Names of the missing rows that have one, by id. Only looked up for a dry run.
+    pub names: HashMap<String, String>,
+}
 
-        log::debug!("Creating endpoint manager");
-        let endpoint_manager = EndpointManager::new(endpoints_config, auth_client.clone(), config.mock_graph_api.clone(), config.rate_limit.clone());
-        log::debug!("Endpoint manager created");
+impl DeletionReport {
+    /// Rows shown in the log
+    const SAMPLE_SIZE: usize = 10;
 
-        info!("Sync service initialized with backends: {:?}", storage.get_backend_names());
-        info!("OS filter configured: {:?}", os_filter.get_filters());
-        info!("Endpoints configured: {:?}", endpoint_manager.get_enabled_endpoints().iter().map(|e| &e.name).collect::<Vec<_>>());
+    /// Compare the ids stored for an endpoint with the records of a complete fetch
+    pub fn new<'a>(stored_ids: impl Iterator<Item = &'a String>, fetched: &[serde_json::Value]) -> Self {
+        let seen: HashSet<&str> = fetched.iter()
+            .filter_map(|record| record.get("id").and_then(|id| id.as_str()))
+            .collect();
+        let mut stored = 0;
+        let mut missing: Vec<String> = stored_ids
+            .inspect(|_| stored += 1)
+            .filter(|id| !seen.contains(id.as_str()))
+            .cloned()
+            .collect();
+        missing.sort();
+        Self { stored, missing, ..Default::default() }
+    }
 
-        Ok(Self {
-            config,
-            auth_client,
-            storage,
-            os_filter,
-            endpoint_manager,
-        })
+    /// The stored rows among the records a delta query reported as removed
+    pub fn removed(stored: &HashMap<String, String>, removed_ids: &[String]) -> Self {
+        let mut missing: Vec<String> = removed_ids.iter()
+            .filter(|id| stored.contains_key(*id))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        Self { stored: stored.len(), missing, ..Default::default() }
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        info!("Starting sync service with interval: {:?}", self.config.poll_interval);
+    /// Share of the stored rows that would be deleted, in percent
+    pub fn percent(&self) -> f64 {
+        if self.stored == 0 {
+            0.0
+        } else {
+            self.missing.len() as f64 * 100.0 / self.stored as f64
+        }
+    }
 
-        // Parse poll interval
-        let poll_duration = self.config.parse_poll_interval()
-            .context("Failed to parse poll interval")?;
+    /// Whether the deletions are more than `maxDeletePercent` allows
+    pub fn exceeds(&self, max_delete_percent: Option<f64>) -> bool {
+        max_delete_percent.is_some_and(|max| self.percent() > max)
+    }
 
-        let mut interval_timer = interval(poll_duration);
+    /// A missing row as shown in the log: its name and id, or only its id
+    fn label(&self, id: &str) -> String {
+        match self.names.get(id) {
+            Some(name) => format!("{} ({})", name, id),
+            None => id.to_string(),
+        }
+    }
 
-        loop {
-            interval_timer.tick().await;
+    /// Write every missing row to `path` as NDJSON, one `{"id", "name"}` object per line
+    pub fn write_list(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let rows: Vec<serde_json::Value> = self.missing.iter()
+            .map(|id| serde_json::json!({ "id": id, "name": self.names.get(id) }))
+            .collect();
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write_ndjson(&mut file, &rows)?;
+        file.flush()?;
+        Ok(())
+    }
 
-            if let Err(e) = self.sync_all_endpoints().await {
-                error!("Sync operation failed: {}", e);
-                metrics::SYNC_FAILURE_TOTAL.inc();
+    /// Log what a sync of `endpoint` would delete, and why. `list` is the file
+    /// holding every row, if it was written.
+    fn log(&self, endpoint: &EndpointConfig, reason: &str, max_delete_percent: Option<f64>, list: Option<&Path>) {
+        if self.missing.is_empty() {
+            info!("Dry run: sync of endpoint {} would not delete any of {} rows", endpoint.name, self.stored);
+            return;
+        }
 
-                // Wait a bit before retrying
-                sleep(Duration::from_secs(30)).await;
-            }
+        let sample = self.missing.iter().take(Self::SAMPLE_SIZE).map(|id| self.label(id)).collect::<Vec<_>>().join(", ");
+        let more = self.missing.len().saturating_sub(Self::SAMPLE_SIZE);
+        let sample = if more > 0 { format!("{} and {} more", sample, more) } else { sample };
+        let sample = match list {
+            Some(list) => format!("{} (full list in {})", sample, list.display()),
+            None => sample,
+        };
+        if self.exceeds(max_delete_percent) {
+            warn!(
+                "Dry run: sync of endpoint {} would delete {} of {} rows ({:.1}%) {}, more than maxDeletePercent allows - \
+                 the deletes would be refused: {}",
+                endpoint.name, self.missing.len(), self.stored, self.percent(), reason, sample
+            );
+        } else {
+            info!(
+                "Dry run: sync of endpoint {} would delete {} of {} rows ({:.1}%) {}: {}",
+                endpoint.name, self.missing.len(), self.stored, self.percent(), reason, sample
+            );
         }
     }
+}
 
-    async fn sync_all_endpoints(&mut self) -> Result<()> {
-        let sync_timer = metrics::Timer::new();
-        info!("Starting multi-endpoint sync operation");
+/// Whether a complete fetch of `fetched` records is implausibly small next to the `stored`
+/// rows of its endpoint, which usually means Graph returned only part of the data
+fn implausible_shrink(fetched: usize, stored: usize, min_expected_ratio: f64) -> bool {
+    stored > 0 && (fetched as f64) < stored as f64 * min_expected_ratio
+}
 
-        let enabled_endpoints: Vec<_> = self.endpoint_manager.get_enabled_endpoints()
-            .into_iter()
-            .cloned()
-            .collect();
+/// Hash each record, tag it with its hash and compare against the hashes already stored.
+/// Only new or changed records are returned for writing.
+pub fn detect_changes(
+    data: Vec<serde_json::Value>,
+    existing_hashes: &HashMap<String, String>,
+) -> (Vec<serde_json::Value>, ChangeCounts) {
+    detect_changes_in(data, existing_hashes, HASH_COLUMN)
+}
 
-        if enabled_endpoints.is_empty() {
-            warn!("No endpoints are enabled for synchronization");
-            return Ok(());
-        }
+/// Like `detect_changes`, tagging each record with its hash in `hash_column`
+pub fn detect_changes_in(
+    data: Vec<serde_json::Value>,
+    existing_hashes: &HashMap<String, String>,
+    hash_column: &str,
+) -> (Vec<serde_json::Value>, ChangeCounts) {
+    let mut counts = ChangeCounts::default();
+    let mut changed = Vec::with_capacity(data.len());
 
-        let mut total_processed = 0;
-        let mut total_errors = 0;
+    for mut item in data {
+        let result = match item.as_object_mut() {
+            Some(obj) => {
+                obj.remove(hash_column);
+                let fields: HashMap<String, serde_json::Value> = obj.iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                let hash = calculate_device_hash(&fields);
 
-        for endpoint in enabled_endpoints {
-            match self.sync_endpoint(&endpoint).await {
-                Ok(processed) => {
-                    total_processed += processed;
-                    info!("Successfully synced {} items from endpoint: {}", processed, endpoint.name);
-                }
-                Err(e) => {
-                    error!("Failed to sync endpoint {}: {}", endpoint.name, e);
-                    total_errors += 1;
-                }
+                let id = obj.get("id").map(|v| match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                });
+
+                let result = match id.as_ref().and_then(|id| existing_hashes.get(id)) {
+                    Some(existing) if *existing == hash => StorageResult::Skipped,
+                    Some(_) => StorageResult::Updated,
+                    None => StorageResult::Inserted,
+                };
+
+                obj.insert(hash_column.to_string(), serde_json::Value::String(hash));
+                result
             }
+            // Non-object items cannot be hashed by id, so always write them
+            None => StorageResult::Inserted,
+        };
 
-            // Small delay between endpoints to avoid rate limiting
-            sleep(Duration::from_millis(500)).await;
+        counts.record(&result);
+        if !matches!(result, StorageResult::Skipped) {
+            changed.push(item);
         }
+    }
 
-        let duration = sync_timer.start.elapsed();
-        sync_timer.observe_duration(&metrics::SYNC_DURATION_SECONDS);
+    (changed, counts)
+}
 
-        if total_errors == 0 {
-            metrics::SYNC_SUCCESS_TOTAL.inc();
-        } else {
-            metrics::SYNC_FAILURE_TOTAL.inc();
-        }
+/// Resolve records of one fetch that share an id, which would otherwise overwrite
+/// each other when stored. Each collision is logged with both device names and counted;
+/// records without an id are passed through.
+pub fn resolve_id_collisions(
+    endpoint_name: &str,
+    data: Vec<serde_json::Value>,
+    policy: UuidCollisionPolicy,
+    name_fields: &[String],
+) -> Vec<serde_json::Value> {
+    let mut resolved: Vec<serde_json::Value> = Vec::with_capacity(data.len());
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut suffixes: HashMap<String, usize> = HashMap::new();
 
-        info!(
-            "Multi-endpoint sync completed: {} items processed, {} errors, duration: {:?}",
-            total_processed, total_errors, duration
+    for mut item in data {
+        let Some(id) = record_id(&item) else {
+            resolved.push(item);
+            continue;
+        };
+        let Some(&position) = positions.get(&id) else {
+            positions.insert(id, resolved.len());
+            resolved.push(item);
+            continue;
+        };
+
+        warn!(
+            "Endpoint {}: devices '{}' and '{}' share id {}, applying {:?}",
+            endpoint_name,
+            record_name(&resolved[position], name_fields),
+            record_name(&item, name_fields),
+            id,
+            policy
         );
+        metrics::DEVICE_UUID_COLLISIONS_TOTAL.with_label_values(&[endpoint_name]).inc();
 
-        Ok(())
+        match policy {
+            UuidCollisionPolicy::FirstWins => {}
+            UuidCollisionPolicy::LastWins => resolved[position] = item,
+            UuidCollisionPolicy::StoreBothWithSuffix => {
+                let suffix = suffixes.entry(id.clone()).or_insert(1);
+                let new_id = loop {
+                    *suffix += 1;
+                    let candidate = format!("{}-{}", id, suffix);
+                    if !positions.contains_key(&candidate) {
+                        break candidate;
+                    }
+                };
+                item["id"] = serde_json::Value::String(new_id.clone());
+                positions.insert(new_id, resolved.len());
+                resolved.push(item);
+            }
+        }
     }
 
-    async fn sync_endpoint(&mut self, endpoint: &EndpointConfig) -> Result<usize> {
-        info!("Syncing endpoint: {} -> {}", endpoint.name, endpoint.table_name);
+    resolved
+}
 
-        // Ensure table exists for this endpoint
-        self.ensure_endpoint_table_exists(endpoint).await?;
+fn record_id(item: &serde_json::Value) -> Option<String> {
+    item.as_object()?.get("id").map(|v| match v {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    })
+}
 
-        // Fetch data from the endpoint
-        let data = self.endpoint_manager.fetch_all_endpoint_data(endpoint).await?;
-        info!("Fetched {} items from endpoint: {}", data.len(), endpoint.name);
+fn record_name(item: &serde_json::Value, name_fields: &[String]) -> String {
+    let fields: HashMap<String, serde_json::Value> = item.as_object()
+        .map(|obj| obj.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+        .unwrap_or_default();
+    get_device_name(&fields, name_fields)
+}
 
-        if data.is_empty() {
-            return Ok(0);
+/// Number of poll ticks that came due while a sync of the given duration was running
+pub fn overlapped_ticks(sync_duration: Duration, poll_interval: Duration) -> u64 {
+    if poll_interval.is_zero() {
+        return 0;
+    }
+    (sync_duration.as_nanos() / poll_interval.as_nanos()) as u64
+}
+
+/// When scheduled syncs come due: every `pollInterval`, or at the times of `cronSchedule`
+enum SyncSchedule {
+    Interval {
+        timer: tokio::time::Interval,
+        period: Duration,
+    },
+    Cron {
+        schedule: cron::Schedule,
+        expression: String,
+        /// When the next sync is due; `None` once the schedule has no more times
+        next: Option<DateTime<Utc>>,
+        queue: bool,
+    },
+}
+
+impl SyncSchedule {
+    /// With syncOnStartup off the first sync waits a full interval, or for the first
+    /// scheduled time, so a fleet-wide deploy does not make every instance hit Graph at
+    /// the same moment
+    fn new(config: &AppConfig) -> Result<Self> {
+        if let Some(schedule) = config.parse_cron_schedule()? {
+            let expression = config.cron_schedule.clone().unwrap_or_default();
+            let next = if config.sync_on_startup {
+                Some(Utc::now())
+            } else {
+                schedule.upcoming(Utc).next()
+            };
+            if !config.sync_on_startup {
+                info!("syncOnStartup is off - first sync at {:?}", next);
+            }
+            return Ok(Self::Cron {
+                schedule,
+                expression,
+                next,
+                queue: config.on_overlap == OverlapPolicy::Queue,
+            });
         }
 
-        // Apply device filtering if this is the devices endpoint
-        let filtered_data = if endpoint.name == "devices" {
-            self.apply_device_filtering(&data)?
+        let period = config.parse_poll_interval()
+            .context("Failed to parse poll interval")?;
+        let first_sync = if config.sync_on_startup {
+            tokio::time::Instant::now()
         } else {
-            data
+            info!("syncOnStartup is off - first sync in {:?}", period);
+            tokio::time::Instant::now() + period
         };
 
-        // Store data in the database
-        let stored_count = self.storage.store_endpoint_data(&endpoint.table_name, &filtered_data).await?;
-
-        info!("Stored {} items in table: {}", stored_count, endpoint.table_name);
+        // Syncs run inline, so a sync that outlives the poll interval shows up as
+        // missed ticks. Never let them burst into back-to-back runs.
+        let mut timer = interval_at(first_sync, period);
+        timer.set_missed_tick_behavior(match config.on_overlap {
+            OverlapPolicy::Skip => MissedTickBehavior::Skip,
+            OverlapPolicy::Queue => MissedTickBehavior::Delay,
+        });
+        Ok(Self::Interval { timer, period })
+    }
 
-        // Update metrics
-        metrics::DEVICES_FETCHED_TOTAL.inc_by(filtered_data.len() as f64);
-        metrics::DEVICES_PROCESSED_TOTAL.inc_by(stored_count as f64);
+    /// Wait until the next sync is due
+    async fn tick(&mut self) {
+        match self {
+            Self::Interval { timer, .. } => {
+                timer.tick().await;
+            }
+            Self::Cron { next: Some(due), .. } => {
+                sleep((*due - Utc::now()).to_std().unwrap_or_default()).await;
+            }
+            Self::Cron { next: None, .. } => std::future::pending().await,
+        }
+    }
 
-        Ok(stored_count)
+    /// Account for a sync that ran from `started` to `finished` and return how many
+    /// scheduled syncs came due meanwhile. Like the interval, a cron schedule queues at
+    /// most one of them.
+    fn finish(&mut self, started: DateTime<Utc>, finished: DateTime<Utc>) -> u64 {
+        match self {
+            Self::Interval { period, .. } => {
+                overlapped_ticks((finished - started).to_std().unwrap_or_default(), *period)
+            }
+            Self::Cron { schedule, next, queue, .. } => {
+                let missed = schedule.after(&started).take_while(|time| *time <= finished).count() as u64;
+                *next = if *queue && missed > 0 {
+                    Some(finished)
+                } else {
+                    schedule.after(&finished).next()
+                };
+                missed
+            }
+        }
     }
 
-    async fn ensure_endpoint_table_exists(&mut self, endpoint: &EndpointConfig) -> Result<()> {
-        // Create a generic table schema for the endpoint
-        let schema = self.generate_table_schema(&endpoint.table_name);
-        self.storage.create_table_if_not_exists(&endpoint.table_name, &schema).await?;
-        Ok(())
+    /// How the schedule reads in the log
+    fn describe(&self) -> String {
+        match self {
+            Self::Interval { period, .. } => format!("poll interval of {:?}", period),
+            Self::Cron { expression, .. } => format!("cron schedule '{}'", expression),
+        }
     }
+}
 
-    fn generate_table_schema(&self, table_name: &str) -> String {
-        // Generate a generic schema that can accommodate any JSON data
-        // This is database-specific, but we'll use a SQLite-compatible format as the base
-        format!(
-            "CREATE TABLE IF NOT EXISTS {} (
-                id TEXT PRIMARY KEY,
-                data TEXT,
-                last_sync_date_time TEXT,
-                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
-            )",
-            table_name
-        )
+/// Validate the endpoints configuration and create the manager that fetches from them
+fn build_endpoint_manager(config: &AppConfig, auth_client: &AuthClient) -> Result<EndpointManager> {
+    log::debug!("Getting endpoints configuration");
+    let endpoints_config = config.get_endpoints_config();
+    log::debug!("Validating endpoints configuration");
+    endpoints_config.validate().context("Invalid endpoints configuration")?;
+    log::debug!("Endpoints configuration validated");
+
+    log::debug!("Creating endpoint manager");
+    let mut endpoint_manager = EndpointManager::new(endpoints_config, auth_client.clone(), config.mock_graph_api.clone(), config.rate_limit.clone())
+        .with_http_body_logging(config.log_http_bodies);
+    if let Some(ref replay_from) = config.replay_from {
+        info!("Replaying Graph responses recorded in {} instead of calling Graph", replay_from);
+        endpoint_manager = endpoint_manager.with_replay(ResponseReplay::new(replay_from)?);
+    } else if config.record_responses {
+        let dir = config.response_capture_dir()?;
+        warn!("Recording raw Graph responses to {} - they contain tenant data", dir.display());
+        endpoint_manager = endpoint_manager.with_response_recorder(ResponseRecorder::new(dir));
     }
+    log::debug!("Endpoint manager created");
 
-    fn apply_device_filtering(&self, data: &[serde_json::Value]) -> Result<Vec<serde_json::Value>> {
-        let mut filtered_data = Vec::new();
+    Ok(endpoint_manager)
+}
 
-        for item in data {
-            // Convert to HashMap for easier processing
-            if let Some(device_map) = item.as_object() {
-                let device_hash: HashMap<String, serde_json::Value> = device_map.iter()
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .collect();
+/// Keep only devices whose OS passes the configured filter
+fn filter_devices(os_filter: &DeviceOsFilter, name_fields: &[String], data: &[serde_json::Value]) -> Vec<serde_json::Value> {
+    let mut filtered_data = Vec::new();
 
-                let device_name = get_device_name(&device_hash);
-                let device_os = get_device_os(&device_hash);
+    for item in data {
+        // Convert to HashMap for easier processing
+        if let Some(device_map) = item.as_object() {
+            let device_hash: HashMap<String, serde_json::Value> = device_map.iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
 
-                // Apply OS filter
-                if self.os_filter.should_include_device(Some(&device_name), device_os.as_deref()) {
-                    filtered_data.push(item.clone());
-                } else {
-                    debug!("Filtered out device: {} (OS: {:?})", device_name, device_os);
-                }
-            } else {
-                // If it's not an object, include it anyway
+            let device_name = get_device_name(&device_hash, name_fields);
+            let device_os = get_device_os(&device_hash);
+
+            // Apply OS filter
+            if os_filter.should_include_device(Some(&device_name), device_os.as_deref()) {
                 filtered_data.push(item.clone());
+            } else {
+                debug!("Filtered out device: {} (OS: {:?})", device_name, device_os);
             }
+        } else {
+            // If it's not an object, include it anyway
+            filtered_data.push(item.clone());
         }
-
-        info!("Applied device filtering: {} -> {} items", data.len(), filtered_data.len());
-        Ok(filtered_data)
     }
 
-    /// Legacy method for backward compatibility - now uses endpoint-based approach
-    async fn process_device(&mut self, device_data: HashMap<String, serde_json::Value>) -> Result<bool> {
-        warn!("process_device is deprecated - use endpoint-based sync instead");
-
-        // Convert to JSON value for endpoint processing
-        let json_value = serde_json::to_value(device_data)?;
+    info!("Applied device filtering: {} -> {} items", data.len(), filtered_data.len());
+    filtered_data
+}
 
-        // Find the devices endpoint
-        let enabled_endpoints = self.endpoint_manager.get_enabled_endpoints();
-        let devices_endpoint = enabled_endpoints
-            .iter()
-            .find(|e| e.name == "devices")
-            .ok_or_else(|| anyhow::anyhow!("Devices endpoint not found or not enabled"))?;
+/// Filter, trim and pseudonymize fetched records exactly as they would be stored
+fn prepare_records(
+    config: &AppConfig,
+    os_filter: &DeviceOsFilter,
+    endpoint: &EndpointConfig,
+    transform: Option<&Transform>,
+    data: Vec<serde_json::Value>,
+) -> Result<Vec<serde_json::Value>> {
+    // Apply device filtering if this is the devices endpoint
+    let mut records = if endpoint.name == "devices" {
+        let mut devices = filter_devices(os_filter, &config.device_name_fields, &data);
+        if let Some(cutoff) = stale_device_cutoff(config) {
+            let before = devices.len();
+            devices.retain(|device| !checked_in_before(device, cutoff));
+            let skipped = before - devices.len();
+            if skipped > 0 {
+                info!("Skipped {} devices that have not checked in since {}", skipped, cutoff.to_rfc3339());
+                metrics::STALE_DEVICES_SKIPPED_TOTAL.inc_by(skipped as f64);
+            }
+        }
+        devices
+    } else {
+        data
+    };
 
-        // Process as single-item endpoint data
-        let data = vec![json_value];
-        let filtered_data = self.apply_device_filtering(&data)?;
+    if let Some(transform) = transform {
+        records = apply_transform(transform, records)
+            .with_context(|| format!("Transform of endpoint {} failed", endpoint.name))?;
+    }
 
-        if filtered_data.is_empty() {
-            return Ok(false); // Device was filtered out
+    // Devices without an id get one derived from their fingerprint by uuidStrategy,
+    // so the same device lands on the same row every sync
+    if endpoint.name == "devices" {
+        let scheme = config.uuid_scheme()?;
+        for item in &mut records {
+            assign_device_id(item, scheme, &config.normalize_fields)?;
         }
+    }
 
-        // Store in the devices table
-        let stored_count = self.storage.store_endpoint_data(&devices_endpoint.table_name, &filtered_data).await?;
+    // Normalize, drop or pseudonymize fields before hashing and schema evolution see them
+    for item in &mut records {
+        apply_field_normalization(&config.normalize_fields, item);
+    }
+    // Keyed before field selection, which may drop the key field
+    if let Some(ref merge_key) = endpoint.merge_key {
+        records = key_by_merge_field(&endpoint.name, merge_key, records);
+    }
+    for item in &mut records {
+        apply_field_selection(endpoint, item);
+        apply_field_hashing(endpoint, item, &config.hash_salt);
+    }
 
-        metrics::DEVICES_PROCESSED_TOTAL.inc();
-        Ok(stored_count > 0)
+    Ok(records)
+}
+
+/// Set a device record's missing or empty `id` to its fingerprint UUID
+fn assign_device_id(item: &mut serde_json::Value, scheme: UuidScheme, normalization: &FieldNormalization) -> Result<()> {
+    let Some(obj) = item.as_object_mut() else {
+        return Ok(());
+    };
+    if obj.get("id").and_then(|id| id.as_str()).is_some_and(|id| !id.is_empty()) {
+        return Ok(());
     }
 
-    /// Clean up resources
-    pub async fn cleanup(&mut self) -> Result<()> {
-        info!("Cleaning up sync service resources...");
+    let device_data: HashMap<String, serde_json::Value> = obj.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    let uuid = get_or_generate_device_uuid(&device_data, scheme, normalization)?;
+    obj.insert("id".to_string(), serde_json::Value::String(uuid.to_string()));
+    Ok(())
+}
 
-        // Clean up storage backends
-        if let Err(e) = self.storage.cleanup().await {
-            error!("Failed to cleanup storage backends: {}", e);
-        }
+/// Replace each record's id with its `mergeKey` value, so it is stored on the row of
+/// the record it belongs to. Records without a value have no row to merge into and
+/// are dropped.
+fn key_by_merge_field(endpoint_name: &str, merge_key: &str, records: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+    let before = records.len();
+    let keyed: Vec<serde_json::Value> = records.into_iter()
+        .filter_map(|mut record| {
+            let key = match record.get(merge_key)? {
+                serde_json::Value::String(s) if !s.is_empty() => serde_json::Value::String(s.clone()),
+                serde_json::Value::Number(n) => serde_json::Value::String(n.to_string()),
+                _ => return None,
+            };
+            record.as_object_mut()?.insert("id".to_string(), key);
+            Some(record)
+        })
+        .collect();
 
-        info!("Sync service cleanup completed");
-        Ok(())
+    let skipped = before - keyed.len();
+    if skipped > 0 {
+        warn!(
+            "Skipped {} records of endpoint {} without a value in mergeKey field {}",
+            skipped, endpoint_name, merge_key
+        );
     }
+    keyed
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
+/// Oldest check-in time a device may have to be kept, if `staleDeviceMaxAgeDays` is set
+fn stale_device_cutoff(config: &AppConfig) -> Option<chrono::DateTime<Utc>> {
+    config.stale_device_max_age_days
+        .map(|days| Utc::now() - chrono::Duration::days(i64::from(days)))
+}
 
-    #[tokio::test]
-    async fn test_device_filtering() {
-        let config = AppConfig {
-            client_id: "test".to_string(),
-            client_secret: "test".to_string(),
-            tenant_id: "test".to_string(),
-            poll_interval: Some("1h".to_string()),
-            cron_schedule: None,
-            device_os_filter: vec!["Windows".to_string()],
-            enable_prometheus: false,
-            prometheus_port: 9898,
-            log_level: "info".to_string(),
-            database: crate::config::DatabaseConfig {
-                sqlite: Some(crate::config::SqliteConfig {
-                    enabled: true,
-                    database_path: ":memory:".to_string(),
-                }),
-                postgres: None,
-                mssql: None,
-            },
-            endpoints: None,
-            backup: None,
-            webhook: None,
-            rate_limit: None,
-            mock_graph_api: None,
-        };
+/// Whether a device's `lastSyncDateTime` is before `cutoff`. Devices without a valid
+/// one, or that never checked in, are not stale.
+fn checked_in_before(device: &serde_json::Value, cutoff: chrono::DateTime<Utc>) -> bool {
+    device.get("lastSyncDateTime")
+        .and_then(|value| value.as_str())
+        .filter(|value| !value.starts_with(&NEVER_CHECKED_IN[..5]))
+        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
+        .is_some_and(|value| value < cutoff)
+}
 
-        let auth_client = AuthClient::new(config.clone());
-        let mut storage_manager = StorageManager::new(&config.database).await.unwrap();
-        storage_manager.initialize().await.unwrap();
+/// Compile an endpoint's `transform`, if it has one
+fn compile_transform(endpoint: &EndpointConfig) -> Result<Option<Transform>> {
+    endpoint.transform.as_deref()
+        .map(|transform| Transform::parse(transform)
+            .with_context(|| format!("Invalid transform for endpoint {}", endpoint.name)))
+        .transpose()
+}
 
-        let endpoints_config = config.get_endpoints_config();
-        let endpoint_manager = EndpointManager::new(endpoints_config, auth_client.clone(), None, None);
+/// Replace each record with the result of the endpoint's `transform`, which has to be an
+/// object to be stored as a row
+fn apply_transform(transform: &Transform, records: Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>> {
+    records.iter()
+        .map(|record| match transform.apply(record)? {
+            transformed @ serde_json::Value::Object(_) => Ok(transformed),
+            other => anyhow::bail!(
+                "Expected an object for record {}, got {}",
+                record.get("id").and_then(|v| v.as_str()).unwrap_or("without id"),
+                other
+            ),
+        })
+        .collect()
+}
 
-        let sync_service = SyncService {
-            config: config.clone(),
-            auth_client,
-            storage: storage_manager,
-            os_filter: DeviceOsFilter::new(&["Windows".to_string()]),
-            endpoint_manager,
+/// Schema changes a sync of one endpoint would make, per backend
+pub struct SchemaPreview {
+    pub endpoint: String,
+    pub table_name: String,
+    pub backends: Vec<(&'static str, Result<Vec<String>>)>,
+}
+
+/// Fetch the first page of each enabled endpoint (or only the named one) and work out
+/// the `CREATE TABLE`/`ALTER TABLE` statements a sync would run in each backend,
+/// without running them. The databases are opened read-only, so nothing is written
+/// to them and they have to exist already.
+pub async fn preview_schema(config: &AppConfig, endpoint_name: Option<&str>) -> Result<Vec<SchemaPreview>> {
+    let auth_client = AuthClient::new(config.clone());
+    let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+    let endpoint_manager = build_endpoint_manager(config, &auth_client)?;
+
+    let endpoints: Vec<&EndpointConfig> = match endpoint_name {
+        Some(name) => vec![endpoint_manager.get_enabled_endpoints().into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name) || e.table_name.eq_ignore_ascii_case(name))
+            .with_context(|| format!("Unknown or disabled endpoint: {}", name))?],
+        None => endpoint_manager.get_enabled_endpoints(),
+    };
+
+    let mut storage = StorageManager::open_read_only(&config.database).await?;
+    register_column_types(&mut storage, &endpoints);
+    let mut previews = Vec::with_capacity(endpoints.len());
+
+    for endpoint in endpoints {
+        let response = endpoint_manager.fetch_endpoint_data(endpoint).await
+            .with_context(|| format!("Failed to fetch endpoint {}", endpoint.name))?;
+        let page = match response.get("value").and_then(|v| v.as_array()) {
+            Some(items) => items.clone(),
+            None => vec![response],
         };
+        info!("Fetched a sample of {} items from endpoint: {}", page.len(), endpoint.name);
 
-        let test_data = vec![
-            json!({
-                "deviceName": "Windows Device",
-                "operatingSystem": "Windows"
-            }),
-            json!({
-                "deviceName": "Mac Device",
-                "operatingSystem": "macOS"
-            }),
-            json!({
-                "deviceName": "Another Windows Device",
-                "operatingSystem": "Windows"
-            })
-        ];
+        let transform = compile_transform(endpoint)?;
+        let records = prepare_records(config, &os_filter, endpoint, transform.as_ref(), page)?;
+        let (records, _) = detect_changes_in(records, &HashMap::new(), &endpoint.hash_column());
+        // Every field seen on the page, typed to fit all of its values
+        let sample = crate::storage::schema_sample(&records, records.len());
 
-        let filtered_data = sync_service.apply_device_filtering(&test_data).unwrap();
+        previews.push(SchemaPreview {
+            endpoint: endpoint.name.clone(),
+            table_name: endpoint.table_name.clone(),
+            backends: storage.preview_schema_changes(&endpoint.table_name, &sample).await,
+        });
+    }
 
-        // Should only include Windows devices
-        assert_eq!(filtered_data.len(), 2);
-        for device in &filtered_data {
-            assert_eq!(device["operatingSystem"], "Windows");
+    storage.cleanup().await?;
+    Ok(previews)
+}
+
+/// Hand each endpoint's `columnTypes` to the backends, which use them when adding columns
+fn register_column_types(storage: &mut StorageManager, endpoints: &[&EndpointConfig]) {
+    for endpoint in endpoints.iter().filter(|endpoint| !endpoint.column_types.is_empty()) {
+        storage.set_column_types(&endpoint.table_name, &endpoint.column_types);
+    }
+}
+
+/// Tag records with the sync run that writes them. Applied after change detection,
+/// so the run id never takes part in the hash.
+fn apply_run_id(records: &mut [serde_json::Value], column: &str, run_id: &str) {
+    for record in records {
+        if let Some(obj) = record.as_object_mut() {
+            obj.insert(column.to_string(), serde_json::Value::String(run_id.to_string()));
         }
     }
 }
+
+/// Write records as newline-delimited JSON, one record per line
+pub fn write_ndjson<W: Write>(out: &mut W, records: &[serde_json::Value]) -> std::io::Result<()> {
+    for record in records {
+        serde_json::to_writer(&mut *out, record)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// One-shot export: fetch every enabled endpoint and write its prepared records to `out`
+/// as newline-delimited JSON. No storage backend is opened.
+pub async fn export_ndjson<W: Write>(config: &AppConfig, out: &mut W) -> Result<usize> {
+    let auth_client = AuthClient::new(config.clone());
+    let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+    let endpoint_manager = build_endpoint_manager(config, &auth_client)?;
+
+    let mut total = 0;
+    for endpoint in endpoint_manager.get_enabled_endpoints() {
+        let data = endpoint_manager.fetch_all_endpoint_data(endpoint).await
+            .with_context(|| format!("Failed to fetch endpoint {}", endpoint.name))?;
+        let transform = compile_transform(endpoint)?;
+        let records = prepare_records(config, &os_filter, endpoint, transform.as_ref(), data)?;
+
+        write_ndjson(out, &records)?;
+        out.flush()?;
+
+        info!("Exported {} items from endpoint: {}", records.len(), endpoint.name);
+        total += records.len();
+    }
+
+    Ok(total)
+}
+
+/// Rebuild an endpoint's table from the raw JSON stored with each row, applying the
+/// current filter, field selection and hashing without calling Graph.
+/// Returns `None` if the table holds no raw JSON.
+pub async fn reprocess_endpoint(config: &AppConfig, endpoint_name: &str) -> Result<Option<usize>> {
+    let endpoints_config = config.get_endpoints_config();
+    let endpoint = endpoints_config.endpoints.iter()
+        .find(|e| e.name.eq_ignore_ascii_case(endpoint_name)
+            || (e.merge_key.is_none() && e.table_name.eq_ignore_ascii_case(endpoint_name)))
+        .with_context(|| format!("Unknown endpoint: {}", endpoint_name))?;
+    // Its rows hold the raw JSON of the endpoint it merges into, not its own
+    if endpoint.merge_key.is_some() {
+        anyhow::bail!("Endpoint {} merges into table {} and cannot be reprocessed", endpoint.name, endpoint.table_name);
+    }
+
+    let mut storage = StorageManager::new(&config.database).await?;
+    let raw_records = storage.get_raw_records(&endpoint.table_name).await?;
+    if raw_records.is_empty() {
+        storage.cleanup().await?;
+        return Ok(None);
+    }
+
+    // Keep each record's raw JSON next to it so rewriting the row does not drop it
+    let mut raw_by_id = HashMap::new();
+    let mut data = Vec::with_capacity(raw_records.len());
+    for raw in raw_records {
+        match serde_json::from_str::<serde_json::Value>(&raw) {
+            Ok(mut item) => {
+                preprocess_odata_record(endpoint, &mut item);
+                if let Some(id) = item.get("id").and_then(|v| v.as_str()) {
+                    raw_by_id.insert(id.to_string(), raw);
+                }
+                data.push(item);
+            }
+            Err(e) => warn!("Skipping unparsable raw JSON in table {}: {}", endpoint.table_name, e),
+        }
+    }
+
+    let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+    let transform = compile_transform(endpoint)?;
+    let records = prepare_records(config, &os_filter, endpoint, transform.as_ref(), data)?;
+
+    // Every record is written; the hash is recomputed from the new columns
+    let (mut records, _) = detect_changes(records, &HashMap::new());
+    if config.database.store_run_id {
+        let run_id = uuid::Uuid::new_v4().to_string();
+        info!("Reprocessing endpoint {} as run {}", endpoint.name, run_id);
+        apply_run_id(&mut records, &config.database.run_id_column, &run_id);
+    }
+    for record in &mut records {
+        let raw = record.get("id").and_then(|v| v.as_str()).and_then(|id| raw_by_id.remove(id));
+        if let (Some(raw), Some(obj)) = (raw, record.as_object_mut()) {
+            obj.insert(RAW_JSON_COLUMN.to_string(), serde_json::Value::String(raw));
+        }
+    }
+
+    let stored = storage.store_endpoint_data(&endpoint.table_name, &records).await?.stored();
+    storage.cleanup().await?;
+
+    info!("Reprocessed {} items in table: {}", stored, endpoint.table_name);
+    Ok(Some(stored))
+}
+
+/// Longest [`SyncService::cleanup`] waits for webhooks still being sent
+const WEBHOOK_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Webhooks being sent in the background, kept so they can be waited for before the
+/// process exits
+#[derive(Debug, Default)]
+struct WebhookSends {
+    tasks: tokio::task::JoinSet<()>,
+}
+
+impl WebhookSends {
+    fn spawn(&mut self, send: impl std::future::Future<Output = ()> + Send + 'static) {
+        // Finished sends are only collected here, so a long-running service does not pile them up
+        while self.tasks.try_join_next().is_some() {}
+        self.tasks.spawn(send);
+    }
+
+    /// Wait for every send started so far
+    async fn finish(&mut self) {
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+/// Asks a running service to finish the sync in progress, start no new one and return
+/// from [`SyncService::run`], as opposed to stopping it mid-sync
+#[derive(Debug, Clone)]
+pub struct DrainSignal {
+    requested_at: Arc<watch::Sender<Option<DateTime<Utc>>>>,
+}
+
+impl Default for DrainSignal {
+    fn default() -> Self {
+        Self { requested_at: Arc::new(watch::channel(None).0) }
+    }
+}
+
+impl DrainSignal {
+    /// Request the drain; false if it was already requested
+    pub fn request(&self) -> bool {
+        self.requested_at.send_if_modified(|requested_at| {
+            let first = requested_at.is_none();
+            requested_at.get_or_insert_with(Utc::now);
+            first
+        })
+    }
+
+    pub fn requested_at(&self) -> Option<DateTime<Utc>> {
+        *self.requested_at.borrow()
+    }
+
+    /// Resolves once the drain has been requested
+    pub async fn requested(&self) {
+        let mut requested_at = self.requested_at.subscribe();
+        let _ = requested_at.wait_for(Option::is_some).await;
+    }
+}
+
+pub struct SyncService {
+    config: AppConfig,
+    auth_client: AuthClient,
+    storage: StorageManager,
+    os_filter: DeviceOsFilter,
+    endpoint_manager: EndpointManager,
+    /// Compiled `transform` of each enabled endpoint that has one, by endpoint name
+    transforms: HashMap<String, Transform>,
+    webhooks: Option<Arc<WebhookManager>>,
+    webhook_sends: WebhookSends,
+    audit_log: Option<AuditLog>,
+    endpoint_breakers: HashMap<String, CircuitBreaker>,
+    endpoint_circuits: SharedCircuits,
+    maintenance_interval: Option<Duration>,
+    last_maintenance: Instant,
+    /// Backup manager and SQLite database file when `backupBeforeSync` is on
+    pre_sync_backup: Option<(SqliteBackupManager, std::path::PathBuf)>,
+    drain: DrainSignal,
+    /// Write complete fetches even when `minExpectedRatio` finds them implausibly small
+    allow_shrink: bool,
+}
+
+impl SyncService {
+    pub async fn new(config: AppConfig) -> Result<Self> {
+        if config.mock_writes_to_real_database() {
+            warn!("Mock Graph API is enabled while real credentials and a persistent database are configured - synthetic devices will be written to that database");
+        }
+
+        // One budget for every retry loop of a sync, reset when each sync starts
+        let retry_budget = config.sync.retry_budget();
+        let webhooks = config.webhook.clone()
+            .filter(|webhook| webhook.enabled)
+            .map(|webhook| Arc::new(WebhookManager::new(webhook).with_retry_budget(retry_budget.clone())));
+        if let Some(ref webhooks) = webhooks {
+            webhooks.start_digest();
+        }
+
+        log::debug!("Creating auth client");
+        let auth_client = AuthClient::new(config.clone()).with_webhooks(webhooks.clone());
+
+        log::debug!("Creating storage manager");
+        let mut storage = StorageManager::new(&config.database).await?
+            .with_webhooks(webhooks.clone());
+        log::debug!("Initializing storage");
+        storage.initialize().await?;
+        log::debug!("Storage initialized");
+
+        log::debug!("Creating OS filter");
+        let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+
+        let endpoint_manager = build_endpoint_manager(&config, &auth_client)?
+            .with_retry_budget(retry_budget);
+        register_column_types(&mut storage, &endpoint_manager.get_enabled_endpoints());
+        let mut transforms = HashMap::new();
+        for endpoint in endpoint_manager.get_enabled_endpoints() {
+            if let Some(transform) = compile_transform(endpoint)? {
+                transforms.insert(endpoint.name.clone(), transform);
+            }
+        }
+        let maintenance_interval = config.parse_maintenance_interval()?;
+        let audit_log = config.audit_log.as_ref()
+            .filter(|audit_log| audit_log.enabled)
+            .map(|audit_log| AuditLog::new(&audit_log.path));
+        let pre_sync_backup = match (&config.backup, &config.database.sqlite) {
+            (Some(backup), Some(sqlite)) if backup.enabled && backup.backup_before_sync && sqlite.enabled => {
+                let db_path = crate::path_utils::resolve_path(&sqlite.database_path)
+                    .with_context(|| format!("Failed to resolve database path: {}", sqlite.database_path))?;
+                Some((SqliteBackupManager::new(&backup.directory, backup.max_backups)?, db_path))
+            }
+            _ => None,
+        };
+
+        info!("Sync service initialized with backends: {:?}", storage.get_backend_names());
+        info!("OS filter configured: {:?}", os_filter.get_filters());
+        info!("Endpoints configured: {:?}", endpoint_manager.get_enabled_endpoints().iter().map(|e| &e.name).collect::<Vec<_>>());
+
+        Ok(Self {
+            config,
+            auth_client,
+            storage,
+            os_filter,
+            endpoint_manager,
+            transforms,
+            webhooks,
+            webhook_sends: WebhookSends::default(),
+            audit_log,
+            endpoint_breakers: HashMap::new(),
+            endpoint_circuits: SharedCircuits::default(),
+            maintenance_interval,
+            last_maintenance: Instant::now(),
+            pre_sync_backup,
+            drain: DrainSignal::default(),
+            allow_shrink: false,
+        })
+    }
+
+    /// Publish endpoint circuit state into a map shared with the `/health` handler
+    pub fn with_endpoint_circuits(mut self, endpoint_circuits: SharedCircuits) -> Self {
+        self.endpoint_circuits = endpoint_circuits;
+        self
+    }
+
+    /// Have [`run`](Self::run) return after the sync in progress once `drain` is requested
+    pub fn with_drain_signal(mut self, drain: DrainSignal) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    /// Skip the `minExpectedRatio` check, for syncs after a legitimate large decommission.
+    /// [`run`](Self::run) only skips it for its first sync
+    pub fn with_shrink_allowed(mut self, allow_shrink: bool) -> Self {
+        self.allow_shrink = allow_shrink;
+        self
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut schedule = SyncSchedule::new(&self.config)?;
+        info!("Starting sync service with {}", schedule.describe());
+        self.restore_last_errors().await;
+
+        loop {
+            // A drain only ever waits for the sync in progress, never for the next tick
+            tokio::select! {
+                biased;
+                _ = self.drain.requested() => {
+                    info!("Drain requested - not starting another sync");
+                    return Ok(());
+                }
+                _ = schedule.tick() => {}
+            }
+
+            let started = Instant::now();
+            let started_at = Utc::now();
+            let result = self.sync_all_endpoints().await;
+            let missed = schedule.finish(started_at, Utc::now());
+            self.report_overlap(started.elapsed(), missed, &schedule.describe());
+            // `run --allow-shrink` covers the first sync only, later ones are checked again
+            self.allow_shrink = false;
+
+            if let Err(e) = result {
+                error!("Sync operation failed: {}", e);
+                metrics::SYNC_FAILURE_TOTAL.inc();
+
+                // Wait a bit before retrying
+                tokio::select! {
+                    _ = self.drain.requested() => {}
+                    _ = sleep(Duration::from_secs(self.config.sync.retry_delay_after_failure_seconds)) => {}
+                }
+            }
+
+            // A draining instance hands over as soon as it can
+            if self.drain.requested_at().is_none() {
+                self.run_maintenance_if_due().await;
+            }
+        }
+    }
+
+    /// Copy the SQLite database with `backupBeforeSync`, pruning old copies down to
+    /// `maxBackups`. Returns the new backup's path.
+    async fn backup_before_sync(&mut self) -> Result<Option<std::path::PathBuf>> {
+        let Some((backup_manager, db_path)) = &self.pre_sync_backup else {
+            return Ok(None);
+        };
+
+        self.storage.checkpoint().await?;
+        let backup_path = backup_manager.create_backup(db_path, BackupType::PreUpdate)?;
+        info!("Backed up {} before syncing to {}", db_path.display(), backup_path.display());
+        Ok(Some(backup_path))
+    }
+
+    /// VACUUM/ANALYZE the synced tables between syncs once `maintenanceInterval` has passed
+    async fn run_maintenance_if_due(&mut self) {
+        let Some(interval) = self.maintenance_interval else { return };
+        if self.last_maintenance.elapsed() < interval {
+            return;
+        }
+        self.last_maintenance = Instant::now();
+
+        let tables: Vec<String> = self.endpoint_manager.get_enabled_endpoints()
+            .iter()
+            .map(|endpoint| endpoint.table_name.clone())
+            .collect();
+        info!("Running database maintenance on tables: {:?}", tables);
+        self.storage.run_maintenance(&tables).await;
+    }
+
+    /// Log and count scheduled syncs that were due while the last sync was still running
+    fn report_overlap(&self, sync_duration: Duration, missed: u64, schedule: &str) {
+        if missed == 0 {
+            return;
+        }
+
+        match self.config.on_overlap {
+            OverlapPolicy::Skip => {
+                warn!(
+                    "Sync took {:?}, longer than the {} allows - skipping {} overlapping sync(s)",
+                    sync_duration, schedule, missed
+                );
+                metrics::SYNC_SKIPPED_OVERLAP_TOTAL.inc_by(missed as f64);
+            }
+            OverlapPolicy::Queue => {
+                warn!(
+                    "Sync took {:?}, longer than the {} allows - starting the queued sync immediately",
+                    sync_duration, schedule
+                );
+                // Only one run is queued, any further missed ticks are dropped
+                if missed > 1 {
+                    metrics::SYNC_SKIPPED_OVERLAP_TOTAL.inc_by((missed - 1) as f64);
+                }
+            }
+        }
+    }
+
+    /// Run a single sync of all enabled endpoints
+    /// Sync every enabled endpoint once. Returns the run id the sync's webhooks and audit
+    /// entries carry.
+    pub async fn sync_once(&mut self) -> Result<String> {
+        self.sync_all_endpoints().await
+    }
+
+    /// Storage the service writes to
+    pub fn storage_mut(&mut self) -> &mut StorageManager {
+        &mut self.storage
+    }
+
+    pub fn endpoint_manager(&self) -> &EndpointManager {
+        &self.endpoint_manager
+    }
+
+    async fn sync_all_endpoints(&mut self) -> Result<String> {
+        let sync_timer = metrics::Timer::new();
+        let run_id = uuid::Uuid::new_v4().to_string();
+        info!("Starting multi-endpoint sync operation (run {})", run_id);
+        self.endpoint_manager.request_budget().reset();
+        self.endpoint_manager.retry_budget().reset();
+        self.endpoint_manager.reset_capture();
+
+        let enabled_endpoints: Vec<_> = self.endpoint_manager.get_enabled_endpoints()
+            .into_iter()
+            .cloned()
+            .collect();
+
+        if enabled_endpoints.is_empty() {
+            warn!("No endpoints are enabled for synchronization");
+            return Ok(run_id);
+        }
+
+        self.backup_before_sync().await
+            .context("Pre-sync backup failed - not syncing without it")?;
+
+        let mut total_processed = 0;
+        let mut total_errors = 0;
+        let mut run_counts = EndpointCounts::default();
+
+        for endpoint in enabled_endpoints {
+            if self.endpoint_manager.request_budget().is_exhausted() {
+                warn!(
+                    "Skipping endpoint {} (priority {}) - the request budget for this sync is exhausted",
+                    endpoint.name, endpoint.priority
+                );
+                metrics::ENDPOINT_SKIPPED_TOTAL.with_label_values(&[&endpoint.name, "request_budget"]).inc();
+                continue;
+            }
+
+            if self.endpoint_manager.retry_budget().is_exhausted() {
+                warn!(
+                    "Skipping endpoint {} (priority {}) - the retry budget for this sync is exhausted",
+                    endpoint.name, endpoint.priority
+                );
+                metrics::ENDPOINT_SKIPPED_TOTAL.with_label_values(&[&endpoint.name, "retry_budget"]).inc();
+                continue;
+            }
+
+            let breaker = self.endpoint_breakers.entry(endpoint.name.clone()).or_default();
+            let now = Utc::now();
+            // `endpoint retry` marks the copy shared with the HTTP server
+            let probe_requested = self.endpoint_circuits.read()
+                .map(|circuits| circuits.get(&endpoint.name).is_some_and(|shared| shared.probe_requested()))
+                .unwrap_or(false);
+            if probe_requested && breaker.request_probe(now) {
+                info!("Re-testing endpoint {} on request", endpoint.name);
+            } else if !breaker.allows(now) {
+                info!(
+                    "Skipping endpoint {} - disabled after {} consecutive failures until {}",
+                    endpoint.name,
+                    breaker.consecutive_failures(),
+                    breaker.status(now).open_until.map(|t| t.to_rfc3339()).unwrap_or_default()
+                );
+                metrics::ENDPOINT_SKIPPED_TOTAL.with_label_values(&[&endpoint.name, "circuit_open"]).inc();
+                continue;
+            } else if breaker.state(now) == CircuitState::HalfOpen {
+                info!("Re-testing endpoint {} after its cooldown", endpoint.name);
+            }
+
+            let result = self.sync_endpoint(&endpoint, &run_id).await;
+            match &result {
+                Ok(counts) => {
+                    let processed = counts.changes.stored();
+                    total_processed += processed;
+                    run_counts.fetched += counts.fetched;
+                    run_counts.changes += counts.changes;
+                    metrics::record_endpoint_result(&endpoint.name, processed, 0);
+                    info!("Successfully synced {} items from endpoint: {}", processed, endpoint.name);
+                }
+                Err(e) => {
+                    error!("Failed to sync endpoint {}: {}", endpoint.name, e);
+                    metrics::record_endpoint_result(&endpoint.name, 0, 1);
+                    total_errors += 1;
+                }
+            }
+            // Running out of budget says nothing about the endpoint's health
+            match result {
+                Err(e) if RequestBudgetExhausted::is_cause_of(&e) || RetryBudgetExhausted::is_cause_of(&e) => {}
+                result => {
+                    self.update_endpoint_circuit(&endpoint.name, result.err());
+                    self.save_last_error(&endpoint.name).await;
+                }
+            }
+
+            // Small delay between endpoints to avoid rate limiting
+            sleep(Duration::from_millis(self.config.sync.inter_endpoint_delay_ms)).await;
+        }
+
+        let duration = sync_timer.start.elapsed();
+        sync_timer.observe_duration(&metrics::SYNC_DURATION_SECONDS);
+
+        if total_errors == 0 {
+            metrics::SYNC_SUCCESS_TOTAL.inc();
+            self.send_sync_completed(&run_id, duration, run_counts);
+        } else {
+            metrics::SYNC_FAILURE_TOTAL.inc();
+        }
+        metrics::record_last_sync(duration, total_errors == 0);
+
+        info!(
+            "Multi-endpoint sync completed (run {}): {} items processed, {} errors, duration: {:?}",
+            run_id, total_processed, total_errors, duration
+        );
+
+        Ok(run_id)
+    }
+
+    /// Feed one endpoint result into its circuit breaker and publish the new state
+    fn update_endpoint_circuit(&mut self, endpoint_name: &str, error: Option<anyhow::Error>) {
+        let now = Utc::now();
+        let breaker = self.endpoint_breakers.entry(endpoint_name.to_string()).or_default();
+
+        match error {
+            None => {
+                if breaker.record_success() {
+                    info!("Endpoint {} succeeded again and is re-enabled", endpoint_name);
+                }
+            }
+            Some(e) => {
+                breaker.set_last_error(LastError { message: format!("{:#}", e), at: now });
+                if let Some(until) = breaker.record_failure(&self.config.circuit_breaker, now) {
+                    let failures = breaker.consecutive_failures();
+                    warn!(
+                        "Disabling endpoint {} until {} after {} consecutive failures: {}",
+                        endpoint_name, until.to_rfc3339(), failures, e
+                    );
+
+                    if let Some(webhooks) = &self.webhooks {
+                        let webhooks = Arc::clone(webhooks);
+                        let endpoint_name = endpoint_name.to_string();
+                        let error = e.to_string();
+                        self.webhook_sends.spawn(async move {
+                            if let Err(e) = webhooks.send_endpoint_disabled(endpoint_name, failures, until, error).await {
+                                warn!("Failed to send endpoint disabled webhook: {}", e);
+                            }
+                        });
+                    }
+                }
+            }
+        }
+
+        metrics::ENDPOINT_CIRCUIT_OPEN
+            .with_label_values(&[endpoint_name])
+            .set(if breaker.state(now) == CircuitState::Closed { 0.0 } else { 1.0 });
+        if let Ok(mut circuits) = self.endpoint_circuits.write() {
+            circuits.insert(endpoint_name.to_string(), breaker.clone());
+        }
+    }
+
+    /// Store an endpoint's last error in its sync state, or clear it after a success, so
+    /// it survives restarts. A failure to save is only logged.
+    async fn save_last_error(&mut self, endpoint_name: &str) {
+        let last_error = self.endpoint_breakers.get(endpoint_name)
+            .and_then(|breaker| breaker.last_error())
+            .cloned();
+
+        let result = async {
+            let previous = self.storage.get_sync_state(endpoint_name).await?;
+            if previous.as_ref().and_then(|state| state.last_error.as_ref()).is_none() && last_error.is_none() {
+                return Ok(());
+            }
+            let state = SyncState {
+                endpoint_name: endpoint_name.to_string(),
+                last_error: last_error.as_ref().map(|e| e.message.clone()),
+                last_error_at: last_error.as_ref().map(|e| e.at),
+                ..previous.unwrap_or_default()
+            };
+            self.storage.set_sync_state(&state).await
+        }.await;
+
+        if let Err(e) = result {
+            warn!("Failed to save the last error of endpoint {}: {}", endpoint_name, e);
+        }
+    }
+
+    /// Load the last errors saved by a previous run, so `/health` shows them before the
+    /// failing endpoints are retried
+    async fn restore_last_errors(&mut self) {
+        let endpoint_names: Vec<String> = self.endpoint_manager.get_enabled_endpoints()
+            .into_iter()
+            .map(|endpoint| endpoint.name.clone())
+            .collect();
+
+        for endpoint_name in endpoint_names {
+            let state = match self.storage.get_sync_state(&endpoint_name).await {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Failed to load the last error of endpoint {}: {}", endpoint_name, e);
+                    continue;
+                }
+            };
+            let Some((message, at)) = state.and_then(|state| state.last_error.zip(state.last_error_at)) else {
+                continue;
+            };
+
+            let breaker = self.endpoint_breakers.entry(endpoint_name.clone()).or_default();
+            breaker.set_last_error(LastError { message, at });
+            if let Ok(mut circuits) = self.endpoint_circuits.write() {
+                circuits.insert(endpoint_name, breaker.clone());
+            }
+        }
+    }
+
+    async fn sync_endpoint(&mut self, endpoint: &EndpointConfig, run_id: &str) -> Result<EndpointCounts> {
+        let counts = self.sync_endpoint_records(endpoint, run_id).await?;
+        self.prune_stale_devices(endpoint, run_id).await?;
+        Ok(counts)
+    }
+
+    /// Remove stored devices that have not checked in for `staleDeviceMaxAgeDays`, with
+    /// `deleteDryRun` and `maxDeletePercent` applied like for missing rows. They are marked
+    /// deleted with `deletionMode: soft` and deleted otherwise. Only runs for the devices
+    /// endpoint, and also when nothing new was fetched.
+    async fn prune_stale_devices(&mut self, endpoint: &EndpointConfig, run_id: &str) -> Result<()> {
+        if endpoint.name != "devices" {
+            return Ok(());
+        }
+        let Some(cutoff) = stale_device_cutoff(&self.config) else {
+            return Ok(());
+        };
+
+        let missing = self.storage.stale_row_ids(&endpoint.table_name, cutoff).await?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let stored = self.storage.get_table_count(&endpoint.table_name).await?;
+        let mut report = DeletionReport { stored: stored.max(0) as usize, missing, ..Default::default() };
+        let reason = format!("that have not checked in since {}", cutoff.format("%Y-%m-%dT%H:%M:%SZ"));
+        if self.config.delete_dry_run {
+            self.report_dry_run(endpoint, &mut report, &reason, &format!("{}-stale", endpoint.name)).await;
+            return Ok(());
+        }
+
+        let mode = if endpoint.deletion_mode == DeletionMode::Soft { DeletionMode::Soft } else { DeletionMode::Hard };
+        let pruned = self.delete_missing_rows(endpoint, run_id, &endpoint.hash_column(), report, mode, &reason).await?;
+        metrics::STALE_DEVICES_PRUNED_TOTAL.inc_by(pruned as f64);
+        Ok(())
+    }
+
+    async fn sync_endpoint_records(&mut self, endpoint: &EndpointConfig, run_id: &str) -> Result<EndpointCounts> {
+        info!("Syncing endpoint: {} -> {}", endpoint.name, endpoint.table_name);
+
+        // Ensure table exists for this endpoint
+        self.ensure_endpoint_table_exists(endpoint).await?;
+
+        // Endpoints with an incremental field only fetch what changed since the stored
+        // watermark; without one (first run, or a backend that missed a sync) pull everything
+        let auto = endpoint.sync_mode == SyncMode::Auto;
+        let sync_state = if endpoint.incremental_field.is_some() || endpoint.use_etag || endpoint.use_delta || auto {
+            self.storage.get_sync_state(&endpoint.name).await?
+        } else {
+            None
+        };
+        // A full pull ignores the watermark and ETag, so whatever they skipped is caught up
+        let full_sync = endpoint.full_sync_due(sync_state.as_ref().and_then(|state| state.last_full_sync_at), Utc::now());
+        if full_sync && auto {
+            info!("Running a full sync of endpoint {} (syncMode auto)", endpoint.name);
+        }
+        let watermark = sync_state.as_ref()
+            .and_then(|state| state.watermark.as_deref())
+            .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
+            .map(|value| value.with_timezone(&Utc));
+        let etag = sync_state.as_ref()
+            .filter(|_| endpoint.use_etag && !full_sync)
+            .and_then(|state| state.etag.as_deref());
+
+        // A full pull starts a new delta round, which returns every record again
+        let delta_link = sync_state.as_ref()
+            .filter(|_| endpoint.use_delta && !full_sync)
+            .and_then(|state| state.delta_link.as_deref());
+
+        // Fetch data from the endpoint
+        let filter = watermark.filter(|_| !full_sync && !endpoint.use_delta).and_then(|watermark| incremental_filter(endpoint, watermark));
+        let incremental_fetch = filter.is_some();
+        let fetched = if endpoint.use_delta {
+            Some(self.endpoint_manager.fetch_endpoint_delta(endpoint, delta_link).await?)
+        } else {
+            match filter {
+                Some(filter) => {
+                    info!("Fetching changes of endpoint {} with filter: {}", endpoint.name, filter);
+                    let incremental = EndpointConfig { filter: Some(filter), ..endpoint.clone() };
+                    self.endpoint_manager.fetch_all_endpoint_data_if_changed(&incremental, etag).await?
+                }
+                None => self.endpoint_manager.fetch_all_endpoint_data_if_changed(endpoint, etag).await?,
+            }
+        };
+        let Some(fetched) = fetched else {
+            metrics::GRAPH_NOT_MODIFIED_TOTAL.with_label_values(&[&endpoint.name]).inc();
+            return Ok(EndpointCounts::default());
+        };
+        let (data, new_etag, new_delta_link) = (fetched.data, fetched.etag, fetched.delta_link);
+        let incremental_fetch = incremental_fetch || fetched.changes_only;
+        info!("Fetched {} items from endpoint: {}", data.len(), endpoint.name);
+        let graph_count = data.len();
+
+        // Compare against stored hashes so unchanged records are not rewritten
+        let hash_column = endpoint.hash_column();
+        let existing_hashes = self.storage.get_all_hashes(&endpoint.table_name, &hash_column).await?;
+
+        // A complete fetch far smaller than what is stored is more likely a partial Graph
+        // outage than retired devices, so nothing is written or pruned
+        if !incremental_fetch && !fetched.truncated && !self.allow_shrink
+            && implausible_shrink(data.len(), existing_hashes.len(), self.config.min_expected_ratio)
+        {
+            self.report_implausible_shrink(endpoint, data.len(), existing_hashes.len());
+            anyhow::bail!(
+                "Fetched only {} records for the {} rows of table {}, below minExpectedRatio {} - nothing was written",
+                data.len(), existing_hashes.len(), endpoint.table_name, self.config.min_expected_ratio
+            );
+        }
+
+        if data.is_empty() && fetched.removed_ids.is_empty() {
+            // Nothing changed since the last delta query, but the next one starts from the new link
+            if endpoint.use_delta && new_delta_link.is_some() {
+                let state = SyncState {
+                    endpoint_name: endpoint.name.clone(),
+                    delta_link: new_delta_link,
+                    updated_at: Some(Utc::now()),
+                    ..sync_state.unwrap_or_default()
+                };
+                self.storage.set_sync_state(&state).await?;
+            }
+            return Ok(EndpointCounts::default());
+        }
+
+        // A result cut off at maxItems may have skipped older changes, so it keeps the watermark
+        let new_watermark = endpoint.incremental_field.as_deref()
+            .filter(|_| !fetched.truncated)
+            .and_then(|field| max_incremental_value(field, &data))
+            .max(watermark);
+
+        let filtered_data = prepare_records(&self.config, &self.os_filter, endpoint, self.transforms.get(&endpoint.name), data)?;
+        let filtered_data = resolve_id_collisions(
+            &endpoint.name,
+            filtered_data,
+            self.config.on_uuid_collision,
+            &self.config.device_name_fields,
+        );
+        let fetched_count = filtered_data.len();
+        // Only a complete fetch shows which stored rows Graph no longer returns, while a
+        // delta query names the records removed since its last round
+        let mut deletions = if !(self.config.delete_dry_run || endpoint.deletion_mode != DeletionMode::None) {
+            None
+        } else if !incremental_fetch && !fetched.truncated {
+            Some(DeletionReport::new(existing_hashes.keys(), &filtered_data))
+        } else if !fetched.removed_ids.is_empty() {
+            Some(DeletionReport::removed(&existing_hashes, &fetched.removed_ids))
+        } else {
+            None
+        };
+        if let Some(report) = deletions.as_mut().filter(|_| self.config.delete_dry_run) {
+            self.report_dry_run(endpoint, report, MISSING_ROWS, &endpoint.name).await;
+            metrics::DELETE_DRY_RUN_ROWS.with_label_values(&[&endpoint.name]).set(report.missing.len() as f64);
+        }
+        // Soft-deleted rows have no hash, so one that comes back is counted as inserted
+        let inserted_ids: Vec<String> = if endpoint.deletion_mode == DeletionMode::Soft {
+            filtered_data.iter()
+                .filter_map(|record| record.get("id").and_then(|id| id.as_str()))
+                .filter(|id| !existing_hashes.contains_key(*id))
+                .map(str::to_string)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let (mut changed_data, counts) = detect_changes_in(filtered_data, &existing_hashes, &hash_column);
+        if self.config.database.store_run_id {
+            apply_run_id(&mut changed_data, &self.config.database.run_id_column, run_id);
+        }
+
+        // Store data in the database. The backend tells inserts from updates, also for rows
+        // that had no hash; records it could not write count as skipped.
+        let counts = if changed_data.is_empty() {
+            counts
+        } else {
+            match self.storage.store_endpoint_data(&endpoint.table_name, &changed_data).await {
+                Ok(written) => ChangeCounts { skipped: counts.skipped + written.skipped, ..written },
+                Err(e) => {
+                    // Columns may have been added before the write failed
+                    self.audit_writes(run_id, endpoint, ChangeCounts::default());
+                    return Err(e);
+                }
+            }
+        };
+
+        let stored_count = counts.stored();
+        info!(
+            "Stored {} items in table: {} ({} inserted, {} updated, {} skipped)",
+            stored_count, endpoint.table_name, counts.inserted, counts.updated, counts.skipped
+        );
+
+        // Indexed columns that data has just added get their indexes now; existing ones
+        // are left alone
+        if !endpoint.indexes.is_empty() && self.config.database.auto_migrate {
+            self.storage.create_indexes(&endpoint.table_name, &endpoint.indexes).await;
+        }
+        self.audit_writes(run_id, endpoint, counts);
+
+        if !inserted_ids.is_empty() {
+            let restored = self.storage.mark_rows_deleted(&endpoint.table_name, &hash_column, &inserted_ids, None).await?;
+            if restored > 0 {
+                info!("{} rows of table {} are returned by Graph again and no longer marked deleted", restored, endpoint.table_name);
+            }
+        }
+        if let Some(report) = deletions.filter(|_| !self.config.delete_dry_run) {
+            self.delete_missing_rows(endpoint, run_id, &hash_column, report, endpoint.deletion_mode, MISSING_ROWS).await?;
+        }
+
+        // Only move the watermark (and ETag) once the records up to it are stored
+        if new_watermark.is_some() || endpoint.use_etag || endpoint.use_delta || auto {
+            let previous = sync_state.unwrap_or_default();
+            let now = Utc::now();
+            // A full pull cut off at maxItems did not see everything
+            let full_sync_done = auto && full_sync && !fetched.truncated;
+            let state = SyncState {
+                endpoint_name: endpoint.name.clone(),
+                watermark: new_watermark.map(|value| value.to_rfc3339()).or(previous.watermark.clone()),
+                etag: if endpoint.use_etag { new_etag } else { previous.etag.clone() },
+                // A fetch cut off at maxItems has no delta link, so the next sync starts a new round
+                delta_link: if endpoint.use_delta { new_delta_link } else { previous.delta_link.clone() },
+                updated_at: Some(now),
+                last_full_sync_at: if full_sync_done { Some(now) } else { previous.last_full_sync_at },
+                ..previous
+            };
+            self.storage.set_sync_state(&state).await?;
+            debug!("Sync state of endpoint {} is now {:?}", endpoint.name, state);
+        }
+
+        // Update metrics
+        metrics::DEVICES_FETCHED_TOTAL.inc_by(fetched_count as f64);
+        metrics::DEVICES_PROCESSED_TOTAL.inc_by(stored_count as f64);
+        metrics::DB_INSERT_TOTAL.inc_by(counts.inserted as f64);
+        metrics::DB_UPDATE_TOTAL.inc_by(counts.updated as f64);
+        metrics::DB_SKIP_TOTAL.inc_by(counts.skipped as f64);
+
+        Ok(EndpointCounts { fetched: graph_count, changes: counts })
+    }
+
+    /// Log what a dry run would delete, naming the rows by `deviceNameFields`, and write
+    /// the full list to `<list_name>.ndjson` in the dry run directory. A failed name
+    /// lookup or write only leaves the names or the file out.
+    async fn report_dry_run(&mut self, endpoint: &EndpointConfig, report: &mut DeletionReport, reason: &str, list_name: &str) {
+        if !report.missing.is_empty() {
+            let name_columns: Vec<String> = self.config.device_name_fields.iter()
+                .map(|field| sanitize_column_name(field))
+                .collect();
+            match self.storage.row_names(&endpoint.table_name, &report.missing, &name_columns).await {
+                Ok(names) => report.names = names,
+                Err(e) => warn!("Dry run: could not look up the names of the rows of table {}: {}", endpoint.table_name, e),
+            }
+        }
+
+        let list = match self.config.delete_dry_run_dir() {
+            Ok(dir) if !report.missing.is_empty() => {
+                let path = dir.join(format!("{}.ndjson", list_name));
+                match report.write_list(&path) {
+                    Ok(()) => Some(path),
+                    Err(e) => {
+                        warn!("Dry run: could not write the rows of endpoint {} to {}: {}", endpoint.name, path.display(), e);
+                        None
+                    }
+                }
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Dry run: could not resolve the dry run directory: {}", e);
+                None
+            }
+        };
+        report.log(endpoint, reason, self.config.max_delete_percent, list.as_deref());
+    }
+
+    /// Apply `mode` to the reported rows, unless there are more than `maxDeletePercent`
+    /// allows. `reason` completes the log lines. Returns the number of rows removed.
+    async fn delete_missing_rows(
+        &mut self,
+        endpoint: &EndpointConfig,
+        run_id: &str,
+        hash_column: &str,
+        report: DeletionReport,
+        mode: DeletionMode,
+        reason: &str,
+    ) -> Result<u64> {
+        if report.missing.is_empty() {
+            return Ok(0);
+        }
+        if report.exceeds(self.config.max_delete_percent) {
+            error!(
+                "Not deleting {} of {} rows ({:.1}%) of table {} {}, more than maxDeletePercent allows",
+                report.missing.len(), report.stored, report.percent(), endpoint.table_name, reason
+            );
+            metrics::DELETES_REFUSED_TOTAL.with_label_values(&[&endpoint.name]).inc();
+            self.send_deletes_refused(endpoint, &report);
+            return Ok(0);
+        }
+
+        let deleted = match mode {
+            DeletionMode::None => return Ok(0),
+            DeletionMode::Soft => {
+                let now = Utc::now().to_rfc3339();
+                self.storage.mark_rows_deleted(&endpoint.table_name, hash_column, &report.missing, Some(&now)).await?
+            }
+            DeletionMode::Hard => self.storage.delete_rows(&endpoint.table_name, &report.missing).await?,
+        };
+        if deleted > 0 {
+            info!(
+                "{} {} rows of table {} {}",
+                if mode == DeletionMode::Soft { "Marked deleted" } else { "Deleted" },
+                deleted, endpoint.table_name, reason
+            );
+            metrics::ROWS_DELETED_TOTAL.with_label_values(&[&endpoint.name]).inc_by(deleted as f64);
+            self.audit_deletes(run_id, endpoint, deleted as usize);
+        }
+        Ok(deleted)
+    }
+
+    /// Send the `sync_completed` webhook with what the run wrote to the database
+    fn send_sync_completed(&mut self, run_id: &str, duration: Duration, counts: EndpointCounts) {
+        let Some(webhooks) = &self.webhooks else {
+            return;
+        };
+
+        let webhooks = Arc::clone(webhooks);
+        let sync_id = run_id.to_string();
+        self.webhook_sends.spawn(async move {
+            let result = webhooks.send_sync_completed(
+                sync_id,
+                duration.as_secs_f64(),
+                counts.fetched as u32,
+                counts.changes.updated as u32,
+                counts.changes.inserted as u32,
+                counts.changes.skipped as u32,
+            ).await;
+            if let Err(e) = result {
+                warn!("Failed to send sync completed webhook: {}", e);
+            }
+        });
+    }
+
+    /// Send the `deletes_refused` webhook for a deletion `maxDeletePercent` refused
+    fn send_deletes_refused(&mut self, endpoint: &EndpointConfig, report: &DeletionReport) {
+        let (Some(webhooks), Some(max_delete_percent)) = (&self.webhooks, self.config.max_delete_percent) else {
+            return;
+        };
+
+        let webhooks = Arc::clone(webhooks);
+        let data = DeletesRefusedData {
+            endpoint: endpoint.name.clone(),
+            table: endpoint.table_name.clone(),
+            rows_missing: report.missing.len(),
+            rows_stored: report.stored,
+            percent: report.percent(),
+            max_delete_percent,
+        };
+        self.webhook_sends.spawn(async move {
+            if let Err(e) = webhooks.send_deletes_refused(data).await {
+                warn!("Failed to send deletes refused webhook: {}", e);
+            }
+        });
+    }
+
+    /// Log, count and send the `sync_aborted` webhook for a fetch `minExpectedRatio` rejected
+    fn report_implausible_shrink(&mut self, endpoint: &EndpointConfig, fetched: usize, stored: usize) {
+        error!(
+            "Not syncing endpoint {}: fetched {} records but table {} holds {} rows, fewer than minExpectedRatio {} expects. \
+             If the devices were really retired, run `sync --allow-shrink` once or restart the service with `run --allow-shrink`",
+            endpoint.name, fetched, endpoint.table_name, stored, self.config.min_expected_ratio
+        );
+        metrics::SYNC_ABORTED_TOTAL.with_label_values(&[&endpoint.name]).inc();
+
+        if let Some(webhooks) = &self.webhooks {
+            let webhooks = Arc::clone(webhooks);
+            let data = SyncAbortedData {
+                endpoint: endpoint.name.clone(),
+                table: endpoint.table_name.clone(),
+                records_fetched: fetched,
+                rows_stored: stored,
+                min_expected_ratio: self.config.min_expected_ratio,
+            };
+            self.webhook_sends.spawn(async move {
+                if let Err(e) = webhooks.send_sync_aborted(data).await {
+                    warn!("Failed to send sync aborted webhook: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Record the schema changes and row counts of a stored endpoint in the audit log.
+    /// Failing to write it is logged but does not fail the sync, the data is already stored.
+    fn audit_writes(&mut self, run_id: &str, endpoint: &EndpointConfig, counts: ChangeCounts) {
+        // Always drained, so changes do not pile up while auditing is off
+        let schema_changes = self.storage.take_schema_changes();
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+
+        let config_hash = self.config.loaded_file.as_ref().map(|file| file.hash.clone());
+        let entry = |event| AuditEntry {
+            timestamp: Utc::now(),
+            run_id: run_id.to_string(),
+            config_hash: config_hash.clone(),
+            event,
+        };
+
+        let mut entries: Vec<AuditEntry> = schema_changes.into_iter()
+            .map(|change| entry(AuditEvent::SchemaChange {
+                backend: change.backend.to_string(),
+                table: change.table,
+                statement: change.statement,
+            }))
+            .collect();
+        if counts.inserted + counts.updated > 0 {
+            entries.push(entry(AuditEvent::Write {
+                endpoint: endpoint.name.clone(),
+                table: endpoint.table_name.clone(),
+                inserted: counts.inserted,
+                updated: counts.updated,
+                deleted: 0,
+            }));
+        }
+
+        if let Err(e) = audit_log.append(&entries) {
+            error!("Failed to write audit log {}: {:#}", audit_log.path().display(), e);
+        }
+    }
+
+    /// Record rows deleted from an endpoint's table in the audit log
+    fn audit_deletes(&self, run_id: &str, endpoint: &EndpointConfig, deleted: usize) {
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            run_id: run_id.to_string(),
+            config_hash: self.config.loaded_file.as_ref().map(|file| file.hash.clone()),
+            event: AuditEvent::Write {
+                endpoint: endpoint.name.clone(),
+                table: endpoint.table_name.clone(),
+                inserted: 0,
+                updated: 0,
+                deleted,
+            },
+        };
+        if let Err(e) = audit_log.append(&[entry]) {
+            error!("Failed to write audit log {}: {:#}", audit_log.path().display(), e);
+        }
+    }
+
+    async fn ensure_endpoint_table_exists(&mut self, endpoint: &EndpointConfig) -> Result<()> {
+        // Without autoMigrate tables and indexes are created through migration review, not here
+        if !self.config.database.auto_migrate {
+            return Ok(());
+        }
+
+        // Each backend creates a generic table in its own dialect
+        self.storage.create_table_if_not_exists(&endpoint.table_name).await?;
+
+        // Indexes whose columns the table already has are created before any data is
+        // stored; the rest follow once storing has added their columns
+        if !endpoint.indexes.is_empty() {
+            self.storage.create_indexes(&endpoint.table_name, &endpoint.indexes).await;
+        }
+        Ok(())
+    }
+
+    fn apply_device_filtering(&self, data: &[serde_json::Value]) -> Result<Vec<serde_json::Value>> {
+        Ok(filter_devices(&self.os_filter, &self.config.device_name_fields, data))
+    }
+
+    /// Legacy method for backward compatibility - now uses endpoint-based approach
+    async fn process_device(&mut self, device_data: HashMap<String, serde_json::Value>) -> Result<bool> {
+        warn!("process_device is deprecated - use endpoint-based sync instead");
+
+        // Convert to JSON value for endpoint processing
+        let json_value = serde_json::to_value(device_data)?;
+
+        // Find the devices endpoint
+        let enabled_endpoints = self.endpoint_manager.get_enabled_endpoints();
+        let devices_endpoint = enabled_endpoints
+            .iter()
+            .find(|e| e.name == "devices")
+            .ok_or_else(|| anyhow::anyhow!("Devices endpoint not found or not enabled"))?;
+
+        // Process as single-item endpoint data
+        let data = vec![json_value];
+        let filtered_data = self.apply_device_filtering(&data)?;
+
+        if filtered_data.is_empty() {
+            return Ok(false); // Device was filtered out
+        }
+
+        // Store in the devices table
+        let stored_count = self.storage.store_endpoint_data(&devices_endpoint.table_name, &filtered_data).await?.stored();
+
+        metrics::DEVICES_PROCESSED_TOTAL.inc();
+        Ok(stored_count > 0)
+    }
+
+    /// Clean up resources
+    pub async fn cleanup(&mut self) -> Result<()> {
+        info!("Cleaning up sync service resources...");
+
+        // Webhooks still being sent would be dropped with the process. They go first, as
+        // with a digest they only add to its buffer
+        if tokio::time::timeout(WEBHOOK_SEND_TIMEOUT, self.webhook_sends.finish()).await.is_err() {
+            warn!("Stopped waiting for webhooks still being sent after {:?}", WEBHOOK_SEND_TIMEOUT);
+        }
+
+        // Buffered webhook events would be lost with the process
+        if let Some(ref webhooks) = self.webhooks {
+            if let Err(e) = webhooks.flush_digest().await {
+                error!("Failed to send the final webhook digest: {}", e);
+            }
+        }
+
+        // Clean up storage backends
+        if let Err(e) = self.storage.cleanup().await {
+            error!("Failed to cleanup storage backends: {}", e);
+        }
+
+        info!("Sync service cleanup completed");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_sample_records() {
+        let records = vec![
+            json!({"id": "1", "deviceName": null}),
+            json!({"id": "2", "deviceName": "PC-02", "model": "Surface"}),
+        ];
+        assert_eq!(
+            crate::storage::schema_sample(&records, records.len()),
+            json!({"id": "1", "deviceName": "PC-02", "model": "Surface"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_device_filtering() {
+        let config = AppConfig {
+            client_id: "test".to_string(),
+            client_secret: "test".to_string(),
+            tenant_id: "test".to_string(),
+            device_os_filter: vec!["Windows".to_string()],
+            enable_prometheus: false,
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: ":memory:".to_string(),
+                    vacuum_into: None,
+                }),
+                postgres: None,
+                mssql: None,
+                ..AppConfig::default().database
+            },
+            ..AppConfig::default()
+        };
+
+        let auth_client = AuthClient::new(config.clone());
+        let mut storage_manager = StorageManager::new(&config.database).await.unwrap();
+        storage_manager.initialize().await.unwrap();
+
+        let endpoints_config = config.get_endpoints_config();
+        let endpoint_manager = EndpointManager::new(endpoints_config, auth_client.clone(), None, None);
+
+        let sync_service = SyncService {
+            config: config.clone(),
+            auth_client,
+            storage: storage_manager,
+            os_filter: DeviceOsFilter::new(&["Windows".to_string()]),
+            endpoint_manager,
+            transforms: HashMap::new(),
+            webhooks: None,
+            webhook_sends: WebhookSends::default(),
+            audit_log: None,
+            endpoint_breakers: HashMap::new(),
+            endpoint_circuits: SharedCircuits::default(),
+            maintenance_interval: None,
+            last_maintenance: Instant::now(),
+            pre_sync_backup: None,
+            drain: DrainSignal::default(),
+            allow_shrink: false,
+        };
+
+        let test_data = vec![
+            json!({
+                "deviceName": "Windows Device",
+                "operatingSystem": "Windows"
+            }),
+            json!({
+                "deviceName": "Mac Device",
+                "operatingSystem": "macOS"
+            }),
+            json!({
+                "deviceName": "Another Windows Device",
+                "operatingSystem": "Windows"
+            })
+        ];
+
+        let filtered_data = sync_service.apply_device_filtering(&test_data).unwrap();
+
+        // Should only include Windows devices
+        assert_eq!(filtered_data.len(), 2);
+        for device in &filtered_data {
+            assert_eq!(device["operatingSystem"], "Windows");
+        }
+    }
+
+    #[test]
+    fn test_write_ndjson() {
+        let records = vec![
+            json!({"id": "1", "deviceName": "PC-01"}),
+            json!({"id": "2", "notes": "line one\nline two"}),
+        ];
+        let mut out = Vec::new();
+        write_ndjson(&mut out, &records).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, record) in lines.iter().zip(&records) {
+            assert_eq!(&serde_json::from_str::<serde_json::Value>(line).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_prepare_records_transform() {
+        let config = AppConfig { device_os_filter: vec!["Windows*".to_string()], ..Default::default() };
+        let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+        let endpoint = EndpointConfig {
+            transform: Some("merge(@, {compliant: complianceState == 'compliant', model: hardwareInformation.model})".to_string()),
+            exclude_fields: vec!["hardwareInformation".to_string()],
+            ..Default::default()
+        };
+        let data = vec![
+            json!({"id": "1", "operatingSystem": "Windows", "complianceState": "compliant", "hardwareInformation": {"model": "Surface"}}),
+            json!({"id": "2", "operatingSystem": "iOS", "complianceState": "noncompliant"}),
+        ];
+
+        let transform = compile_transform(&endpoint).unwrap();
+        let records = prepare_records(&config, &os_filter, &endpoint, transform.as_ref(), data.clone()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["compliant"], true);
+        assert_eq!(records[0]["model"], "Surface");
+        assert!(records[0].get("hardwareInformation").is_none());
+
+        let not_an_object = EndpointConfig { transform: Some("id".to_string()), ..Default::default() };
+        let transform = compile_transform(&not_an_object).unwrap();
+        assert!(prepare_records(&config, &os_filter, &not_an_object, transform.as_ref(), data).is_err());
+    }
+
+    #[test]
+    fn test_prepare_records_normalizes_fields() {
+        use crate::config::NormalizeRule;
+        let config = AppConfig {
+            device_os_filter: vec!["*".to_string()],
+            normalize_fields: HashMap::from([
+                ("wiFiMacAddress".to_string(), vec![NormalizeRule::Uppercase, NormalizeRule::StripSeparators]),
+                ("serialNumber".to_string(), vec![NormalizeRule::Trim, NormalizeRule::Uppercase]),
+            ]),
+            ..Default::default()
+        };
+        let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+        let endpoint = EndpointConfig { hash_fields: vec!["serialNumber".to_string()], ..Default::default() };
+        let data = vec![
+            json!({"id": "1", "operatingSystem": "Windows", "wiFiMacAddress": "aa:bb:cc", "serialNumber": "abc123 "}),
+            json!({"id": "2", "operatingSystem": "Windows", "wiFiMacAddress": "AA-BB-CC", "serialNumber": "ABC123"}),
+        ];
+
+        let records = prepare_records(&config, &os_filter, &endpoint, None, data).unwrap();
+        assert_eq!(records[0]["wiFiMacAddress"], "AABBCC");
+        assert_eq!(records[1]["wiFiMacAddress"], "AABBCC");
+        // Normalized before being pseudonymized, so both tokens match
+        assert_eq!(records[0]["serialNumber"], records[1]["serialNumber"]);
+    }
+
+    #[test]
+    fn test_prepare_records_derives_missing_device_ids() {
+        let namespace = uuid::Uuid::NAMESPACE_DNS;
+        let config = AppConfig {
+            device_os_filter: vec!["*".to_string()],
+            uuid_strategy: crate::config::UuidStrategy::V5,
+            uuid_namespace: Some(namespace.to_string()),
+            ..Default::default()
+        };
+        let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+        let devices = EndpointConfig { name: "devices".to_string(), ..Default::default() };
+        let data = vec![
+            json!({"id": "kept", "operatingSystem": "Windows", "serialNumber": "ABC123"}),
+            json!({"operatingSystem": "Windows", "serialNumber": "ABC123"}),
+        ];
+
+        let records = prepare_records(&config, &os_filter, &devices, None, data.clone()).unwrap();
+        assert_eq!(records[0]["id"], "kept");
+        let derived = crate::uuid_utils::get_or_generate_device_uuid(
+            &data[1].as_object().unwrap().clone().into_iter().collect(),
+            config.uuid_scheme().unwrap(),
+            &config.normalize_fields,
+        ).unwrap();
+        assert_eq!(records[1]["id"], derived.to_string());
+        assert_eq!(derived.get_version_num(), 5);
+    }
+
+    #[test]
+    fn test_implausible_shrink() {
+        assert!(implausible_shrink(40, 100, 0.5));
+        assert!(!implausible_shrink(50, 100, 0.5));
+        // Nothing stored yet, or the check turned off
+        assert!(!implausible_shrink(0, 0, 0.5));
+        assert!(!implausible_shrink(0, 100, 0.0));
+    }
+
+    #[test]
+    fn test_deletion_report() {
+        let stored: Vec<String> = ["a", "b", "c", "d"].iter().map(|id| id.to_string()).collect();
+        let fetched = vec![json!({"id": "b"}), json!({"id": "d"}), json!({"id": "e"})];
+
+        let report = DeletionReport::new(stored.iter(), &fetched);
+        assert_eq!(report.stored, 4);
+        assert_eq!(report.missing, vec!["a".to_string(), "c".to_string()]);
+        assert_eq!(report.percent(), 50.0);
+        assert!(!report.exceeds(None));
+        assert!(!report.exceeds(Some(50.0)));
+        assert!(report.exceeds(Some(10.0)));
+
+        // Nothing stored yet, nothing to delete
+        assert_eq!(DeletionReport::new([].iter(), &fetched).percent(), 0.0);
+    }
+
+    #[test]
+    fn test_prepare_records_keys_by_merge_field() {
+        let config = AppConfig::default();
+        let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+        let endpoint = EndpointConfig {
+            name: "compliance".to_string(),
+            merge_key: Some("deviceId".to_string()),
+            store_fields: Some(vec!["complianceState".to_string()]),
+            ..Default::default()
+        };
+        let data = vec![
+            json!({"id": "state-1", "deviceId": "device-1", "complianceState": "compliant"}),
+            json!({"id": "state-2", "complianceState": "noncompliant"}),
+        ];
+
+        let records = prepare_records(&config, &os_filter, &endpoint, None, data).unwrap();
+        assert_eq!(records, vec![json!({"id": "device-1", "complianceState": "compliant"})]);
+
+        let (records, _) = detect_changes_in(records, &HashMap::new(), &endpoint.hash_column());
+        assert!(records[0].get("device_hash_compliance").is_some());
+        assert!(records[0].get(HASH_COLUMN).is_none());
+    }
+
+    #[test]
+    fn test_prepare_records_skips_stale_devices() {
+        let config = AppConfig {
+            device_os_filter: vec!["*".to_string()],
+            stale_device_max_age_days: Some(30),
+            ..Default::default()
+        };
+        let os_filter = DeviceOsFilter::new(&config.device_os_filter);
+        let devices = EndpointConfig { name: "devices".to_string(), ..Default::default() };
+        let recent = (Utc::now() - chrono::Duration::days(2)).to_rfc3339();
+        let data = vec![
+            json!({"id": "1", "operatingSystem": "Windows", "lastSyncDateTime": recent}),
+            json!({"id": "2", "operatingSystem": "Windows", "lastSyncDateTime": "2020-01-01T00:00:00Z"}),
+            json!({"id": "3", "operatingSystem": "Windows", "lastSyncDateTime": NEVER_CHECKED_IN}),
+            json!({"id": "4", "operatingSystem": "Windows"}),
+        ];
+
+        let ids = |records: Vec<serde_json::Value>| -> Vec<String> {
+            records.iter().map(|r| r["id"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(ids(prepare_records(&config, &os_filter, &devices, None, data.clone()).unwrap()), ["1", "3", "4"]);
+
+        // Only devices are pruned
+        let users = EndpointConfig { name: "users".to_string(), ..Default::default() };
+        assert_eq!(prepare_records(&config, &os_filter, &users, None, data).unwrap().len(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sync_schedule_first_tick() {
+        let mut config = AppConfig::default();
+        config.poll_interval = Some("1h".to_string());
+
+        let started = tokio::time::Instant::now();
+        let mut schedule = SyncSchedule::new(&config).unwrap();
+        schedule.tick().await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // With syncOnStartup off the first sync waits a full interval
+        config.sync_on_startup = false;
+        let started = tokio::time::Instant::now();
+        let mut schedule = SyncSchedule::new(&config).unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(59 * 60), schedule.tick()).await.is_err());
+        schedule.tick().await;
+        assert_eq!(started.elapsed(), Duration::from_secs(3600));
+
+        // ... or for the first time of the cron schedule, which replaces the interval
+        config.cron_schedule = Some("0 0 1 1 *".to_string());
+        let mut schedule = SyncSchedule::new(&config).unwrap();
+        assert!(matches!(schedule, SyncSchedule::Cron { next: Some(next), .. } if next > Utc::now()));
+        assert!(tokio::time::timeout(Duration::from_secs(3600), schedule.tick()).await.is_err());
+
+        config.sync_on_startup = true;
+        let mut schedule = SyncSchedule::new(&config).unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(1), schedule.tick()).await.is_ok());
+    }
+
+    #[test]
+    fn test_cron_schedule_overlap() {
+        use chrono::TimeZone;
+
+        let mut config = AppConfig::default();
+        config.cron_schedule = Some("0 * * * *".to_string());
+        let started = Utc.with_ymd_and_hms(2026, 3, 1, 10, 0, 0).unwrap();
+        let finished = Utc.with_ymd_and_hms(2026, 3, 1, 12, 30, 0).unwrap();
+
+        // 11:00 and 12:00 came due during the sync; the next one runs at 13:00
+        let mut schedule = SyncSchedule::new(&config).unwrap();
+        assert_eq!(schedule.finish(started, finished), 2);
+        assert!(matches!(schedule, SyncSchedule::Cron { next: Some(next), .. }
+            if next == Utc.with_ymd_and_hms(2026, 3, 1, 13, 0, 0).unwrap()));
+        assert_eq!(schedule.finish(started, started + chrono::Duration::minutes(5)), 0);
+
+        // Queued, one of them starts right away
+        config.on_overlap = OverlapPolicy::Queue;
+        let mut schedule = SyncSchedule::new(&config).unwrap();
+        assert_eq!(schedule.finish(started, finished), 2);
+        assert!(matches!(schedule, SyncSchedule::Cron { next: Some(next), .. } if next == finished));
+
+        config.cron_schedule = Some("every hour".to_string());
+        assert!(SyncSchedule::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_overlapped_ticks() {
+        let poll = Duration::from_secs(60);
+        assert_eq!(overlapped_ticks(Duration::from_secs(30), poll), 0);
+        assert_eq!(overlapped_ticks(Duration::from_secs(60), poll), 1);
+        assert_eq!(overlapped_ticks(Duration::from_secs(150), poll), 2);
+        assert_eq!(overlapped_ticks(Duration::from_secs(150), Duration::ZERO), 0);
+    }
+
+    #[test]
+    fn test_detect_changes() {
+        let first_run = vec![
+            json!({"id": "1", "deviceName": "A"}),
+            json!({"id": "2", "deviceName": "B"}),
+        ];
+        let (changed, counts) = detect_changes(first_run, &HashMap::new());
+        assert_eq!(counts, ChangeCounts { inserted: 2, updated: 0, skipped: 0 });
+        assert_eq!(changed.len(), 2);
+
+        // Simulate the stored hashes from the first run
+        let stored: HashMap<String, String> = changed.iter()
+            .map(|item| (
+                item["id"].as_str().unwrap().to_string(),
+                item[HASH_COLUMN].as_str().unwrap().to_string(),
+            ))
+            .collect();
+
+        let second_run = vec![
+            json!({"id": "1", "deviceName": "A"}),
+            json!({"id": "2", "deviceName": "B renamed"}),
+            json!({"id": "3", "deviceName": "C"}),
+        ];
+        let (changed, counts) = detect_changes(second_run, &stored);
+        assert_eq!(counts, ChangeCounts { inserted: 1, updated: 1, skipped: 1 });
+        let changed_ids: Vec<&str> = changed.iter().map(|i| i["id"].as_str().unwrap()).collect();
+        assert_eq!(changed_ids, vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_resolve_id_collisions() {
+        let fetch = || vec![
+            json!({"id": "1", "deviceName": "A"}),
+            json!({"id": "2", "deviceName": "B"}),
+            json!({"id": "1", "deviceName": "C"}),
+            json!({"deviceName": "no id"}),
+            json!({"id": "1", "deviceName": "D"}),
+        ];
+        let fields = vec!["deviceName".to_string()];
+        let summary = |records: Vec<serde_json::Value>| -> Vec<(String, String)> {
+            records.iter()
+                .map(|r| (r["id"].as_str().unwrap_or("").to_string(), r["deviceName"].as_str().unwrap().to_string()))
+                .collect()
+        };
+        let pairs = |expected: &[(&str, &str)]| -> Vec<(String, String)> {
+            expected.iter().map(|(id, name)| (id.to_string(), name.to_string())).collect()
+        };
+
+        let first = resolve_id_collisions("devices", fetch(), UuidCollisionPolicy::FirstWins, &fields);
+        assert_eq!(summary(first), pairs(&[("1", "A"), ("2", "B"), ("", "no id")]));
+
+        let last = resolve_id_collisions("devices", fetch(), UuidCollisionPolicy::LastWins, &fields);
+        assert_eq!(summary(last), pairs(&[("1", "D"), ("2", "B"), ("", "no id")]));
+
+        let both = resolve_id_collisions("devices", fetch(), UuidCollisionPolicy::StoreBothWithSuffix, &fields);
+        assert_eq!(summary(both), pairs(&[("1", "A"), ("2", "B"), ("1-2", "C"), ("", "no id"), ("1-3", "D")]));
+    }
+
+    #[tokio::test]
+    async fn test_drain_finishes_sync_and_returns() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let config = AppConfig {
+            mock_graph_api: Some(crate::mock_graph_api::MockGraphApiConfig {
+                enabled: true,
+                response_delay_ms: (0, 0),
+                ..Default::default()
+            }),
+            endpoints: Some(crate::endpoint::EndpointsConfig {
+                endpoints: vec![EndpointConfig { mock_object_count: Some(20), ..Default::default() }],
+                ..Default::default()
+            }),
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: temp_file.path().to_string_lossy().into_owned(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            device_os_filter: vec!["*".to_string()],
+            poll_interval: Some("1h".to_string()),
+            sync_on_startup: true,
+            ..Default::default()
+        };
+        let drain = DrainSignal::default();
+        let mut service = SyncService::new(config).await.unwrap()
+            .with_drain_signal(drain.clone())
+            .with_shrink_allowed(true);
+
+        // Requested once the first sync has created its table: the sync completes, and the
+        // next hour is not waited for
+        let sync_started = || {
+            rusqlite::Connection::open(temp_file.path())
+                .and_then(|connection| connection.query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE name = 'devices'", [], |row| row.get::<_, i64>(0)
+                ))
+                .is_ok_and(|tables| tables > 0)
+        };
+        let (result, _) = tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::join!(service.run(), async {
+                while !sync_started() {
+                    sleep(Duration::from_millis(5)).await;
+                }
+                assert!(drain.request());
+            })
+        }).await.unwrap();
+        result.unwrap();
+        assert!(!drain.request());
+        assert!(drain.requested_at().is_some());
+        assert_eq!(service.storage.get_table_count("devices").await.unwrap(), 20);
+        // `run --allow-shrink` only ever covers the first sync
+        assert!(!service.allow_shrink);
+    }
+
+    #[tokio::test]
+    async fn test_stale_prune_follows_deletion_settings() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let config = AppConfig {
+            mock_graph_api: Some(crate::mock_graph_api::MockGraphApiConfig {
+                enabled: true,
+                ..Default::default()
+            }),
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: temp_file.path().to_string_lossy().into_owned(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            stale_device_max_age_days: Some(30),
+            delete_dry_run: true,
+            data_dir: Some(data_dir.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let mut service = SyncService::new(config).await.unwrap();
+        let mut endpoint = service.endpoint_manager().get_enabled_endpoints()[0].clone();
+        service.storage.create_table_if_not_exists("devices").await.unwrap();
+        let device = |id: &str, last_sync: String| serde_json::json!({"id": id, "lastSyncDateTime": last_sync, HASH_COLUMN: id});
+        let mut old = device("old", "2020-01-01T00:00:00Z".to_string());
+        old["deviceName"] = serde_json::json!("LAPTOP-OLD");
+        service.storage.store_endpoint_data("devices", &[old, device("current", Utc::now().to_rfc3339())]).await.unwrap();
+
+        // A dry run only logs, and writes every row it would delete with its name
+        service.prune_stale_devices(&endpoint, "run-1").await.unwrap();
+        assert_eq!(service.storage.get_table_count("devices").await.unwrap(), 2);
+        let list = std::fs::read_to_string(data_dir.path().join("delete-dry-run").join("devices-stale.ndjson")).unwrap();
+        assert_eq!(list.trim(), r#"{"id":"old","name":"LAPTOP-OLD"}"#);
+
+        // Soft deletion keeps the row and marks it
+        service.config.delete_dry_run = false;
+        endpoint.deletion_mode = DeletionMode::Soft;
+        service.prune_stale_devices(&endpoint, "run-2").await.unwrap();
+        assert_eq!(service.storage.get_table_count("devices").await.unwrap(), 2);
+        assert!(!service.storage.get_all_hashes("devices", HASH_COLUMN).await.unwrap().contains_key("old"));
+
+        // 1 of 3 rows is more than maxDeletePercent allows, until the limit is lifted
+        service.storage.store_endpoint_data("devices", &[device("retired", "2020-06-01T00:00:00Z".to_string())]).await.unwrap();
+        endpoint.deletion_mode = DeletionMode::None;
+        service.config.max_delete_percent = Some(10.0);
+        service.prune_stale_devices(&endpoint, "run-3").await.unwrap();
+        assert_eq!(service.storage.get_table_count("devices").await.unwrap(), 3);
+        service.config.max_delete_percent = None;
+        service.prune_stale_devices(&endpoint, "run-4").await.unwrap();
+        assert_eq!(service.storage.get_table_count("devices").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_flags_removed_device() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let config = AppConfig {
+            mock_graph_api: Some(crate::mock_graph_api::MockGraphApiConfig {
+                enabled: true,
+                response_delay_ms: (0, 0),
+                ..Default::default()
+            }),
+            endpoints: Some(crate::endpoint::EndpointsConfig {
+                endpoints: vec![EndpointConfig {
+                    mock_object_count: Some(20),
+                    deletion_mode: DeletionMode::Soft,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: temp_file.path().to_string_lossy().into_owned(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            device_os_filter: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let mut service = SyncService::new(config).await.unwrap();
+        service.sync_once().await.unwrap();
+
+        let deleted_ids = || {
+            let connection = rusqlite::Connection::open(temp_file.path()).unwrap();
+            let mut statement = connection
+                .prepare("SELECT id FROM devices WHERE deleted_at IS NOT NULL")
+                .unwrap();
+            let ids = statement.query_map([], |row| row.get::<_, String>(0)).unwrap();
+            ids.collect::<rusqlite::Result<Vec<String>>>().unwrap()
+        };
+        let stored_ids = service.storage.get_all_hashes("devices", HASH_COLUMN).await.unwrap();
+        let removed_id = stored_ids.keys().next().unwrap().clone();
+
+        let mock_api = service.endpoint_manager().mock_api().unwrap().clone();
+        let removed_device = mock_api.get_device_by_id(&removed_id).await.unwrap();
+        assert!(mock_api.remove_mock_device(&removed_id).await);
+        service.sync_once().await.unwrap();
+
+        // The row stays, flagged, and no longer counts as stored
+        assert_eq!(deleted_ids(), vec![removed_id.clone()]);
+        assert_eq!(service.storage.get_table_count("devices").await.unwrap(), 20);
+        let stored_ids = service.storage.get_all_hashes("devices", HASH_COLUMN).await.unwrap();
+        assert_eq!(stored_ids.len(), 19);
+        assert!(!stored_ids.contains_key(&removed_id));
+
+        // A device that comes back is no longer flagged
+        mock_api.add_mock_device(removed_device).await;
+        service.sync_once().await.unwrap();
+        assert!(deleted_ids().is_empty());
+        assert_eq!(service.storage.get_all_hashes("devices", HASH_COLUMN).await.unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_refused_deletion_sends_webhook() {
+        use axum::{routing::post, Json, Router};
+
+        let received = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let sink = received.clone();
+        let app = Router::new().route("/webhook", post(move |Json(body): Json<serde_json::Value>| async move {
+            sink.lock().unwrap().push(body);
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let config = AppConfig {
+            mock_graph_api: Some(crate::mock_graph_api::MockGraphApiConfig {
+                enabled: true,
+                response_delay_ms: (0, 0),
+                ..Default::default()
+            }),
+            endpoints: Some(crate::endpoint::EndpointsConfig {
+                endpoints: vec![EndpointConfig {
+                    mock_object_count: Some(20),
+                    deletion_mode: DeletionMode::Hard,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: temp_file.path().to_string_lossy().into_owned(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            webhook: Some(crate::webhook::WebhookConfig {
+                enabled: true,
+                url: format!("http://{}/webhook", addr),
+                events: vec![crate::webhook::WebhookEvent::DeletesRefused],
+                ..Default::default()
+            }),
+            max_delete_percent: Some(10.0),
+            device_os_filter: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let mut service = SyncService::new(config).await.unwrap();
+        service.sync_once().await.unwrap();
+
+        // 4 of 20 rows missing is more than 10%, so none are deleted
+        let mock_api = service.endpoint_manager().mock_api().unwrap().clone();
+        let stored_ids = service.storage.get_all_hashes("devices", HASH_COLUMN).await.unwrap();
+        for id in stored_ids.keys().take(4) {
+            assert!(mock_api.remove_mock_device(id).await);
+        }
+        service.sync_once().await.unwrap();
+        assert_eq!(service.storage.get_table_count("devices").await.unwrap(), 20);
+
+        service.webhook_sends.finish().await;
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["event"], "deletes_refused");
+        assert_eq!(received[0]["data"]["endpoint"], "devices");
+        assert_eq!(received[0]["data"]["rows_missing"], 4);
+        assert_eq!(received[0]["data"]["rows_stored"], 20);
+        assert_eq!(received[0]["data"]["max_delete_percent"], 10.0);
+    }
+
+    #[tokio::test]
+    async fn test_sync_completed_webhook_counts() {
+        use axum::{routing::post, Json, Router};
+
+        let received = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+        let sink = received.clone();
+        let app = Router::new().route("/webhook", post(move |Json(body): Json<serde_json::Value>| async move {
+            sink.lock().unwrap().push(body);
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let config = AppConfig {
+            mock_graph_api: Some(crate::mock_graph_api::MockGraphApiConfig {
+                enabled: true,
+                response_delay_ms: (0, 0),
+                // Randomly edited devices would count as updated
+                device_update_frequency: 0.0,
+                ..Default::default()
+            }),
+            endpoints: Some(crate::endpoint::EndpointsConfig {
+                endpoints: vec![EndpointConfig {
+                    mock_object_count: Some(20),
+                    deletion_mode: DeletionMode::Soft,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: temp_file.path().to_string_lossy().into_owned(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            webhook: Some(crate::webhook::WebhookConfig {
+                enabled: true,
+                url: format!("http://{}/webhook", addr),
+                ..Default::default()
+            }),
+            device_os_filter: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let mut service = SyncService::new(config).await.unwrap();
+        // Webhooks are sent in the background, so they may arrive in any order
+        let completed = |sync_id: String| {
+            received.lock().unwrap().iter()
+                .find(|event| event["event"] == "sync_completed" && event["data"]["sync_id"] == sync_id.as_str())
+                .map(|event| event["data"].clone())
+                .unwrap_or_else(|| panic!("no sync_completed webhook was sent for sync {}", sync_id))
+        };
+
+        let sync_id = service.sync_once().await.unwrap();
+        service.webhook_sends.finish().await;
+        let data = completed(sync_id);
+        assert_eq!(data["devices_fetched"], 20);
+        assert_eq!(data["devices_inserted"], 20);
+        assert_eq!(data["devices_updated"], 0);
+        assert_eq!(data["devices_skipped"], 0);
+
+        // A soft-deleted device that comes back has no hash, but its row is updated, not inserted
+        let stored_ids = service.storage.get_all_hashes("devices", HASH_COLUMN).await.unwrap();
+        let removed_id = stored_ids.keys().next().unwrap().clone();
+        let mock_api = service.endpoint_manager().mock_api().unwrap().clone();
+        let removed_device = mock_api.get_device_by_id(&removed_id).await.unwrap();
+        assert!(mock_api.remove_mock_device(&removed_id).await);
+        service.sync_once().await.unwrap();
+        mock_api.add_mock_device(removed_device).await;
+        let sync_id = service.sync_once().await.unwrap();
+        service.webhook_sends.finish().await;
+
+        let data = completed(sync_id);
+        assert_eq!(data["devices_fetched"], 20);
+        assert_eq!(data["devices_inserted"], 0);
+        assert_eq!(data["devices_updated"], 1);
+        assert_eq!(data["devices_skipped"], 19);
+    }
+
+    #[tokio::test]
+    async fn test_run_id_per_sync() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let config = AppConfig {
+            mock_graph_api: Some(crate::mock_graph_api::MockGraphApiConfig {
+                enabled: true,
+                response_delay_ms: (0, 0),
+                device_update_frequency: 0.0,
+                ..Default::default()
+            }),
+            endpoints: Some(crate::endpoint::EndpointsConfig {
+                endpoints: vec![EndpointConfig { mock_object_count: Some(3), ..Default::default() }],
+                ..Default::default()
+            }),
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: temp_file.path().to_string_lossy().into_owned(),
+                    vacuum_into: None,
+                }),
+                store_run_id: true,
+                ..AppConfig::default().database
+            },
+            ..Default::default()
+        };
+        let mut service = SyncService::new(config).await.unwrap();
+        let endpoint = service.endpoint_manager().get_enabled_endpoints()[0].clone();
+        service.sync_endpoint_records(&endpoint, "run-1").await.unwrap();
+
+        // Only the row whose record changed is tagged with the second run
+        let mock_api = service.endpoint_manager().mock_api().unwrap().clone();
+        let mut ids: Vec<String> = service.storage.get_all_hashes("devices", HASH_COLUMN).await.unwrap().into_keys().collect();
+        ids.sort();
+        let mut changed = mock_api.get_device_by_id(&ids[0]).await.unwrap();
+        changed.model = format!("{} (refurbished)", changed.model);
+        mock_api.add_mock_device(changed).await;
+        service.sync_endpoint_records(&endpoint, "run-2").await.unwrap();
+
+        let connection = rusqlite::Connection::open(temp_file.path()).unwrap();
+        let run_ids: Vec<(String, String)> = connection
+            .prepare("SELECT id, run_id FROM devices ORDER BY id").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(run_ids, vec![
+            (ids[0].clone(), "run-2".to_string()),
+            (ids[1].clone(), "run-1".to_string()),
+            (ids[2].clone(), "run-1".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_reprocess_endpoint() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("reprocess.db");
+        let mut config = AppConfig {
+            device_os_filter: vec!["Windows".to_string()],
+            ..AppConfig::default()
+        };
+        config.database.sqlite.as_mut().unwrap().database_path = db_path.to_string_lossy().to_string();
+
+        let connection = rusqlite::Connection::open(&db_path).unwrap();
+        connection.execute_batch(
+            "CREATE TABLE devices (id TEXT PRIMARY KEY, data TEXT, last_sync_date_time TEXT);
+             INSERT INTO devices (id) VALUES ('0');"
+        ).unwrap();
+
+        // Rows without raw JSON cannot be rebuilt
+        assert_eq!(reprocess_endpoint(&config, "devices").await.unwrap(), None);
+        assert!(reprocess_endpoint(&config, "nonexistent").await.is_err());
+
+        let raw_windows = r#"{"id":"1","deviceName":"PC-01","operatingSystem":"Windows","@odata.etag":"x"}"#;
+        let raw_android = r#"{"id":"2","deviceName":"PHONE-01","operatingSystem":"Android"}"#;
+        connection.execute("INSERT INTO devices (id, data) VALUES ('1', ?1), ('2', ?2)", [raw_windows, raw_android]).unwrap();
+
+        assert_eq!(reprocess_endpoint(&config, "Devices").await.unwrap(), Some(1));
+
+        let (name, data, hash): (String, String, String) = connection.query_row(
+            "SELECT devicename, data, device_hash FROM devices WHERE id = '1'", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).unwrap();
+        assert_eq!(name, "PC-01");
+        assert_eq!(data, raw_windows);
+        assert!(!hash.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_last_error_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AppConfig {
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: dir.path().join("devices.db").to_string_lossy().to_string(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            ..AppConfig::default()
+        };
+        let endpoint_name = EndpointConfig::default().name;
+
+        let mut sync_service = SyncService::new(config.clone()).await.unwrap();
+        sync_service.update_endpoint_circuit(&endpoint_name, Some(anyhow::anyhow!("403 Forbidden")));
+        sync_service.save_last_error(&endpoint_name).await;
+        let state = sync_service.storage.get_sync_state(&endpoint_name).await.unwrap().unwrap();
+        assert_eq!(state.last_error.as_deref(), Some("403 Forbidden"));
+        assert!(state.last_error_at.is_some());
+
+        let circuits = SharedCircuits::default();
+        let mut restarted = SyncService::new(config).await.unwrap()
+            .with_endpoint_circuits(circuits.clone());
+        restarted.restore_last_errors().await;
+        let status = circuits.read().unwrap()[&endpoint_name].status(Utc::now());
+        assert_eq!(status.state, CircuitState::Closed);
+        assert_eq!(status.last_error.unwrap().message, "403 Forbidden");
+
+        // The next success clears it
+        restarted.update_endpoint_circuit(&endpoint_name, None);
+        restarted.save_last_error(&endpoint_name).await;
+        assert!(circuits.read().unwrap()[&endpoint_name].last_error().is_none());
+        let state = restarted.storage.get_sync_state(&endpoint_name).await.unwrap().unwrap();
+        assert_eq!(state.last_error, None);
+        assert_eq!(state.last_error_at, None);
+    }
+
+    #[tokio::test]
+    async fn test_backup_before_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AppConfig {
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: dir.path().join("devices.db").to_string_lossy().to_string(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            backup: Some(crate::backup::BackupConfig {
+                directory: dir.path().join("backups").to_string_lossy().to_string(),
+                max_backups: 1,
+                backup_before_sync: true,
+                ..Default::default()
+            }),
+            ..AppConfig::default()
+        };
+        let mut sync_service = SyncService::new(config).await.unwrap();
+        let endpoint = EndpointConfig::default();
+        sync_service.ensure_endpoint_table_exists(&endpoint).await.unwrap();
+        sync_service.storage
+            .store_endpoint_data("devices", &[serde_json::json!({"id": "1", "model": "Surface"})])
+            .await
+            .unwrap();
+
+        // The write is still in the WAL; the backup must include it anyway
+        let backup_path = sync_service.backup_before_sync().await.unwrap().unwrap();
+        let backup = rusqlite::Connection::open(&backup_path).unwrap();
+        let rows: i64 = backup.query_row("SELECT COUNT(*) FROM devices", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 1);
+
+        let metadata = std::fs::read_to_string(backup_path.with_extension("json")).unwrap();
+        assert!(metadata.contains("PreUpdate"));
+    }
+
+    #[tokio::test]
+    async fn test_indexes_created_at_table_setup() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_path = dir.path().join("devices.db");
+        let config = AppConfig {
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: database_path.to_string_lossy().to_string(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            ..AppConfig::default()
+        };
+        let mut sync_service = SyncService::new(config).await.unwrap();
+        let endpoint = EndpointConfig {
+            indexes: vec![vec!["synced_at".to_string()], vec!["model".to_string()]],
+            ..EndpointConfig::default()
+        };
+        sync_service.ensure_endpoint_table_exists(&endpoint).await.unwrap();
+
+        // The default schema already has synced_at; model waits for data to add it
+        let connection = rusqlite::Connection::open(&database_path).unwrap();
+        let mut statement = connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'devices' AND name LIKE 'idx_%'")
+            .unwrap();
+        let indexes: Vec<String> = statement.query_map([], |row| row.get(0)).unwrap().map(Result::unwrap).collect();
+        assert_eq!(indexes, ["idx_devices_synced_at"]);
+
+        // Without autoMigrate indexes go through migration review like the table
+        sync_service.config.database.auto_migrate = false;
+        let endpoint = EndpointConfig {
+            indexes: vec![vec!["last_sync_date_time".to_string()]],
+            ..EndpointConfig::default()
+        };
+        sync_service.ensure_endpoint_table_exists(&endpoint).await.unwrap();
+        let indexes: Vec<String> = statement.query_map([], |row| row.get(0)).unwrap().map(Result::unwrap).collect();
+        assert_eq!(indexes, ["idx_devices_synced_at"]);
+    }
+
+    #[tokio::test]
+    async fn test_audit_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_path = dir.path().join("audit.jsonl");
+        let config = AppConfig {
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: dir.path().join("devices.db").to_string_lossy().to_string(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            audit_log: Some(crate::audit::AuditLogConfig {
+                enabled: true,
+                path: audit_path.to_string_lossy().to_string(),
+            }),
+            ..AppConfig::default()
+        };
+        let mut sync_service = SyncService::new(config).await.unwrap();
+        let endpoint = EndpointConfig::default();
+        sync_service.ensure_endpoint_table_exists(&endpoint).await.unwrap();
+        sync_service.storage
+            .store_endpoint_data("devices", &[serde_json::json!({"id": "1", "model": "Surface"})])
+            .await
+            .unwrap();
+
+        sync_service.audit_writes("run-1", &endpoint, ChangeCounts { inserted: 1, updated: 0, skipped: 4 });
+        // Nothing written and no schema changes left, so nothing is logged
+        sync_service.audit_writes("run-2", &endpoint, ChangeCounts { inserted: 0, updated: 0, skipped: 5 });
+
+        let entries: Vec<serde_json::Value> = std::fs::read_to_string(&audit_path).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let (write, schema_changes) = entries.split_last().unwrap();
+        assert!(!schema_changes.is_empty());
+        assert!(schema_changes.iter().all(|e| e["event"] == "schemaChange" && e["backend"] == "SQLite"));
+        assert!(schema_changes.iter().any(|e| e["statement"].as_str().unwrap().contains("ADD COLUMN model")));
+        // The table itself was created by this sync, so its CREATE TABLE is the first change
+        assert!(schema_changes[0]["statement"].as_str().unwrap().starts_with("CREATE TABLE IF NOT EXISTS devices"));
+        assert_eq!(schema_changes[0]["table"], "devices");
+        assert_eq!(write["event"], "write");
+        assert_eq!(write["runId"], "run-1");
+        assert_eq!(write["inserted"], 1);
+        assert!(write.get("model").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_circuit_updates() {
+        let config = AppConfig {
+            database: crate::config::DatabaseConfig {
+                sqlite: Some(crate::config::SqliteConfig {
+                    enabled: true,
+                    database_path: ":memory:".to_string(),
+                    vacuum_into: None,
+                }),
+                ..AppConfig::default().database
+            },
+            circuit_breaker: crate::circuit_breaker::CircuitBreakerConfig {
+                max_consecutive_failures: 2,
+                ..Default::default()
+            },
+            ..AppConfig::default()
+        };
+        let circuits = SharedCircuits::default();
+        let mut sync_service = SyncService::new(config).await.unwrap()
+            .with_endpoint_circuits(circuits.clone());
+
+        sync_service.update_endpoint_circuit("groups", Some(anyhow::anyhow!("403 Forbidden")));
+        assert_eq!(circuits.read().unwrap()["groups"].state(Utc::now()), CircuitState::Closed);
+
+        sync_service.update_endpoint_circuit("groups", Some(anyhow::anyhow!("403 Forbidden")));
+        assert_eq!(circuits.read().unwrap()["groups"].state(Utc::now()), CircuitState::Open);
+        assert_eq!(metrics::ENDPOINT_CIRCUIT_OPEN.with_label_values(&["groups"]).get(), 1.0);
+
+        sync_service.update_endpoint_circuit("groups", None);
+        assert_eq!(circuits.read().unwrap()["groups"].state(Utc::now()), CircuitState::Closed);
+        assert_eq!(metrics::ENDPOINT_CIRCUIT_OPEN.with_label_values(&["groups"]).get(), 0.0);
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use jmespath::Expression;
+use serde_json::Value;
+
+/// A per-record transform written in JMESPath, compiled once and applied to every
+/// record of an endpoint
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+    expression: Expression<'static>,
+}
+
+impl Transform {
+    pub fn parse(source: &str) -> Result<Self> {
+        let expression = jmespath::compile(source)
+            .with_context(|| format!("Invalid JMESPath expression: {}", source))?;
+        Ok(Self { expression })
+    }
+
+    /// Evaluate the expression against one record
+    pub fn apply(&self, record: &Value) -> Result<Value> {
+        let result = self.expression.search(record)?;
+        Ok(serde_json::to_value(&*result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(expression: &str, record: &Value) -> Value {
+        Transform::parse(expression).unwrap().apply(record).unwrap()
+    }
+
+    #[test]
+    fn test_transform_paths_and_projections() {
+        let device = json!({
+            "id": "d1",
+            "deviceName": "LAPTOP-1",
+            "hardwareInformation": { "model": "Surface", "serialNumber": "SN1" },
+            "deviceActionResults": [
+                { "actionName": "sync", "actionState": "done" },
+                { "actionName": "wipe", "actionState": "pending" }
+            ],
+            "assignments": [["a", "b"], ["c"]],
+            "usersLoggedOn": []
+        });
+
+        assert_eq!(apply("hardwareInformation.model", &device), json!("Surface"));
+        assert_eq!(apply("deviceActionResults[-1].actionName", &device), json!("wipe"));
+        assert_eq!(apply("deviceActionResults[*].actionName", &device), json!(["sync", "wipe"]));
+        assert_eq!(apply("deviceActionResults[?actionState == 'pending'].actionName | [0]", &device), json!("wipe"));
+        assert_eq!(apply("deviceActionResults[:1].actionName", &device), json!(["sync"]));
+        assert_eq!(apply("assignments[]", &device), json!(["a", "b", "c"]));
+        assert_eq!(apply("missing.field", &device), Value::Null);
+        assert_eq!(apply("usersLoggedOn || `\"none\"`", &device), json!("none"));
+        assert_eq!(
+            apply("{id: id, model: hardwareInformation.model, pending: length(deviceActionResults[?actionState != 'done'])}", &device),
+            json!({"id": "d1", "model": "Surface", "pending": 1})
+        );
+        assert_eq!(apply("[id, deviceName]", &device), json!(["d1", "LAPTOP-1"]));
+    }
+
+    #[test]
+    fn test_transform_functions() {
+        let record = json!({
+            "id": "d1",
+            "complianceState": "noncompliant",
+            "isEncrypted": false,
+            "storage": [100, 250.5, 50],
+            "disks": [{ "name": "C", "freeGb": 12 }, { "name": "D", "freeGb": 340 }]
+        });
+
+        assert_eq!(
+            apply("merge(@, {compliant: complianceState == 'compliant', unencrypted: !isEncrypted})", &record)["unencrypted"],
+            json!(true)
+        );
+        assert_eq!(apply("sum(storage)", &record), json!(400.5));
+        assert_eq!(apply("max_by(disks, &freeGb).name", &record), json!("D"));
+        assert_eq!(apply("sort_by(disks, &freeGb)[0].name", &record), json!("C"));
+        assert_eq!(apply("map(&name, disks)", &record), json!(["C", "D"]));
+        assert_eq!(apply("not_null(missing, id)", &record), json!("d1"));
+        assert!(Transform::parse("length(id)").unwrap().apply(&json!({"id": 5})).is_err());
+    }
+
+    #[test]
+    fn test_transform_parse_errors() {
+        for invalid in ["", "a.", "{a b}", "a = b", "'unterminated", "`{bad json`"] {
+            assert!(Transform::parse(invalid).is_err(), "{} should not parse", invalid);
+        }
+    }
+}
@@ -3,7 +3,18 @@ use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use uuid::Uuid;
 
-use crate::fingerprint::{extract_device_identifiers, generate_device_fingerprint};
+use crate::fingerprint::{extract_device_identifiers, generate_device_fingerprint, FieldNormalization};
+
+/// How a device UUID is derived from its fingerprint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UuidScheme {
+    /// Salted SHA-256 with the version 4 bits set by hand
+    #[default]
+    Sha256,
+    /// Standard UUIDv5, reproducible by any RFC 4122 implementation given the
+    /// same namespace and fingerprint
+    V5 { namespace: Uuid },
+}
 
 /// Generates or validates a UUID for a device
 /// 
@@ -11,6 +22,8 @@ use crate::fingerprint::{extract_device_identifiers, generate_device_fingerprint
 /// Otherwise, it generates a deterministic UUID based on device fingerprint.
 pub fn get_or_generate_device_uuid(
     device_data: &HashMap<String, serde_json::Value>,
+    scheme: UuidScheme,
+    normalization: &FieldNormalization,
 ) -> Result<Uuid, uuid::Error> {
     // First, check if device already has a valid UUID
     if let Some(existing_uuid) = device_data.get("id").or_else(|| device_data.get("uuid")) {
@@ -34,9 +47,10 @@ pub fn get_or_generate_device_uuid(
         azure_id.as_deref(),
         model.as_deref(),
         enrolled.as_deref(),
+        normalization,
     );
 
-    let uuid = generate_uuid_from_fingerprint(&fingerprint);
+    let uuid = generate_uuid_from_fingerprint(&fingerprint, scheme);
     debug!("Generated UUID {} from fingerprint {}", uuid, fingerprint);
     
     Ok(uuid)
@@ -44,8 +58,12 @@ pub fn get_or_generate_device_uuid(
 
 /// Generates a deterministic UUID from a fingerprint string
 /// 
-/// Uses SHA256 hash of the fingerprint, truncated to 16 bytes for UUID v4
-fn generate_uuid_from_fingerprint(fingerprint: &str) -> Uuid {
+/// The default scheme uses a SHA256 hash of the fingerprint, truncated to 16 bytes for UUID v4
+fn generate_uuid_from_fingerprint(fingerprint: &str, scheme: UuidScheme) -> Uuid {
+    if let UuidScheme::V5 { namespace } = scheme {
+        return Uuid::new_v5(&namespace, fingerprint.as_bytes());
+    }
+
     let mut hasher = Sha256::new();
     hasher.update(fingerprint.as_bytes());
     hasher.update(b"uuid_generation_salt"); // Add salt for UUID generation
@@ -68,17 +86,17 @@ pub fn is_valid_uuid(uuid_str: &str) -> bool {
     Uuid::parse_str(uuid_str).is_ok()
 }
 
-/// Extracts device name for logging purposes
-pub fn get_device_name(device_data: &HashMap<String, serde_json::Value>) -> String {
-    device_data
-        .get("deviceName")
-        .and_then(|v| v.as_str())
-        .unwrap_or_else(|| {
-            device_data
-                .get("displayName")
-                .and_then(|v| v.as_str())
-                .unwrap_or("unknown")
-        })
+/// Fields tried in order when no `deviceNameFields` are configured
+pub const DEFAULT_DEVICE_NAME_FIELDS: &[&str] = &["deviceName", "displayName"];
+
+/// Extracts device name for logging purposes: the first of `name_fields` holding a
+/// non-empty string, otherwise "unknown"
+pub fn get_device_name<S: AsRef<str>>(device_data: &HashMap<String, serde_json::Value>, name_fields: &[S]) -> String {
+    name_fields
+        .iter()
+        .filter_map(|field| device_data.get(field.as_ref()).and_then(|v| v.as_str()))
+        .find(|name| !name.is_empty())
+        .unwrap_or("unknown")
         .to_string()
 }
 
@@ -110,9 +128,20 @@ impl DeviceInfo {
     /// Creates a new DeviceInfo from raw device data
     pub fn from_device_data(
         device_data: HashMap<String, serde_json::Value>,
+        scheme: UuidScheme,
+    ) -> Result<Self, uuid::Error> {
+        Self::from_normalized_device_data(device_data, scheme, &FieldNormalization::new())
+    }
+
+    /// Like [`from_device_data`](Self::from_device_data), normalizing identifiers by
+    /// `normalizeFields` before fingerprinting
+    pub(crate) fn from_normalized_device_data(
+        device_data: HashMap<String, serde_json::Value>,
+        scheme: UuidScheme,
+        normalization: &FieldNormalization,
     ) -> Result<Self, uuid::Error> {
-        let uuid = get_or_generate_device_uuid(&device_data)?;
-        let name = get_device_name(&device_data);
+        let uuid = get_or_generate_device_uuid(&device_data, scheme, normalization)?;
+        let name = get_device_name(&device_data, DEFAULT_DEVICE_NAME_FIELDS);
         let os = get_device_os(&device_data);
         
         let (serial, imei, hw_id, azure_id, model, enrolled) = extract_device_identifiers(&device_data);
@@ -123,6 +152,7 @@ impl DeviceInfo {
             azure_id.as_deref(),
             model.as_deref(),
             enrolled.as_deref(),
+            normalization,
         );
 
         Ok(DeviceInfo {
@@ -143,20 +173,34 @@ mod tests {
     #[test]
     fn test_generate_uuid_from_fingerprint() {
         let fingerprint = "test_fingerprint";
-        let uuid1 = generate_uuid_from_fingerprint(fingerprint);
-        let uuid2 = generate_uuid_from_fingerprint(fingerprint);
+        let uuid1 = generate_uuid_from_fingerprint(fingerprint, UuidScheme::Sha256);
+        let uuid2 = generate_uuid_from_fingerprint(fingerprint, UuidScheme::Sha256);
         
         // Same fingerprint should generate same UUID
         assert_eq!(uuid1, uuid2);
         
         // Different fingerprint should generate different UUID
-        let uuid3 = generate_uuid_from_fingerprint("different_fingerprint");
+        let uuid3 = generate_uuid_from_fingerprint("different_fingerprint", UuidScheme::Sha256);
         assert_ne!(uuid1, uuid3);
         
         // Verify it's a valid UUID v4
         assert_eq!(uuid1.get_version(), Some(uuid::Version::Random));
     }
 
+    #[test]
+    fn test_generate_uuid_v5_from_fingerprint() {
+        let namespace = Uuid::parse_str("6ba7b811-9dad-11d1-80b4-00c04fd430c8").unwrap();
+        let scheme = UuidScheme::V5 { namespace };
+
+        // Python: uuid.uuid5(uuid.NAMESPACE_URL, "python.org")
+        let uuid = generate_uuid_from_fingerprint("python.org", scheme);
+        assert_eq!(uuid.to_string(), "7af94e2b-4dd9-50f0-9c9a-8a48519bdef0");
+        assert_eq!(uuid.get_version(), Some(uuid::Version::Sha1));
+
+        let other = UuidScheme::V5 { namespace: Uuid::NAMESPACE_OID };
+        assert_ne!(generate_uuid_from_fingerprint("python.org", other), uuid);
+    }
+
     #[test]
     fn test_get_or_generate_device_uuid() {
         // Test with existing valid UUID
@@ -164,19 +208,19 @@ mod tests {
         let existing_uuid = Uuid::new_v4();
         device_data.insert("id".to_string(), json!(existing_uuid.to_string()));
         
-        let result = get_or_generate_device_uuid(&device_data).unwrap();
+        let result = get_or_generate_device_uuid(&device_data, UuidScheme::default(), &HashMap::new()).unwrap();
         assert_eq!(result, existing_uuid);
         
         // Test with invalid UUID (should generate new one)
         device_data.insert("id".to_string(), json!("invalid-uuid"));
         device_data.insert("serialNumber".to_string(), json!("ABC123"));
         
-        let result = get_or_generate_device_uuid(&device_data).unwrap();
+        let result = get_or_generate_device_uuid(&device_data, UuidScheme::default(), &HashMap::new()).unwrap();
         assert_ne!(result, existing_uuid);
         
         // Test with no UUID (should generate from fingerprint)
         device_data.remove("id");
-        let result2 = get_or_generate_device_uuid(&device_data).unwrap();
+        let result2 = get_or_generate_device_uuid(&device_data, UuidScheme::default(), &HashMap::new()).unwrap();
         assert_eq!(result, result2); // Should be deterministic
     }
 
@@ -192,17 +236,38 @@ mod tests {
         let mut device_data = HashMap::new();
         device_data.insert("deviceName".to_string(), json!("Test Device"));
         
-        assert_eq!(get_device_name(&device_data), "Test Device");
+        assert_eq!(get_device_name(&device_data, DEFAULT_DEVICE_NAME_FIELDS), "Test Device");
         
         // Test fallback to displayName
         device_data.remove("deviceName");
         device_data.insert("displayName".to_string(), json!("Display Name"));
         
-        assert_eq!(get_device_name(&device_data), "Display Name");
+        assert_eq!(get_device_name(&device_data, DEFAULT_DEVICE_NAME_FIELDS), "Display Name");
         
         // Test fallback to unknown
         device_data.remove("displayName");
-        assert_eq!(get_device_name(&device_data), "unknown");
+        assert_eq!(get_device_name(&device_data, DEFAULT_DEVICE_NAME_FIELDS), "unknown");
+    }
+
+    #[test]
+    fn test_get_device_name_configured_fields() {
+        let mut device_data = HashMap::new();
+        device_data.insert("deviceName".to_string(), json!(""));
+        device_data.insert("managedDeviceName".to_string(), json!("jdoe_Windows_1/2/2025"));
+        device_data.insert("serialNumber".to_string(), json!("SN-12345"));
+        device_data.insert("displayName".to_string(), json!("Display Name"));
+
+        let fields = ["serialNumber".to_string(), "managedDeviceName".to_string()];
+        assert_eq!(get_device_name(&device_data, &fields), "SN-12345");
+
+        // Empty and non-string values are skipped
+        let fields = ["deviceName", "enrolled", "managedDeviceName"];
+        device_data.insert("enrolled".to_string(), json!(true));
+        assert_eq!(get_device_name(&device_data, &fields), "jdoe_Windows_1/2/2025");
+
+        let fields = ["deviceName", "azureADDeviceId"];
+        assert_eq!(get_device_name(&device_data, &fields), "unknown");
+        assert_eq!(get_device_name::<String>(&device_data, &[]), "unknown");
     }
 
     #[test]
@@ -230,7 +295,7 @@ mod tests {
         device_data.insert("operatingSystem".to_string(), json!("Windows"));
         device_data.insert("serialNumber".to_string(), json!("ABC123"));
         
-        let device_info = DeviceInfo::from_device_data(device_data).unwrap();
+        let device_info = DeviceInfo::from_device_data(device_data, UuidScheme::default()).unwrap();
         
         assert_eq!(device_info.name, "Test Device");
         assert_eq!(device_info.os, Some("Windows".to_string()));
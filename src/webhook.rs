@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -7,6 +8,8 @@ use log::{info, warn, error, debug};
 use reqwest::Client;
 use tokio::time::timeout;
 
+use crate::rate_limiter::RetryBudget;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebhookConfig {
     pub enabled: bool,
@@ -14,9 +17,43 @@ pub struct WebhookConfig {
     pub timeout_seconds: u64,
     pub retry_attempts: u32,
     pub retry_delay_seconds: u64,
+    /// Events to send; empty sends every event not in `exclude_events`
     pub events: Vec<WebhookEvent>,
+    /// Events never sent when `events` is empty; ignored when `events` lists some
+    #[serde(default, alias = "excludeEvents", skip_serializing_if = "Vec::is_empty")]
+    pub exclude_events: Vec<WebhookEvent>,
     pub headers: Option<HashMap<String, String>>,
     pub secret: Option<String>,
+    /// Largest request body sent; bigger `data` fields are left out
+    #[serde(default = "default_max_payload_bytes")]
+    pub max_payload_bytes: usize,
+    /// Buffer events and send them as one JSON array this often instead of one request
+    /// per event; unset sends every event right away
+    #[serde(default, alias = "digestIntervalSeconds", skip_serializing_if = "Option::is_none")]
+    pub digest_interval_seconds: Option<u64>,
+    /// Buffered events that trigger a digest before the interval is up
+    #[serde(default = "default_digest_max_events", alias = "digestMaxEvents")]
+    pub digest_max_events: usize,
+}
+
+fn default_max_payload_bytes() -> usize {
+    65536
+}
+
+fn default_digest_max_events() -> usize {
+    100
+}
+
+impl WebhookConfig {
+    /// Whether `event` is selected: an explicit `events` list wins, otherwise every event
+    /// except `exclude_events`
+    pub fn sends_event(&self, event: &WebhookEvent) -> bool {
+        if self.events.is_empty() {
+            !self.exclude_events.contains(event)
+        } else {
+            self.events.contains(event)
+        }
+    }
 }
 
 impl Default for WebhookConfig {
@@ -31,10 +68,16 @@ impl Default for WebhookConfig {
                 WebhookEvent::SyncStarted,
                 WebhookEvent::SyncCompleted,
                 WebhookEvent::SyncFailed,
+                WebhookEvent::SyncAborted,
+                WebhookEvent::DeletesRefused,
                 WebhookEvent::DevicesUpdated,
             ],
+            exclude_events: Vec::new(),
             headers: None,
             secret: None,
+            max_payload_bytes: default_max_payload_bytes(),
+            digest_interval_seconds: None,
+            digest_max_events: default_digest_max_events(),
         }
     }
 }
@@ -45,10 +88,30 @@ pub enum WebhookEvent {
     SyncStarted,
     SyncCompleted,
     SyncFailed,
+    SyncAborted,
+    DeletesRefused,
     DevicesUpdated,
     DatabaseError,
     AuthenticationFailed,
     ConfigurationChanged,
+    EndpointDisabled,
+    BackendCircuitChanged,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 11] = [
+        WebhookEvent::SyncStarted,
+        WebhookEvent::SyncCompleted,
+        WebhookEvent::SyncFailed,
+        WebhookEvent::SyncAborted,
+        WebhookEvent::DeletesRefused,
+        WebhookEvent::DevicesUpdated,
+        WebhookEvent::DatabaseError,
+        WebhookEvent::AuthenticationFailed,
+        WebhookEvent::ConfigurationChanged,
+        WebhookEvent::EndpointDisabled,
+        WebhookEvent::BackendCircuitChanged,
+    ];
 }
 
 #[derive(Debug, Serialize)]
@@ -83,6 +146,25 @@ pub struct SyncFailedData {
     pub duration_seconds: f64,
 }
 
+#[derive(Debug, Serialize)]
+pub struct SyncAbortedData {
+    pub endpoint: String,
+    pub table: String,
+    pub records_fetched: usize,
+    pub rows_stored: usize,
+    pub min_expected_ratio: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletesRefusedData {
+    pub endpoint: String,
+    pub table: String,
+    pub rows_missing: usize,
+    pub rows_stored: usize,
+    pub percent: f64,
+    pub max_delete_percent: f64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct DevicesUpdatedData {
     pub sync_id: String,
@@ -102,11 +184,35 @@ pub struct DatabaseErrorData {
 pub struct AuthenticationFailedData {
     pub error: String,
     pub tenant_id: String,
+    /// Machine-readable cause, e.g. `expired_secret` or `conditional_access`
+    pub reason_code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EndpointDisabledData {
+    pub endpoint: String,
+    pub consecutive_failures: u32,
+    pub disabled_until: DateTime<Utc>,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackendCircuitData {
+    pub backend: String,
+    /// `open` when writes to the backend stop, `closed` when they resume
+    pub state: String,
+    pub consecutive_failures: u32,
+    pub open_until: Option<DateTime<Utc>>,
+    pub error: Option<String>,
 }
 
+#[derive(Debug)]
 pub struct WebhookManager {
     config: WebhookConfig,
     client: Client,
+    retry_budget: RetryBudget,
+    /// Events waiting for the next digest, in the order they happened
+    digest: Mutex<Vec<serde_json::Value>>,
 }
 
 impl WebhookManager {
@@ -116,7 +222,78 @@ impl WebhookManager {
             .build()
             .expect("Failed to create HTTP client for webhooks");
 
-        Self { config, client }
+        Self { config, client, retry_budget: RetryBudget::default(), digest: Mutex::new(Vec::new()) }
+    }
+
+    /// Send the buffered events every `digest_interval_seconds`, for as long as the
+    /// manager is alive. Does nothing in immediate mode.
+    pub fn start_digest(self: &Arc<Self>) {
+        let Some(seconds) = self.config.digest_interval_seconds else { return };
+        let manager = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(seconds.max(1)));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(manager) = manager.upgrade() else { break };
+                if let Err(e) = manager.flush_digest().await {
+                    warn!("Failed to send webhook digest: {}", e);
+                }
+            }
+        });
+    }
+
+    fn digest_enabled(&self) -> bool {
+        self.config.digest_interval_seconds.is_some()
+    }
+
+    /// Events buffered for the next digest
+    pub fn pending_digest_events(&self) -> usize {
+        self.digest.lock().map(|digest| digest.len()).unwrap_or(0)
+    }
+
+    /// Send every buffered event now as JSON arrays of at most `max_payload_bytes`;
+    /// called on the interval, when the buffer is full and on shutdown. Events of a
+    /// digest that cannot be sent go back into the buffer for the next flush.
+    pub async fn flush_digest(&self) -> Result<()> {
+        let events = match self.digest.lock() {
+            Ok(mut digest) => std::mem::take(&mut *digest),
+            Err(_) => return Ok(()),
+        };
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let mut batches = split_digest(events, self.config.max_payload_bytes).into_iter();
+        while let Some(batch) = batches.next() {
+            let body = serde_json::to_vec(&batch)?;
+            if let Err(e) = self.deliver(&format!("digest of {} events", batch.len()), &body).await {
+                let unsent: Vec<serde_json::Value> = batch.into_iter().chain(batches.flatten()).collect();
+                self.requeue_digest(unsent);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Put events that could not be sent back in front of the ones buffered since,
+    /// dropping the oldest past `MAX_PENDING_DIGEST_EVENTS`
+    fn requeue_digest(&self, mut unsent: Vec<serde_json::Value>) {
+        let Ok(mut digest) = self.digest.lock() else { return };
+        unsent.append(&mut digest);
+        let dropped = unsent.len().saturating_sub(MAX_PENDING_DIGEST_EVENTS);
+        if dropped > 0 {
+            warn!("Webhook digest buffer is full; dropping the {} oldest events", dropped);
+            unsent.drain(..dropped);
+        }
+        warn!("Keeping {} webhook events for the next digest", unsent.len());
+        *digest = unsent;
+    }
+
+    /// Draw retries from the budget of the sync the webhooks report on
+    pub fn with_retry_budget(mut self, retry_budget: RetryBudget) -> Self {
+        self.retry_budget = retry_budget;
+        self
     }
 
     pub fn is_enabled(&self) -> bool {
@@ -124,7 +301,7 @@ impl WebhookManager {
     }
 
     pub fn should_send_event(&self, event: &WebhookEvent) -> bool {
-        self.is_enabled() && self.config.events.contains(event)
+        self.is_enabled() && self.config.sends_event(event)
     }
 
     pub async fn send_sync_started(&self, sync_id: String, scheduled: bool) -> Result<()> {
@@ -173,6 +350,22 @@ impl WebhookManager {
         self.send_webhook(WebhookEvent::SyncFailed, serde_json::to_value(data)?).await
     }
 
+    pub async fn send_sync_aborted(&self, data: SyncAbortedData) -> Result<()> {
+        if !self.should_send_event(&WebhookEvent::SyncAborted) {
+            return Ok(());
+        }
+
+        self.send_webhook(WebhookEvent::SyncAborted, serde_json::to_value(data)?).await
+    }
+
+    pub async fn send_deletes_refused(&self, data: DeletesRefusedData) -> Result<()> {
+        if !self.should_send_event(&WebhookEvent::DeletesRefused) {
+            return Ok(());
+        }
+
+        self.send_webhook(WebhookEvent::DeletesRefused, serde_json::to_value(data)?).await
+    }
+
     pub async fn send_devices_updated(&self, sync_id: String, updated_count: u32, inserted_count: u32, total_devices: u32) -> Result<()> {
         if !self.should_send_event(&WebhookEvent::DevicesUpdated) {
             return Ok(());
@@ -200,47 +393,101 @@ impl WebhookManager {
         self.send_webhook(WebhookEvent::DatabaseError, serde_json::to_value(data)?).await
     }
 
-    pub async fn send_authentication_failed(&self, error: String, tenant_id: String) -> Result<()> {
+    pub async fn send_authentication_failed(&self, error: String, tenant_id: String, reason_code: Option<String>) -> Result<()> {
         if !self.should_send_event(&WebhookEvent::AuthenticationFailed) {
             return Ok(());
         }
 
-        let data = AuthenticationFailedData { error, tenant_id };
+        let data = AuthenticationFailedData { error, tenant_id, reason_code };
         self.send_webhook(WebhookEvent::AuthenticationFailed, serde_json::to_value(data)?).await
     }
 
+    pub async fn send_endpoint_disabled(
+        &self,
+        endpoint: String,
+        consecutive_failures: u32,
+        disabled_until: DateTime<Utc>,
+        error: String,
+    ) -> Result<()> {
+        if !self.should_send_event(&WebhookEvent::EndpointDisabled) {
+            return Ok(());
+        }
+
+        let data = EndpointDisabledData {
+            endpoint,
+            consecutive_failures,
+            disabled_until,
+            error,
+        };
+        self.send_webhook(WebhookEvent::EndpointDisabled, serde_json::to_value(data)?).await
+    }
+
+    pub async fn send_backend_circuit_changed(&self, data: BackendCircuitData) -> Result<()> {
+        if !self.should_send_event(&WebhookEvent::BackendCircuitChanged) {
+            return Ok(());
+        }
+
+        self.send_webhook(WebhookEvent::BackendCircuitChanged, serde_json::to_value(data)?).await
+    }
+
     async fn send_webhook(&self, event: WebhookEvent, data: serde_json::Value) -> Result<()> {
-        let payload = WebhookPayload {
+        let mut payload = WebhookPayload {
             event: event.clone(),
             timestamp: Utc::now(),
             service: "IntuneDeviceDatabaseSynchronization".to_string(),
             version: env!("CARGO_PKG_VERSION").to_string(),
             data,
         };
+        let body = fit_payload(&mut payload, self.config.max_payload_bytes)?;
+
+        if self.digest_enabled() {
+            let buffered = match self.digest.lock() {
+                Ok(mut digest) => {
+                    digest.push(serde_json::from_slice(&body)?);
+                    digest.len()
+                }
+                Err(_) => return Err(anyhow::anyhow!("Webhook digest buffer is poisoned")),
+            };
+            debug!("Buffered webhook for event {:?} ({} pending)", event, buffered);
+            if buffered >= self.config.digest_max_events.max(1) {
+                return self.flush_digest().await;
+            }
+            return Ok(());
+        }
 
-        debug!("Sending webhook for event: {:?}", event);
+        self.deliver(&format!("event {:?}", event), &body).await
+    }
+
+    /// POST a request body, retrying per the config; `what` names it in the log
+    async fn deliver(&self, what: &str, body: &[u8]) -> Result<()> {
+        debug!("Sending webhook for {}", what);
 
         for attempt in 1..=self.config.retry_attempts {
-            match self.send_webhook_attempt(&payload).await {
+            match self.send_webhook_attempt(body).await {
                 Ok(_) => {
-                    info!("Webhook sent successfully for event: {:?}", event);
+                    info!("Webhook sent successfully for {}", what);
                     return Ok(());
                 }
                 Err(e) => {
-                    warn!("Webhook attempt {} failed for event {:?}: {}", attempt, event, e);
+                    warn!("Webhook attempt {} failed for {}: {}", attempt, what, e);
                     
                     if attempt < self.config.retry_attempts {
-                        tokio::time::sleep(Duration::from_secs(self.config.retry_delay_seconds)).await;
+                        let delay = Duration::from_secs(self.config.retry_delay_seconds);
+                        if let Err(exhausted) = self.retry_budget.spend(delay) {
+                            error!("Giving up on webhook for {}: {}", what, exhausted);
+                            return Err(e.context(exhausted));
+                        }
+                        tokio::time::sleep(delay).await;
                     }
                 }
             }
         }
 
-        error!("All webhook attempts failed for event: {:?}", event);
+        error!("All webhook attempts failed for {}", what);
         Err(anyhow::anyhow!("Failed to send webhook after {} attempts", self.config.retry_attempts))
     }
 
-    async fn send_webhook_attempt(&self, payload: &WebhookPayload) -> Result<()> {
+    async fn send_webhook_attempt(&self, body: &[u8]) -> Result<()> {
         let mut request = self.client.post(&self.config.url);
 
         // Add custom headers
@@ -261,7 +508,7 @@ impl WebhookManager {
         // Send request with timeout
         let response = timeout(
             Duration::from_secs(self.config.timeout_seconds),
-            request.json(payload).send()
+            request.body(body.to_vec()).send()
         ).await
         .context("Webhook request timed out")?
         .context("Failed to send webhook request")?;
@@ -283,6 +530,77 @@ impl WebhookManager {
     }
 }
 
+/// Events kept for the next digest while the webhook endpoint cannot be reached
+const MAX_PENDING_DIGEST_EVENTS: usize = 10_000;
+
+/// Split buffered events into digests whose JSON array fits in `max_bytes`, keeping
+/// their order. Each event already fits on its own, so it never has to be cut.
+fn split_digest(events: Vec<serde_json::Value>, max_bytes: usize) -> Vec<Vec<serde_json::Value>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    // The brackets of the array
+    let mut batch_bytes = 2;
+    for event in events {
+        let event_bytes = event.to_string().len();
+        // Events after the first are preceded by a comma
+        if !batch.is_empty() && batch_bytes + 1 + event_bytes > max_bytes {
+            batches.push(std::mem::take(&mut batch));
+            batch_bytes = 2;
+        }
+        batch_bytes += event_bytes + usize::from(!batch.is_empty());
+        batch.push(event);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Serialize a payload, leaving out the largest `data` fields until it fits in
+/// `max_bytes`. The names of the left out fields are listed in `data.omitted_fields`.
+/// Payloads only carry counts and short messages, so this guards against fields that
+/// grow with the tenant, like an embedded device list.
+fn fit_payload(payload: &mut WebhookPayload, max_bytes: usize) -> Result<Vec<u8>> {
+    let mut body = serde_json::to_vec(payload)?;
+    if body.len() <= max_bytes {
+        return Ok(body);
+    }
+
+    let original_bytes = body.len();
+    let mut omitted = Vec::new();
+    while body.len() > max_bytes {
+        let largest = payload.data.as_object().and_then(|data| {
+            data.iter()
+                .filter(|(key, _)| key.as_str() != "omitted_fields")
+                .max_by_key(|(_, value)| value.to_string().len())
+                .map(|(key, _)| key.clone())
+        });
+        match largest {
+            Some(key) => {
+                if let Some(data) = payload.data.as_object_mut() {
+                    data.remove(&key);
+                    omitted.push(key);
+                    data.insert("omitted_fields".to_string(), serde_json::json!(omitted));
+                }
+            }
+            // Nothing left to remove; send the envelope alone
+            None => {
+                payload.data = serde_json::Value::Null;
+                body = serde_json::to_vec(payload)?;
+                break;
+            }
+        }
+        body = serde_json::to_vec(payload)?;
+    }
+
+    warn!(
+        "Webhook payload for event {:?} was {} bytes, more than max_payload_bytes ({}); left out: {}",
+        payload.event, original_bytes, max_bytes,
+        if omitted.is_empty() { "all data".to_string() } else { omitted.join(", ") }
+    );
+    Ok(body)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -293,6 +611,7 @@ mod tests {
         assert!(!config.enabled);
         assert_eq!(config.timeout_seconds, 30);
         assert_eq!(config.retry_attempts, 3);
+        assert_eq!(config.max_payload_bytes, 65536);
         assert!(config.events.contains(&WebhookEvent::SyncStarted));
     }
 
@@ -309,6 +628,37 @@ mod tests {
         assert!(manager.should_send_event(&WebhookEvent::SyncStarted));
     }
 
+    #[test]
+    fn test_exclude_events() {
+        let config = WebhookConfig {
+            enabled: true,
+            url: "https://example.com/webhook".to_string(),
+            events: Vec::new(),
+            exclude_events: vec![WebhookEvent::DatabaseError],
+            ..Default::default()
+        };
+        let manager = WebhookManager::new(config.clone());
+        assert!(!manager.should_send_event(&WebhookEvent::DatabaseError));
+        assert!(WebhookEvent::ALL.iter()
+            .filter(|event| **event != WebhookEvent::DatabaseError)
+            .all(|event| manager.should_send_event(event)));
+
+        // An explicit events list wins over the excludes
+        let manager = WebhookManager::new(WebhookConfig {
+            events: vec![WebhookEvent::SyncFailed, WebhookEvent::DatabaseError],
+            ..config
+        });
+        assert!(manager.should_send_event(&WebhookEvent::DatabaseError));
+        assert!(!manager.should_send_event(&WebhookEvent::SyncStarted));
+
+        let parsed: WebhookConfig = serde_json::from_value(serde_json::json!({
+            "enabled": true, "url": "https://example.com/webhook", "timeout_seconds": 30,
+            "retry_attempts": 3, "retry_delay_seconds": 5, "events": [],
+            "excludeEvents": ["database_error"], "headers": null, "secret": null
+        })).unwrap();
+        assert_eq!(parsed.exclude_events, vec![WebhookEvent::DatabaseError]);
+    }
+
     #[test]
     fn test_webhook_manager_disabled() {
         let config = WebhookConfig::default();
@@ -317,6 +667,133 @@ mod tests {
         assert!(!manager.should_send_event(&WebhookEvent::SyncStarted));
     }
 
+    #[test]
+    fn test_fit_payload() {
+        let payload = |data| WebhookPayload {
+            event: WebhookEvent::SyncCompleted,
+            timestamp: Utc::now(),
+            service: "IntuneDeviceDatabaseSynchronization".to_string(),
+            version: "1.0".to_string(),
+            data,
+        };
+
+        let mut small = payload(serde_json::json!({"sync_id": "sync-1", "devices_fetched": 10}));
+        let body = fit_payload(&mut small, 65536).unwrap();
+        assert_eq!(body, serde_json::to_vec(&small).unwrap());
+        assert!(small.data.get("omitted_fields").is_none());
+
+        let devices: Vec<_> = (0..1000).map(|i| serde_json::json!({"id": i, "deviceName": "PC"})).collect();
+        let mut large = payload(serde_json::json!({"sync_id": "sync-1", "devices_fetched": 1000, "devices": devices}));
+        let body = fit_payload(&mut large, 1024).unwrap();
+        assert!(body.len() <= 1024);
+        let sent: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sent["data"]["sync_id"], "sync-1");
+        assert_eq!(sent["data"]["devices_fetched"], 1000);
+        assert_eq!(sent["data"]["omitted_fields"], serde_json::json!(["devices"]));
+
+        // Even the envelope alone is too big: data is dropped entirely
+        let mut tiny = payload(serde_json::json!({"sync_id": "sync-1"}));
+        let body = fit_payload(&mut tiny, 10).unwrap();
+        let sent: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(sent["data"].is_null());
+        assert_eq!(sent["event"], "sync_completed");
+    }
+
+    #[tokio::test]
+    async fn test_webhook_digest() {
+        use axum::{routing::post, Json, Router};
+
+        let received = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let sink = received.clone();
+        let app = Router::new().route("/webhook", post(move |Json(body): Json<serde_json::Value>| async move {
+            sink.lock().unwrap().push(body);
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let manager = WebhookManager::new(WebhookConfig {
+            enabled: true,
+            url: format!("http://{}/webhook", addr),
+            digest_interval_seconds: Some(3600),
+            digest_max_events: 3,
+            ..Default::default()
+        });
+
+        manager.send_sync_started("sync-1".to_string(), true).await.unwrap();
+        manager.send_sync_failed("sync-1".to_string(), "boom".to_string(), 1.0).await.unwrap();
+        assert_eq!(manager.pending_digest_events(), 2);
+        assert!(received.lock().unwrap().is_empty());
+
+        // The third event fills the buffer and sends all three as one array
+        manager.send_sync_started("sync-2".to_string(), true).await.unwrap();
+        assert_eq!(manager.pending_digest_events(), 0);
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let events = received[0].as_array().unwrap();
+            assert_eq!(events.len(), 3);
+            assert_eq!(events[0]["event"], "sync_started");
+            assert_eq!(events[1]["event"], "sync_failed");
+            assert_eq!(events[2]["data"]["sync_id"], "sync-2");
+        }
+
+        // Shutdown sends whatever is left
+        manager.send_sync_started("sync-3".to_string(), true).await.unwrap();
+        manager.flush_digest().await.unwrap();
+        manager.flush_digest().await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert_eq!(received.lock().unwrap()[1].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_split_digest() {
+        let events: Vec<serde_json::Value> = (0..5).map(|i| serde_json::json!({"n": i})).collect();
+        let event_bytes = events[0].to_string().len();
+
+        // Room for two events, their comma and the brackets
+        let batches = split_digest(events.clone(), 2 * event_bytes + 3);
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 1]);
+        assert!(batches.iter().all(|batch| serde_json::to_vec(batch).unwrap().len() <= 2 * event_bytes + 3));
+        assert_eq!(batches.concat(), events);
+
+        assert_eq!(split_digest(events.clone(), 65536).len(), 1);
+        // An event larger than the limit still goes out, on its own
+        assert_eq!(split_digest(events, 1).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_failed_digest_is_kept() {
+        let mut server = mockito::Server::new_async().await;
+        let failing = server.mock("POST", "/webhook").with_status(500).expect(1).create_async().await;
+
+        let manager = WebhookManager::new(WebhookConfig {
+            enabled: true,
+            url: format!("{}/webhook", server.url()),
+            retry_attempts: 1,
+            digest_interval_seconds: Some(3600),
+            ..Default::default()
+        });
+        manager.send_sync_started("sync-1".to_string(), true).await.unwrap();
+        manager.send_sync_started("sync-2".to_string(), true).await.unwrap();
+
+        assert!(manager.flush_digest().await.is_err());
+        failing.assert_async().await;
+        assert_eq!(manager.pending_digest_events(), 2);
+
+        // Sent with the next flush, oldest first
+        failing.remove_async().await;
+        let delivered = server.mock("POST", "/webhook")
+            .match_body(mockito::Matcher::Regex(r#"sync-1.*sync-2.*sync-3"#.to_string()))
+            .expect(1)
+            .create_async()
+            .await;
+        manager.send_sync_started("sync-3".to_string(), true).await.unwrap();
+        manager.flush_digest().await.unwrap();
+        delivered.assert_async().await;
+        assert_eq!(manager.pending_digest_events(), 0);
+    }
+
     #[test]
     fn test_webhook_secret_header() {
         let config = WebhookConfig {